@@ -0,0 +1,122 @@
+// Command line arguments
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: lst [-i IMAGE] [--eval EXPRESSION]...
+       lst [-i IMAGE] run FILE...
+       lst [-i IMAGE] build-image SOURCES... -o OUTPUT
+
+Without a command, starts an interactive shell.
+
+options:
+  -i, --image IMAGE   start from the image instead of an empty machine
+  -e, --eval EXPR     evaluate the expression and print the result
+  -o, --output FILE   file where build-image saves the image
+  -h, --help          show this message
+
+build-image loads the given source files, and the .st files in the given
+directories (sorted by name), then saves the image.
+
+exit status:
+  0  success
+  1  uncaught Smalltalk error
+  2  invalid arguments
+  3  syntax error in the sources
+  4  error reading or writing files or images";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Repl,
+    Help,
+    Eval(Vec<String>),
+    Run(Vec<PathBuf>),
+    BuildImage { sources: Vec<PathBuf>, output: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    pub image: Option<PathBuf>,
+    pub command: Command,
+}
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut image = None;
+    let mut output = None;
+    let mut expressions = vec![];
+    let mut command: Option<String> = None;
+    let mut operands = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Options { image, command: Command::Help }),
+            "-i" | "--image" => image = Some(PathBuf::from(value(&arg)?)),
+            "-e" | "--eval" => expressions.push(value(&arg)?),
+            "-o" | "--output" => output = Some(PathBuf::from(value(&arg)?)),
+            option if option.starts_with('-') && option.len() > 1 => {
+                return Err(format!("unknown option {}", option));
+            }
+            _ if command.is_none() => command = Some(arg),
+            _ => operands.push(PathBuf::from(arg)),
+        }
+    }
+
+    let command = match command.as_deref() {
+        None if expressions.is_empty() => Command::Repl,
+        None => Command::Eval(expressions),
+        Some(_) if !expressions.is_empty() => return Err("--eval can't be used with a command".to_string()),
+        Some("run") if operands.is_empty() => return Err("run needs the files to run".to_string()),
+        Some("run") => Command::Run(operands),
+        Some("build-image") if operands.is_empty() => return Err("build-image needs the sources".to_string()),
+        Some("build-image") => {
+            let output = output.take().ok_or("build-image needs an output file (-o)")?;
+            Command::BuildImage { sources: operands, output }
+        }
+        Some(command) => return Err(format!("unknown command {}", command)),
+    };
+    if output.is_some() {
+        return Err("-o can only be used with build-image".to_string());
+    }
+
+    Ok(Options { image, command })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Options, String> {
+        parse(line.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_cli_commands() {
+        assert_eq!(args(""), Ok(Options { image: None, command: Command::Repl }));
+        assert_eq!(args("-i base.lsti"), Ok(Options { image: Some("base.lsti".into()), command: Command::Repl }));
+        assert_eq!(args("--eval 3 -e 4"), Ok(Options {
+            image: None,
+            command: Command::Eval(vec!["3".to_string(), "4".to_string()]),
+        }));
+        assert_eq!(args("run a.st b.st -i base.lsti"), Ok(Options {
+            image: Some("base.lsti".into()),
+            command: Command::Run(vec!["a.st".into(), "b.st".into()]),
+        }));
+        assert_eq!(args("build-image sources -o out.lsti"), Ok(Options {
+            image: None,
+            command: Command::BuildImage { sources: vec!["sources".into()], output: "out.lsti".into() },
+        }));
+    }
+
+    #[test]
+    fn test_cli_errors() {
+        assert_eq!(args("run"), Err("run needs the files to run".to_string()));
+        assert_eq!(args("build-image sources"), Err("build-image needs an output file (-o)".to_string()));
+        assert_eq!(args("run a.st -o out"), Err("-o can only be used with build-image".to_string()));
+        assert_eq!(args("run a.st -e 3"), Err("--eval can't be used with a command".to_string()));
+        assert_eq!(args("-i"), Err("-i needs a value".to_string()));
+        assert_eq!(args("--verbose"), Err("unknown option --verbose".to_string()));
+        assert_eq!(args("compile a.st"), Err("unknown command compile".to_string()));
+    }
+}
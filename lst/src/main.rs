@@ -1,2 +1,160 @@
-fn main() {
+mod cli;
+mod repl;
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::env;
+
+use rustyline::error::ReadlineError;
+use vm::compiler::LoadError;
+use vm::execution::{ExecutionError, VirtualMachine};
+use vm::image::ImageError;
+
+use cli::{Command, Options};
+
+const EXIT_SMALLTALK_ERROR: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_SYNTAX_ERROR: u8 = 3;
+const EXIT_IO_ERROR: u8 = 4;
+
+// Errors that end the program
+#[derive(Debug)]
+enum Failure {
+    Load(LoadError),
+    Image(ImageError),
+    Io(PathBuf, io::Error),
+    Readline(ReadlineError),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::Load(LoadError::Execution(_)) => EXIT_SMALLTALK_ERROR,
+            Failure::Load(_) => EXIT_SYNTAX_ERROR,
+            Failure::Image(_) | Failure::Io(..) | Failure::Readline(_) => EXIT_IO_ERROR,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Load(err) => write!(f, "{}", err),
+            Failure::Image(err) => write!(f, "{}", err),
+            Failure::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            Failure::Readline(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<LoadError> for Failure {
+    fn from(err: LoadError) -> Self {
+        Failure::Load(err)
+    }
+}
+
+impl From<ExecutionError> for Failure {
+    fn from(err: ExecutionError) -> Self {
+        Failure::Load(LoadError::Execution(err))
+    }
+}
+
+impl From<ImageError> for Failure {
+    fn from(err: ImageError) -> Self {
+        Failure::Image(err)
+    }
+}
+
+impl From<ReadlineError> for Failure {
+    fn from(err: ReadlineError) -> Self {
+        Failure::Readline(err)
+    }
+}
+
+fn main() -> ExitCode {
+    let options = match cli::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("lst: {}\n\n{}", message, cli::USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+
+    match run(options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("lst: {}", err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+fn run(options: Options) -> Result<(), Failure> {
+    if options.command == Command::Help {
+        println!("{}", cli::USAGE);
+        return Ok(());
+    }
+
+    let mut vm = match &options.image {
+        Some(path) => VirtualMachine::load_image(path)?,
+        None => VirtualMachine::new()?,
+    };
+
+    match options.command {
+        Command::Help => {}
+        Command::Repl => repl::run(&mut vm)?,
+        Command::Eval(expressions) => {
+            for expression in expressions {
+                println!("{}", repl::evaluate(&mut vm, &expression)?);
+            }
+        }
+        Command::Run(files) => {
+            for file in files {
+                load_file(&mut vm, &file)?;
+            }
+        }
+        Command::BuildImage { sources, output } => {
+            for file in source_files(&sources)? {
+                load_file(&mut vm, &file)?;
+            }
+            vm.collect_garbage()?;
+            vm.compact()?;
+            vm.save_image(&output)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn load_file(vm: &mut VirtualMachine, path: &Path) -> Result<(), Failure> {
+    let source = fs::read_to_string(path).map_err(|err| Failure::Io(path.to_path_buf(), err))?;
+    Ok(vm.load_source(&path.display().to_string(), &source)?)
+}
+
+// Files given as sources, with directories replaced by the .st files in them,
+// sorted by name so that the order of loading can be controlled
+fn source_files(sources: &[PathBuf]) -> Result<Vec<PathBuf>, Failure> {
+    let mut files = vec![];
+    for source in sources {
+        if !source.is_dir() {
+            files.push(source.clone());
+            continue;
+        }
+
+        let entries = fs::read_dir(source).map_err(|err| Failure::Io(source.clone(), err))?;
+        let mut found = vec![];
+        for entry in entries {
+            let path = entry.map_err(|err| Failure::Io(source.clone(), err))?.path();
+            if path.is_file() && path.extension().is_some_and(|extension| extension == "st") {
+                found.push(path);
+            }
+        }
+        found.sort();
+        files.extend(found);
+    }
+
+    Ok(files)
 }
@@ -0,0 +1,194 @@
+// Interactive shell
+//
+// Reads expressions (and class definitions), evaluates them in the machine and
+// prints the printString of the result. Input goes on in the next line while an
+// expression is incomplete. History is kept in the file named by LST_HISTORY, or
+// in ~/.lst_history.
+
+use std::borrow::Cow;
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Editor, Helper};
+
+use vm::compiler::{needs_more_input, LoadError};
+use vm::execution::VirtualMachine;
+use vm::objects::{
+    number::{Float, Fraction},
+    object::{ObjectPointer, ObjectType, Pointer},
+};
+
+const PROMPT: &str = "lst> ";
+const HISTORY_FILE: &str = ".lst_history";
+
+pub fn run(vm: &mut VirtualMachine) -> Result<(), ReadlineError> {
+    let mut editor = Editor::<ShellHelper, FileHistory>::new()?;
+    editor.set_helper(Some(ShellHelper));
+
+    let history = history_file();
+    if let Some(path) = &history {
+        // There's no history the first time
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        match editor.readline(PROMPT) {
+            Ok(line) if line.trim().is_empty() => {}
+            Ok(line) => {
+                editor.add_history_entry(line.as_str())?;
+                match evaluate(vm, &line) {
+                    Ok(text) => println!("{}", text),
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            // Ctrl-C drops the current input
+            Err(ReadlineError::Interrupted) => {}
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    if let Some(path) = &history
+        && let Err(err) = editor.save_history(path)
+    {
+        eprintln!("lst: can't save the history to {}: {}", path.display(), err);
+    }
+
+    Ok(())
+}
+
+fn history_file() -> Option<PathBuf> {
+    env::var_os("LST_HISTORY")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE)))
+}
+
+// Evaluates the input, returning the printString of the result
+pub fn evaluate(vm: &mut VirtualMachine, source: &str) -> Result<String, LoadError> {
+    let result = vm.evaluate_source("doit", source)?;
+    let text = print_string(vm, result);
+    vm.memory_mut().decrement_ref(result)?;
+
+    Ok(text)
+}
+
+// printString of an object, or a description of it when the class library doesn't
+// provide one
+pub fn print_string(vm: &mut VirtualMachine, ptr: ObjectPointer) -> String {
+    if let Ok(string) = vm.send(ptr, "printString", &[]) {
+        let text = vm.string_value(string).map(str::to_string);
+        let _ = vm.memory_mut().decrement_ref(string);
+        if let Ok(text) = text {
+            return text;
+        }
+    }
+
+    describe(vm, ptr)
+}
+
+fn describe(vm: &VirtualMachine, ptr: ObjectPointer) -> String {
+    if ptr.is_null() {
+        return "nil".to_string();
+    } else if ptr == vm.true_object() {
+        return "true".to_string();
+    } else if ptr == vm.false_object() {
+        return "false".to_string();
+    } else if let Some(value) = ptr.small_integer() {
+        return value.to_string();
+    }
+
+    match vm.memory().object_type(ptr) {
+        Ok(ObjectType::Symbol) => format!("#{}", vm.symbol_value(ptr).unwrap_or_default()),
+        Ok(ObjectType::String) => format!("'{}'", vm.string_value(ptr).unwrap_or_default().replace('\'', "''")),
+        Ok(ObjectType::LargeInteger) => vm.integer_value(ptr).map_or_else(|| "a LargeInteger".to_string(), |value| value.to_string()),
+        Ok(ObjectType::Fraction) => vm.memory().get::<Fraction>(ptr).map_or_else(|_| "a Fraction".to_string(), |fraction| fraction.to_string()),
+        Ok(ObjectType::Char) => vm.char_value(ptr).and_then(char::from_u32).map_or_else(|| "a Char".to_string(), |value| format!("${}", value)),
+        Ok(ObjectType::Float) => vm.memory().get::<Float>(ptr).map_or_else(|_| "a Float".to_string(), |float| float.to_string()),
+        _ => {
+            let name = vm.class_of(ptr).map(|class| vm.class_name(class)).unwrap_or_default();
+            let article = if name.starts_with(['A', 'E', 'I', 'O', 'U']) { "an" } else { "a" };
+            format!("{} {}", article, name)
+        }
+    }
+}
+
+// Keeps reading lines while the input is incomplete
+struct ShellHelper;
+
+impl Helper for ShellHelper {}
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Cow::Borrowed(line)
+    }
+}
+
+impl Validator for ShellHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if needs_more_input(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL: &str = "\
+Class Object
+[
+]
+Class Class
+[
+    new
+        ^ <3 self>
+]
+Class Point
+| x |
+[
+    x: value
+        x <- value
+|
+    printString
+        ^ 'a Point'
+]
+";
+
+    #[test]
+    fn test_repl_prints_results() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        assert_eq!(evaluate(&mut vm, "3")?, "3");
+        assert_eq!(evaluate(&mut vm, "#foo")?, "#foo");
+        assert_eq!(evaluate(&mut vm, "'it''s'")?, "'it''s'");
+        assert_eq!(evaluate(&mut vm, "2.5")?, "2.5");
+        assert_eq!(evaluate(&mut vm, "$a")?, "$a");
+        assert_eq!(evaluate(&mut vm, "x <- 4. x")?, "4");
+        assert_eq!(evaluate(&mut vm, "nil")?, "nil");
+
+        // With a class library, printString is used instead
+        vm.load_source("kernel.st", KERNEL)?;
+        assert_eq!(evaluate(&mut vm, "Point new x: 1")?, "a Point");
+        assert_eq!(evaluate(&mut vm, "Object new")?, "an Object");
+
+        assert!(evaluate(&mut vm, "3 ]").is_err());
+
+        Ok(())
+    }
+}
@@ -17,6 +17,14 @@ pub fn derive(input: TokenStream) -> TokenStream {
             fn set_invalid(obj: &mut Self) {
                 obj.header.set_invalid();
             }
+
+            fn header(obj: &Self) -> &ObjectHeader {
+                &obj.header
+            }
+
+            fn header_mut(obj: &mut Self) -> &mut ObjectHeader {
+                &mut obj.header
+            }
         }
     );
 
@@ -0,0 +1,125 @@
+// Abstract syntax tree for Little Smalltalk sources
+
+use num_bigint::BigInt;
+
+// Byte offsets in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    // Smallest span covering both
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identifier {
+    pub name: String,
+    pub span: Span,
+}
+
+// A source file holds class definitions, optionally mixed with statements to be
+// executed once the file is loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFile {
+    pub items: Vec<Item>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Class(ClassDefinition),
+    Statement(Statement),
+}
+
+//   Class Name :SuperClass
+//   | instanceVariables |
+//   [
+//       method
+//   |
+//       method
+//   ]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassDefinition {
+    pub name: Identifier,
+    pub super_class: Option<Identifier>,
+    pub inst_vars: Vec<Identifier>,
+    pub methods: Vec<MethodDefinition>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethodDefinition {
+    pub selector: String,
+    pub arguments: Vec<Identifier>,
+    pub temporaries: Vec<Identifier>,
+    pub body: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Expression(Expression),
+    Return(Expression, Span),
+}
+
+impl Statement {
+    pub fn span(&self) -> Span {
+        match self {
+            Statement::Expression(expression) => expression.span,
+            Statement::Return(_, span) => *span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpressionKind {
+    // Including the pseudo variables: self, super, nil, true and false
+    Variable(String),
+    Literal(Literal),
+    Assignment(Identifier, Box<Expression>),
+    Send(Box<Expression>, Message),
+    // Messages sent to the same receiver. The value is the result of the last one
+    Cascade(Box<Expression>, Vec<Message>),
+    Block(Block),
+    // <primitive number arguments>, or just <number arguments>
+    Primitive(u8, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub selector: String,
+    pub arguments: Vec<Expression>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub parameters: Vec<Identifier>,
+    pub temporaries: Vec<Identifier>,
+    pub body: Vec<Statement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Integer(BigInt),
+    Float(f64),
+    Char(char),
+    String(String),
+    Symbol(String),
+    Array(Vec<Literal>),
+    ByteArray(Vec<u8>),
+}
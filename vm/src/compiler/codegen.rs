@@ -0,0 +1,584 @@
+// Code generation
+//
+// Compiles method definitions into bytecode and literal arrays. As in the original
+// Little Smalltalk, block arguments and temporaries live in the context of the
+// method that contains them, next to the method temporaries, so every name in a
+// method gets its own slot in that array.
+//
+// Messages with literal block arguments that are commonly used for control flow
+// (ifTrue:, and:, whileTrue:...) are compiled inline, using branches.
+
+use num_traits::ToPrimitive;
+
+use crate::execution::bytecode::{Constant, Instruction};
+use crate::execution::primitives::{GLOBAL_VALUE, SET_GLOBAL};
+use super::ast::{
+    Block, Expression, ExpressionKind, Identifier, Literal, Message, MethodDefinition, Span, Statement,
+};
+use super::ParseError;
+
+const PSEUDO_VARIABLES: [&str; 5] = ["self", "super", "nil", "true", "false"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledMethod {
+    pub selector: String,
+    pub bytecode: Vec<u8>,
+    pub literals: Vec<Literal>,
+    pub stack_max: usize,
+    pub context_size: usize,
+}
+
+enum Variable {
+    Instance(u8),
+    Argument(u8),
+    Temporary(u8),
+    Global(String),
+}
+
+pub struct MethodCompiler<'a> {
+    file: &'a str,
+    source: &'a str,
+    inst_vars: &'a [String],
+    // Names that don't refer to any variable in scope are globals. Otherwise, only
+    // capitalized names are
+    implicit_globals: bool,
+    // Whether the method belongs to a class. Sending to super looks for the method
+    // in the superclass of that class, so statements outside of classes can't
+    in_class: bool,
+    arguments: Vec<String>,
+    // Names of the temporaries in scope, with their index in the context
+    scopes: Vec<Vec<(String, usize)>>,
+    context_size: usize,
+    bytecode: Vec<u8>,
+    literals: Vec<Literal>,
+    depth: usize,
+    stack_max: usize,
+}
+
+impl<'a> MethodCompiler<'a> {
+    pub fn new(file: &'a str, source: &'a str, inst_vars: &'a [String]) -> Self {
+        MethodCompiler {
+            file,
+            source,
+            inst_vars,
+            implicit_globals: false,
+            in_class: false,
+            arguments: vec![],
+            scopes: vec![],
+            context_size: 0,
+            bytecode: vec![],
+            literals: vec![],
+            depth: 0,
+            stack_max: 0,
+        }
+    }
+
+    // Compiles statements outside of any class, as the body of a method with no
+    // arguments returning the value of the last one. Undeclared variables are taken
+    // as globals
+    pub fn compile_statements(mut self, selector: &str, statements: &[Statement]) -> Result<CompiledMethod, ParseError> {
+        self.implicit_globals = true;
+        self.statements_value(statements)?;
+        if !matches!(statements.last(), Some(Statement::Return(..))) {
+            self.emit(Instruction::StackReturn, -1);
+        }
+        self.finish(selector)
+    }
+
+    pub fn compile(mut self, method: &MethodDefinition) -> Result<CompiledMethod, ParseError> {
+        self.in_class = true;
+        for argument in &method.arguments {
+            self.check_declaration(argument)?;
+            self.arguments.push(argument.name.clone());
+        }
+        self.declare(&method.temporaries)?;
+        self.method_body(&method.body)?;
+        self.finish(&method.selector)
+    }
+
+    fn finish(self, selector: &str) -> Result<CompiledMethod, ParseError> {
+        if self.bytecode.len() > u16::MAX as usize {
+            return Err(self.error(Span::default(), "method too large"));
+        }
+
+        Ok(CompiledMethod {
+            selector: selector.to_string(),
+            bytecode: self.bytecode,
+            literals: self.literals,
+            stack_max: self.stack_max,
+            context_size: self.context_size,
+        })
+    }
+
+    // Methods return self unless they return explicitly
+    fn method_body(&mut self, statements: &[Statement]) -> Result<(), ParseError> {
+        for statement in statements {
+            match statement {
+                Statement::Return(expression, _) => {
+                    return self.return_statement(expression);
+                }
+                Statement::Expression(expression) => {
+                    self.expression(expression)?;
+                    self.emit(Instruction::PopTop, -1);
+                }
+            }
+        }
+
+        self.emit(Instruction::SelfReturn, 0);
+        Ok(())
+    }
+
+    fn return_statement(&mut self, expression: &Expression) -> Result<(), ParseError> {
+        if matches!(&expression.kind, ExpressionKind::Variable(name) if name == "self") {
+            self.emit(Instruction::SelfReturn, 0);
+        } else {
+            self.expression(expression)?;
+            self.emit(Instruction::StackReturn, -1);
+        }
+
+        Ok(())
+    }
+
+    // Leaves the value of the last statement on the stack (nil if there are none).
+    // Code after a return statement is never reached, so it's not compiled
+    fn statements_value(&mut self, statements: &[Statement]) -> Result<(), ParseError> {
+        if statements.is_empty() {
+            self.emit(Instruction::PushConstant(Constant::Nil), 1);
+        }
+
+        for (index, statement) in statements.iter().enumerate() {
+            match statement {
+                Statement::Return(expression, _) => {
+                    self.return_statement(expression)?;
+                    // Keep the stack balanced, as if a value had been left
+                    self.depth += 1;
+                    return Ok(());
+                }
+                Statement::Expression(expression) => {
+                    self.expression(expression)?;
+                    if index + 1 < statements.len() {
+                        self.emit(Instruction::PopTop, -1);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn expression(&mut self, expression: &Expression) -> Result<(), ParseError> {
+        match &expression.kind {
+            ExpressionKind::Variable(name) => self.push_variable(name, expression.span),
+            ExpressionKind::Literal(literal) => self.push_literal(literal, expression.span),
+            ExpressionKind::Assignment(target, value) => self.assignment(target, value),
+            ExpressionKind::Send(receiver, message) => self.send(receiver, message),
+            ExpressionKind::Cascade(receiver, messages) => self.cascade(receiver, messages),
+            ExpressionKind::Block(block) => self.block(block, expression.span),
+            ExpressionKind::Primitive(number, arguments) => {
+                for argument in arguments {
+                    self.expression(argument)?;
+                }
+                let args = self.operand(arguments.len(), expression.span, "too many primitive arguments")?;
+                self.emit(Instruction::DoPrimitive { args, number: *number }, 1 - arguments.len() as isize);
+                Ok(())
+            }
+        }
+    }
+
+    fn push_variable(&mut self, name: &str, span: Span) -> Result<(), ParseError> {
+        let instruction = match name {
+            "super" if !self.in_class => return Err(self.error(span, "cannot use super outside of a class")),
+            "self" | "super" => Instruction::PushArgument(0),
+            "nil" => Instruction::PushConstant(Constant::Nil),
+            "true" => Instruction::PushConstant(Constant::True),
+            "false" => Instruction::PushConstant(Constant::False),
+            _ => match self.resolve(name, span)? {
+                Variable::Instance(index) => Instruction::PushInstance(index),
+                Variable::Argument(index) => Instruction::PushArgument(index),
+                Variable::Temporary(index) => Instruction::PushTemporary(index),
+                Variable::Global(name) => {
+                    self.push_literal(&Literal::Symbol(name), span)?;
+                    self.emit(Instruction::DoPrimitive { args: 1, number: GLOBAL_VALUE }, 0);
+                    return Ok(());
+                }
+            },
+        };
+
+        self.emit(instruction, 1);
+        Ok(())
+    }
+
+    fn push_literal(&mut self, literal: &Literal, span: Span) -> Result<(), ParseError> {
+        let constant = match literal {
+            Literal::Integer(value) => match value.to_i8() {
+                Some(-1) => Some(Constant::MinusOne),
+                Some(0) => Some(Constant::Zero),
+                Some(1) => Some(Constant::One),
+                Some(2) => Some(Constant::Two),
+                _ => None,
+            },
+            _ => None,
+        };
+        let instruction = match constant {
+            Some(constant) => Instruction::PushConstant(constant),
+            None => Instruction::PushLiteral(self.literal(literal, span)?),
+        };
+
+        self.emit(instruction, 1);
+        Ok(())
+    }
+
+    fn assignment(&mut self, target: &Identifier, value: &Expression) -> Result<(), ParseError> {
+        if PSEUDO_VARIABLES.contains(&target.name.as_str()) {
+            return Err(self.error(target.span, &format!("cannot assign to {}", target.name)));
+        }
+
+        match self.resolve(&target.name, target.span)? {
+            Variable::Instance(index) => {
+                self.expression(value)?;
+                self.emit(Instruction::AssignInstance(index), 0);
+            }
+            Variable::Temporary(index) => {
+                self.expression(value)?;
+                self.emit(Instruction::AssignTemporary(index), 0);
+            }
+            Variable::Argument(_) => {
+                return Err(self.error(target.span, &format!("cannot assign to argument {}", target.name)));
+            }
+            Variable::Global(name) => {
+                self.push_literal(&Literal::Symbol(name), target.span)?;
+                self.expression(value)?;
+                self.emit(Instruction::DoPrimitive { args: 2, number: SET_GLOBAL }, -1);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, receiver: &Expression, message: &Message) -> Result<(), ParseError> {
+        if self.inline_control(receiver, message)? {
+            return Ok(());
+        }
+
+        self.expression(receiver)?;
+        self.message(message, is_super(receiver))
+    }
+
+    // Sends a message to the value on top of the stack
+    fn message(&mut self, message: &Message, to_super: bool) -> Result<(), ParseError> {
+        for argument in &message.arguments {
+            self.expression(argument)?;
+        }
+
+        let args = self.operand(message.arguments.len(), message.span, "too many arguments")?;
+        let selector = self.literal(&Literal::Symbol(message.selector.clone()), message.span)?;
+        let instruction = if to_super {
+            Instruction::SendToSuper { args, selector }
+        } else {
+            Instruction::SendMessage { args, selector }
+        };
+        self.emit(instruction, -(args as isize));
+
+        Ok(())
+    }
+
+    fn cascade(&mut self, receiver: &Expression, messages: &[Message]) -> Result<(), ParseError> {
+        self.expression(receiver)?;
+        for (index, message) in messages.iter().enumerate() {
+            let last = index + 1 == messages.len();
+            if !last {
+                self.emit(Instruction::Duplicate, 1);
+            }
+            self.message(message, is_super(receiver))?;
+            if !last {
+                self.emit(Instruction::PopTop, -1);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn block(&mut self, block: &Block, span: Span) -> Result<(), ParseError> {
+        let arglocation = self.context_size;
+        self.scopes.push(vec![]);
+        self.declare(&block.parameters)?;
+        self.declare(&block.temporaries)?;
+
+        let args = self.operand(block.parameters.len(), span, "too many block arguments")?;
+        let arglocation = self.operand(arglocation, span, "too many temporaries")?;
+        let create = self.emit(Instruction::CreateBlock { args, arglocation, end: 0 }, 1);
+
+        // The body runs on a stack of its own, which must fit in the one allocated
+        // for the method
+        let depth = std::mem::replace(&mut self.depth, 0);
+        self.statements_value(&block.body)?;
+        self.emit(Instruction::BlockReturn, -1);
+        self.depth = depth;
+
+        self.scopes.pop();
+        self.patch(create, span)
+    }
+
+    // Compiles the body of a literal block in place
+    fn inline_block(&mut self, block: &Expression) -> Result<(), ParseError> {
+        let ExpressionKind::Block(block) = &block.kind else {
+            unreachable!("only literal blocks are inlined");
+        };
+
+        self.scopes.push(vec![]);
+        self.declare(&block.temporaries)?;
+        self.statements_value(&block.body)?;
+        self.scopes.pop();
+
+        Ok(())
+    }
+
+    fn inline_control(&mut self, receiver: &Expression, message: &Message) -> Result<bool, ParseError> {
+        let arguments = &message.arguments;
+        if !arguments.iter().all(is_inlinable) {
+            return Ok(false);
+        }
+
+        match message.selector.as_str() {
+            "ifTrue:" | "ifFalse:" => {
+                self.expression(receiver)?;
+                let skip = if message.selector == "ifTrue:" { Instruction::BranchIfFalse(0) } else { Instruction::BranchIfTrue(0) };
+                let skip = self.emit(skip, -1);
+                self.inline_block(&arguments[0])?;
+                let end = self.emit(Instruction::Branch(0), -1);
+                self.patch(skip, message.span)?;
+                self.emit(Instruction::PushConstant(Constant::Nil), 1);
+                self.patch(end, message.span)?;
+            }
+            "ifTrue:ifFalse:" | "ifFalse:ifTrue:" => {
+                self.expression(receiver)?;
+                let skip = if message.selector == "ifTrue:ifFalse:" { Instruction::BranchIfFalse(0) } else { Instruction::BranchIfTrue(0) };
+                let skip = self.emit(skip, -1);
+                self.inline_block(&arguments[0])?;
+                let end = self.emit(Instruction::Branch(0), -1);
+                self.patch(skip, message.span)?;
+                self.inline_block(&arguments[1])?;
+                self.patch(end, message.span)?;
+            }
+            "and:" | "or:" => {
+                self.expression(receiver)?;
+                let skip = if message.selector == "and:" { Instruction::AndBranch(0) } else { Instruction::OrBranch(0) };
+                let skip = self.emit(skip, -1);
+                self.inline_block(&arguments[0])?;
+                self.patch(skip, message.span)?;
+            }
+            "whileTrue:" | "whileFalse:" if is_inlinable(receiver) => {
+                let start = self.bytecode.len();
+                self.inline_block(receiver)?;
+                let exit = if message.selector == "whileTrue:" { Instruction::BranchIfFalse(0) } else { Instruction::BranchIfTrue(0) };
+                let exit = self.emit(exit, -1);
+                self.inline_block(&arguments[0])?;
+                self.emit(Instruction::PopTop, -1);
+                self.emit(Instruction::Branch(self.target(start, message.span)?), 0);
+                self.patch(exit, message.span)?;
+                self.emit(Instruction::PushConstant(Constant::Nil), 1);
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    fn resolve(&self, name: &str, span: Span) -> Result<Variable, ParseError> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((_, index)) = scope.iter().rev().find(|(temporary, _)| temporary == name) {
+                return Ok(Variable::Temporary(*index as u8));
+            }
+        }
+        if let Some(index) = self.arguments.iter().position(|argument| argument == name) {
+            return Ok(Variable::Argument(index as u8 + 1));
+        }
+        if let Some(index) = self.inst_vars.iter().position(|inst_var| inst_var == name) {
+            return Ok(Variable::Instance(self.operand(index, span, "too many instance variables")?));
+        }
+
+        if self.implicit_globals || name.starts_with(char::is_uppercase) {
+            Ok(Variable::Global(name.to_string()))
+        } else {
+            Err(self.error(span, &format!("undefined variable {}", name)))
+        }
+    }
+
+    fn declare(&mut self, names: &[Identifier]) -> Result<(), ParseError> {
+        if self.scopes.is_empty() {
+            self.scopes.push(vec![]);
+        }
+
+        for name in names {
+            self.check_declaration(name)?;
+            let index = self.context_size;
+            self.operand(index, name.span, "too many temporaries")?;
+            self.scopes.last_mut().unwrap().push((name.name.clone(), index));
+            self.context_size += 1;
+        }
+
+        Ok(())
+    }
+
+    fn check_declaration(&self, name: &Identifier) -> Result<(), ParseError> {
+        if PSEUDO_VARIABLES.contains(&name.name.as_str()) {
+            return Err(self.error(name.span, &format!("{} can't be used as a variable name", name.name)));
+        }
+
+        Ok(())
+    }
+
+    // Index of a literal, adding it unless an equal one is already there
+    fn literal(&mut self, literal: &Literal, span: Span) -> Result<u8, ParseError> {
+        let index = match self.literals.iter().position(|other| other == literal) {
+            Some(index) => index,
+            None => {
+                self.literals.push(literal.clone());
+                self.literals.len() - 1
+            }
+        };
+
+        self.operand(index, span, "too many literals")
+    }
+
+    // Emits an instruction, adjusting the depth of the stack. Returns its position
+    fn emit(&mut self, instruction: Instruction, effect: isize) -> usize {
+        let position = self.bytecode.len();
+        instruction.encode(&mut self.bytecode);
+        self.depth = self.depth.saturating_add_signed(effect);
+        self.stack_max = self.stack_max.max(self.depth);
+
+        position
+    }
+
+    // Points the jump (or block) at the given position to the current one
+    fn patch(&mut self, position: usize, span: Span) -> Result<(), ParseError> {
+        let target = self.target(self.bytecode.len(), span)?;
+        let (instruction, end) = Instruction::decode(&self.bytecode, position)
+            .expect("patching an instruction that was just emitted");
+
+        let mut bytes = vec![];
+        instruction.with_target(target).encode(&mut bytes);
+        self.bytecode[position..end].copy_from_slice(&bytes);
+
+        Ok(())
+    }
+
+    fn target(&self, position: usize, span: Span) -> Result<u16, ParseError> {
+        u16::try_from(position).map_err(|_| self.error(span, "method too large"))
+    }
+
+    fn operand(&self, value: usize, span: Span, message: &str) -> Result<u8, ParseError> {
+        u8::try_from(value).map_err(|_| self.error(span, message))
+    }
+
+    fn error(&self, span: Span, message: &str) -> ParseError {
+        ParseError::new(self.file, self.source, span.start, message)
+    }
+}
+
+fn is_super(expression: &Expression) -> bool {
+    matches!(&expression.kind, ExpressionKind::Variable(name) if name == "super")
+}
+
+// Literal blocks without parameters
+fn is_inlinable(expression: &Expression) -> bool {
+    matches!(&expression.kind, ExpressionKind::Block(block) if block.parameters.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Parser;
+    use Instruction::*;
+
+    fn compile(source: &str, inst_vars: &[&str]) -> Result<CompiledMethod, ParseError> {
+        let method = Parser::new("test.st", source)?.method_definition()?;
+        let inst_vars: Vec<String> = inst_vars.iter().map(|name| name.to_string()).collect();
+        MethodCompiler::new("test.st", source, &inst_vars).compile(&method)
+    }
+
+    fn instructions(bytecode: &[u8]) -> Vec<Instruction> {
+        let mut position = 0;
+        let mut instructions = vec![];
+        while position < bytecode.len() {
+            let (instruction, next) = Instruction::decode(bytecode, position).unwrap();
+            instructions.push(instruction);
+            position = next;
+        }
+        instructions
+    }
+
+    #[test]
+    fn test_codegen_variables_and_sends() {
+        let method = compile("at: index put: value | old | old <- list at: index. ^ old + 3", &["size", "list"]).unwrap();
+
+        assert_eq!(method.selector, "at:put:");
+        assert_eq!(instructions(&method.bytecode), vec![
+            PushInstance(1), PushArgument(1), SendMessage { args: 1, selector: 0 },
+            AssignTemporary(0), PopTop,
+            PushTemporary(0), PushLiteral(1), SendMessage { args: 1, selector: 2 }, StackReturn,
+        ]);
+        assert_eq!(method.literals, vec![
+            Literal::Symbol("at:".to_string()),
+            Literal::Integer(3.into()),
+            Literal::Symbol("+".to_string()),
+        ]);
+        assert_eq!((method.stack_max, method.context_size), (2, 1));
+
+        // Integers that need more than 32 bits are literals like any other
+        let method = compile("foo ^ 3000000000", &[]).unwrap();
+        assert_eq!(instructions(&method.bytecode), vec![PushLiteral(0), StackReturn]);
+        assert_eq!(method.literals, vec![Literal::Integer(3000000000u32.into())]);
+    }
+
+    #[test]
+    fn test_codegen_blocks() {
+        let method = compile("do: aBlock | t | list do: [:x | t <- x]. ^ t", &["list"]).unwrap();
+
+        assert_eq!(instructions(&method.bytecode), vec![
+            PushInstance(0),
+            CreateBlock { args: 1, arglocation: 1, end: 8 },
+            PushTemporary(1), AssignTemporary(0), BlockReturn,
+            SendMessage { args: 1, selector: 0 }, PopTop,
+            PushTemporary(0), StackReturn,
+        ]);
+        assert_eq!(method.context_size, 2);
+    }
+
+    #[test]
+    fn test_codegen_inlined_control() {
+        let method = compile("max: other ^ self > other ifTrue: [self] ifFalse: [other]", &[]).unwrap();
+        assert_eq!(instructions(&method.bytecode), vec![
+            PushArgument(0), PushArgument(1), SendMessage { args: 1, selector: 0 },
+            BranchIfFalse(11), PushArgument(0), Branch(12),
+            PushArgument(1), StackReturn,
+        ]);
+
+        let method = compile("loop [ self next ] whileTrue: [ self step ]", &[]).unwrap();
+        assert_eq!(instructions(&method.bytecode), vec![
+            PushArgument(0), SendMessage { args: 0, selector: 0 },
+            BranchIfFalse(13), PushArgument(0), SendMessage { args: 0, selector: 1 },
+            PopTop, Branch(0),
+            PushConstant(Constant::Nil), PopTop, SelfReturn,
+        ]);
+    }
+
+    #[test]
+    fn test_codegen_cascades_super_and_globals() {
+        let method = compile("init super init; reset. Count <- 1", &[]).unwrap();
+        assert_eq!(instructions(&method.bytecode), vec![
+            PushArgument(0), Duplicate, SendToSuper { args: 0, selector: 0 }, PopTop,
+            SendToSuper { args: 0, selector: 1 }, PopTop,
+            PushLiteral(2), PushConstant(Constant::One), DoPrimitive { args: 2, number: SET_GLOBAL }, PopTop,
+            SelfReturn,
+        ]);
+    }
+
+    #[test]
+    fn test_codegen_errors() {
+        assert_eq!(compile("foo ^ bar", &[]).unwrap_err().to_string(), "test.st:1:7: undefined variable bar");
+        assert_eq!(compile("foo: x x <- 1", &[]).unwrap_err().to_string(), "test.st:1:8: cannot assign to argument x");
+        assert_eq!(compile("foo self <- 1", &[]).unwrap_err().to_string(), "test.st:1:5: cannot assign to self");
+    }
+}
@@ -0,0 +1,410 @@
+// Lexical analysis of Little Smalltalk sources
+
+use num_bigint::BigInt;
+use num_traits::Num;
+
+use crate::objects::number::Float;
+use super::ast::Span;
+use super::ParseError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Identifier(String),
+    // Identifier followed by a colon, e.g. at:
+    Keyword(String),
+    Binary(String),
+    Integer(BigInt),
+    Float(f64),
+    Char(char),
+    String(String),
+    Symbol(String),
+    // <- or :=
+    Assign,
+    Colon,
+    Caret,
+    Period,
+    Semicolon,
+    Bar,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    // #(
+    ArrayStart,
+    // #[
+    ByteArrayStart,
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    // First token in its line. Methods in a class body are separated by bars at
+    // the start of a line
+    pub line_start: bool,
+}
+
+const BINARY_CHARS: &str = "+-*/\\<>=~@%&?!,";
+
+pub struct Lexer<'a> {
+    file: &'a str,
+    source: &'a str,
+    position: usize,
+    // Whether the previous token can be the receiver of a message. A minus sign
+    // right before a digit starts a negative number only when it can't
+    after_operand: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(file: &'a str, source: &'a str) -> Self {
+        Lexer { file, source, position: 0, after_operand: false }
+    }
+
+    pub fn tokenize(mut self) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = vec![];
+        loop {
+            let token = self.next_token()?;
+            let end = token.kind == TokenKind::End;
+            tokens.push(token);
+            if end {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, ParseError> {
+        let line_start = self.skip_blanks()?;
+        let start = self.position;
+
+        let kind = match self.peek() {
+            None => TokenKind::End,
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => self.identifier(),
+            Some(c) if c.is_ascii_digit() => self.number(start, false)?,
+            Some('-') if !self.after_operand && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) => {
+                self.advance();
+                self.number(start, true)?
+            }
+            Some('$') => {
+                self.advance();
+                TokenKind::Char(self.advance().ok_or_else(|| self.error(start, "character literal expected"))?)
+            }
+            Some('\'') => TokenKind::String(self.string(start)?),
+            Some('#') => self.hash(start)?,
+            Some(':') => {
+                self.advance();
+                if self.eat('=') { TokenKind::Assign } else { TokenKind::Colon }
+            }
+            Some('<') if self.peek_at(1) == Some('-') => {
+                self.position += 2;
+                TokenKind::Assign
+            }
+            Some(c) => {
+                self.advance();
+                match c {
+                    '^' => TokenKind::Caret,
+                    '.' => TokenKind::Period,
+                    ';' => TokenKind::Semicolon,
+                    '|' => TokenKind::Bar,
+                    '(' => TokenKind::LeftParen,
+                    ')' => TokenKind::RightParen,
+                    '[' => TokenKind::LeftBracket,
+                    ']' => TokenKind::RightBracket,
+                    c if BINARY_CHARS.contains(c) => {
+                        while self.peek().is_some_and(|c| BINARY_CHARS.contains(c)) {
+                            self.advance();
+                        }
+                        TokenKind::Binary(self.source[start..self.position].to_string())
+                    }
+                    c => return Err(self.error(start, &format!("unexpected character '{}'", c))),
+                }
+            }
+        };
+
+        self.after_operand = matches!(kind,
+            TokenKind::Identifier(_) | TokenKind::Integer(_) | TokenKind::Float(_) |
+            TokenKind::Char(_) | TokenKind::String(_) | TokenKind::Symbol(_) |
+            TokenKind::RightParen | TokenKind::RightBracket
+        );
+
+        Ok(Token { kind, span: Span::new(start, self.position), line_start })
+    }
+
+    // Skips whitespace and comments. Returns whether a new line was started
+    fn skip_blanks(&mut self) -> Result<bool, ParseError> {
+        let mut line_start = self.position == 0;
+        loop {
+            match self.peek() {
+                Some('\n') => {
+                    line_start = true;
+                    self.advance();
+                }
+                Some(c) if c.is_whitespace() => {
+                    self.advance();
+                }
+                Some('"') => {
+                    let start = self.position;
+                    self.advance();
+                    while self.advance().ok_or_else(|| self.error(start, "unterminated comment"))? != '"' {}
+                }
+                _ => return Ok(line_start),
+            }
+        }
+    }
+
+    fn identifier(&mut self) -> TokenKind {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.advance();
+        }
+        let name = self.source[start..self.position].to_string();
+
+        if self.peek() == Some(':') && self.peek_at(1) != Some('=') {
+            self.advance();
+            TokenKind::Keyword(name + ":")
+        } else {
+            TokenKind::Identifier(name)
+        }
+    }
+
+    // Integers, optionally with a radix and an exponent in that radix (16r1F, 1e10,
+    // 2r1e4), and floats, which have a fraction or a negative exponent (1.5, 1e-3,
+    // 1.5e10)
+    fn number(&mut self, start: usize, negative: bool) -> Result<TokenKind, ParseError> {
+        let mut radix = 10;
+        let mut digits = self.digits(10);
+        if self.peek() == Some('r') {
+            radix = digits.parse::<u32>().ok()
+                .filter(|radix| (2..=36).contains(radix))
+                .ok_or_else(|| self.error(start, "invalid radix"))?;
+            self.advance();
+            digits = self.digits(radix);
+            if digits.is_empty() {
+                return Err(self.error(start, "digits expected after the radix"));
+            }
+        }
+
+        let mut float = false;
+        if radix == 10 && self.peek() == Some('.') && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+            self.advance();
+            digits = format!("{}.{}", digits, self.digits(10));
+            float = true;
+        }
+        let mut exponent = None;
+        if self.peek() == Some('e') {
+            let sign = if self.peek_at(1) == Some('-') { 1 } else { 0 };
+            if self.peek_at(1 + sign).is_some_and(|c| c.is_ascii_digit()) {
+                self.position += 1 + sign;
+                exponent = Some(format!("{}{}", if sign == 1 { "-" } else { "" }, self.digits(10)));
+            }
+        }
+
+        let sign = if negative { "-" } else { "" };
+        if float || exponent.as_ref().is_some_and(|exponent| exponent.starts_with('-')) {
+            if radix != 10 {
+                return Err(self.error(start, "negative exponent in an integer literal"));
+            }
+            let exponent = exponent.map(|exponent| format!("e{}", exponent)).unwrap_or_default();
+            return Float::parse(&format!("{}{}{}", sign, digits, exponent))
+                .map(TokenKind::Float)
+                .ok_or_else(|| self.error(start, "float literal out of range"));
+        }
+
+        let value = BigInt::from_str_radix(&format!("{}{}", sign, digits), radix)
+            .map_err(|_| self.error(start, "invalid integer literal"))?;
+        match exponent {
+            Some(exponent) => {
+                let exponent = exponent.parse::<u16>().map_err(|_| self.error(start, "exponent out of range"))?;
+                Ok(TokenKind::Integer(value * BigInt::from(radix).pow(exponent.into())))
+            }
+            None => Ok(TokenKind::Integer(value)),
+        }
+    }
+
+    fn digits(&mut self, radix: u32) -> String {
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_digit(radix) && !(radix > 10 && c.is_ascii_lowercase())) {
+            self.advance();
+        }
+        self.source[start..self.position].to_string()
+    }
+
+    // Quoted string. Quotes are doubled inside it
+    fn string(&mut self, start: usize) -> Result<String, ParseError> {
+        self.advance();
+        let mut value = String::new();
+        loop {
+            match self.advance() {
+                None => return Err(self.error(start, "unterminated string")),
+                Some('\'') if self.eat('\'') => value.push('\''),
+                Some('\'') => return Ok(value),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn hash(&mut self, start: usize) -> Result<TokenKind, ParseError> {
+        self.advance();
+        match self.peek() {
+            Some('(') => {
+                self.advance();
+                Ok(TokenKind::ArrayStart)
+            }
+            Some('[') => {
+                self.advance();
+                Ok(TokenKind::ByteArrayStart)
+            }
+            Some('\'') => Ok(TokenKind::Symbol(self.string(self.position)?)),
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                // Keyword selectors may have several parts
+                let name_start = self.position;
+                while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+                    self.advance();
+                }
+                Ok(TokenKind::Symbol(self.source[name_start..self.position].to_string()))
+            }
+            Some(c) if BINARY_CHARS.contains(c) || c == '|' => {
+                let name_start = self.position;
+                while self.peek().is_some_and(|c| BINARY_CHARS.contains(c) || c == '|') {
+                    self.advance();
+                }
+                Ok(TokenKind::Symbol(self.source[name_start..self.position].to_string()))
+            }
+            _ => Err(self.error(start, "symbol expected after #")),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.position..].chars().next()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.source[self.position..].chars().nth(n)
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, position: usize, message: &str) -> ParseError {
+        ParseError::new(self.file, self.source, position, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        Lexer::new("test.st", source)
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_lexer_tokens() {
+        assert_eq!(kinds("x <- a at: 1 put: $c. ^ 'it''s'"), vec![
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Assign,
+            TokenKind::Identifier("a".to_string()),
+            TokenKind::Keyword("at:".to_string()),
+            TokenKind::Integer(1.into()),
+            TokenKind::Keyword("put:".to_string()),
+            TokenKind::Char('c'),
+            TokenKind::Period,
+            TokenKind::Caret,
+            TokenKind::String("it's".to_string()),
+            TokenKind::End,
+        ]);
+        assert_eq!(kinds("[:x | x >= 2] \"comment\" #(#at:put: #+) #[1]"), vec![
+            TokenKind::LeftBracket,
+            TokenKind::Colon,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Bar,
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Binary(">=".to_string()),
+            TokenKind::Integer(2.into()),
+            TokenKind::RightBracket,
+            TokenKind::ArrayStart,
+            TokenKind::Symbol("at:put:".to_string()),
+            TokenKind::Symbol("+".to_string()),
+            TokenKind::RightParen,
+            TokenKind::ByteArrayStart,
+            TokenKind::Integer(1.into()),
+            TokenKind::RightBracket,
+            TokenKind::End,
+        ]);
+    }
+
+    #[test]
+    fn test_lexer_numbers() {
+        assert_eq!(kinds("-7 16r1F 2.5 1.0e3 1.5e-2 1e-3 3-2 3 - -2"), vec![
+            TokenKind::Integer((-7).into()),
+            TokenKind::Integer(31.into()),
+            TokenKind::Float(2.5),
+            TokenKind::Float(1000.0),
+            TokenKind::Float(0.015),
+            TokenKind::Float(0.001),
+            TokenKind::Integer(3.into()),
+            TokenKind::Binary("-".to_string()),
+            TokenKind::Integer(2.into()),
+            TokenKind::Integer(3.into()),
+            TokenKind::Binary("-".to_string()),
+            TokenKind::Integer((-2).into()),
+            TokenKind::End,
+        ]);
+        // The period ends the statement
+        assert_eq!(kinds("1."), vec![TokenKind::Integer(1.into()), TokenKind::Period, TokenKind::End]);
+        // Exponents without a fraction make integers, in the radix of the literal
+        assert_eq!(kinds("-3e2 1e10 2r1e4 16r1Fe2 0e5"), vec![
+            TokenKind::Integer((-300).into()),
+            TokenKind::Integer(10_000_000_000u64.into()),
+            TokenKind::Integer(16.into()),
+            TokenKind::Integer((31 * 256).into()),
+            TokenKind::Integer(0.into()),
+            TokenKind::End,
+        ]);
+        // Integers have no limit
+        assert_eq!(kinds("-16r10000000000000000"), vec![TokenKind::Integer(-(BigInt::from(1) << 64u32)), TokenKind::End]);
+    }
+
+    #[test]
+    fn test_lexer_line_start() {
+        let tokens = Lexer::new("test.st", "a\n  | b").tokenize().unwrap();
+        assert!(tokens[0].line_start);
+        assert!(tokens[1].line_start);
+        assert!(!tokens[2].line_start);
+    }
+
+    #[test]
+    fn test_lexer_errors() {
+        let error = Lexer::new("test.st", "a\n  'open").tokenize().unwrap_err();
+        assert_eq!(error.to_string(), "test.st:2:3: unterminated string");
+
+        let error = Lexer::new("test.st", "a { b").tokenize().unwrap_err();
+        assert_eq!(error.to_string(), "test.st:1:3: unexpected character '{'");
+
+        let error = Lexer::new("test.st", "x <- 2r1e-2").tokenize().unwrap_err();
+        assert_eq!(error.to_string(), "test.st:1:6: negative exponent in an integer literal");
+        let error = Lexer::new("test.st", "x <- 1e99999").tokenize().unwrap_err();
+        assert_eq!(error.to_string(), "test.st:1:6: exponent out of range");
+        let error = Lexer::new("test.st", "x <- 1.0e400").tokenize().unwrap_err();
+        assert_eq!(error.to_string(), "test.st:1:6: float literal out of range");
+        let error = Lexer::new("test.st", "x <- -1.0e-400").tokenize().unwrap_err();
+        assert_eq!(error.to_string(), "test.st:1:6: float literal out of range");
+    }
+}
@@ -0,0 +1,260 @@
+// Loading sources into the virtual machine
+//
+// Class definitions create (or update) the class, stored in a global with its
+// name, and install its methods. Statements outside of classes are executed as
+// soon as they are reached, followed by any processes they fork.
+
+use crate::execution::VirtualMachine;
+use crate::objects::{
+    byte::ByteArray,
+    object::{ObjectPointer, ObjectType, Pointer},
+};
+use super::ast::{ClassDefinition, Item, Literal, Statement};
+use super::codegen::{CompiledMethod, MethodCompiler};
+use super::{parse_file, LoadError, ParseError};
+
+// Superclass of the classes defined without one
+const ROOT_CLASS: &str = "Object";
+
+impl VirtualMachine {
+    pub fn load_source(&mut self, file: &str, source: &str) -> Result<(), LoadError> {
+        let result = self.evaluate_source(file, source)?;
+        Ok(self.memory_mut().decrement_ref(result)?)
+    }
+
+    // Loads the source like load_source, returning the value of the last statement
+    // (nil if there are none). The caller owns a reference to it
+    pub fn evaluate_source(&mut self, file: &str, source: &str) -> Result<ObjectPointer, LoadError> {
+        let mut result = ObjectPointer::null();
+        for item in parse_file(file, source)?.items {
+            match item {
+                Item::Class(class) => {
+                    self.define_class(file, source, &class)?;
+                }
+                Item::Statement(statement) => {
+                    let previous = std::mem::replace(&mut result, ObjectPointer::null());
+                    self.memory_mut().decrement_ref(previous)?;
+                    result = self.evaluate(file, source, &[statement])?;
+                    self.run_processes()?;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Runs statements, returning the value of the last one. The caller owns a
+    // reference to it
+    pub fn evaluate(&mut self, file: &str, source: &str, statements: &[Statement]) -> Result<ObjectPointer, LoadError> {
+        let compiled = MethodCompiler::new(file, source, &[]).compile_statements("doIt", statements)?;
+        let method = self.compiled_method(&compiled)?;
+        self.memory_mut().increment_ref(method)?;
+
+        let result = self.run_method(method, ObjectPointer::null(), ObjectPointer::null(), &[]);
+        self.memory_mut().decrement_ref(method)?;
+
+        Ok(result?)
+    }
+
+    pub fn define_class(&mut self, file: &str, source: &str, definition: &ClassDefinition) -> Result<ObjectPointer, LoadError> {
+        let name = &definition.name.name;
+        let super_class = match &definition.super_class {
+            Some(super_class) => self.global(&super_class.name)
+                .filter(|&class| self.is_kind_of(class, ObjectType::Class))
+                .ok_or_else(|| ParseError::new(file, source, super_class.span.start, &format!("undefined class {}", super_class.name)))?,
+            None if name != ROOT_CLASS => self.global(ROOT_CLASS).unwrap_or(ObjectPointer::null()),
+            None => ObjectPointer::null(),
+        };
+
+        let inst_vars: Vec<String> = definition.inst_vars.iter().map(|inst_var| inst_var.name.clone()).collect();
+        let inst_var_names: Vec<&str> = inst_vars.iter().map(String::as_str).collect();
+        let class = self.new_class(name, super_class, &inst_var_names)?;
+        self.set_global(name, class)?;
+
+        for method in &definition.methods {
+            let compiled = MethodCompiler::new(file, source, &inst_vars).compile(method)?;
+            let method = self.compiled_method(&compiled)?;
+            self.install_method(class, method)?;
+        }
+
+        Ok(class)
+    }
+
+    // Method object for the output of the compiler
+    pub fn compiled_method(&mut self, compiled: &CompiledMethod) -> Result<ObjectPointer, LoadError> {
+        let literals = compiled.literals.iter()
+            .map(|literal| self.literal_object(literal))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(self.new_method(
+            &compiled.selector,
+            compiled.bytecode.clone(),
+            &literals,
+            compiled.stack_max,
+            compiled.context_size,
+        )?)
+    }
+
+    pub fn literal_object(&mut self, literal: &Literal) -> Result<ObjectPointer, LoadError> {
+        let object = match literal {
+            Literal::Integer(value) => self.new_integer(value.clone())?,
+            Literal::Float(value) => self.new_float(*value)?,
+            Literal::Char(value) => self.new_char(*value)?,
+            Literal::String(value) => self.new_string(value)?,
+            Literal::Symbol(value) => self.new_symbol(value)?,
+            Literal::ByteArray(bytes) => self.memory_mut().allocate(ByteArray::new(bytes.clone()))?,
+            Literal::Array(elements) => {
+                let array = self.new_array(elements.len())?;
+                for (index, element) in elements.iter().enumerate() {
+                    let element = self.literal_object(element)?;
+                    self.memory_mut().put_inst_var(array, index, element)?;
+                }
+                array
+            }
+        };
+
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigInt;
+
+    use super::*;
+    use crate::execution::ExecutionError;
+    use crate::objects::class::Class;
+
+    const SOURCE: &str = "\
+Class Object
+[
+    == other
+        ^ <1 self other>
+|
+    isNil
+        ^ false
+]
+Class Class
+[
+    new
+        ^ <3 self>
+]
+Class Block
+[
+    value
+        ^ <8 self>
+]
+Class UndefinedObject
+[
+    isNil
+        ^ true
+]
+Class Pair
+| first second |
+[
+    first: a second: b
+        first <- a.
+        second <- b
+|
+    swap
+        | t |
+        t <- first.
+        first <- second.
+        second <- t
+|
+    first
+        ^ first
+|
+    firstOr: aBlock
+        ^ first isNil ifTrue: [ aBlock value ] ifFalse: [ first ]
+]
+";
+
+    #[test]
+    fn test_loader_defines_classes() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        let pair = vm.global("Pair").unwrap();
+        let object = vm.global("Object").unwrap();
+        assert_eq!(vm.class_name(pair), "Pair");
+        assert_eq!(vm.memory().get::<Class>(pair)?.super_class(), object);
+        assert!(vm.lookup_method(pair, pair, "firstOr:")?.is_some());
+        assert!(vm.lookup_method(pair, pair, "isNil")?.is_some());
+
+        let error = vm.load_source("bad.st", "Class Foo :Bar\n[\n]\n").unwrap_err();
+        assert_eq!(error.to_string(), "bad.st:1:12: undefined class Bar");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loader_runs_statements() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        vm.load_source("script.st", "p <- Pair new. p first: 3 second: 4; swap. Result <- p first. Other <- Pair new firstOr: [ #none ]")?;
+        assert_eq!(vm.global("Result"), Some(ObjectPointer::from_small_integer(4)));
+        assert_eq!(vm.symbol_value(vm.global("Other").unwrap())?, "none");
+
+        let result = vm.evaluate_source("script.st", "Pair new first: 5 second: 6; first")?;
+        assert_eq!(result, ObjectPointer::from_small_integer(5));
+        assert_eq!(vm.evaluate_source("script.st", "")?, ObjectPointer::null());
+
+        let error = vm.load_source("script.st", "3 foo").unwrap_err();
+        assert_eq!(error, LoadError::Execution(ExecutionError::MessageNotUnderstood("foo".to_string())));
+        let error = vm.load_source("script.st", "Result <- 3.\n[ super foo ] value").unwrap_err();
+        assert_eq!(error.to_string(), "script.st:2:3: cannot use super outside of a class");
+        assert_eq!(vm.global("Result"), Some(ObjectPointer::from_small_integer(3)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loader_literals() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        let array = vm.literal_object(&Literal::Array(vec![
+            Literal::Integer(7.into()),
+            Literal::Symbol("foo".to_string()),
+            Literal::ByteArray(vec![1, 2]),
+        ]))?;
+
+        assert_eq!(vm.memory().inst_var(array, 0)?, ObjectPointer::from_small_integer(7));
+        assert_eq!(vm.symbol_value(vm.memory().inst_var(array, 1)?)?, "foo");
+        assert_eq!(vm.memory().get::<ByteArray>(vm.memory().inst_var(array, 2)?)?.value(), &[1, 2]);
+
+        // Integers that don't fit in a small integer are large integers
+        let large = vm.literal_object(&Literal::Integer(BigInt::from(1) << 40u32))?;
+        assert_eq!(vm.memory().object_type(large)?, ObjectType::LargeInteger);
+        assert_eq!(vm.integer_value(large), Some(BigInt::from(1) << 40u32));
+        let large = vm.evaluate_source("test.st", "-1099511627776")?;
+        assert_eq!(vm.integer_value(large), Some(-(BigInt::from(1) << 40u32)));
+        let large = vm.evaluate_source("test.st", "1e10")?;
+        assert_eq!(vm.memory().object_type(large)?, ObjectType::LargeInteger);
+        assert_eq!(vm.integer_value(large), Some(BigInt::from(10_000_000_000u64)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_loader_char_literals_do_not_allocate() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        // Compiling a statement interns a symbol, the first time
+        vm.load_source("test.st", "nil")?;
+        let objects = vm.memory().live_objects();
+
+        let a = vm.evaluate_source("test.st", "$a")?;
+        assert_eq!(vm.evaluate_source("test.st", "$a")?, a);
+        assert_eq!(vm.char_value(a), Some('a' as u32));
+        assert_eq!(vm.evaluate_source("test.st", "$\u{ff}")?, vm.new_char('\u{ff}')?);
+        assert_eq!(vm.memory().live_objects(), objects);
+
+        // Beyond a byte, they are created the first time, and found after that
+        let lambda = vm.evaluate_source("test.st", "$\u{3bb}")?;
+        assert_eq!(vm.evaluate_source("test.st", "$\u{3bb}")?, lambda);
+        assert_eq!(vm.char_value(lambda), Some(0x3bb));
+        assert_eq!(vm.memory().live_objects().len(), objects.len() + 1);
+
+        Ok(())
+    }
+}
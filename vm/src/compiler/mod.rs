@@ -0,0 +1,128 @@
+pub mod ast;
+mod codegen;
+mod lexer;
+mod loader;
+mod parser;
+
+use std::fmt::{self, Display};
+
+use crate::execution::ExecutionError;
+use crate::memory::MemoryError;
+
+use lexer::{Lexer, TokenKind};
+
+pub use codegen::{CompiledMethod, MethodCompiler};
+pub use parser::Parser;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    // Lines and columns start at 1. Columns count characters, not bytes
+    pub fn new(file: &str, source: &str, position: usize, message: &str) -> Self {
+        let before = &source[..position.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+        ParseError {
+            file: file.to_string(),
+            line,
+            column: before[line_start..].chars().count() + 1,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse_file(file: &str, source: &str) -> Result<ast::SourceFile, ParseError> {
+    Parser::new(file, source)?.source_file()
+}
+
+// Whether the source stops in the middle of an expression or a class definition,
+// so that more lines are needed to complete it. Used for interactive input
+pub fn needs_more_input(source: &str) -> bool {
+    let tokens = match Lexer::new("", source).tokenize() {
+        Ok(tokens) => tokens,
+        Err(err) => return err.message.starts_with("unterminated"),
+    };
+
+    let depth: i32 = tokens.iter()
+        .map(|token| match token.kind {
+            TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::ArrayStart | TokenKind::ByteArrayStart => 1,
+            TokenKind::RightParen | TokenKind::RightBracket => -1,
+            _ => 0,
+        })
+        .sum();
+    // The last token is always End
+    let last = tokens.len().checked_sub(2).map(|index| &tokens[index].kind);
+
+    depth > 0 || matches!(last, Some(TokenKind::Keyword(_) | TokenKind::Binary(_) | TokenKind::Assign | TokenKind::Caret))
+}
+
+// Errors found while loading sources into the virtual machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    Parse(ParseError),
+    Execution(ExecutionError),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Parse(err) => write!(f, "{}", err),
+            LoadError::Execution(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<ParseError> for LoadError {
+    fn from(err: ParseError) -> Self {
+        LoadError::Parse(err)
+    }
+}
+
+impl From<ExecutionError> for LoadError {
+    fn from(err: ExecutionError) -> Self {
+        LoadError::Execution(err)
+    }
+}
+
+impl From<MemoryError> for LoadError {
+    fn from(err: MemoryError) -> Self {
+        LoadError::Execution(ExecutionError::Memory(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needs_more_input() {
+        assert!(!needs_more_input("3 + 4"));
+        assert!(!needs_more_input(""));
+        assert!(needs_more_input("3 +"));
+        assert!(needs_more_input("x <-"));
+        assert!(needs_more_input("a at: 1 put:"));
+        assert!(needs_more_input("[ :x | x"));
+        assert!(needs_more_input("'unterminated"));
+        assert!(needs_more_input("Class Foo\n[\n    bar\n        ^ 1\n"));
+        assert!(!needs_more_input("Class Foo\n[\n    bar\n        ^ 1\n]"));
+        // Errors are reported once the expression is complete
+        assert!(!needs_more_input("3 ] 4"));
+    }
+}
@@ -0,0 +1,567 @@
+// Recursive descent parser for Little Smalltalk sources
+//
+// Follows the syntax of the original Little Smalltalk. Method bodies end at a bar
+// starting a line (separating it from the next method) or at the bracket closing
+// the class, so a bar used as a binary operator can't be the first token in its
+// line.
+
+use num_traits::ToPrimitive;
+
+use super::ast::{
+    Block, ClassDefinition, Expression, ExpressionKind, Identifier, Item, Literal,
+    Message, MethodDefinition, SourceFile, Span, Statement,
+};
+use super::lexer::{Lexer, Token, TokenKind};
+use super::ParseError;
+
+pub struct Parser<'a> {
+    file: &'a str,
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(file: &'a str, source: &'a str) -> Result<Self, ParseError> {
+        let tokens = Lexer::new(file, source).tokenize()?;
+
+        Ok(Parser { file, source, tokens, position: 0 })
+    }
+
+    pub fn source_file(&mut self) -> Result<SourceFile, ParseError> {
+        let mut items = vec![];
+        while !self.at(&TokenKind::End) {
+            if self.at_class_definition() {
+                items.push(Item::Class(self.class_definition()?));
+            } else {
+                items.push(Item::Statement(self.statement()?));
+                if !self.eat(&TokenKind::Period) && !self.at(&TokenKind::End) && !self.at_class_definition() {
+                    return Err(self.error("expected a period"));
+                }
+            }
+        }
+
+        Ok(SourceFile { items })
+    }
+
+    pub fn class_definition(&mut self) -> Result<ClassDefinition, ParseError> {
+        let start = self.expect_identifier("expected Class")?.span;
+        let name = self.expect_identifier("expected a class name")?;
+        let super_class = if self.eat(&TokenKind::Colon) {
+            Some(self.expect_identifier("expected a superclass name")?)
+        } else {
+            None
+        };
+        let inst_vars = self.variable_declarations()?;
+
+        self.expect(&TokenKind::LeftBracket, "expected [ starting the methods of the class")?;
+        let mut methods = vec![];
+        if !self.at(&TokenKind::RightBracket) {
+            methods.push(self.method_definition()?);
+            while self.eat(&TokenKind::Bar) {
+                methods.push(self.method_definition()?);
+            }
+        }
+        let end = self.expect(&TokenKind::RightBracket, "expected ] or a bar separating methods")?.span;
+
+        Ok(ClassDefinition { name, super_class, inst_vars, methods, span: start.to(end) })
+    }
+
+    pub fn method_definition(&mut self) -> Result<MethodDefinition, ParseError> {
+        let start = self.peek().span;
+        let (selector, arguments) = self.message_pattern()?;
+        let temporaries = self.variable_declarations()?;
+        let body = self.statements(|parser| {
+            parser.at(&TokenKind::RightBracket) || parser.at_method_separator() || parser.at(&TokenKind::End)
+        })?;
+        let end = body.last().map_or(start, Statement::span);
+
+        Ok(MethodDefinition { selector, arguments, temporaries, body, span: start.to(end) })
+    }
+
+    fn message_pattern(&mut self) -> Result<(String, Vec<Identifier>), ParseError> {
+        let token = self.advance();
+        match token.kind {
+            TokenKind::Identifier(name) => Ok((name, vec![])),
+            TokenKind::Binary(selector) => Ok((selector, vec![self.expect_identifier("expected an argument name")?])),
+            TokenKind::Bar => Ok(("|".to_string(), vec![self.expect_identifier("expected an argument name")?])),
+            TokenKind::Keyword(keyword) => {
+                let mut selector = keyword;
+                let mut arguments = vec![self.expect_identifier("expected an argument name")?];
+                while let TokenKind::Keyword(keyword) = &self.peek().kind {
+                    selector.push_str(keyword);
+                    self.advance();
+                    arguments.push(self.expect_identifier("expected an argument name")?);
+                }
+                Ok((selector, arguments))
+            }
+            _ => Err(self.error_at(token.span, "expected a message pattern")),
+        }
+    }
+
+    // | a b c |
+    fn variable_declarations(&mut self) -> Result<Vec<Identifier>, ParseError> {
+        let mut names = vec![];
+        if self.eat(&TokenKind::Bar) {
+            while !self.eat(&TokenKind::Bar) {
+                names.push(self.expect_identifier("expected a variable name or a bar")?);
+            }
+        }
+
+        Ok(names)
+    }
+
+    // Statements separated by periods, up to (not including) the terminator
+    fn statements(&mut self, at_end: impl Fn(&Self) -> bool) -> Result<Vec<Statement>, ParseError> {
+        let mut statements = vec![];
+        while !at_end(self) {
+            statements.push(self.statement()?);
+            if !self.eat(&TokenKind::Period) {
+                break;
+            }
+        }
+
+        Ok(statements)
+    }
+
+    pub fn statement(&mut self) -> Result<Statement, ParseError> {
+        if self.at(&TokenKind::Caret) {
+            let start = self.advance().span;
+            let expression = self.expression()?;
+            let span = start.to(expression.span);
+            Ok(Statement::Return(expression, span))
+        } else {
+            Ok(Statement::Expression(self.expression()?))
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expression, ParseError> {
+        if let TokenKind::Identifier(name) = &self.peek().kind
+            && self.peek_at(1).kind == TokenKind::Assign
+        {
+            let target = Identifier { name: name.clone(), span: self.peek().span };
+            self.position += 2;
+            let value = self.expression()?;
+            let span = target.span.to(value.span);
+            return Ok(Expression { kind: ExpressionKind::Assignment(target, Box::new(value)), span });
+        }
+
+        let expression = self.keyword_expression()?;
+        if !self.at(&TokenKind::Semicolon) {
+            return Ok(expression);
+        }
+
+        // The receiver of the cascade is the receiver of the last message
+        let ExpressionKind::Send(receiver, first) = expression.kind else {
+            return Err(self.error("cascade without a message to cascade"));
+        };
+        let mut messages = vec![first];
+        while self.eat(&TokenKind::Semicolon) {
+            messages.push(self.cascaded_message()?);
+        }
+        let span = expression.span.to(messages.last().map_or(expression.span, |message| message.span));
+
+        Ok(Expression { kind: ExpressionKind::Cascade(receiver, messages), span })
+    }
+
+    fn cascaded_message(&mut self) -> Result<Message, ParseError> {
+        let token = self.peek().clone();
+        match token.kind {
+            TokenKind::Identifier(selector) => {
+                self.advance();
+                Ok(Message { selector, arguments: vec![], span: token.span })
+            }
+            TokenKind::Keyword(_) => self.keyword_message(),
+            _ if self.at_binary_operator() => {
+                let selector = self.binary_operator();
+                let argument = self.unary_expression()?;
+                let span = token.span.to(argument.span);
+                Ok(Message { selector, arguments: vec![argument], span })
+            }
+            _ => Err(self.error("expected a message after ;")),
+        }
+    }
+
+    fn keyword_expression(&mut self) -> Result<Expression, ParseError> {
+        let receiver = self.binary_expression()?;
+        if !matches!(self.peek().kind, TokenKind::Keyword(_)) {
+            return Ok(receiver);
+        }
+
+        let message = self.keyword_message()?;
+        Ok(send(receiver, message))
+    }
+
+    fn keyword_message(&mut self) -> Result<Message, ParseError> {
+        let start = self.peek().span;
+        let mut selector = String::new();
+        let mut arguments = vec![];
+        while let TokenKind::Keyword(keyword) = &self.peek().kind {
+            selector.push_str(keyword);
+            self.advance();
+            arguments.push(self.binary_expression()?);
+        }
+        let span = start.to(arguments.last().map_or(start, |argument| argument.span));
+
+        Ok(Message { selector, arguments, span })
+    }
+
+    fn binary_expression(&mut self) -> Result<Expression, ParseError> {
+        let mut receiver = self.unary_expression()?;
+        while self.at_binary_operator() {
+            let start = self.peek().span;
+            let selector = self.binary_operator();
+            let argument = self.unary_expression()?;
+            let span = start.to(argument.span);
+            receiver = send(receiver, Message { selector, arguments: vec![argument], span });
+        }
+
+        Ok(receiver)
+    }
+
+    fn unary_expression(&mut self) -> Result<Expression, ParseError> {
+        let mut receiver = self.primary()?;
+        while let TokenKind::Identifier(selector) = &self.peek().kind {
+            let message = Message { selector: selector.clone(), arguments: vec![], span: self.peek().span };
+            self.advance();
+            receiver = send(receiver, message);
+        }
+
+        Ok(receiver)
+    }
+
+    fn primary(&mut self) -> Result<Expression, ParseError> {
+        let token = self.peek().clone();
+        let kind = match token.kind {
+            TokenKind::Identifier(name) => {
+                self.advance();
+                ExpressionKind::Variable(name)
+            }
+            TokenKind::LeftParen => {
+                self.advance();
+                let expression = self.expression()?;
+                let end = self.expect(&TokenKind::RightParen, "expected )")?.span;
+                return Ok(Expression { span: token.span.to(end), ..expression });
+            }
+            TokenKind::LeftBracket => {
+                self.advance();
+                ExpressionKind::Block(self.block()?)
+            }
+            TokenKind::Binary(ref operator) if operator == "<" => {
+                self.advance();
+                self.primitive()?
+            }
+            _ => ExpressionKind::Literal(self.literal("expected an expression")?),
+        };
+
+        let end = self.tokens[self.position - 1].span;
+        Ok(Expression { kind, span: token.span.to(end) })
+    }
+
+    // [:a :b | | temporaries | statements ]
+    fn block(&mut self) -> Result<Block, ParseError> {
+        let mut parameters = vec![];
+        while self.eat(&TokenKind::Colon) {
+            parameters.push(self.expect_identifier("expected a block parameter name")?);
+        }
+        if !parameters.is_empty() && !self.at(&TokenKind::RightBracket) {
+            self.expect(&TokenKind::Bar, "expected a bar after the block parameters")?;
+        }
+        let temporaries = self.variable_declarations()?;
+        let body = self.statements(|parser| parser.at(&TokenKind::RightBracket))?;
+        self.expect(&TokenKind::RightBracket, "expected ]")?;
+
+        Ok(Block { parameters, temporaries, body })
+    }
+
+    // <primitive number arguments>. The primitive keyword is optional
+    fn primitive(&mut self) -> Result<ExpressionKind, ParseError> {
+        if matches!(&self.peek().kind, TokenKind::Identifier(name) if name == "primitive") {
+            self.advance();
+        }
+        let token = self.advance();
+        let number = match &token.kind {
+            TokenKind::Integer(number) => number.to_u8(),
+            _ => None,
+        };
+        let number = number.ok_or_else(|| self.error_at(token.span, "expected a primitive number"))?;
+
+        let mut arguments = vec![];
+        while !matches!(&self.peek().kind, TokenKind::Binary(operator) if operator == ">") {
+            if self.at(&TokenKind::End) {
+                return Err(self.error("expected > closing the primitive"));
+            }
+            arguments.push(self.primary()?);
+        }
+        self.advance();
+
+        Ok(ExpressionKind::Primitive(number, arguments))
+    }
+
+    fn literal(&mut self, message: &str) -> Result<Literal, ParseError> {
+        let token = self.advance();
+        match token.kind {
+            TokenKind::Integer(value) => Ok(Literal::Integer(value)),
+            TokenKind::Float(value) => Ok(Literal::Float(value)),
+            TokenKind::Char(value) => Ok(Literal::Char(value)),
+            TokenKind::String(value) => Ok(Literal::String(value)),
+            TokenKind::Symbol(value) => Ok(Literal::Symbol(value)),
+            TokenKind::ArrayStart => self.literal_array(),
+            TokenKind::ByteArrayStart => self.byte_array(),
+            _ => Err(self.error_at(token.span, message)),
+        }
+    }
+
+    // Inside literal arrays, names and operators stand for symbols, and the # can
+    // be left out of nested arrays
+    fn literal_array(&mut self) -> Result<Literal, ParseError> {
+        let mut elements = vec![];
+        while !self.eat(&TokenKind::RightParen) {
+            let element = match self.peek().kind.clone() {
+                TokenKind::Identifier(name) | TokenKind::Keyword(name) | TokenKind::Binary(name) => {
+                    self.advance();
+                    Literal::Symbol(name)
+                }
+                TokenKind::Bar => {
+                    self.advance();
+                    Literal::Symbol("|".to_string())
+                }
+                TokenKind::LeftParen => {
+                    self.advance();
+                    self.literal_array()?
+                }
+                TokenKind::LeftBracket => {
+                    self.advance();
+                    self.byte_array()?
+                }
+                _ => self.literal("expected a literal or )")?,
+            };
+            elements.push(element);
+        }
+
+        Ok(Literal::Array(elements))
+    }
+
+    fn byte_array(&mut self) -> Result<Literal, ParseError> {
+        let mut bytes = vec![];
+        while !self.eat(&TokenKind::RightBracket) {
+            let token = self.advance();
+            let byte = match &token.kind {
+                TokenKind::Integer(value) => value.to_u8(),
+                _ => None,
+            };
+            bytes.push(byte.ok_or_else(|| self.error_at(token.span, "expected a byte value or ]"))?);
+        }
+
+        Ok(Literal::ByteArray(bytes))
+    }
+
+    fn at_class_definition(&self) -> bool {
+        matches!(&self.peek().kind, TokenKind::Identifier(name) if name == "Class")
+            && matches!(&self.peek_at(1).kind, TokenKind::Identifier(name) if name.starts_with(char::is_uppercase))
+    }
+
+    fn at_method_separator(&self) -> bool {
+        self.at(&TokenKind::Bar) && self.peek().line_start
+    }
+
+    fn at_binary_operator(&self) -> bool {
+        matches!(self.peek().kind, TokenKind::Binary(_)) || (self.at(&TokenKind::Bar) && !self.peek().line_start)
+    }
+
+    fn binary_operator(&mut self) -> String {
+        match self.advance().kind {
+            TokenKind::Binary(selector) => selector,
+            _ => "|".to_string(),
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        self.peek_at(0)
+    }
+
+    // The last token is always End
+    fn peek_at(&self, n: usize) -> &Token {
+        &self.tokens[(self.position + n).min(self.tokens.len() - 1)]
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.peek().clone();
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn at(&self, kind: &TokenKind) -> bool {
+        &self.peek().kind == kind
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.at(kind) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: &TokenKind, message: &str) -> Result<Token, ParseError> {
+        if self.at(kind) {
+            Ok(self.advance())
+        } else {
+            Err(self.error(message))
+        }
+    }
+
+    fn expect_identifier(&mut self, message: &str) -> Result<Identifier, ParseError> {
+        match self.peek().kind.clone() {
+            TokenKind::Identifier(name) => Ok(Identifier { name, span: self.advance().span }),
+            _ => Err(self.error(message)),
+        }
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        self.error_at(self.peek().span, message)
+    }
+
+    fn error_at(&self, span: Span, message: &str) -> ParseError {
+        ParseError::new(self.file, self.source, span.start, message)
+    }
+}
+
+fn send(receiver: Expression, message: Message) -> Expression {
+    let span = receiver.span.to(message.span);
+
+    Expression { kind: ExpressionKind::Send(Box::new(receiver), message), span }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::parse_file;
+
+    fn parse_statement(source: &str) -> Statement {
+        Parser::new("test.st", source).unwrap().statement().unwrap()
+    }
+
+    // Compact rendering of expressions, with parentheses around every send
+    fn render(expression: &Expression) -> String {
+        match &expression.kind {
+            ExpressionKind::Variable(name) => name.clone(),
+            ExpressionKind::Literal(literal) => format!("{:?}", literal),
+            ExpressionKind::Assignment(target, value) => format!("{} <- {}", target.name, render(value)),
+            ExpressionKind::Send(receiver, message) => format!("({} {})", render(receiver), render_message(message)),
+            ExpressionKind::Cascade(receiver, messages) => format!(
+                "({} {})",
+                render(receiver),
+                messages.iter().map(render_message).collect::<Vec<_>>().join("; "),
+            ),
+            ExpressionKind::Block(block) => format!(
+                "[{} | {}]",
+                block.parameters.iter().map(|p| p.name.as_str()).collect::<Vec<_>>().join(" "),
+                block.body.iter().map(render_statement).collect::<Vec<_>>().join(". "),
+            ),
+            ExpressionKind::Primitive(number, arguments) => format!(
+                "<{} {}>",
+                number,
+                arguments.iter().map(render).collect::<Vec<_>>().join(" "),
+            ),
+        }
+    }
+
+    fn render_message(message: &Message) -> String {
+        let mut parts = vec![message.selector.clone()];
+        parts.extend(message.arguments.iter().map(render));
+        parts.join(" ")
+    }
+
+    fn render_statement(statement: &Statement) -> String {
+        match statement {
+            Statement::Expression(expression) => render(expression),
+            Statement::Return(expression, _) => format!("^ {}", render(expression)),
+        }
+    }
+
+    #[test]
+    fn test_parser_precedence() {
+        let statement = parse_statement("x <- a foo: b bar + 2 * c baz: 3");
+        assert_eq!(
+            render_statement(&statement),
+            "x <- (a foo:baz: (((b bar) + Integer(2)) * c) Integer(3))",
+        );
+        assert_eq!(render_statement(&parse_statement("^ (a + b) negated")), "^ ((a + b) negated)");
+    }
+
+    #[test]
+    fn test_parser_cascades_blocks_and_primitives() {
+        assert_eq!(render_statement(&parse_statement("a foo; bar: 1; + 2")), "(a foo; bar: Integer(1); + Integer(2))");
+        assert_eq!(render_statement(&parse_statement("[:x :y | x. ^ y]")), "[x y | x. ^ y]");
+        assert_eq!(render_statement(&parse_statement("<primitive 21 self 3>")), "<21 self Integer(3)>");
+        assert_eq!(render_statement(&parse_statement("<21 self>")), "<21 self>");
+        assert_eq!(
+            render_statement(&parse_statement("#(1 foo at:put: $a (2) #[3])")),
+            format!("{:?}", Literal::Array(vec![
+                Literal::Integer(1.into()),
+                Literal::Symbol("foo".to_string()),
+                Literal::Symbol("at:".to_string()),
+                Literal::Symbol("put:".to_string()),
+                Literal::Char('a'),
+                Literal::Array(vec![Literal::Integer(2.into())]),
+                Literal::ByteArray(vec![3]),
+            ])),
+        );
+    }
+
+    #[test]
+    fn test_parser_class_definition() {
+        let source = "\
+Class Set :Collection
+| list |
+[
+    add: newElement
+        (list includes: newElement)
+            ifFalse: [list add: newElement].
+        ^ newElement
+|
+    | aSet
+        | result |
+        result <- self | aSet.
+        ^ result
+|
+    size
+        ^ list size
+]
+Set new add: 3
+";
+        let file = parse_file("set.st", source).unwrap();
+        assert_eq!(file.items.len(), 2);
+
+        let Item::Class(class) = &file.items[0] else { panic!("class expected") };
+        assert_eq!(class.name.name, "Set");
+        assert_eq!(class.super_class.as_ref().unwrap().name, "Collection");
+        assert_eq!(class.inst_vars[0].name, "list");
+
+        let methods: Vec<_> = class.methods.iter()
+            .map(|method| (method.selector.as_str(), method.arguments.len(), method.temporaries.len(), method.body.len()))
+            .collect();
+        assert_eq!(methods, vec![("add:", 1, 0, 2), ("|", 1, 1, 2), ("size", 0, 0, 1)]);
+        assert_eq!(&source[class.methods[2].span.start..class.methods[2].span.end], "size\n        ^ list size");
+
+        let Item::Statement(statement) = &file.items[1] else { panic!("statement expected") };
+        assert_eq!(render_statement(statement), "((Set new) add: Integer(3))");
+    }
+
+    #[test]
+    fn test_parser_errors() {
+        let error = parse_file("bad.st", "Class Foo\n[\n  foo\n    ^ (1 + ]\n").unwrap_err();
+        assert_eq!((error.line, error.column), (4, 12));
+        assert_eq!(error.to_string(), "bad.st:4:12: expected an expression");
+
+        let error = parse_file("bad.st", "a foo 3").unwrap_err();
+        assert_eq!(error.to_string(), "bad.st:1:7: expected a period");
+
+        let error = parse_file("bad.st", "x <- <primitive 300>").unwrap_err();
+        assert_eq!(error.to_string(), "bad.st:1:17: expected a primitive number");
+    }
+}
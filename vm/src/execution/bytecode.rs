@@ -0,0 +1,305 @@
+// Bytecode set
+//
+// As in Little Smalltalk, instructions are encoded in a byte: the high nibble is
+// the opcode and the low nibble its operand. Operands that don't fit in 4 bits use
+// the Extended opcode, which moves the real opcode to the low nibble and takes the
+// operand from the next byte.
+//
+// Some instructions take additional bytes after the operand:
+//
+//   * SendMessage: operand is the number of arguments, followed by the index of
+//     the literal with the selector
+//   * CreateBlock: operand is the number of arguments of the block, followed by
+//     the index of the temporary holding the first argument, and the (two bytes,
+//     big endian) position of the first instruction after the body of the block
+//   * DoPrimitive: operand is the number of arguments, followed by the number of
+//     the primitive
+//   * DoSpecial: operand is the special operation. Branches are followed by the
+//     (two bytes, big endian) target. SendToSuper is followed by the number of
+//     arguments and the index of the literal with the selector
+
+pub const EXTENDED: u8 =          0;
+pub const PUSH_INSTANCE: u8 =     1;
+pub const PUSH_ARGUMENT: u8 =     2;
+pub const PUSH_TEMPORARY: u8 =    3;
+pub const PUSH_LITERAL: u8 =      4;
+pub const PUSH_CONSTANT: u8 =     5;
+pub const ASSIGN_INSTANCE: u8 =   6;
+pub const ASSIGN_TEMPORARY: u8 =  7;
+pub const SEND_MESSAGE: u8 =      8;
+pub const CREATE_BLOCK: u8 =      9;
+pub const DO_PRIMITIVE: u8 =      10;
+pub const DO_SPECIAL: u8 =        15;
+
+pub const SELF_RETURN: u8 =       1;
+pub const STACK_RETURN: u8 =      2;
+pub const BLOCK_RETURN: u8 =      3;
+pub const DUPLICATE: u8 =         4;
+pub const POP_TOP: u8 =           5;
+pub const BRANCH: u8 =            6;
+pub const BRANCH_IF_TRUE: u8 =    7;
+pub const BRANCH_IF_FALSE: u8 =   8;
+pub const AND_BRANCH: u8 =        9;
+pub const OR_BRANCH: u8 =         10;
+pub const SEND_TO_SUPER: u8 =     11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constant {
+    Nil,
+    True,
+    False,
+    MinusOne,
+    Zero,
+    One,
+    Two,
+}
+
+impl Constant {
+    const ALL: [Constant; 7] = [
+        Constant::Nil,
+        Constant::True,
+        Constant::False,
+        Constant::MinusOne,
+        Constant::Zero,
+        Constant::One,
+        Constant::Two,
+    ];
+
+    pub fn from_operand(operand: u8) -> Option<Constant> {
+        Self::ALL.get(operand as usize).copied()
+    }
+
+    pub fn operand(self) -> u8 {
+        self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    PushInstance(u8),
+    // Argument 0 is self
+    PushArgument(u8),
+    PushTemporary(u8),
+    PushLiteral(u8),
+    PushConstant(Constant),
+    AssignInstance(u8),
+    AssignTemporary(u8),
+    SendMessage { args: u8, selector: u8 },
+    CreateBlock { args: u8, arglocation: u8, end: u16 },
+    DoPrimitive { args: u8, number: u8 },
+    SelfReturn,
+    StackReturn,
+    BlockReturn,
+    Duplicate,
+    PopTop,
+    Branch(u16),
+    BranchIfTrue(u16),
+    BranchIfFalse(u16),
+    // Jumps if the top of the stack is false, leaving it there. Otherwise pops it
+    AndBranch(u16),
+    // Jumps if the top of the stack is true, leaving it there. Otherwise pops it
+    OrBranch(u16),
+    SendToSuper { args: u8, selector: u8 },
+}
+
+impl Instruction {
+    // Decodes the instruction at the given position. Returns it along with the
+    // position of the next one
+    pub fn decode(bytes: &[u8], position: usize) -> Option<(Instruction, usize)> {
+        let mut reader = Reader { bytes, position };
+        let first = reader.byte()?;
+        let (opcode, operand) = match (first >> 4, first & 0x0F) {
+            (EXTENDED, opcode) => (opcode, reader.byte()?),
+            (opcode, operand) => (opcode, operand),
+        };
+
+        let instruction = match opcode {
+            PUSH_INSTANCE => Instruction::PushInstance(operand),
+            PUSH_ARGUMENT => Instruction::PushArgument(operand),
+            PUSH_TEMPORARY => Instruction::PushTemporary(operand),
+            PUSH_LITERAL => Instruction::PushLiteral(operand),
+            PUSH_CONSTANT => Instruction::PushConstant(Constant::from_operand(operand)?),
+            ASSIGN_INSTANCE => Instruction::AssignInstance(operand),
+            ASSIGN_TEMPORARY => Instruction::AssignTemporary(operand),
+            SEND_MESSAGE => Instruction::SendMessage { args: operand, selector: reader.byte()? },
+            CREATE_BLOCK => Instruction::CreateBlock {
+                args: operand,
+                arglocation: reader.byte()?,
+                end: reader.word()?,
+            },
+            DO_PRIMITIVE => Instruction::DoPrimitive { args: operand, number: reader.byte()? },
+            DO_SPECIAL => match operand {
+                SELF_RETURN => Instruction::SelfReturn,
+                STACK_RETURN => Instruction::StackReturn,
+                BLOCK_RETURN => Instruction::BlockReturn,
+                DUPLICATE => Instruction::Duplicate,
+                POP_TOP => Instruction::PopTop,
+                BRANCH => Instruction::Branch(reader.word()?),
+                BRANCH_IF_TRUE => Instruction::BranchIfTrue(reader.word()?),
+                BRANCH_IF_FALSE => Instruction::BranchIfFalse(reader.word()?),
+                AND_BRANCH => Instruction::AndBranch(reader.word()?),
+                OR_BRANCH => Instruction::OrBranch(reader.word()?),
+                SEND_TO_SUPER => Instruction::SendToSuper { args: reader.byte()?, selector: reader.byte()? },
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some((instruction, reader.position))
+    }
+
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        match *self {
+            Instruction::PushInstance(n) => encode_op(bytes, PUSH_INSTANCE, n),
+            Instruction::PushArgument(n) => encode_op(bytes, PUSH_ARGUMENT, n),
+            Instruction::PushTemporary(n) => encode_op(bytes, PUSH_TEMPORARY, n),
+            Instruction::PushLiteral(n) => encode_op(bytes, PUSH_LITERAL, n),
+            Instruction::PushConstant(c) => encode_op(bytes, PUSH_CONSTANT, c.operand()),
+            Instruction::AssignInstance(n) => encode_op(bytes, ASSIGN_INSTANCE, n),
+            Instruction::AssignTemporary(n) => encode_op(bytes, ASSIGN_TEMPORARY, n),
+            Instruction::SendMessage { args, selector } => {
+                encode_op(bytes, SEND_MESSAGE, args);
+                bytes.push(selector);
+            }
+            Instruction::CreateBlock { args, arglocation, end } => {
+                encode_op(bytes, CREATE_BLOCK, args);
+                bytes.push(arglocation);
+                bytes.extend(end.to_be_bytes());
+            }
+            Instruction::DoPrimitive { args, number } => {
+                encode_op(bytes, DO_PRIMITIVE, args);
+                bytes.push(number);
+            }
+            Instruction::SelfReturn => encode_op(bytes, DO_SPECIAL, SELF_RETURN),
+            Instruction::StackReturn => encode_op(bytes, DO_SPECIAL, STACK_RETURN),
+            Instruction::BlockReturn => encode_op(bytes, DO_SPECIAL, BLOCK_RETURN),
+            Instruction::Duplicate => encode_op(bytes, DO_SPECIAL, DUPLICATE),
+            Instruction::PopTop => encode_op(bytes, DO_SPECIAL, POP_TOP),
+            Instruction::Branch(target) => encode_branch(bytes, BRANCH, target),
+            Instruction::BranchIfTrue(target) => encode_branch(bytes, BRANCH_IF_TRUE, target),
+            Instruction::BranchIfFalse(target) => encode_branch(bytes, BRANCH_IF_FALSE, target),
+            Instruction::AndBranch(target) => encode_branch(bytes, AND_BRANCH, target),
+            Instruction::OrBranch(target) => encode_branch(bytes, OR_BRANCH, target),
+            Instruction::SendToSuper { args, selector } => {
+                encode_op(bytes, DO_SPECIAL, SEND_TO_SUPER);
+                bytes.push(args);
+                bytes.push(selector);
+            }
+        }
+    }
+
+    // Replaces the target of a branch or the end of a block. Used by the compiler,
+    // which doesn't know the target until the code after the jump is generated
+    pub fn with_target(self, target: u16) -> Instruction {
+        match self {
+            Instruction::CreateBlock { args, arglocation, .. } => Instruction::CreateBlock { args, arglocation, end: target },
+            Instruction::Branch(_) => Instruction::Branch(target),
+            Instruction::BranchIfTrue(_) => Instruction::BranchIfTrue(target),
+            Instruction::BranchIfFalse(_) => Instruction::BranchIfFalse(target),
+            Instruction::AndBranch(_) => Instruction::AndBranch(target),
+            Instruction::OrBranch(_) => Instruction::OrBranch(target),
+            other => other,
+        }
+    }
+}
+
+fn encode_op(bytes: &mut Vec<u8>, opcode: u8, operand: u8) {
+    if operand < 16 {
+        bytes.push((opcode << 4) | operand);
+    } else {
+        bytes.push((EXTENDED << 4) | opcode);
+        bytes.push(operand);
+    }
+}
+
+fn encode_branch(bytes: &mut Vec<u8>, special: u8, target: u16) {
+    encode_op(bytes, DO_SPECIAL, special);
+    bytes.extend(target.to_be_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn word(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.byte()?, self.byte()?]))
+    }
+}
+
+// Assembles a sequence of instructions. Mostly useful for tests
+pub fn assemble(instructions: &[Instruction]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for instruction in instructions {
+        instruction.encode(&mut bytes);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytecode_short_and_extended_forms() {
+        assert_eq!(assemble(&[Instruction::PushTemporary(3)]), vec![0x33]);
+        assert_eq!(assemble(&[Instruction::PushTemporary(20)]), vec![0x03, 20]);
+        assert_eq!(assemble(&[Instruction::StackReturn]), vec![0xF2]);
+        assert_eq!(assemble(&[Instruction::Branch(0x0102)]), vec![0xF6, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        let instructions = [
+            Instruction::PushInstance(1),
+            Instruction::PushArgument(0),
+            Instruction::PushTemporary(200),
+            Instruction::PushLiteral(17),
+            Instruction::PushConstant(Constant::Two),
+            Instruction::AssignInstance(16),
+            Instruction::AssignTemporary(2),
+            Instruction::SendMessage { args: 2, selector: 40 },
+            Instruction::CreateBlock { args: 1, arglocation: 3, end: 300 },
+            Instruction::DoPrimitive { args: 2, number: 10 },
+            Instruction::SelfReturn,
+            Instruction::StackReturn,
+            Instruction::BlockReturn,
+            Instruction::Duplicate,
+            Instruction::PopTop,
+            Instruction::Branch(1),
+            Instruction::BranchIfTrue(2),
+            Instruction::BranchIfFalse(3),
+            Instruction::AndBranch(4),
+            Instruction::OrBranch(5),
+            Instruction::SendToSuper { args: 0, selector: 1 },
+        ];
+        let bytes = assemble(&instructions);
+
+        let mut position = 0;
+        let mut decoded = vec![];
+        while position < bytes.len() {
+            let (instruction, next) = Instruction::decode(&bytes, position).unwrap();
+            decoded.push(instruction);
+            position = next;
+        }
+        assert_eq!(decoded, instructions);
+    }
+
+    #[test]
+    fn test_bytecode_invalid() {
+        assert_eq!(Instruction::decode(&[0xE0], 0), None);
+        assert_eq!(Instruction::decode(&[0xF0], 0), None);
+        assert_eq!(Instruction::decode(&[0x5F], 0), None);
+        // Truncated operands
+        assert_eq!(Instruction::decode(&[0x03], 0), None);
+        assert_eq!(Instruction::decode(&[0xF6, 0x01], 0), None);
+        assert_eq!(Instruction::decode(&[0x33], 1), None);
+    }
+}
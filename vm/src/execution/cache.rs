@@ -0,0 +1,85 @@
+// Method cache
+//
+// Direct mapped cache of method lookups, indexed by a hash of the class and the
+// selector. Selectors are interned Symbols, so they are compared by pointer. Each
+// entry keeps the method found and the number of superclasses that had to be
+// followed to get to it, so that the object holding the instance variables for
+// the method can be found from any receiver.
+//
+// Entries are not counted references, so the cache has to be flushed whenever a
+// lookup could change its result (a method gets installed) or a class pointer
+// could start referring to a different class (a class is allocated, or objects are
+// reclaimed or moved by the collector).
+
+use crate::objects::object::ObjectPointer;
+
+const CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    class: ObjectPointer,
+    selector: ObjectPointer,
+    method: ObjectPointer,
+    depth: usize,
+}
+
+#[derive(Debug)]
+pub struct MethodCache {
+    entries: Vec<Option<Entry>>,
+}
+
+impl MethodCache {
+    pub fn new() -> Self {
+        MethodCache { entries: vec![None; CACHE_SIZE] }
+    }
+
+    pub fn lookup(&self, class: ObjectPointer, selector: ObjectPointer) -> Option<(ObjectPointer, usize)> {
+        match &self.entries[Self::index(class, selector)] {
+            Some(entry) if entry.class == class && entry.selector == selector => Some((entry.method, entry.depth)),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, class: ObjectPointer, selector: ObjectPointer, method: ObjectPointer, depth: usize) {
+        self.entries[Self::index(class, selector)] = Some(Entry {
+            class,
+            selector,
+            method,
+            depth,
+        });
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+
+    fn index(class: ObjectPointer, selector: ObjectPointer) -> usize {
+        let hash = (class ^ selector.rotate_left(32)).wrapping_mul(0x9e3779b97f4a7c15);
+
+        (hash >> 32) as usize % CACHE_SIZE
+    }
+}
+
+impl Default for MethodCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_lookup_and_flush() {
+        let mut cache = MethodCache::new();
+        cache.insert(10, 30, 20, 1);
+
+        assert_eq!(cache.lookup(10, 30), Some((20, 1)));
+        assert_eq!(cache.lookup(10, 31), None);
+        assert_eq!(cache.lookup(11, 30), None);
+
+        cache.flush();
+        assert_eq!(cache.lookup(10, 30), None);
+    }
+}
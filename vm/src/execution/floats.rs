@@ -0,0 +1,218 @@
+// Float arithmetic
+//
+// Floats follow IEEE 754: dividing by zero gives an infinity, and operations without
+// a meaningful result (0.0 / 0.0, -1.0 sqrt) give nan. Only the conversions to
+// integers, which have neither, fail for them.
+//
+// The float primitives take any number, and convert the ones that aren't floats to
+// the closest float, except for truncated and rounded, which are exact.
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::FromPrimitive;
+
+use crate::objects::{
+    number::Float,
+    object::ObjectPointer,
+    string::StringObject,
+};
+use super::{ExecutionError, Number, VirtualMachine};
+use super::primitives::{
+    FLOAT_ADD, FLOAT_SUBTRACT, FLOAT_MULTIPLY, FLOAT_DIVIDE,
+    FLOAT_LESS, FLOAT_GREATER, FLOAT_LESS_EQUAL, FLOAT_GREATER_EQUAL, FLOAT_EQUAL,
+    TRUNCATED, ROUNDED, SQRT, LN, EXP, SIN, COS, TAN, ARCTAN, FLOAT_PRINT, FLOAT_READ,
+};
+
+impl VirtualMachine {
+    pub fn new_float(&mut self, value: f64) -> Result<ObjectPointer, ExecutionError> {
+        Ok(self.memory.allocate(Float::new(value))?)
+    }
+
+    pub fn float_value(&self, ptr: ObjectPointer) -> Option<f64> {
+        self.memory.get::<Float>(ptr).ok().map(Float::value)
+    }
+
+    // Applies one of the float primitives. None when the arguments aren't the ones
+    // the primitive takes, or it has no result for them
+    pub(super) fn float_operation(&mut self, number: u8, args: &[ObjectPointer])
+        -> Result<Option<ObjectPointer>, ExecutionError>
+    {
+        if let (FLOAT_READ, &[text]) = (number, args) {
+            let value = self.memory.get::<StringObject>(text).ok().and_then(|text| Float::read(text.value()));
+            return value.map(|value| self.new_float(value)).transpose();
+        }
+
+        if let (TRUNCATED | ROUNDED, &[arg]) = (number, args) {
+            let value = match self.number_value(arg) {
+                Some(Number::Float(value)) if number == TRUNCATED => return self.float_to_integer(value.trunc()),
+                Some(Number::Float(value)) => return self.float_to_integer(value.round()),
+                Some(Number::Integer(value)) => BigRational::from_integer(value),
+                Some(Number::Fraction(value)) => value,
+                None => return Ok(None),
+            };
+            let value = if number == TRUNCATED { value.trunc() } else { value.round() };
+            return Ok(Some(self.new_integer(value.to_integer())?));
+        }
+
+        let values = args.iter()
+            .map(|&ptr| self.number_value(ptr).as_ref().map(Number::to_f64))
+            .collect::<Option<Vec<f64>>>();
+        let value = match (number, values.as_deref()) {
+            (FLOAT_ADD, Some(&[a, b])) => a + b,
+            (FLOAT_SUBTRACT, Some(&[a, b])) => a - b,
+            (FLOAT_MULTIPLY, Some(&[a, b])) => a * b,
+            (FLOAT_DIVIDE, Some(&[a, b])) => a / b,
+            (FLOAT_LESS, Some(&[a, b])) => return Ok(Some(self.boolean(a < b))),
+            (FLOAT_GREATER, Some(&[a, b])) => return Ok(Some(self.boolean(a > b))),
+            (FLOAT_LESS_EQUAL, Some(&[a, b])) => return Ok(Some(self.boolean(a <= b))),
+            (FLOAT_GREATER_EQUAL, Some(&[a, b])) => return Ok(Some(self.boolean(a >= b))),
+            (FLOAT_EQUAL, Some(&[a, b])) => return Ok(Some(self.boolean(a == b))),
+            (SQRT, Some(&[a])) => a.sqrt(),
+            (LN, Some(&[a])) => a.ln(),
+            (EXP, Some(&[a])) => a.exp(),
+            (SIN, Some(&[a])) => a.sin(),
+            (COS, Some(&[a])) => a.cos(),
+            (TAN, Some(&[a])) => a.tan(),
+            (ARCTAN, Some(&[a])) => a.atan(),
+            (FLOAT_PRINT, Some(&[a])) => {
+                let text = Float::new(a).to_string();
+                return Ok(Some(self.new_string(&text)?));
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(self.new_float(value)?))
+    }
+
+    // Integral floats as integers, large ones if needed. Nan and the infinities have
+    // no integer
+    fn float_to_integer(&mut self, value: f64) -> Result<Option<ObjectPointer>, ExecutionError> {
+        BigInt::from_f64(value)
+            .map(|value| self.new_integer(value))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LoadError;
+    use crate::execution::primitives::Outcome;
+    use crate::objects::object::Pointer;
+
+    // Float answered by a primitive
+    fn apply(vm: &mut VirtualMachine, number: u8, args: &[ObjectPointer]) -> Result<Option<f64>, ExecutionError> {
+        Ok(match vm.primitive(number, args)? {
+            Outcome::Value(ptr) => vm.float_value(ptr),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_floats_print_round_trip() {
+        let values = [
+            0.1, 1.0 / 3.0, 2.5, 100.0, -0.0, 1e300, 1.5e-7, 5e-324, f64::MAX, f64::MIN_POSITIVE,
+            f64::EPSILON, 123456789.125, f64::INFINITY, f64::NEG_INFINITY,
+        ];
+        for value in values {
+            let text = Float::new(value).to_string();
+            assert_eq!(Float::read(&text).map(f64::to_bits), Some(value.to_bits()), "{}", text);
+        }
+
+        // Shortest representations
+        assert_eq!(Float::new(0.1).to_string(), "0.1");
+        assert_eq!(Float::new(100.0).to_string(), "100.0");
+        assert_eq!(Float::new(1e300).to_string(), "1.0e300");
+        assert_eq!(Float::new(1.5e-7).to_string(), "1.5e-7");
+        assert_eq!(Float::new(0.1 + 0.2).to_string(), "0.30000000000000004");
+        assert_eq!(Float::new(f64::NAN).to_string(), "nan");
+        assert_eq!(Float::new(f64::NEG_INFINITY).to_string(), "-inf");
+        assert!(Float::read("nan").is_some_and(f64::is_nan));
+        assert_eq!(Float::read(" 2 "), Some(2.0));
+        assert_eq!(Float::read("infinity"), None);
+        assert_eq!(Float::read("1.5x"), None);
+
+        // Only literals are parsed
+        assert_eq!(Float::parse("-1.5e-3"), Some(-1.5e-3));
+        assert_eq!(Float::parse("inf"), None);
+        assert_eq!(Float::parse("nan"), None);
+        // Nor does any literal out of range
+        assert_eq!(Float::parse("1e400"), None);
+        assert_eq!(Float::parse("-1.5e400"), None);
+        assert_eq!(Float::parse("1e-400"), None);
+        assert_eq!(Float::parse("0.0e-400"), Some(0.0));
+        assert_eq!(Float::parse("5e-324"), Some(5e-324));
+        for text in ["1e5e", "1.", ".5", "1e", "1e-", "-", "1-2", "1.2.3", "--1", "1e+5"] {
+            assert_eq!(Float::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_floats_literals_round_trip() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        for value in [0.1, -2.5e-10, 1e300, 5e-324] {
+            let result = vm.evaluate_source("test.st", &Float::new(value).to_string())?;
+            assert_eq!(vm.float_value(result), Some(value));
+            vm.memory_mut().decrement_ref(result)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_floats_operations() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let (yes, no) = (Outcome::Value(vm.true_object()), Outcome::Value(vm.false_object()));
+        let mut float = |value| -> Result<ObjectPointer, ExecutionError> {
+            let ptr = vm.new_float(value)?;
+            vm.memory_mut().increment_ref(ptr)?;
+            Ok(ptr)
+        };
+        let (a, b, zero, nan, big) = (float(7.5)?, float(-2.0)?, float(0.0)?, float(f64::NAN)?, float(1e20)?);
+        assert_eq!(apply(&mut vm, FLOAT_ADD, &[a, b])?, Some(5.5));
+        assert_eq!(apply(&mut vm, FLOAT_SUBTRACT, &[a, b])?, Some(9.5));
+        assert_eq!(apply(&mut vm, FLOAT_MULTIPLY, &[a, b])?, Some(-15.0));
+        assert_eq!(apply(&mut vm, FLOAT_DIVIDE, &[a, b])?, Some(-3.75));
+        assert_eq!(apply(&mut vm, FLOAT_DIVIDE, &[a, zero])?, Some(f64::INFINITY));
+        assert!(apply(&mut vm, FLOAT_DIVIDE, &[zero, zero])?.is_some_and(f64::is_nan));
+        assert!(apply(&mut vm, SQRT, &[b])?.is_some_and(f64::is_nan));
+        assert_eq!(apply(&mut vm, SQRT, &[big])?, Some(1e10));
+        assert_eq!(apply(&mut vm, LN, &[zero])?, Some(f64::NEG_INFINITY));
+        assert_eq!(apply(&mut vm, EXP, &[zero])?, Some(1.0));
+        assert_eq!(apply(&mut vm, SIN, &[zero])?, Some(0.0));
+        assert_eq!(apply(&mut vm, COS, &[zero])?, Some(1.0));
+        assert_eq!(apply(&mut vm, TAN, &[zero])?, Some(0.0));
+        assert_eq!(apply(&mut vm, ARCTAN, &[zero])?, Some(0.0));
+
+        assert_eq!(vm.primitive(FLOAT_LESS, &[b, a])?, yes);
+        assert_eq!(vm.primitive(FLOAT_GREATER_EQUAL, &[b, a])?, no);
+        assert_eq!(vm.primitive(FLOAT_LESS_EQUAL, &[a, a])?, yes);
+        // Nan isn't equal to anything, itself included
+        assert_eq!(vm.primitive(FLOAT_EQUAL, &[nan, nan])?, no);
+        assert_eq!(vm.primitive(FLOAT_GREATER, &[nan, a])?, no);
+
+        assert_eq!(vm.primitive(TRUNCATED, &[a])?, Outcome::Value(ObjectPointer::from_small_integer(7)));
+        assert_eq!(vm.primitive(ROUNDED, &[a])?, Outcome::Value(ObjectPointer::from_small_integer(8)));
+        let Outcome::Value(large) = vm.primitive(TRUNCATED, &[big])? else { panic!("truncated failed") };
+        assert_eq!(vm.integer_value(large), Some(BigInt::from(10).pow(20)));
+        assert_eq!(vm.primitive(ROUNDED, &[nan])?, Outcome::Failed);
+        assert_eq!(apply(&mut vm, FLOAT_ADD, &[a, ObjectPointer::from_small_integer(1)])?, Some(8.5));
+        assert_eq!(vm.primitive(FLOAT_ADD, &[a, vm.true_object()])?, Outcome::Failed);
+        // Exact numbers are rounded exactly
+        let large = vm.new_number(Number::Fraction(BigRational::new((BigInt::from(1) << 80) + 1, 2.into())))?;
+        let Outcome::Value(rounded) = vm.primitive(ROUNDED, &[large])? else { panic!("rounded failed") };
+        assert_eq!(vm.integer_value(rounded), Some((BigInt::from(1) << 79) + 1));
+        assert_eq!(apply(&mut vm, SQRT, &[ObjectPointer::from_small_integer(16)])?, Some(4.0));
+
+        let Outcome::Value(text) = vm.primitive(FLOAT_PRINT, &[a])? else { panic!("printString failed") };
+        assert_eq!(vm.string_value(text)?, "7.5");
+        let Outcome::Value(read) = vm.primitive(FLOAT_READ, &[text])? else { panic!("readFrom: failed") };
+        assert_eq!(vm.float_value(read), Some(7.5));
+        let garbage = vm.new_string("seven")?;
+        assert_eq!(vm.primitive(FLOAT_READ, &[garbage])?, Outcome::Failed);
+        let infinity = vm.new_string("-inf")?;
+        assert_eq!(apply(&mut vm, FLOAT_READ, &[infinity])?, Some(f64::NEG_INFINITY));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+// Saving and loading the virtual machine as an image
+//
+// Besides the object memory (see the image module), images hold the roots of the
+// machine, right after the header:
+//
+//   * true, false and the active context (u64 each)
+//   * the first and last processes of the ready queue (u64 each)
+//   * globals: their number (u32), then the name and value of each of them
+//   * interned symbols: their number (u32), then a pointer to each of them
+//   * the preallocated chars: a pointer to each of them, by their code
+//   * the other interned chars: their number (u32), then a pointer to each of them
+//
+// Reference counts are saved along with the objects, so they already account for
+// the references held by the roots.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::image::{self, ImageError, ImageReader, ImageWriter};
+use crate::memory::ObjectMemory;
+use crate::objects::{
+    character::{Char, PREALLOCATED_CHARS},
+    object::{ObjectPointer, ObjectType, Pointer},
+    symbol::Symbol,
+};
+use super::cache::MethodCache;
+use super::scheduler::Scheduler;
+use super::VirtualMachine;
+
+impl VirtualMachine {
+    pub fn save_image(&self, path: impl AsRef<Path>) -> Result<(), ImageError> {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        self.write_image(&mut file)?;
+        Ok(file.flush()?)
+    }
+
+    pub fn load_image(path: impl AsRef<Path>) -> Result<Self, ImageError> {
+        Self::read_image(BufReader::new(fs::File::open(path)?))
+    }
+
+    pub fn write_image<W: Write>(&self, writer: W) -> Result<(), ImageError> {
+        let mut writer = ImageWriter::new(writer);
+        image::write_header(&mut writer, &self.memory)?;
+
+        writer.pointer(self.true_object)?;
+        writer.pointer(self.false_object)?;
+        writer.pointer(self.active)?;
+        let [first, last, _] = self.scheduler.roots();
+        writer.pointer(first)?;
+        writer.pointer(last)?;
+
+        // Sorted, so that saving the same machine twice gives the same image
+        let mut globals: Vec<(&String, &ObjectPointer)> = self.globals.iter().collect();
+        globals.sort();
+        writer.length(globals.len())?;
+        for (name, &value) in globals {
+            writer.str(name)?;
+            writer.pointer(value)?;
+        }
+
+        let mut symbols: Vec<ObjectPointer> = self.symbols.values().copied().collect();
+        symbols.sort();
+        writer.length(symbols.len())?;
+        for symbol in symbols {
+            writer.pointer(symbol)?;
+        }
+
+        for &char_object in &self.chars {
+            writer.pointer(char_object)?;
+        }
+
+        let mut wide_chars: Vec<ObjectPointer> = self.wide_chars.values().copied().collect();
+        wide_chars.sort();
+        writer.length(wide_chars.len())?;
+        for char_object in wide_chars {
+            writer.pointer(char_object)?;
+        }
+
+        image::write_objects(&mut writer, &self.memory)
+    }
+
+    pub fn read_image<R: Read>(reader: R) -> Result<Self, ImageError> {
+        let mut reader = ImageReader::new(reader);
+        let mut memory = image::read_header(&mut reader)?;
+
+        let true_object = reader.pointer()?;
+        let false_object = reader.pointer()?;
+        let active = reader.pointer()?;
+        let scheduler = Scheduler::restore(reader.pointer()?, reader.pointer()?);
+
+        let mut globals = HashMap::new();
+        for _ in 0..reader.length()? {
+            let name = reader.string()?;
+            globals.insert(name, reader.pointer()?);
+        }
+
+        let symbol_count = reader.length()?;
+        let symbols = (0..symbol_count)
+            .map(|_| reader.pointer())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let chars = (0..PREALLOCATED_CHARS)
+            .map(|_| reader.pointer())
+            .collect::<Result<Vec<_>, _>>()?;
+        let wide_char_count = reader.length()?;
+        let wide_chars = (0..wide_char_count)
+            .map(|_| reader.pointer())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        image::read_objects(&mut reader, &mut memory)?;
+        if !reader.at_end()? {
+            return Err(ImageError::Corrupt("unexpected data after the objects".to_string()));
+        }
+        let roots = [true_object, false_object, active].into_iter().chain(scheduler.roots());
+        check_references(&memory, roots.chain(globals.values().copied()))?;
+        for (code, &char_object) in chars.iter().enumerate() {
+            if memory.get::<Char>(char_object).map(Char::value).ok() != Some(code as u32) {
+                return Err(ImageError::Corrupt(format!("not the char {}: {:#x}", code, char_object)));
+            }
+        }
+
+        let mut wide_char_table = HashMap::new();
+        for char_object in wide_chars {
+            match memory.get::<Char>(char_object).map(Char::value) {
+                Ok(code) if code as usize >= PREALLOCATED_CHARS => wide_char_table.insert(code, char_object),
+                _ => return Err(ImageError::Corrupt(format!("not a wide char: {:#x}", char_object))),
+            };
+        }
+
+        let mut symbol_table = HashMap::new();
+        for symbol in symbols {
+            if memory.object_type(symbol).ok() != Some(ObjectType::Symbol) {
+                return Err(ImageError::Corrupt(format!("not a symbol: {:#x}", symbol)));
+            }
+            symbol_table.insert(memory.get::<Symbol>(symbol)?.value().to_string(), symbol);
+        }
+
+        Ok(VirtualMachine {
+            memory,
+            globals,
+            true_object,
+            false_object,
+            active,
+            symbols: symbol_table,
+            chars,
+            wide_chars: wide_char_table,
+            method_cache: MethodCache::new(),
+            scheduler,
+        })
+    }
+}
+
+// Makes sure that the roots and every object in memory only point to objects that
+// are there
+fn check_references(memory: &ObjectMemory, roots: impl Iterator<Item = ObjectPointer>) -> Result<(), ImageError> {
+    let mut references: Vec<ObjectPointer> = roots.collect();
+    for ptr in memory.live_objects() {
+        references.extend(memory.object(ptr)?.references());
+    }
+
+    match references.into_iter().find(|&ptr| !ptr.is_null() && memory.object(ptr).is_err()) {
+        Some(ptr) => Err(ImageError::Corrupt(format!("dangling pointer: {:#x}", ptr))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LoadError;
+    use crate::objects::class::Class;
+
+    const SOURCE: &str = "\
+Class Object
+[
+    == other
+        ^ <1 self other>
+]
+Class Class
+[
+    new
+        ^ <3 self>
+]
+Class Point
+| x y |
+[
+    x: ax y: ay
+        x <- ax.
+        y <- ay
+|
+    sum
+        ^ x
+]
+";
+
+    fn write(vm: &VirtualMachine) -> Result<Vec<u8>, ImageError> {
+        let mut bytes = vec![];
+        vm.write_image(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn test_image_round_trip() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+        vm.load_source("script.st", "P <- Point new x: 3 y: 4. Name <- 'point'. Bytes <- #[1 2 3]. F <- 2.5. L <- $\u{3bb}")?;
+
+        let bytes = write(&vm).unwrap();
+        let mut loaded = VirtualMachine::read_image(bytes.as_slice()).unwrap();
+
+        // Same objects, at the same locations
+        assert_eq!(loaded.memory().live_objects(), vm.memory().live_objects());
+        for ptr in vm.memory().live_objects() {
+            assert_eq!(loaded.memory().ref_count(ptr)?, vm.memory().ref_count(ptr)?);
+            assert_eq!(loaded.memory().object(ptr)?.references(), vm.memory().object(ptr)?.references());
+        }
+        assert_eq!(loaded.true_object(), vm.true_object());
+        assert_eq!(loaded.global("P"), vm.global("P"));
+        assert_eq!(loaded.find_symbol("x:y:"), vm.find_symbol("x:y:"));
+        assert_eq!(loaded.new_char('a')?, vm.new_char('a')?);
+        assert_eq!(loaded.new_char('λ')?, loaded.global("L").unwrap());
+        assert_eq!(write(&loaded).unwrap(), bytes);
+
+        let point = loaded.global("Point").unwrap();
+        assert_eq!(loaded.class_name(point), "Point");
+        assert_eq!(loaded.memory().get::<Class>(point)?.super_class(), loaded.global("Object").unwrap());
+        assert_eq!(loaded.send(loaded.global("P").unwrap(), "sum", &[])?, ObjectPointer::from_small_integer(3));
+
+        // The loaded machine keeps working as usual
+        loaded.load_source("script.st", "Q <- Point new x: 5 y: 6")?;
+        assert_eq!(loaded.send(loaded.global("Q").unwrap(), "sum", &[])?, ObjectPointer::from_small_integer(5));
+        loaded.collect_garbage()?;
+        assert!(loaded.find_symbol("sum").is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_errors() -> Result<(), LoadError> {
+        assert_eq!(VirtualMachine::read_image(b"nope".as_slice()).err(), Some(ImageError::NotAnImage));
+
+        let vm = VirtualMachine::new()?;
+        let mut bytes = write(&vm).unwrap();
+        bytes[4] = 99;
+        assert_eq!(VirtualMachine::read_image(bytes.as_slice()).err(), Some(ImageError::UnsupportedVersion(99)));
+
+        let bytes = write(&vm).unwrap();
+        let truncated = &bytes[..bytes.len() - 1];
+        assert!(matches!(VirtualMachine::read_image(truncated).err(), Some(ImageError::Io(_))));
+
+        Ok(())
+    }
+}
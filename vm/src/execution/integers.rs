@@ -0,0 +1,210 @@
+// Integer arithmetic
+//
+// Integers within 32 bits are small integers, stored in the pointer itself. Results
+// that don't fit are promoted to large integers, which have arbitrary precision,
+// and large integer results that fit are turned back into small integers. This way
+// each value has a single representation, and code only ever sees large integers
+// for values that need them.
+//
+// Operations on two small integers are done in 64 bits, which can't overflow for
+// them. The others go through arbitrary precision arithmetic. Integers mixed with
+// other kinds of numbers are dealt with in numbers.
+
+use num_bigint::BigInt;
+use num_integer::{div_floor, gcd, mod_floor};
+use num_traits::{ToPrimitive, Zero};
+
+use crate::objects::{
+    number::{Integer, LargeInteger},
+    object::{ObjectPointer, Pointer},
+};
+use super::{ExecutionError, VirtualMachine};
+use super::numbers::{compare, Answer};
+use super::primitives::{
+    ADD, SUBTRACT, MULTIPLY, DIVIDE, MODULO, QUO, REM, GCD, BIT_AND, BIT_OR, BIT_SHIFT,
+};
+
+impl VirtualMachine {
+    // Small integer when the value fits, large integer otherwise
+    pub fn new_integer(&mut self, value: BigInt) -> Result<ObjectPointer, ExecutionError> {
+        match value.to_i32() {
+            Some(value) => Ok(ObjectPointer::from_small_integer(value)),
+            None => Ok(self.memory.allocate(LargeInteger::new(value))?),
+        }
+    }
+
+    // Value of any kind of integer
+    pub fn integer_value(&self, ptr: ObjectPointer) -> Option<BigInt> {
+        if let Some(value) = ptr.small_integer() {
+            return Some(BigInt::from(value));
+        }
+
+        match self.memory.get::<LargeInteger>(ptr) {
+            Ok(large) => Some(large.value().clone()),
+            Err(_) => self.memory.get::<Integer>(ptr).ok().map(|integer| BigInt::from(integer.value())),
+        }
+    }
+}
+
+// The operations that can be done in 64 bits for any two 32 bit values. None for
+// the others
+pub(super) fn small_operation(number: u8, a: i64, b: i64) -> Option<Answer<i64>> {
+    let value = match number {
+        ADD => a + b,
+        SUBTRACT => a - b,
+        MULTIPLY => a * b,
+        DIVIDE | MODULO | QUO | REM if b == 0 => return None,
+        DIVIDE => div_floor(a, b),
+        MODULO => mod_floor(a, b),
+        QUO => a / b,
+        REM => a % b,
+        GCD => gcd(a, b),
+        BIT_AND => a & b,
+        BIT_OR => a | b,
+        BIT_SHIFT if (-63..=31).contains(&b) => if b < 0 { a >> -b } else { a << b },
+        _ => return compare(number, &a, &b).map(Answer::Boolean),
+    };
+
+    Some(Answer::Number(value))
+}
+
+pub(super) fn large_operation(number: u8, a: &BigInt, b: &BigInt) -> Option<Answer<BigInt>> {
+    let value = match number {
+        ADD => a + b,
+        SUBTRACT => a - b,
+        MULTIPLY => a * b,
+        DIVIDE | MODULO | QUO | REM if b.is_zero() => return None,
+        DIVIDE => div_floor(a.clone(), b.clone()),
+        MODULO => mod_floor(a.clone(), b.clone()),
+        QUO => a / b,
+        REM => a % b,
+        GCD => gcd(a.clone(), b.clone()),
+        BIT_AND => a & b,
+        BIT_OR => a | b,
+        // Right shifts round towards negative infinity, like the small ones
+        BIT_SHIFT => match b.to_i32()? {
+            shift if shift < 0 => a >> shift.unsigned_abs(),
+            shift => a << shift as u32,
+        },
+        _ => return compare(number, a, b).map(Answer::Boolean),
+    };
+
+    Some(Answer::Number(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LoadError;
+    use crate::execution::primitives::{Outcome, LESS, GREATER_EQUAL, EQUAL, NOT_EQUAL};
+    use crate::objects::object::ObjectType;
+
+    fn evaluate(vm: &mut VirtualMachine, source: &str) -> Result<String, LoadError> {
+        let result = vm.evaluate_source("test.st", source)?;
+        let value = vm.integer_value(result).map(|value| value.to_string()).unwrap_or_default();
+        vm.memory_mut().decrement_ref(result)?;
+        Ok(value)
+    }
+
+    #[test]
+    fn test_integers_promotion() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", "\
+Class Integer
+[
+    + n
+        ^ <20 self n>
+|
+    - n
+        ^ <21 self n>
+|
+    * n
+        ^ <22 self n>
+|
+    < n
+        ^ <31 self n>
+|
+    factorial
+        | result i |
+        result <- 1.
+        i <- 1.
+        [ i < self ] whileTrue: [ i <- i + 1. result <- result * i ].
+        ^ result
+]
+Class LargePositiveInteger :Integer
+[
+]
+Class LargeNegativeInteger :Integer
+[
+]
+")?;
+
+        assert_eq!(evaluate(&mut vm, "2147483647 + 1")?, "2147483648");
+        assert_eq!(evaluate(&mut vm, "-2147483647 - 2")?, "-2147483649");
+        assert_eq!(evaluate(&mut vm, "30 factorial")?, "265252859812191058636308480000000");
+        // Back to small integers when the result fits
+        let result = vm.evaluate_source("test.st", "2147483647 + 1 - 1")?;
+        assert_eq!(result, ObjectPointer::from_small_integer(i32::MAX));
+
+        let large = vm.evaluate_source("test.st", "20 factorial")?;
+        assert_eq!(vm.memory().object_type(large)?, ObjectType::LargeInteger);
+        assert_eq!(vm.class_name(vm.class_of(large)?), "LargePositiveInteger");
+        vm.memory_mut().decrement_ref(large)?;
+        assert!(vm.memory().object(large).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_integers_operations() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let big = vm.new_integer(BigInt::from(1) << 70)?;
+        vm.memory_mut().increment_ref(big)?;
+        let int = ObjectPointer::from_small_integer;
+        let truth = vm.true_object();
+        let mut apply = |number, a, b| -> Result<String, ExecutionError> {
+            Ok(match vm.primitive(number, &[a, b])? {
+                Outcome::Value(ptr) if ptr == vm.true_object() => "true".to_string(),
+                Outcome::Value(ptr) if ptr == vm.false_object() => "false".to_string(),
+                Outcome::Value(ptr) => vm.integer_value(ptr).unwrap().to_string(),
+                outcome => format!("{:?}", outcome),
+            })
+        };
+
+        // Division rounds towards negative infinity, quo: towards zero
+        assert_eq!(apply(DIVIDE, int(-7), int(2))?, "-4");
+        assert_eq!(apply(MODULO, int(-7), int(2))?, "1");
+        assert_eq!(apply(QUO, int(-7), int(2))?, "-3");
+        assert_eq!(apply(REM, int(-7), int(2))?, "-1");
+        assert_eq!(apply(DIVIDE, int(i32::MIN), int(-1))?, "2147483648");
+        assert_eq!(apply(DIVIDE, big, int(0))?, "Failed");
+        assert_eq!(apply(MODULO, int(1), int(0))?, "Failed");
+        assert_eq!(apply(DIVIDE, big, int(1 << 30))?, "1099511627776");
+        assert_eq!(apply(MODULO, big, int(-3))?, "-2");
+
+        assert_eq!(apply(GCD, int(-12), int(18))?, "6");
+        assert_eq!(apply(GCD, big, int(96))?, "32");
+        assert_eq!(apply(BIT_AND, int(-1), int(12))?, "12");
+        assert_eq!(apply(BIT_OR, big, int(-1))?, "-1");
+        assert_eq!(apply(BIT_SHIFT, int(1), int(40))?, "1099511627776");
+        assert_eq!(apply(BIT_SHIFT, int(-5), int(-1))?, "-3");
+        assert_eq!(apply(BIT_SHIFT, big, int(-70))?, "1");
+
+        assert_eq!(apply(LESS, int(3), big)?, "true");
+        assert_eq!(apply(GREATER_EQUAL, int(3), int(3))?, "true");
+        assert_eq!(apply(EQUAL, big, big)?, "true");
+        assert_eq!(apply(NOT_EQUAL, big, int(3))?, "true");
+        assert_eq!(apply(ADD, int(3), truth)?, "Failed");
+
+        // Images keep them
+        let negative = vm.new_integer(BigInt::from(-3) << 100)?;
+        vm.set_global("Negative", negative)?;
+        let mut bytes = vec![];
+        vm.write_image(&mut bytes).unwrap();
+        let loaded = VirtualMachine::read_image(bytes.as_slice()).unwrap();
+        assert_eq!(loaded.integer_value(big), Some(BigInt::from(1) << 70));
+        assert_eq!(loaded.integer_value(negative), Some(BigInt::from(-3) << 100));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,693 @@
+// Bytecode interpreter
+//
+// Executes the active context one instruction at a time. Every context has its
+// own stack (an array) holding a reference to each of its elements: pushing a
+// value increments its count, and popping hands that reference to the caller,
+// who has to release it once done.
+//
+// Method contexts are marked as returned by moving their current_byte past any
+// valid position, so that non local returns from blocks that outlive them can be
+// told apart.
+
+use crate::objects::{
+    block::Block,
+    byte::ByteArray,
+    class::Class,
+    interp::Interpreter,
+    method::{METHOD_BYTECODE, METHOD_CLASS, METHOD_CONTEXT_SIZE, METHOD_LITERALS, METHOD_STACK_MAX},
+    object::{Object, ObjectPointer, Pointer},
+};
+use super::bytecode::{Constant, Instruction};
+use super::primitives::Outcome;
+use super::{ExecutionError, VirtualMachine};
+
+const RETURNED: u32 = u32::MAX;
+
+impl VirtualMachine {
+    // Sends a message and runs until it returns. The caller owns a reference to
+    // the result, and has to release it
+    pub fn send(&mut self, receiver: ObjectPointer, selector: &str, args: &[ObjectPointer]) -> Result<ObjectPointer, ExecutionError> {
+        let class = self.class_of(receiver)?;
+        if let Some((method, level)) = self.lookup_method(class, receiver, selector)? {
+            return self.run_method(method, level, receiver, args);
+        }
+
+        let selector = self.new_symbol(selector)?;
+        let (method, level, message) = self.not_understood(receiver, selector, args)?;
+        self.memory.increment_ref(message)?;
+        let result = self.run_method(method, level, receiver, &[message]);
+        self.memory.decrement_ref(message)?;
+
+        result
+    }
+
+    // Runs a method, not necessarily installed in any class, with the given receiver
+    // (and level, the object holding the instance variables it sees). The caller
+    // owns a reference to the result
+    pub fn run_method(&mut self, method: ObjectPointer, level: ObjectPointer, receiver: ObjectPointer, args: &[ObjectPointer]) -> Result<ObjectPointer, ExecutionError> {
+        let values: Vec<ObjectPointer> = [receiver].into_iter().chain(args.iter().copied()).collect();
+        let context = self.activate(method, level, &values, ObjectPointer::null())?;
+
+        // The new context runs on its own. Whatever was active is resumed afterwards
+        let saved = std::mem::replace(&mut self.active, ObjectPointer::null());
+        let result = self.set_active(context).and_then(|_| self.execute());
+        if result.is_err() {
+            self.set_active(ObjectPointer::null())?;
+        }
+        self.active = saved;
+
+        result
+    }
+
+    // Runs the active context until the outermost one returns
+    fn execute(&mut self) -> Result<ObjectPointer, ExecutionError> {
+        loop {
+            if let Some(result) = self.step()? {
+                return Ok(result);
+            }
+        }
+    }
+
+    // Executes a single instruction. Returns the result once the outermost context
+    // returns
+    pub fn step(&mut self) -> Result<Option<ObjectPointer>, ExecutionError> {
+        let (instruction, next) = self.fetch()?;
+        self.context_mut()?.set_current_byte(next as u32);
+
+        match instruction {
+            Instruction::PushInstance(index) => {
+                let value = self.memory.inst_var(self.context()?.receiver(), index as usize)?;
+                self.push(value)?;
+            }
+            Instruction::PushArgument(index) => {
+                let value = self.memory.inst_var(self.context()?.arguments(), index as usize)?;
+                self.push(value)?;
+            }
+            Instruction::PushTemporary(index) => {
+                let value = self.memory.inst_var(self.context()?.context(), index as usize)?;
+                self.push(value)?;
+            }
+            Instruction::PushLiteral(index) => {
+                let value = self.memory.inst_var(self.context()?.literals(), index as usize)?;
+                self.push(value)?;
+            }
+            Instruction::PushConstant(constant) => {
+                let value = self.constant(constant);
+                self.push(value)?;
+            }
+            // Assignments leave the value on the stack
+            Instruction::AssignInstance(index) => {
+                let value = self.top()?;
+                self.memory.put_inst_var(self.context()?.receiver(), index as usize, value)?;
+            }
+            Instruction::AssignTemporary(index) => {
+                let value = self.top()?;
+                self.memory.put_inst_var(self.context()?.context(), index as usize, value)?;
+            }
+            Instruction::SendMessage { args, selector } => {
+                let selector = self.selector(selector)?;
+                let values = self.pop_values(args as usize + 1)?;
+                let class = self.class_of(values[0])?;
+                self.send_message(class, values[0], selector, values)?;
+            }
+            Instruction::SendToSuper { args, selector } => {
+                let selector = self.selector(selector)?;
+                let values = self.pop_values(args as usize + 1)?;
+                let (method, receiver) = {
+                    let context = self.context()?;
+                    (context.method(), context.receiver())
+                };
+                let method_class = self.memory.inst_var(method, METHOD_CLASS)?;
+                let class = self.memory.get::<Class>(method_class)?.super_class();
+                let level = self.super_object(receiver);
+                self.send_message(class, level, selector, values)?;
+            }
+            Instruction::CreateBlock { args, arglocation, end } => {
+                let template = self.context()?.block_template(self.active, next as u32);
+                let template = self.memory.allocate_counted(template)?;
+                let block = self.memory.allocate_counted(Block::new(template, args as u32, arglocation as u32))?;
+                self.push(block)?;
+                self.context_mut()?.set_current_byte(end as u32);
+            }
+            Instruction::DoPrimitive { args, number } => {
+                let values = self.pop_values(args as usize)?;
+                let outcome = self.primitive(number, &values);
+                if let Ok(Outcome::Value(value)) = outcome {
+                    self.push(value)?;
+                } else if let Ok(Outcome::Failed) = outcome {
+                    self.push(ObjectPointer::null())?;
+                }
+                self.release(&values)?;
+                outcome?;
+            }
+            Instruction::SelfReturn => {
+                let value = self.memory.inst_var(self.context()?.arguments(), 0)?;
+                self.memory.increment_ref(value)?;
+                return self.method_return(value);
+            }
+            Instruction::StackReturn => {
+                let value = self.pop()?;
+                return self.method_return(value);
+            }
+            Instruction::BlockReturn => {
+                let value = self.pop()?;
+                return self.return_from(self.active, value);
+            }
+            Instruction::Duplicate => {
+                let value = self.top()?;
+                self.push(value)?;
+            }
+            Instruction::PopTop => {
+                let value = self.pop()?;
+                self.release(&[value])?;
+            }
+            Instruction::Branch(target) => self.jump(target)?,
+            Instruction::BranchIfTrue(target) => {
+                let value = self.pop()?;
+                if value == self.true_object {
+                    self.jump(target)?;
+                }
+                self.release(&[value])?;
+            }
+            Instruction::BranchIfFalse(target) => {
+                let value = self.pop()?;
+                if value == self.false_object {
+                    self.jump(target)?;
+                }
+                self.release(&[value])?;
+            }
+            Instruction::AndBranch(target) => self.short_circuit(self.false_object, target)?,
+            Instruction::OrBranch(target) => self.short_circuit(self.true_object, target)?,
+        }
+
+        Ok(None)
+    }
+
+    // Creates the context for a method. values holds the receiver (self) followed
+    // by the arguments. level is the object holding the instance variables the
+    // method has access to
+    pub(super) fn activate(&mut self, method: ObjectPointer, level: ObjectPointer, values: &[ObjectPointer], sender: ObjectPointer) -> Result<ObjectPointer, ExecutionError> {
+        let bytecode = self.memory.inst_var(method, METHOD_BYTECODE)?;
+        let literals = self.memory.inst_var(method, METHOD_LITERALS)?;
+        let stack_max = self.memory.integer_value(self.memory.inst_var(method, METHOD_STACK_MAX)?)?;
+        let context_size = self.memory.integer_value(self.memory.inst_var(method, METHOD_CONTEXT_SIZE)?)?;
+
+        let arguments = self.new_array(values.len())?;
+        for (index, &value) in values.iter().enumerate() {
+            self.memory.put_inst_var(arguments, index, value)?;
+        }
+        let temporaries = self.new_array(context_size.max(0) as usize)?;
+        let stack = self.new_array(stack_max.max(0) as usize)?;
+
+        let mut context = Interpreter::new(method, bytecode, literals, level, arguments, temporaries, stack);
+        context.set_sender(sender);
+
+        Ok(self.memory.allocate_counted(context)?)
+    }
+
+    // Creates a context for the block whose template context is given, returning
+    // to sender
+    pub(super) fn block_context(&mut self, template: ObjectPointer, sender: ObjectPointer) -> Result<ObjectPointer, ExecutionError> {
+        let method = self.memory.get::<Interpreter>(template)?.method();
+        let stack_max = self.memory.integer_value(self.memory.inst_var(method, METHOD_STACK_MAX)?)?;
+        let stack = self.new_array(stack_max.max(0) as usize)?;
+        let context = self.memory.get::<Interpreter>(template)?.block_context(sender, stack);
+
+        Ok(self.memory.allocate_counted(context)?)
+    }
+
+    // Makes a context the active one, releasing the previous
+    pub(super) fn set_active(&mut self, context: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(context)?;
+        let previous = std::mem::replace(&mut self.active, context);
+        self.memory.decrement_ref(previous)?;
+
+        Ok(())
+    }
+
+    pub(super) fn context(&self) -> Result<&Interpreter, ExecutionError> {
+        if self.active.is_null() {
+            return Err(ExecutionError::NoActiveContext);
+        }
+
+        Ok(self.memory.get::<Interpreter>(self.active)?)
+    }
+
+    fn context_mut(&mut self) -> Result<&mut Interpreter, ExecutionError> {
+        if self.active.is_null() {
+            return Err(ExecutionError::NoActiveContext);
+        }
+
+        Ok(self.memory.get_mut::<Interpreter>(self.active)?)
+    }
+
+    pub(super) fn push(&mut self, value: ObjectPointer) -> Result<(), ExecutionError> {
+        let (stack, top) = {
+            let context = self.context()?;
+            (context.stack(), context.stack_top())
+        };
+        if top as usize >= self.memory.get::<Object>(stack)?.size() {
+            return Err(ExecutionError::StackOverflow);
+        }
+
+        self.memory.put_inst_var(stack, top as usize, value)?;
+        self.context_mut()?.set_stack_top(top + 1);
+
+        Ok(())
+    }
+
+    pub(super) fn pop(&mut self) -> Result<ObjectPointer, ExecutionError> {
+        let (stack, top) = {
+            let context = self.context()?;
+            (context.stack(), context.stack_top())
+        };
+        if top == 0 {
+            return Err(ExecutionError::StackUnderflow);
+        }
+
+        let value = self.memory.take_inst_var(stack, top as usize - 1)?;
+        self.context_mut()?.set_stack_top(top - 1);
+
+        Ok(value)
+    }
+
+    fn top(&self) -> Result<ObjectPointer, ExecutionError> {
+        let context = self.context()?;
+        match context.stack_top() {
+            0 => Err(ExecutionError::StackUnderflow),
+            top => Ok(self.memory.inst_var(context.stack(), top as usize - 1)?),
+        }
+    }
+
+    // Pops a number of values, returning them in the order they were pushed
+    fn pop_values(&mut self, count: usize) -> Result<Vec<ObjectPointer>, ExecutionError> {
+        let mut values = (0..count)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<_>, _>>()?;
+        values.reverse();
+
+        Ok(values)
+    }
+
+    pub(super) fn release(&mut self, values: &[ObjectPointer]) -> Result<(), ExecutionError> {
+        for &value in values {
+            self.memory.decrement_ref(value)?;
+        }
+
+        Ok(())
+    }
+
+    fn fetch(&self) -> Result<(Instruction, usize), ExecutionError> {
+        let context = self.context()?;
+        let position = context.current_byte() as usize;
+        let bytecode = self.memory.get::<ByteArray>(context.bytecode())?;
+
+        Instruction::decode(bytecode.value(), position)
+            .ok_or(ExecutionError::InvalidBytecode(position))
+    }
+
+    fn constant(&self, constant: Constant) -> ObjectPointer {
+        match constant {
+            Constant::Nil => ObjectPointer::null(),
+            Constant::True => self.true_object,
+            Constant::False => self.false_object,
+            Constant::MinusOne => ObjectPointer::from_small_integer(-1),
+            Constant::Zero => ObjectPointer::from_small_integer(0),
+            Constant::One => ObjectPointer::from_small_integer(1),
+            Constant::Two => ObjectPointer::from_small_integer(2),
+        }
+    }
+
+    fn selector(&self, literal: u8) -> Result<ObjectPointer, ExecutionError> {
+        Ok(self.memory.inst_var(self.context()?.literals(), literal as usize)?)
+    }
+
+    // Activates the method found for the selector, starting at the given class, or
+    // doesNotUnderstand: when there is none. Takes over the popped values (receiver
+    // and arguments)
+    fn send_message(&mut self, class: ObjectPointer, level: ObjectPointer, selector: ObjectPointer, values: Vec<ObjectPointer>) -> Result<(), ExecutionError> {
+        let context = match self.lookup_selector(class, level, selector) {
+            Ok(Some((method, level))) => self.activate(method, level, &values, self.active),
+            Ok(None) => self.not_understood(values[0], selector, &values[1..])
+                .and_then(|(method, level, message)| self.activate(method, level, &[values[0], message], self.active)),
+            Err(err) => Err(err),
+        };
+        let context = match context {
+            Ok(context) => context,
+            Err(err) => {
+                self.release(&values)?;
+                return Err(err);
+            }
+        };
+        self.set_active(context)?;
+
+        self.release(&values)
+    }
+
+    fn jump(&mut self, target: u16) -> Result<(), ExecutionError> {
+        self.context_mut()?.set_current_byte(target as u32);
+
+        Ok(())
+    }
+
+    // Jumps if the top of the stack is the given value, leaving it there. Pops it
+    // otherwise
+    fn short_circuit(&mut self, value: ObjectPointer, target: u16) -> Result<(), ExecutionError> {
+        if self.top()? == value {
+            self.jump(target)
+        } else {
+            let top = self.pop()?;
+            self.release(&[top])
+        }
+    }
+
+    // ^ return. From a block, this returns from the method that created it
+    fn method_return(&mut self, value: ObjectPointer) -> Result<Option<ObjectPointer>, ExecutionError> {
+        let creator = self.context()?.creator();
+        if creator.is_null() {
+            return self.return_from(self.active, value);
+        }
+
+        // The creator has to be still running, somewhere in the chain of senders
+        let mut chain = vec![];
+        let mut current = self.active;
+        let running = self.memory.get::<Interpreter>(creator)?.current_byte() != RETURNED;
+        while running && !current.is_null() && current != creator {
+            chain.push(current);
+            current = self.memory.get::<Interpreter>(current)?.sender();
+        }
+        if current != creator {
+            self.release(&[value])?;
+            return Err(ExecutionError::CannotReturn);
+        }
+
+        // Every method in between returns as well
+        for context in chain {
+            let context = self.memory.get_mut::<Interpreter>(context)?;
+            if !context.is_block_context() {
+                context.set_current_byte(RETURNED);
+            }
+        }
+
+        self.return_from(creator, value)
+    }
+
+    // Returns a value (owned) from a context to its sender
+    fn return_from(&mut self, context: ObjectPointer, value: ObjectPointer) -> Result<Option<ObjectPointer>, ExecutionError> {
+        let context = self.memory.get_mut::<Interpreter>(context)?;
+        if !context.is_block_context() {
+            context.set_current_byte(RETURNED);
+        }
+
+        let sender = context.sender();
+        self.set_active(sender)?;
+        if sender.is_null() {
+            return Ok(Some(value));
+        }
+
+        self.push(value)?;
+        self.release(&[value])?;
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::bytecode::assemble;
+    use crate::execution::{ARRAY_CLASS, BLOCK_CLASS, INTEGER_CLASS};
+
+    use Instruction::*;
+
+    fn method(vm: &mut VirtualMachine, class: ObjectPointer, selector: &str, code: &[Instruction], literals: &[&str]) -> Result<(), ExecutionError> {
+        let literals = literals.iter()
+            .map(|literal| vm.new_symbol(literal))
+            .collect::<Result<Vec<_>, _>>()?;
+        let method = vm.new_method(selector, assemble(code), &literals, 8, 4)?;
+        vm.install_method(class, method)
+    }
+
+    // Machine with an Integer class providing ==, and a Block class with value
+    fn machine() -> Result<VirtualMachine, ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let array = vm.new_class(ARRAY_CLASS, ObjectPointer::null(), &[])?;
+        vm.set_global(ARRAY_CLASS, array)?;
+
+        let integer = vm.new_class(INTEGER_CLASS, ObjectPointer::null(), &[])?;
+        vm.set_global(INTEGER_CLASS, integer)?;
+        method(&mut vm, integer, "==", &[
+            PushArgument(0), PushArgument(1), DoPrimitive { args: 2, number: 1 }, StackReturn,
+        ], &[])?;
+
+        let block = vm.new_class(BLOCK_CLASS, ObjectPointer::null(), &[])?;
+        vm.set_global(BLOCK_CLASS, block)?;
+        method(&mut vm, block, "value", &[
+            PushArgument(0), DoPrimitive { args: 1, number: 8 }, StackReturn,
+        ], &[])?;
+        method(&mut vm, block, "value:", &[
+            PushArgument(0), PushArgument(1), DoPrimitive { args: 2, number: 8 }, StackReturn,
+        ], &[])?;
+
+        Ok(vm)
+    }
+
+    // Class Test (with instance variable a) and an instance of it
+    fn test_object(vm: &mut VirtualMachine) -> Result<(ObjectPointer, ObjectPointer), ExecutionError> {
+        let class = vm.new_class("Test", ObjectPointer::null(), &["a"])?;
+        vm.set_global("Test", class)?;
+        let object = vm.memory_mut().instantiate(class)?;
+        vm.memory_mut().increment_ref(object)?;
+
+        Ok((class, object))
+    }
+
+    #[test]
+    fn test_interpreter_returns_literal() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        method(&mut vm, integer, "answer", &[PushConstant(Constant::Two), StackReturn], &[])?;
+        method(&mut vm, integer, "itself", &[SelfReturn], &[])?;
+
+        let receiver = vm.memory().new_integer(40);
+        assert_eq!(vm.send(receiver, "answer", &[])?, vm.memory().new_integer(2));
+        assert_eq!(vm.send(receiver, "itself", &[])?, receiver);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_sends_messages() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        method(&mut vm, integer, "isTwo", &[
+            PushArgument(0), PushConstant(Constant::Two), SendMessage { args: 1, selector: 0 }, StackReturn,
+        ], &["=="])?;
+
+        let two = vm.memory().new_integer(2);
+        let three = vm.memory().new_integer(3);
+        assert_eq!(vm.send(two, "isTwo", &[])?, vm.true_object());
+        assert_eq!(vm.send(three, "isTwo", &[])?, vm.false_object());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_branches() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        // self == 1 ifTrue: [ -1 ] ifFalse: [ 0 ]
+        let code = [
+            PushArgument(0), PushConstant(Constant::One), SendMessage { args: 1, selector: 0 },
+            BranchIfFalse(9), PushConstant(Constant::MinusOne), StackReturn,
+            PushConstant(Constant::Zero), StackReturn,
+        ];
+        assert_eq!(assemble(&code[..6]).len(), 9);
+        method(&mut vm, integer, "sign", &code, &["=="])?;
+
+        let one = vm.memory().new_integer(1);
+        let five = vm.memory().new_integer(5);
+        assert_eq!(vm.send(one, "sign", &[])?, vm.memory().new_integer(-1));
+        assert_eq!(vm.send(five, "sign", &[])?, vm.memory().new_integer(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_evaluates_blocks() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        // [:x | x == self] value: 7
+        let code = [
+            CreateBlock { args: 1, arglocation: 0, end: 9 },
+            PushTemporary(0), PushArgument(0), SendMessage { args: 1, selector: 0 }, BlockReturn,
+            PushLiteral(1), SendMessage { args: 1, selector: 2 }, StackReturn,
+        ];
+        assert_eq!(assemble(&code[..5]).len(), 9);
+        let seven = vm.memory().new_integer(7);
+        let literals = [vm.new_symbol("==")?, seven, vm.new_symbol("value:")?];
+        let method = vm.new_method("isSeven", assemble(&code), &literals, 8, 1)?;
+        vm.install_method(integer, method)?;
+
+        assert_eq!(vm.send(seven, "isSeven", &[])?, vm.true_object());
+        assert_eq!(vm.send(vm.memory().new_integer(8), "isSeven", &[])?, vm.false_object());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_non_local_return() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        // [ ^ 2 ] value. ^ 0
+        method(&mut vm, integer, "escape", &[
+            CreateBlock { args: 0, arglocation: 0, end: 6 },
+            PushConstant(Constant::Two), StackReturn,
+            SendMessage { args: 0, selector: 0 }, PopTop,
+            PushConstant(Constant::Zero), StackReturn,
+        ], &["value"])?;
+        // ^ [ ^ 2 ]
+        method(&mut vm, integer, "leak", &[
+            CreateBlock { args: 0, arglocation: 0, end: 6 },
+            PushConstant(Constant::Two), StackReturn,
+            StackReturn,
+        ], &[])?;
+
+        let one = vm.memory().new_integer(1);
+        assert_eq!(vm.send(one, "escape", &[])?, vm.memory().new_integer(2));
+
+        // The method that created the block has already returned
+        let block = vm.send(one, "leak", &[])?;
+        assert_eq!(vm.send(block, "value", &[]), Err(ExecutionError::CannotReturn));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_instance_variables() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let (class, object) = test_object(&mut vm)?;
+        method(&mut vm, class, "a:", &[PushArgument(1), AssignInstance(0), PopTop, SelfReturn], &[])?;
+        method(&mut vm, class, "a", &[PushInstance(0), StackReturn], &[])?;
+
+        let value = vm.memory().new_integer(12);
+        assert_eq!(vm.send(object, "a:", &[value])?, object);
+        assert_eq!(vm.send(object, "a", &[])?, value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_inheritance_and_super() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let (base, _) = test_object(&mut vm)?;
+        method(&mut vm, base, "a", &[PushInstance(0), StackReturn], &[])?;
+        method(&mut vm, base, "init", &[PushConstant(Constant::One), AssignInstance(0), PopTop, SelfReturn], &[])?;
+
+        let derived = vm.new_class("Derived", base, &["b"])?;
+        vm.set_global("Derived", derived)?;
+        method(&mut vm, derived, "init", &[
+            PushArgument(0), SendToSuper { args: 0, selector: 0 }, PopTop,
+            PushConstant(Constant::Two), AssignInstance(0), PopTop, SelfReturn,
+        ], &["init"])?;
+        method(&mut vm, derived, "b", &[PushInstance(0), StackReturn], &[])?;
+
+        let object = vm.memory_mut().instantiate(derived)?;
+        vm.memory_mut().increment_ref(object)?;
+        let result = vm.send(object, "init", &[])?;
+        vm.release(&[result])?;
+
+        // Inherited methods see the instance variables of the superclass
+        assert_eq!(vm.send(object, "a", &[])?, vm.memory().new_integer(1));
+        assert_eq!(vm.send(object, "b", &[])?, vm.memory().new_integer(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_message_not_understood() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let one = vm.memory().new_integer(1);
+
+        assert_eq!(vm.send(one, "foo", &[]), Err(ExecutionError::MessageNotUnderstood("foo".to_string())));
+        assert_eq!(vm.send(ObjectPointer::null(), "foo", &[]), Err(ExecutionError::MessageNotUnderstood("foo".to_string())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_does_not_understand() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        method(&mut vm, integer, "doesNotUnderstand:", &[PushArgument(1), StackReturn], &[])?;
+        method(&mut vm, integer, "tryFoo", &[
+            PushArgument(0), PushConstant(Constant::Two), SendMessage { args: 1, selector: 0 }, StackReturn,
+        ], &["foo:"])?;
+
+        let one = vm.memory().new_integer(1);
+        let message = vm.send(one, "tryFoo", &[])?;
+        assert_eq!(vm.symbol_value(vm.memory().inst_var(message, 0)?)?, "foo:");
+        let arguments = vm.memory().inst_var(message, 1)?;
+        assert_eq!(vm.memory().inst_var(arguments, 0)?, vm.memory().new_integer(2));
+        vm.release(&[message])?;
+
+        let message = vm.send(one, "bar", &[])?;
+        assert_eq!(vm.symbol_value(vm.memory().inst_var(message, 0)?)?, "bar");
+        vm.release(&[message])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_method_cache_invalidation() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let (base, _) = test_object(&mut vm)?;
+        method(&mut vm, base, "a", &[PushConstant(Constant::One), StackReturn], &[])?;
+        let derived = vm.new_class("Derived", base, &[])?;
+        vm.set_global("Derived", derived)?;
+
+        let object = vm.memory_mut().instantiate(derived)?;
+        vm.memory_mut().increment_ref(object)?;
+        assert_eq!(vm.send(object, "a", &[])?, vm.memory().new_integer(1));
+        assert_eq!(vm.send(object, "a", &[])?, vm.memory().new_integer(1));
+
+        // Overriding the method in the subclass takes over the cached lookup
+        method(&mut vm, derived, "a", &[PushConstant(Constant::Two), StackReturn], &[])?;
+        assert_eq!(vm.send(object, "a", &[])?, vm.memory().new_integer(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_reclaims_contexts() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        method(&mut vm, integer, "isOne", &[
+            PushArgument(0), PushConstant(Constant::One), SendMessage { args: 1, selector: 0 }, StackReturn,
+        ], &["=="])?;
+        let live = vm.memory().live_objects().len();
+
+        let one = vm.memory().new_integer(1);
+        assert_eq!(vm.send(one, "isOne", &[])?, vm.true_object());
+        assert_eq!(vm.memory().live_objects().len(), live);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpreter_invalid_bytecode() -> Result<(), ExecutionError> {
+        let mut vm = machine()?;
+        let integer = vm.global(INTEGER_CLASS).unwrap();
+        let method = vm.new_method("bad", vec![0xE0], &[], 8, 0)?;
+        vm.install_method(integer, method)?;
+        let truncated = vm.new_method("truncated", vec![0x50], &[], 8, 0)?;
+        vm.install_method(integer, truncated)?;
+
+        let one = vm.memory().new_integer(1);
+        assert_eq!(vm.send(one, "bad", &[]), Err(ExecutionError::InvalidBytecode(0)));
+        // Running off the end of the method
+        assert_eq!(vm.send(one, "truncated", &[]), Err(ExecutionError::InvalidBytecode(1)));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+// Classes and methods
+//
+// Classes keep their methods in two parallel arrays: message_names, with the
+// selectors, and methods, with the compiled methods (see objects::method for
+// their layout).
+
+use crate::objects::{
+    byte::ByteArray,
+    class::Class,
+    method::{
+        METHOD_BYTECODE, METHOD_CLASS, METHOD_CONTEXT_SIZE, METHOD_LITERALS,
+        METHOD_SELECTOR, METHOD_SIZE, METHOD_STACK_MAX,
+    },
+    object::{Object, ObjectPointer, Pointer},
+};
+use crate::memory::ObjectMemory;
+use super::cache::MethodCache;
+use super::{ExecutionError, VirtualMachine, MESSAGE_CLASS};
+
+pub const DOES_NOT_UNDERSTAND: &str = "doesNotUnderstand:";
+
+impl VirtualMachine {
+    // Creates a class with no methods. Only the instance variables defined by the
+    // class itself are listed; the inherited ones come from the superclass
+    pub fn new_class(&mut self, name: &str, super_class: ObjectPointer, inst_vars: &[&str]) -> Result<ObjectPointer, ExecutionError> {
+        let name = self.new_symbol(name)?;
+        let c_inst_vars = self.new_array(inst_vars.len())?;
+        for (index, inst_var) in inst_vars.iter().enumerate() {
+            let inst_var = self.new_symbol(inst_var)?;
+            self.memory.put_inst_var(c_inst_vars, index, inst_var)?;
+        }
+
+        // The new class may take the place of one that is gone
+        self.method_cache.flush();
+        let mut class = Class::new();
+        class.set_name(name);
+        class.set_super_class(super_class);
+        class.set_c_inst_vars(c_inst_vars);
+
+        Ok(self.memory.allocate_counted(class)?)
+    }
+
+    pub fn new_method(
+        &mut self,
+        selector: &str,
+        bytecode: Vec<u8>,
+        literals: &[ObjectPointer],
+        stack_max: usize,
+        context_size: usize,
+    ) -> Result<ObjectPointer, ExecutionError> {
+        let method = self.new_array(METHOD_SIZE)?;
+        let selector = self.new_symbol(selector)?;
+        let bytecode = self.memory.allocate(ByteArray::new(bytecode))?;
+        let literal_array = self.new_array(literals.len())?;
+        for (index, &literal) in literals.iter().enumerate() {
+            self.memory.put_inst_var(literal_array, index, literal)?;
+        }
+
+        self.memory.put_inst_var(method, METHOD_SELECTOR, selector)?;
+        self.memory.put_inst_var(method, METHOD_BYTECODE, bytecode)?;
+        self.memory.put_inst_var(method, METHOD_LITERALS, literal_array)?;
+        self.memory.put_inst_var(method, METHOD_STACK_MAX, ObjectPointer::from_small_integer(stack_max as i32))?;
+        self.memory.put_inst_var(method, METHOD_CONTEXT_SIZE, ObjectPointer::from_small_integer(context_size as i32))?;
+
+        Ok(method)
+    }
+
+    // Adds a method to a class, replacing any other with the same selector
+    pub fn install_method(&mut self, class: ObjectPointer, method: ObjectPointer) -> Result<(), ExecutionError> {
+        let selector = self.memory.inst_var(method, METHOD_SELECTOR)?;
+        self.memory.put_inst_var(method, METHOD_CLASS, class)?;
+
+        let (names, methods) = {
+            let class = self.memory.get::<Class>(class)?;
+            (class.message_names(), class.methods())
+        };
+
+        self.method_cache.flush();
+        if let Some(index) = find_selector(&self.memory, names, selector)? {
+            self.memory.put_inst_var(methods, index, method)?;
+            return Ok(());
+        }
+
+        // Not there yet. Grow both arrays by one
+        let new_names = self.grow_array(names, selector)?;
+        let new_methods = self.grow_array(methods, method)?;
+        self.memory.increment_ref(new_names)?;
+        self.memory.increment_ref(new_methods)?;
+
+        let class_ref = self.memory.get_mut::<Class>(class)?;
+        class_ref.set_message_names(new_names);
+        class_ref.set_methods(new_methods);
+        self.memory.decrement_ref(names)?;
+        self.memory.decrement_ref(methods)?;
+
+        Ok(())
+    }
+
+    // Finds the method for a selector, starting the search at the given class and
+    // following the superclass chain. level is the object holding the instance
+    // variables for that class; the one for the class where the method was found
+    // is returned along with it
+    pub fn lookup_method(&mut self, class: ObjectPointer, level: ObjectPointer, selector: &str) -> Result<Option<(ObjectPointer, ObjectPointer)>, ExecutionError> {
+        // Selectors are interned, so no method can have one that isn't in the table
+        match self.find_symbol(selector) {
+            Some(selector) => self.lookup_selector(class, level, selector),
+            None => Ok(None),
+        }
+    }
+
+    // Same as lookup_method, with the selector given as a Symbol
+    pub(super) fn lookup_selector(&mut self, class: ObjectPointer, level: ObjectPointer, selector: ObjectPointer) -> Result<Option<(ObjectPointer, ObjectPointer)>, ExecutionError> {
+        let found = cached_lookup(&mut self.method_cache, &self.memory, class, selector)?;
+
+        Ok(found.map(|(method, depth)| (method, self.level(level, depth))))
+    }
+
+    // Method to run when a receiver doesn't understand a message, and the message
+    // to pass it (an instance of Message, with the selector and the arguments)
+    pub(super) fn not_understood(&mut self, receiver: ObjectPointer, selector: ObjectPointer, args: &[ObjectPointer]) -> Result<(ObjectPointer, ObjectPointer, ObjectPointer), ExecutionError> {
+        let class = self.class_of(receiver)?;
+        let Some((method, level)) = self.lookup_method(class, receiver, DOES_NOT_UNDERSTAND)? else {
+            return Err(ExecutionError::MessageNotUnderstood(self.symbol_value(selector)?.to_string()));
+        };
+
+        let arguments = self.new_array(args.len())?;
+        for (index, &arg) in args.iter().enumerate() {
+            self.memory.put_inst_var(arguments, index, arg)?;
+        }
+        let class = self.global(MESSAGE_CLASS).unwrap_or(ObjectPointer::null());
+        let mut message = Object::new(class, ObjectPointer::null(), 2);
+        message.set_inst_var(0, selector);
+        message.set_inst_var(1, arguments);
+
+        Ok((method, level, self.memory.allocate_counted(message)?))
+    }
+
+    // Object holding the instance variables for a superclass, depth levels up
+    fn level(&self, level: ObjectPointer, depth: usize) -> ObjectPointer {
+        (0..depth).fold(level, |level, _| self.super_object(level))
+    }
+
+    // Copy of an array (or an empty one, for null) with an extra element at the end
+    fn grow_array(&mut self, array: ObjectPointer, value: ObjectPointer) -> Result<ObjectPointer, ExecutionError> {
+        let elements = if array.is_null() {
+            vec![]
+        } else {
+            let array = self.memory.get::<Object>(array)?;
+            (0..array.size()).filter_map(|index| array.inst_var(index)).collect()
+        };
+
+        let new_array = self.new_array(elements.len() + 1)?;
+        for (index, element) in elements.into_iter().chain([value]).enumerate() {
+            self.memory.put_inst_var(new_array, index, element)?;
+        }
+
+        Ok(new_array)
+    }
+}
+
+fn cached_lookup(cache: &mut MethodCache, memory: &ObjectMemory, class: ObjectPointer, selector: ObjectPointer) -> Result<Option<(ObjectPointer, usize)>, ExecutionError> {
+    if let Some(found) = cache.lookup(class, selector) {
+        return Ok(Some(found));
+    }
+
+    let found = search(memory, class, selector)?;
+    if let Some((method, depth)) = found {
+        cache.insert(class, selector, method, depth);
+    }
+
+    Ok(found)
+}
+
+// Method for the selector, along with the number of superclasses followed to find it
+fn search(memory: &ObjectMemory, class: ObjectPointer, selector: ObjectPointer) -> Result<Option<(ObjectPointer, usize)>, ExecutionError> {
+    let mut class = class;
+    let mut depth = 0;
+
+    while !class.is_null() {
+        let class_ref = memory.get::<Class>(class)?;
+        if let Some(index) = find_selector(memory, class_ref.message_names(), selector)? {
+            return Ok(Some((memory.inst_var(class_ref.methods(), index)?, depth)));
+        }
+
+        class = class_ref.super_class();
+        depth += 1;
+    }
+
+    Ok(None)
+}
+
+fn find_selector(memory: &ObjectMemory, names: ObjectPointer, selector: ObjectPointer) -> Result<Option<usize>, ExecutionError> {
+    if names.is_null() {
+        return Ok(None);
+    }
+
+    let names = memory.get::<Object>(names)?;
+    Ok((0..names.size()).find(|&index| names.inst_var(index) == Some(selector)))
+}
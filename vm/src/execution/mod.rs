@@ -0,0 +1,342 @@
+pub mod bytecode;
+mod cache;
+mod floats;
+mod image;
+mod integers;
+mod interpreter;
+mod methods;
+mod numbers;
+mod scheduler;
+mod semaphores;
+mod strings;
+mod symbols;
+pub mod primitives;
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use crate::memory::{MemoryError, ObjectMemory, ObjectRef};
+use cache::MethodCache;
+use scheduler::Scheduler;
+
+pub use numbers::Number;
+use crate::objects::{
+    character::{Char, PREALLOCATED_CHARS},
+    class::Class,
+    object::{Object, ObjectPointer, ObjectType, Pointer},
+    process::InvalidTransition,
+    string::StringObject,
+    symbol::Symbol,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecutionError {
+    Memory(MemoryError),
+    InvalidBytecode(usize),
+    StackOverflow,
+    StackUnderflow,
+    NoActiveContext,
+    MessageNotUnderstood(String),
+    // Non local return from a block whose method has already returned
+    CannotReturn,
+    UnknownPrimitive(u8),
+    PrimitiveFailed(u8),
+    InvalidTransition(InvalidTransition),
+    // Waiting on a semaphore outside of any process, with no process left to signal it
+    Deadlock,
+}
+
+impl Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::Memory(err) => write!(f, "Memory error: {}", err),
+            ExecutionError::InvalidBytecode(position) => write!(f, "Invalid bytecode at {}", position),
+            ExecutionError::StackOverflow => write!(f, "Stack overflow"),
+            ExecutionError::StackUnderflow => write!(f, "Stack underflow"),
+            ExecutionError::NoActiveContext => write!(f, "No active context"),
+            ExecutionError::MessageNotUnderstood(selector) => write!(f, "Message not understood: {}", selector),
+            ExecutionError::CannotReturn => write!(f, "Block cannot return: its method has already returned"),
+            ExecutionError::UnknownPrimitive(number) => write!(f, "Unknown primitive: {}", number),
+            ExecutionError::PrimitiveFailed(number) => write!(f, "Primitive failed: {}", number),
+            ExecutionError::InvalidTransition(transition) => write!(f, "Invalid process transition: {}", transition),
+            ExecutionError::Deadlock => write!(f, "Deadlock: waiting on a semaphore no process can signal"),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
+impl From<MemoryError> for ExecutionError {
+    fn from(err: MemoryError) -> Self {
+        ExecutionError::Memory(err)
+    }
+}
+
+impl From<InvalidTransition> for ExecutionError {
+    fn from(err: InvalidTransition) -> Self {
+        ExecutionError::InvalidTransition(err)
+    }
+}
+
+// Names of the globals holding the classes of the special objects
+pub const UNDEFINED_OBJECT_CLASS: &str =  "UndefinedObject";
+pub const TRUE_CLASS: &str =              "True";
+pub const FALSE_CLASS: &str =             "False";
+pub const ARRAY_CLASS: &str =             "Array";
+pub const BLOCK_CLASS: &str =             "Block";
+pub const BYTEARRAY_CLASS: &str =         "ByteArray";
+pub const CHAR_CLASS: &str =              "Char";
+pub const CLASS_CLASS: &str =             "Class";
+pub const CONTEXT_CLASS: &str =           "Context";
+pub const FILE_CLASS: &str =              "File";
+pub const FLOAT_CLASS: &str =             "Float";
+pub const FRACTION_CLASS: &str =          "Fraction";
+pub const INTEGER_CLASS: &str =           "Integer";
+pub const LARGE_POSITIVE_CLASS: &str =    "LargePositiveInteger";
+pub const LARGE_NEGATIVE_CLASS: &str =    "LargeNegativeInteger";
+pub const MESSAGE_CLASS: &str =           "Message";
+pub const PROCESS_CLASS: &str =           "Process";
+pub const SEMAPHORE_CLASS: &str =         "Semaphore";
+pub const STRING_CLASS: &str =            "String";
+pub const SYMBOL_CLASS: &str =            "Symbol";
+
+// The virtual machine: object memory, global variables and the state of the
+// interpreter.
+//
+// nil is represented by the null pointer. true and false are (empty) objects
+// created along with the machine.
+pub struct VirtualMachine {
+    memory: ObjectMemory,
+    globals: HashMap<String, ObjectPointer>,
+    true_object: ObjectPointer,
+    false_object: ObjectPointer,
+    // Context being executed. The machine holds a reference to it
+    active: ObjectPointer,
+    // Interned symbols, so that equal Symbols are the same object. The table holds
+    // a reference to each of them, but doesn't keep them alive through collections
+    symbols: HashMap<String, ObjectPointer>,
+    // The Chars for every byte value, so that they are never allocated again. The
+    // machine holds a reference to each of them
+    chars: Vec<ObjectPointer>,
+    // The other Chars, by code, interned like symbols so that each character is a
+    // single object. Weak with respect to collections as well
+    wide_chars: HashMap<u32, ObjectPointer>,
+    method_cache: MethodCache,
+    scheduler: Scheduler,
+}
+
+impl VirtualMachine {
+    pub fn new() -> Result<Self, ExecutionError> {
+        Self::with_memory(ObjectMemory::new())
+    }
+
+    pub fn with_memory(mut memory: ObjectMemory) -> Result<Self, ExecutionError> {
+        let true_object = memory.allocate(Object::new(ObjectPointer::null(), ObjectPointer::null(), 0))?;
+        let false_object = memory.allocate(Object::new(ObjectPointer::null(), ObjectPointer::null(), 0))?;
+        memory.increment_ref(true_object)?;
+        memory.increment_ref(false_object)?;
+        let chars = (0..PREALLOCATED_CHARS as u32)
+            .map(|code| memory.allocate(Char::new(code)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for &char_object in &chars {
+            memory.increment_ref(char_object)?;
+        }
+
+        let mut vm = VirtualMachine {
+            memory,
+            globals: HashMap::new(),
+            true_object,
+            false_object,
+            active: ObjectPointer::null(),
+            symbols: HashMap::new(),
+            chars,
+            wide_chars: HashMap::new(),
+            method_cache: MethodCache::new(),
+            scheduler: Scheduler::new(),
+        };
+        vm.intern_symbols()?;
+
+        Ok(vm)
+    }
+
+    pub fn memory(&self) -> &ObjectMemory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut ObjectMemory {
+        &mut self.memory
+    }
+
+    pub fn nil(&self) -> ObjectPointer {
+        ObjectPointer::null()
+    }
+
+    pub fn true_object(&self) -> ObjectPointer {
+        self.true_object
+    }
+
+    pub fn false_object(&self) -> ObjectPointer {
+        self.false_object
+    }
+
+    pub fn boolean(&self, value: bool) -> ObjectPointer {
+        if value { self.true_object } else { self.false_object }
+    }
+
+    pub fn global(&self, name: &str) -> Option<ObjectPointer> {
+        self.globals.get(name).copied()
+    }
+
+    pub fn set_global(&mut self, name: &str, value: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(value)?;
+        if let Some(old_value) = self.globals.insert(name.to_string(), value) {
+            self.memory.decrement_ref(old_value)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn globals(&self) -> impl Iterator<Item = (&str, ObjectPointer)> {
+        self.globals.iter().map(|(name, &value)| (name.as_str(), value))
+    }
+
+    // Objects that must survive garbage collection
+    pub fn roots(&self) -> Vec<ObjectPointer> {
+        let mut roots = vec![self.true_object, self.false_object, self.active];
+        roots.extend(self.globals.values());
+        roots.extend(self.scheduler.roots());
+        roots.extend(&self.chars);
+        roots
+    }
+
+    pub fn collect_garbage(&mut self) -> Result<usize, ExecutionError> {
+        let roots = self.roots();
+        self.method_cache.flush();
+        let reclaimed = self.memory.collect_garbage(&roots)?;
+
+        self.forget_reclaimed_symbols();
+        let memory = &self.memory;
+        self.wide_chars.retain(|_, &mut char_object| memory.object(char_object).is_ok());
+
+        Ok(reclaimed)
+    }
+
+    // Moves objects into as few memory blocks as possible, and updates the pointers
+    // to them held by the machine. Returns the number of objects moved. Like
+    // collections, this is only safe between statements: pointers held by a running
+    // primitive wouldn't be updated
+    pub fn compact(&mut self) -> Result<usize, ExecutionError> {
+        let forwarding = self.memory.compact()?;
+        self.method_cache.flush();
+        if forwarding.is_empty() {
+            return Ok(0);
+        }
+
+        let mut update = |ptr: ObjectPointer| forwarding.get(&ptr).copied().unwrap_or(ptr);
+        self.true_object = update(self.true_object);
+        self.false_object = update(self.false_object);
+        self.active = update(self.active);
+        let interned = self.symbols.values_mut().chain(&mut self.chars).chain(self.wide_chars.values_mut());
+        for ptr in self.globals.values_mut().chain(interned) {
+            *ptr = update(*ptr);
+        }
+        self.scheduler.update_roots(&mut update);
+
+        Ok(forwarding.len())
+    }
+
+    // Class of any object. Special objects get their class from the globals, so
+    // this is nil for them until the class library is loaded
+    pub fn class_of(&self, ptr: ObjectPointer) -> Result<ObjectPointer, ExecutionError> {
+        let class_name = if ptr.is_null() {
+            UNDEFINED_OBJECT_CLASS
+        } else if ptr == self.true_object {
+            TRUE_CLASS
+        } else if ptr == self.false_object {
+            FALSE_CLASS
+        } else {
+            match self.memory.object(ptr)? {
+                ObjectRef::Object(obj) => return Ok(obj.class()),
+                ObjectRef::SmallInteger(_) | ObjectRef::Integer(_) => INTEGER_CLASS,
+                ObjectRef::LargeInteger(large) if large.is_negative() => LARGE_NEGATIVE_CLASS,
+                ObjectRef::LargeInteger(_) => LARGE_POSITIVE_CLASS,
+                ObjectRef::Fraction(_) => FRACTION_CLASS,
+                ObjectRef::Float(_) => FLOAT_CLASS,
+                ObjectRef::Symbol(_) => SYMBOL_CLASS,
+                ObjectRef::ByteArray(_) => BYTEARRAY_CLASS,
+                ObjectRef::String(_) => STRING_CLASS,
+                ObjectRef::Block(_) => BLOCK_CLASS,
+                ObjectRef::Class(_) => CLASS_CLASS,
+                ObjectRef::Interpreter(_) => CONTEXT_CLASS,
+                ObjectRef::Process(_) => PROCESS_CLASS,
+                ObjectRef::Semaphore(_) => SEMAPHORE_CLASS,
+                ObjectRef::File(_) => FILE_CLASS,
+                ObjectRef::Char(_) => CHAR_CLASS,
+            }
+        };
+
+        Ok(self.global(class_name).unwrap_or(ObjectPointer::null()))
+    }
+
+    // Ordinary objects delegate the inherited instance variables to a super object.
+    // Other objects play the part of their own super objects
+    pub fn super_object(&self, ptr: ObjectPointer) -> ObjectPointer {
+        let super_obj = match self.memory.object(ptr) {
+            Ok(ObjectRef::Object(obj)) => obj.super_obj(),
+            _ => ObjectPointer::null(),
+        };
+
+        if super_obj.is_null() { ptr } else { super_obj }
+    }
+
+    pub fn new_array(&mut self, size: usize) -> Result<ObjectPointer, ExecutionError> {
+        let class = self.global(ARRAY_CLASS).unwrap_or(ObjectPointer::null());
+
+        Ok(self.memory.allocate_counted(Object::new(class, ObjectPointer::null(), size))?)
+    }
+
+    pub fn new_string(&mut self, value: &str) -> Result<ObjectPointer, ExecutionError> {
+        Ok(self.memory.allocate(StringObject::new(value.to_string(), ObjectPointer::null()))?)
+    }
+
+    // The Char with the given value. There's only one for each character: those for
+    // byte values are preallocated, and the others are created the first time
+    pub fn new_char(&mut self, value: char) -> Result<ObjectPointer, ExecutionError> {
+        let code = value as u32;
+        if let Some(&char_object) = self.chars.get(code as usize).or_else(|| self.wide_chars.get(&code)) {
+            return Ok(char_object);
+        }
+
+        let char_object = self.memory.allocate(Char::new(code))?;
+        self.memory.increment_ref(char_object)?;
+        self.wide_chars.insert(code, char_object);
+
+        Ok(char_object)
+    }
+
+    // Code of a Char
+    pub fn char_value(&self, ptr: ObjectPointer) -> Option<u32> {
+        self.memory.get::<Char>(ptr).ok().map(Char::value)
+    }
+
+    pub fn symbol_value(&self, ptr: ObjectPointer) -> Result<&str, ExecutionError> {
+        Ok(self.memory.get::<Symbol>(ptr)?.value())
+    }
+
+    pub fn string_value(&self, ptr: ObjectPointer) -> Result<&str, ExecutionError> {
+        Ok(self.memory.get::<StringObject>(ptr)?.value())
+    }
+
+    pub fn is_kind_of(&self, ptr: ObjectPointer, object_type: ObjectType) -> bool {
+        self.memory.object_type(ptr).is_ok_and(|t| t == object_type)
+    }
+
+    // Name of a class, for error messages and the like
+    pub fn class_name(&self, class: ObjectPointer) -> String {
+        self.memory.get::<Class>(class)
+            .ok()
+            .and_then(|class| self.symbol_value(class.name()).ok())
+            .unwrap_or("nil")
+            .to_string()
+    }
+}
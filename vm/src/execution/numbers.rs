@@ -0,0 +1,349 @@
+// Numbers
+//
+// Integers, fractions and floats make up a tower, in that order of generality: any
+// number can be converted to a more general kind without leaving the tower. The
+// arithmetic primitives convert the less general of their arguments to the kind of
+// the other one, so 1/2 + 1 is computed with fractions and 1/3 + 0.5 with floats.
+//
+// Exact results are normalized: fractions are kept in lowest terms, and the ones
+// that turn out to be integral become integers, which become small integers if
+// they fit (see integers). So (2/4) = (1/2), and 1/2 + 1/2 is 1.
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+
+use crate::objects::{
+    number::Fraction,
+    object::{ObjectPointer, Pointer},
+};
+use super::{ExecutionError, VirtualMachine};
+use super::integers::{large_operation, small_operation};
+use super::primitives::{
+    ADD, SUBTRACT, MULTIPLY, DIVIDE, MODULO, QUO, REM, EXACT_DIVIDE, NUMERATOR,
+    LESS, GREATER, LESS_EQUAL, GREATER_EQUAL, EQUAL, NOT_EQUAL,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    Integer(BigInt),
+    Fraction(BigRational),
+    Float(f64),
+}
+
+// Result of an arithmetic primitive: a number, or a boolean for comparisons
+pub(super) enum Answer<T> {
+    Number(T),
+    Boolean(bool),
+}
+
+impl Number {
+    fn generality(&self) -> u8 {
+        match self {
+            Number::Integer(_) => 0,
+            Number::Fraction(_) => 1,
+            Number::Float(_) => 2,
+        }
+    }
+
+    // Same number, as the kind with the given generality. Numbers that are already
+    // as general are left as they are
+    fn coerce(self, generality: u8) -> Number {
+        match (self, generality) {
+            (Number::Integer(value), 1) => Number::Fraction(BigRational::from_integer(value)),
+            (Number::Integer(value), 2) => Number::Float(value.to_f64().unwrap_or(f64::NAN)),
+            (Number::Fraction(value), 2) => Number::Float(value.to_f64().unwrap_or(f64::NAN)),
+            (number, _) => number,
+        }
+    }
+
+    // Closest float. Integers and fractions too large for one become infinities
+    pub fn to_f64(&self) -> f64 {
+        match self.clone().coerce(2) {
+            Number::Float(value) => value,
+            _ => unreachable!("numbers of generality 2 are floats"),
+        }
+    }
+}
+
+impl VirtualMachine {
+    pub fn number_value(&self, ptr: ObjectPointer) -> Option<Number> {
+        if let Some(value) = self.integer_value(ptr) {
+            return Some(Number::Integer(value));
+        }
+
+        match self.memory.get::<Fraction>(ptr) {
+            Ok(fraction) => Some(Number::Fraction(fraction.value().clone())),
+            Err(_) => self.float_value(ptr).map(Number::Float),
+        }
+    }
+
+    pub fn new_number(&mut self, value: Number) -> Result<ObjectPointer, ExecutionError> {
+        match value {
+            Number::Fraction(value) if value.is_integer() => self.new_integer(value.to_integer()),
+            Number::Fraction(value) => Ok(self.memory.allocate(Fraction::new(value))?),
+            Number::Integer(value) => self.new_integer(value),
+            Number::Float(value) => self.new_float(value),
+        }
+    }
+
+    // Applies one of the arithmetic primitives. None when an argument isn't a number,
+    // or the operation isn't defined for them, like a division by zero
+    pub(super) fn number_operation(&mut self, number: u8, receiver: ObjectPointer, arg: ObjectPointer)
+        -> Result<Option<ObjectPointer>, ExecutionError>
+    {
+        // Small integers are by far the most common
+        if let (Some(a), Some(b)) = (receiver.small_integer(), arg.small_integer()) {
+            match small_operation(number, a as i64, b as i64) {
+                Some(Answer::Number(value)) if i32::try_from(value).is_ok() => {
+                    return Ok(Some(ObjectPointer::from_small_integer(value as i32)));
+                }
+                Some(Answer::Boolean(value)) => return Ok(Some(self.boolean(value))),
+                _ => {}
+            }
+        }
+
+        let (Some(a), Some(b)) = (self.number_value(receiver), self.number_value(arg)) else {
+            return Ok(None);
+        };
+        let generality = a.generality().max(b.generality());
+        let answer = match (a.coerce(generality), b.coerce(generality)) {
+            (Number::Integer(a), Number::Integer(b)) if number == EXACT_DIVIDE => {
+                fraction_operation(number, &BigRational::from_integer(a), &BigRational::from_integer(b))
+            }
+            (Number::Integer(a), Number::Integer(b)) => match large_operation(number, &a, &b) {
+                Some(Answer::Number(value)) => Some(Answer::Number(Number::Integer(value))),
+                Some(Answer::Boolean(value)) => Some(Answer::Boolean(value)),
+                None => None,
+            },
+            (Number::Fraction(a), Number::Fraction(b)) => fraction_operation(number, &a, &b),
+            (Number::Float(a), Number::Float(b)) => float_operation(number, a, b),
+            _ => None,
+        };
+
+        match answer {
+            Some(Answer::Number(value)) => Ok(Some(self.new_number(value)?)),
+            Some(Answer::Boolean(value)) => Ok(Some(self.boolean(value))),
+            None => Ok(None),
+        }
+    }
+
+    // Numerator or denominator of an integer or a fraction. Integers are their own
+    // numerator, over 1
+    pub(super) fn number_part(&mut self, number: u8, receiver: ObjectPointer)
+        -> Result<Option<ObjectPointer>, ExecutionError>
+    {
+        let value = match self.number_value(receiver) {
+            Some(Number::Integer(_)) if number == NUMERATOR => return Ok(Some(receiver)),
+            Some(Number::Integer(_)) => return Ok(Some(ObjectPointer::from_small_integer(1))),
+            Some(Number::Fraction(value)) if number == NUMERATOR => value.numer().clone(),
+            Some(Number::Fraction(value)) => value.denom().clone(),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(self.new_integer(value)?))
+    }
+}
+
+// Integer divisions (// and quo:) answer integers, and their remainders (\\ and
+// rem:) numbers of the kind of the arguments
+fn fraction_operation(number: u8, a: &BigRational, b: &BigRational) -> Option<Answer<Number>> {
+    let value = match number {
+        ADD => a + b,
+        SUBTRACT => a - b,
+        MULTIPLY => a * b,
+        EXACT_DIVIDE | DIVIDE | MODULO | QUO | REM if b.is_zero() => return None,
+        EXACT_DIVIDE => a / b,
+        DIVIDE => return Some(Answer::Number(Number::Integer((a / b).floor().to_integer()))),
+        MODULO => a - b * (a / b).floor(),
+        QUO => return Some(Answer::Number(Number::Integer((a / b).trunc().to_integer()))),
+        REM => a - b * (a / b).trunc(),
+        _ => return compare(number, a, b).map(Answer::Boolean),
+    };
+
+    Some(Answer::Number(Number::Fraction(value)))
+}
+
+// Floats divide by zero as usual, into infinities or nan. Integer divisions have no
+// such results, and fail instead
+fn float_operation(number: u8, a: f64, b: f64) -> Option<Answer<Number>> {
+    let value = match number {
+        ADD => a + b,
+        SUBTRACT => a - b,
+        MULTIPLY => a * b,
+        EXACT_DIVIDE => a / b,
+        DIVIDE => return BigInt::from_f64((a / b).floor()).map(|value| Answer::Number(Number::Integer(value))),
+        MODULO => a - b * (a / b).floor(),
+        QUO => return BigInt::from_f64((a / b).trunc()).map(|value| Answer::Number(Number::Integer(value))),
+        REM => a % b,
+        _ => return compare(number, &a, &b).map(Answer::Boolean),
+    };
+
+    Some(Answer::Number(Number::Float(value)))
+}
+
+pub(super) fn compare<T: PartialOrd>(number: u8, a: &T, b: &T) -> Option<bool> {
+    match number {
+        LESS => Some(a < b),
+        GREATER => Some(a > b),
+        LESS_EQUAL => Some(a <= b),
+        GREATER_EQUAL => Some(a >= b),
+        EQUAL => Some(a == b),
+        NOT_EQUAL => Some(a != b),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LoadError;
+    use crate::execution::primitives::{Outcome, DENOMINATOR, GCD};
+    use crate::objects::object::ObjectType;
+
+    const KERNEL: &str = "\
+Class Number
+[
+    + n
+        ^ <20 self n>
+|
+    - n
+        ^ <21 self n>
+|
+    * n
+        ^ <22 self n>
+|
+    / n
+        ^ <37 self n>
+|
+    // n
+        ^ <23 self n>
+|
+    \\\\ n
+        ^ <24 self n>
+|
+    < n
+        ^ <31 self n>
+|
+    = n
+        ^ <35 self n>
+|
+    numerator
+        ^ <38 self>
+|
+    denominator
+        ^ <39 self>
+]
+Class Integer :Number
+[
+]
+Class LargePositiveInteger :Integer
+[
+]
+Class LargeNegativeInteger :Integer
+[
+]
+Class Fraction :Number
+[
+]
+Class Float :Number
+[
+]
+";
+
+    fn evaluate(vm: &mut VirtualMachine, source: &str) -> Result<Option<Number>, LoadError> {
+        let result = vm.evaluate_source("test.st", source)?;
+        let value = vm.number_value(result);
+        vm.memory_mut().decrement_ref(result)?;
+        Ok(value)
+    }
+
+    fn fraction(numerator: i32, denominator: i32) -> Option<Number> {
+        Some(Number::Fraction(BigRational::new(numerator.into(), denominator.into())))
+    }
+
+    fn integer(value: i32) -> Option<Number> {
+        Some(Number::Integer(value.into()))
+    }
+
+    #[test]
+    fn test_numbers_fractions() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", KERNEL)?;
+
+        assert_eq!(evaluate(&mut vm, "1 / 3")?, fraction(1, 3));
+        assert_eq!(evaluate(&mut vm, "2 / 4")?, fraction(1, 2));
+        assert_eq!(evaluate(&mut vm, "1 / -3")?, fraction(-1, 3));
+        assert_eq!(vm.evaluate_source("test.st", "(2 / 4) = (1 / 2)")?, vm.true_object());
+        // Integral results are integers
+        assert_eq!(evaluate(&mut vm, "6 / 3")?, integer(2));
+        assert_eq!(evaluate(&mut vm, "(1 / 2) + (1 / 2)")?, integer(1));
+        assert_eq!(evaluate(&mut vm, "(1 / 3) * 3")?, integer(1));
+        assert_eq!(evaluate(&mut vm, "(1 / 2) + 1")?, fraction(3, 2));
+        assert_eq!(evaluate(&mut vm, "(-7 / 2) // 1")?, integer(-4));
+        assert_eq!(evaluate(&mut vm, "(7 / 2) \\\\ (2 / 3)")?, fraction(1, 6));
+        assert_eq!(evaluate(&mut vm, "(6 / 4) numerator")?, integer(3));
+        assert_eq!(evaluate(&mut vm, "(6 / 4) denominator")?, integer(2));
+        assert_eq!(evaluate(&mut vm, "5 denominator")?, integer(1));
+        assert_eq!(vm.evaluate_source("test.st", "(1 / 3) < (1 / 2)")?, vm.true_object());
+
+        // Fractions of large integers
+        assert_eq!(evaluate(&mut vm, "(65536 * 65536 * 65536 * 65536) / 6")?,
+                   Some(Number::Fraction(BigRational::new(BigInt::from(1) << 63, 3.into()))));
+
+        let third = vm.evaluate_source("test.st", "1 / 3")?;
+        assert_eq!(vm.memory().object_type(third)?, ObjectType::Fraction);
+        assert_eq!(vm.class_name(vm.class_of(third)?), "Fraction");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbers_mixed() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", KERNEL)?;
+
+        assert_eq!(evaluate(&mut vm, "(1 / 3) + 0.5")?, Some(Number::Float(1.0 / 3.0 + 0.5)));
+        assert_eq!(evaluate(&mut vm, "0.5 + (1 / 3)")?, Some(Number::Float(0.5 + 1.0 / 3.0)));
+        assert_eq!(evaluate(&mut vm, "1 + 0.5")?, Some(Number::Float(1.5)));
+        assert_eq!(evaluate(&mut vm, "3 / 0.5")?, Some(Number::Float(6.0)));
+        assert_eq!(evaluate(&mut vm, "7.5 // 2")?, integer(3));
+        assert_eq!(evaluate(&mut vm, "-7.5 \\\\ 2")?, Some(Number::Float(0.5)));
+        assert_eq!(vm.evaluate_source("test.st", "(1 / 2) = 0.5")?, vm.true_object());
+        assert_eq!(vm.evaluate_source("test.st", "2 = (4 / 2)")?, vm.true_object());
+        assert_eq!(vm.evaluate_source("test.st", "1 < 1.5")?, vm.true_object());
+        assert_eq!(evaluate(&mut vm, "(65536 * 65536 * 65536 * 65536) + 0.5")?, Some(Number::Float(2f64.powi(64))));
+
+        // Exact divisions by zero fail
+        assert_eq!(evaluate(&mut vm, "1 / 0")?, None);
+        assert_eq!(evaluate(&mut vm, "(1 / 2) // 0")?, None);
+        assert_eq!(evaluate(&mut vm, "1.5 // 0")?, None);
+        assert_eq!(evaluate(&mut vm, "1 / 0.0")?, Some(Number::Float(f64::INFINITY)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_numbers_primitives_and_images() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let third = vm.new_number(Number::Fraction(BigRational::new(1.into(), 3.into())))?;
+        vm.set_global("Third", third)?;
+        let two = ObjectPointer::from_small_integer(2);
+
+        assert_eq!(vm.primitive(DENOMINATOR, &[third])?, Outcome::Value(ObjectPointer::from_small_integer(3)));
+        assert_eq!(vm.primitive(NUMERATOR, &[two])?, Outcome::Value(two));
+        assert_eq!(vm.primitive(ADD, &[third, vm.true_object()])?, Outcome::Failed);
+        assert_eq!(vm.primitive(NUMERATOR, &[vm.true_object()])?, Outcome::Failed);
+        let half = vm.new_float(0.5)?;
+        assert_eq!(vm.primitive(NUMERATOR, &[half])?, Outcome::Failed);
+        // Operations only integers have
+        assert_eq!(vm.primitive(GCD, &[third, two])?, Outcome::Failed);
+
+        let mut bytes = vec![];
+        vm.write_image(&mut bytes).unwrap();
+        let loaded = VirtualMachine::read_image(bytes.as_slice()).unwrap();
+        assert_eq!(loaded.number_value(loaded.global("Third").unwrap()), fraction(1, 3));
+
+        Ok(())
+    }
+}
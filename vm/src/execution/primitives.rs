@@ -0,0 +1,599 @@
+// Primitives
+//
+// Operations the virtual machine performs directly, invoked from methods with the
+// DoPrimitive instruction. Primitives that can't handle their arguments fail, and
+// the instruction pushes nil instead of a result.
+//
+// Primitive numbers are grouped by the kind of object they deal with:
+//
+//     1 -  19  Objects, blocks and globals
+//    20 -  39  Numbers of any kind, integers, fractions and floats
+//    40 -  59  Floats, converting other numbers to floats
+//    60 -  69  Chars
+//    70 -  89  Strings, converting them to symbols and numbers
+//   100 - 109  Processes and semaphores
+//   110 - 119  Files
+
+use std::io;
+
+use crate::memory::ObjectRef;
+use crate::objects::{
+    block::Block,
+    character::Char,
+    file::{File, FileMode, FileValue},
+    interp::Interpreter,
+    object::{Object, ObjectPointer, ObjectType, Pointer},
+};
+use super::{ExecutionError, VirtualMachine};
+
+pub const IDENTICAL: u8 =           1;
+pub const CLASS: u8 =               2;
+pub const NEW: u8 =                 3;
+pub const BASIC_SIZE: u8 =          4;
+pub const BASIC_AT: u8 =            5;
+pub const BASIC_AT_PUT: u8 =        6;
+pub const NEW_INDEXED: u8 =         7;
+pub const BLOCK_VALUE: u8 =         8;
+pub const GLOBAL_VALUE: u8 =        9;
+pub const SET_GLOBAL: u8 =          10;
+
+pub const ADD: u8 =                 20;
+pub const SUBTRACT: u8 =            21;
+pub const MULTIPLY: u8 =            22;
+pub const DIVIDE: u8 =              23;
+pub const MODULO: u8 =              24;
+pub const QUO: u8 =                 25;
+pub const REM: u8 =                 26;
+pub const GCD: u8 =                 27;
+pub const BIT_AND: u8 =             28;
+pub const BIT_OR: u8 =              29;
+pub const BIT_SHIFT: u8 =           30;
+pub const LESS: u8 =                31;
+pub const GREATER: u8 =             32;
+pub const LESS_EQUAL: u8 =          33;
+pub const GREATER_EQUAL: u8 =       34;
+pub const EQUAL: u8 =               35;
+pub const NOT_EQUAL: u8 =           36;
+pub const EXACT_DIVIDE: u8 =        37;
+pub const NUMERATOR: u8 =           38;
+pub const DENOMINATOR: u8 =         39;
+
+pub const FLOAT_ADD: u8 =           40;
+pub const FLOAT_SUBTRACT: u8 =      41;
+pub const FLOAT_MULTIPLY: u8 =      42;
+pub const FLOAT_DIVIDE: u8 =        43;
+pub const FLOAT_LESS: u8 =          44;
+pub const FLOAT_GREATER: u8 =       45;
+pub const FLOAT_LESS_EQUAL: u8 =    46;
+pub const FLOAT_GREATER_EQUAL: u8 = 47;
+pub const FLOAT_EQUAL: u8 =         48;
+pub const TRUNCATED: u8 =           49;
+pub const ROUNDED: u8 =             50;
+pub const SQRT: u8 =                51;
+pub const LN: u8 =                  52;
+pub const EXP: u8 =                 53;
+pub const SIN: u8 =                 54;
+pub const COS: u8 =                 55;
+pub const TAN: u8 =                 56;
+pub const ARCTAN: u8 =              57;
+pub const FLOAT_PRINT: u8 =         58;
+pub const FLOAT_READ: u8 =          59;
+
+pub const CHAR_VALUE: u8 =          60;
+pub const CHAR_FROM_VALUE: u8 =     61;
+pub const CHAR_LESS: u8 =           62;
+pub const CHAR_EQUAL: u8 =          63;
+pub const CHAR_IS_VOWEL: u8 =       64;
+pub const CHAR_IS_LETTER: u8 =      65;
+pub const CHAR_IS_DIGIT: u8 =       66;
+pub const CHAR_AS_UPPERCASE: u8 =   67;
+pub const CHAR_AS_LOWERCASE: u8 =   68;
+
+pub const STRING_AT: u8 =           70;
+pub const STRING_AT_PUT: u8 =       71;
+pub const STRING_SIZE: u8 =         72;
+pub const STRING_CONCAT: u8 =       73;
+pub const STRING_LESS: u8 =         74;
+pub const STRING_EQUAL: u8 =        75;
+pub const STRING_INDEX_OF: u8 =     76;
+pub const STRING_COPY: u8 =         77;
+pub const AS_SYMBOL: u8 =           78;
+pub const AS_NUMBER: u8 =           79;
+pub const STRING_REVERSED: u8 =     80;
+pub const STRING_AS_UPPERCASE: u8 = 81;
+pub const STRING_ENCODE: u8 =       82;
+pub const STRING_DECODE: u8 =       83;
+
+pub const FORK: u8 =                100;
+pub const SUSPEND: u8 =             101;
+pub const RESUME: u8 =              102;
+pub const YIELD: u8 =               103;
+pub const TERMINATE: u8 =           104;
+pub const ACTIVE_PROCESS: u8 =      105;
+pub const PROCESS_STATE: u8 =       106;
+pub const NEW_SEMAPHORE: u8 =       107;
+pub const WAIT: u8 =                108;
+pub const SIGNAL: u8 =              109;
+
+pub const FILE_OPEN: u8 =           110;
+pub const FILE_CLOSE: u8 =          111;
+pub const FILE_MODE: u8 =           112;
+pub const FILE_READ: u8 =           113;
+pub const FILE_WRITE: u8 =          114;
+pub const FILE_SEEK: u8 =           115;
+pub const FILE_POSITION: u8 =       116;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    // Result of the primitive. Not owned: the interpreter takes its own reference
+    Value(ObjectPointer),
+    // A new context has been activated, and will provide the result
+    Activated,
+    Failed,
+}
+
+impl VirtualMachine {
+    pub fn primitive(&mut self, number: u8, args: &[ObjectPointer]) -> Result<Outcome, ExecutionError> {
+        match (number, args) {
+            (IDENTICAL, &[first, second]) => Ok(Outcome::Value(self.boolean(first == second))),
+            (CLASS, &[receiver]) => Ok(Outcome::Value(self.class_of(receiver)?)),
+            (NEW, &[class]) => self.primitive_new(class),
+            (BASIC_SIZE, &[receiver]) => self.primitive_basic_size(receiver),
+            (BASIC_AT, &[receiver, index]) => self.primitive_basic_at(receiver, index),
+            (BASIC_AT_PUT, &[receiver, index, value]) => self.primitive_basic_at_put(receiver, index, value),
+            (NEW_INDEXED, &[class, size]) => self.primitive_new_indexed(class, size),
+            (BLOCK_VALUE, &[block, ref values @ ..]) => self.primitive_block_value(block, values),
+            (GLOBAL_VALUE, &[name]) => self.primitive_global_value(name),
+            (SET_GLOBAL, &[name, value]) => self.primitive_set_global(name, value),
+            (IDENTICAL..=SET_GLOBAL, _) => Ok(Outcome::Failed),
+            (ADD..=EXACT_DIVIDE, &[receiver, arg]) => match self.number_operation(number, receiver, arg)? {
+                Some(value) => Ok(Outcome::Value(value)),
+                None => Ok(Outcome::Failed),
+            },
+            (NUMERATOR | DENOMINATOR, &[receiver]) => match self.number_part(number, receiver)? {
+                Some(value) => Ok(Outcome::Value(value)),
+                None => Ok(Outcome::Failed),
+            },
+            (ADD..=DENOMINATOR, _) => Ok(Outcome::Failed),
+            (FLOAT_ADD..=FLOAT_READ, _) => match self.float_operation(number, args)? {
+                Some(value) => Ok(Outcome::Value(value)),
+                None => Ok(Outcome::Failed),
+            },
+            (CHAR_VALUE..=CHAR_AS_LOWERCASE, _) => self.primitive_char(number, args),
+            (STRING_AT..=STRING_DECODE, _) => match self.string_operation(number, args)? {
+                Some(value) => Ok(Outcome::Value(value)),
+                None => Ok(Outcome::Failed),
+            },
+            (FORK, &[block]) => self.primitive_fork(block),
+            (SUSPEND, &[process]) => self.primitive_process(process, Self::suspend),
+            (RESUME, &[process]) => self.primitive_process(process, Self::resume),
+            (YIELD, &[]) => {
+                self.yield_process()?;
+                Ok(Outcome::Value(ObjectPointer::null()))
+            }
+            (TERMINATE, &[process]) => self.primitive_process(process, Self::terminate),
+            (ACTIVE_PROCESS, &[]) => Ok(Outcome::Value(self.active_process())),
+            (PROCESS_STATE, &[process]) => self.primitive_process_state(process),
+            (NEW_SEMAPHORE, &[]) => Ok(Outcome::Value(self.new_semaphore(0)?)),
+            (NEW_SEMAPHORE, &[signals]) => self.primitive_new_semaphore(signals),
+            (WAIT, &[semaphore]) => self.primitive_semaphore(semaphore, Self::wait),
+            (SIGNAL, &[semaphore]) => self.primitive_semaphore(semaphore, Self::signal),
+            (FORK..=SIGNAL, _) => Ok(Outcome::Failed),
+            (FILE_OPEN, &[name, access]) => self.primitive_file_open(name, access),
+            (FILE_CLOSE, &[file]) => self.primitive_file(file, |object| {
+                object.close();
+                Ok(file)
+            }),
+            (FILE_MODE, &[file, mode]) => match mode.small_integer().and_then(FileMode::from_number) {
+                Some(mode) => self.primitive_file(file, |object| {
+                    object.set_mode(mode);
+                    Ok(file)
+                }),
+                None => Ok(Outcome::Failed),
+            },
+            (FILE_READ, &[file]) => self.primitive_file_read(file),
+            (FILE_WRITE, &[file, value]) => self.primitive_file_write(file, value),
+            (FILE_SEEK, &[file, position]) => match position.small_integer().and_then(|position| u64::try_from(position).ok()) {
+                Some(position) => self.primitive_file(file, |object| file_position(object.seek(position)?)),
+                None => Ok(Outcome::Failed),
+            },
+            (FILE_POSITION, &[file]) => self.primitive_file(file, |object| file_position(object.position()?)),
+            (FILE_OPEN..=FILE_POSITION, _) => Ok(Outcome::Failed),
+            _ => Err(ExecutionError::UnknownPrimitive(number)),
+        }
+    }
+
+    fn primitive_new(&mut self, class: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        if !self.is_kind_of(class, ObjectType::Class) {
+            return Ok(Outcome::Failed);
+        }
+
+        Ok(Outcome::Value(self.memory.instantiate(class)?))
+    }
+
+    fn primitive_basic_size(&self, receiver: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let size = match self.memory.object(receiver) {
+            Ok(ObjectRef::Object(obj)) => obj.size(),
+            Ok(ObjectRef::ByteArray(bytes)) => bytes.value().len(),
+            _ => 0,
+        };
+
+        Ok(Outcome::Value(ObjectPointer::from_small_integer(size as i32)))
+    }
+
+    fn primitive_basic_at(&self, receiver: ObjectPointer, index: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let Some(index) = self.array_index(receiver, index) else {
+            return Ok(Outcome::Failed);
+        };
+
+        Ok(Outcome::Value(self.memory.inst_var(receiver, index)?))
+    }
+
+    fn primitive_basic_at_put(&mut self, receiver: ObjectPointer, index: ObjectPointer, value: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let Some(index) = self.array_index(receiver, index) else {
+            return Ok(Outcome::Failed);
+        };
+
+        self.memory.put_inst_var(receiver, index, value)?;
+        Ok(Outcome::Value(value))
+    }
+
+    fn primitive_new_indexed(&mut self, class: ObjectPointer, size: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        match size.small_integer() {
+            Some(size) if size >= 0 && self.is_kind_of(class, ObjectType::Class) => {
+                let array = self.memory.allocate_counted(Object::new(class, ObjectPointer::null(), size as usize))?;
+                Ok(Outcome::Value(array))
+            }
+            _ => Ok(Outcome::Failed),
+        }
+    }
+
+    // Evaluates a block, in a new context sent from the active one. The arguments
+    // are stored in the temporaries of the method that created the block
+    fn primitive_block_value(&mut self, block: ObjectPointer, values: &[ObjectPointer]) -> Result<Outcome, ExecutionError> {
+        let Ok(block) = self.memory.get::<Block>(block) else {
+            return Ok(Outcome::Failed);
+        };
+        if block.numargs() as usize != values.len() {
+            return Ok(Outcome::Failed);
+        }
+
+        let (template, arglocation) = (block.interpreter(), block.arglocation() as usize);
+        let temporaries = self.memory.get::<Interpreter>(template)?.context();
+        for (index, &value) in values.iter().enumerate() {
+            self.memory.put_inst_var(temporaries, arglocation + index, value)?;
+        }
+
+        let context = self.block_context(template, self.active)?;
+        self.set_active(context)?;
+
+        Ok(Outcome::Activated)
+    }
+
+    // Undefined globals are nil
+    fn primitive_global_value(&self, name: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let Ok(name) = self.symbol_value(name) else {
+            return Ok(Outcome::Failed);
+        };
+
+        Ok(Outcome::Value(self.global(name).unwrap_or(ObjectPointer::null())))
+    }
+
+    fn primitive_set_global(&mut self, name: ObjectPointer, value: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let Ok(name) = self.symbol_value(name).map(str::to_string) else {
+            return Ok(Outcome::Failed);
+        };
+
+        self.set_global(&name, value)?;
+        Ok(Outcome::Value(value))
+    }
+
+    fn primitive_char(&mut self, number: u8, args: &[ObjectPointer]) -> Result<Outcome, ExecutionError> {
+        if let (CHAR_FROM_VALUE, &[code]) = (number, args) {
+            let value = code.small_integer()
+                .and_then(|code| u32::try_from(code).ok())
+                .and_then(char::from_u32);
+            return match value {
+                Some(value) => Ok(Outcome::Value(self.new_char(value)?)),
+                None => Ok(Outcome::Failed),
+            };
+        }
+
+        let chars = args.iter()
+            .map(|&ptr| self.memory.get::<Char>(ptr).ok())
+            .collect::<Option<Vec<&Char>>>();
+        let value = match (number, chars.as_deref()) {
+            (CHAR_VALUE, Some([c])) => ObjectPointer::from_small_integer(c.value() as i32),
+            (CHAR_LESS, Some([c, other])) => self.boolean(c.value() < other.value()),
+            (CHAR_EQUAL, Some([c, other])) => self.boolean(c.value() == other.value()),
+            (CHAR_IS_VOWEL, Some([c])) => self.boolean(c.is_vowel()),
+            (CHAR_IS_LETTER, Some([c])) => self.boolean(c.is_letter()),
+            (CHAR_IS_DIGIT, Some([c])) => self.boolean(c.is_digit()),
+            (CHAR_AS_UPPERCASE | CHAR_AS_LOWERCASE, Some([c])) => {
+                let code = if number == CHAR_AS_UPPERCASE { c.to_uppercase() } else { c.to_lowercase() };
+                self.new_char(char::from_u32(code).unwrap_or_default())?
+            }
+            _ => return Ok(Outcome::Failed),
+        };
+
+        Ok(Outcome::Value(value))
+    }
+
+    fn primitive_fork(&mut self, block: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        match self.memory.get::<Block>(block) {
+            Ok(block_object) if block_object.numargs() == 0 => Ok(Outcome::Value(self.fork(block)?)),
+            _ => Ok(Outcome::Failed),
+        }
+    }
+
+    // Changes the state of a process. Fails when it's not allowed to
+    fn primitive_process(&mut self, process: ObjectPointer, change: fn(&mut Self, ObjectPointer) -> Result<(), ExecutionError>) -> Result<Outcome, ExecutionError> {
+        if !self.is_kind_of(process, ObjectType::Process) {
+            return Ok(Outcome::Failed);
+        }
+
+        match change(self, process) {
+            Ok(()) => Ok(Outcome::Value(process)),
+            Err(ExecutionError::InvalidTransition(_)) => Ok(Outcome::Failed),
+            Err(err) => Err(err),
+        }
+    }
+
+    // State of a process, as a symbol (#ready, #terminated...)
+    fn primitive_process_state(&mut self, process: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let Ok(state) = self.process_state(process) else {
+            return Ok(Outcome::Failed);
+        };
+
+        let symbol = self.new_symbol(&state.to_string())?;
+        Ok(Outcome::Value(symbol))
+    }
+
+    fn primitive_new_semaphore(&mut self, signals: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        match signals.small_integer() {
+            Some(signals) if signals >= 0 => Ok(Outcome::Value(self.new_semaphore(signals as u32)?)),
+            _ => Ok(Outcome::Failed),
+        }
+    }
+
+    fn primitive_semaphore(&mut self, semaphore: ObjectPointer, operation: fn(&mut Self, ObjectPointer) -> Result<(), ExecutionError>) -> Result<Outcome, ExecutionError> {
+        if !self.is_kind_of(semaphore, ObjectType::Semaphore) {
+            return Ok(Outcome::Failed);
+        }
+
+        operation(self, semaphore)?;
+        Ok(Outcome::Value(semaphore))
+    }
+
+    // Names and access modes may be strings or symbols
+    fn primitive_file_open(&mut self, name: ObjectPointer, access: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let text = |ptr| self.string_value(ptr).or_else(|_| self.symbol_value(ptr)).ok();
+        let (Some(name), Some(access)) = (text(name), text(access)) else {
+            return Ok(Outcome::Failed);
+        };
+
+        match File::open(name, access) {
+            Ok(file) => Ok(Outcome::Value(self.memory.allocate(file)?)),
+            Err(_) => Ok(Outcome::Failed),
+        }
+    }
+
+    // Runs an operation on a file, failing when it's not a file or the operation
+    // can't be done
+    fn primitive_file(&mut self, file: ObjectPointer, operation: impl FnOnce(&mut File) -> io::Result<ObjectPointer>) -> Result<Outcome, ExecutionError> {
+        if !self.is_kind_of(file, ObjectType::File) {
+            return Ok(Outcome::Failed);
+        }
+
+        match operation(self.memory.get_mut::<File>(file)?) {
+            Ok(value) => Ok(Outcome::Value(value)),
+            Err(_) => Ok(Outcome::Failed),
+        }
+    }
+
+    // Chars are read as bytes, and nil marks the end of the file
+    fn primitive_file_read(&mut self, file: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        if !self.is_kind_of(file, ObjectType::File) {
+            return Ok(Outcome::Failed);
+        }
+
+        let value = match self.memory.get_mut::<File>(file)?.read() {
+            Ok(Some(FileValue::Char(byte))) => self.new_char(byte as char)?,
+            Ok(Some(FileValue::Line(line))) => self.new_string(&line)?,
+            Ok(Some(FileValue::Integer(value))) => ObjectPointer::from_small_integer(value),
+            Ok(None) => ObjectPointer::null(),
+            Err(_) => return Ok(Outcome::Failed),
+        };
+
+        Ok(Outcome::Value(value))
+    }
+
+    fn primitive_file_write(&mut self, file: ObjectPointer, value: ObjectPointer) -> Result<Outcome, ExecutionError> {
+        let value = if let Some(code) = self.char_value(value) {
+            match u8::try_from(code) {
+                Ok(byte) => FileValue::Char(byte),
+                Err(_) => return Ok(Outcome::Failed),
+            }
+        } else if let Some(value) = value.small_integer() {
+            FileValue::Integer(value)
+        } else if let Ok(line) = self.string_value(value) {
+            FileValue::Line(line.to_string())
+        } else {
+            return Ok(Outcome::Failed);
+        };
+
+        self.primitive_file(file, |object| object.write(&value).map(|_| file))
+    }
+
+    // Zero based index for a one based Smalltalk index into an ordinary object
+    fn array_index(&self, receiver: ObjectPointer, index: ObjectPointer) -> Option<usize> {
+        let size = self.memory.get::<Object>(receiver).ok()?.size();
+        match index.small_integer()? {
+            index if index >= 1 && index as usize <= size => Some(index as usize - 1),
+            _ => None,
+        }
+    }
+}
+
+// Positions in a file, as SmallIntegers
+fn file_position(position: u64) -> io::Result<ObjectPointer> {
+    i32::try_from(position)
+        .map(ObjectPointer::from_small_integer)
+        .map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_primitives_objects() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let class = vm.new_class("Test", ObjectPointer::null(), &["a", "b"])?;
+        let one = ObjectPointer::from_small_integer(1);
+        let three = ObjectPointer::from_small_integer(3);
+
+        let Outcome::Value(object) = vm.primitive(NEW, &[class])? else { panic!("new failed") };
+        vm.memory_mut().increment_ref(object)?;
+        assert_eq!(vm.primitive(CLASS, &[object])?, Outcome::Value(class));
+        assert_eq!(vm.primitive(BASIC_SIZE, &[object])?, Outcome::Value(ObjectPointer::from_small_integer(2)));
+        assert_eq!(vm.primitive(BASIC_AT_PUT, &[object, one, three])?, Outcome::Value(three));
+        assert_eq!(vm.primitive(BASIC_AT, &[object, one])?, Outcome::Value(three));
+        assert_eq!(vm.primitive(IDENTICAL, &[object, object])?, Outcome::Value(vm.true_object()));
+        assert_eq!(vm.primitive(IDENTICAL, &[object, one])?, Outcome::Value(vm.false_object()));
+
+        let name = vm.new_symbol("Answer")?;
+        assert_eq!(vm.primitive(GLOBAL_VALUE, &[name])?, Outcome::Value(ObjectPointer::null()));
+        assert_eq!(vm.primitive(SET_GLOBAL, &[name, three])?, Outcome::Value(three));
+        assert_eq!(vm.primitive(GLOBAL_VALUE, &[name])?, Outcome::Value(three));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_primitives_fail() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let one = ObjectPointer::from_small_integer(1);
+        let class = vm.new_class("Array", ObjectPointer::null(), &[])?;
+        let Outcome::Value(array) = vm.primitive(NEW_INDEXED, &[class, one])? else {
+            panic!("new: failed");
+        };
+
+        // One based, and within bounds
+        assert_eq!(vm.primitive(BASIC_AT, &[array, ObjectPointer::from_small_integer(0)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(BASIC_AT, &[array, ObjectPointer::from_small_integer(2)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(BASIC_AT, &[one, one])?, Outcome::Failed);
+        assert_eq!(vm.primitive(NEW, &[one])?, Outcome::Failed);
+        // Wrong number of arguments
+        assert_eq!(vm.primitive(IDENTICAL, &[one])?, Outcome::Failed);
+        assert_eq!(vm.primitive(BLOCK_VALUE, &[one])?, Outcome::Failed);
+        assert_eq!(vm.primitive(200, &[]), Err(ExecutionError::UnknownPrimitive(200)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_primitives_chars() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let (a, b, upper_a, seven) = (vm.new_char('a')?, vm.new_char('b')?, vm.new_char('A')?, vm.new_char('7')?);
+        let (yes, no) = (Outcome::Value(vm.true_object()), Outcome::Value(vm.false_object()));
+
+        assert_eq!(vm.primitive(CHAR_VALUE, &[a])?, Outcome::Value(ObjectPointer::from_small_integer(97)));
+        assert_eq!(vm.primitive(CHAR_FROM_VALUE, &[ObjectPointer::from_small_integer(98)])?, Outcome::Value(b));
+        assert_eq!(vm.primitive(CHAR_LESS, &[a, b])?, yes);
+        assert_eq!(vm.primitive(CHAR_LESS, &[b, a])?, no);
+        assert_eq!(vm.primitive(CHAR_EQUAL, &[a, a])?, yes);
+        assert_eq!(vm.primitive(CHAR_IS_VOWEL, &[upper_a])?, yes);
+        assert_eq!(vm.primitive(CHAR_IS_VOWEL, &[b])?, no);
+        assert_eq!(vm.primitive(CHAR_IS_LETTER, &[a])?, yes);
+        assert_eq!(vm.primitive(CHAR_IS_LETTER, &[seven])?, no);
+        assert_eq!(vm.primitive(CHAR_IS_DIGIT, &[seven])?, yes);
+        assert_eq!(vm.primitive(CHAR_AS_UPPERCASE, &[a])?, Outcome::Value(upper_a));
+        assert_eq!(vm.primitive(CHAR_AS_LOWERCASE, &[upper_a])?, Outcome::Value(a));
+        assert_eq!(vm.primitive(CHAR_AS_LOWERCASE, &[seven])?, Outcome::Value(seven));
+
+        // Uppercase ß would be two characters
+        let sharp_s = vm.new_char('ß')?;
+        assert_eq!(vm.primitive(CHAR_AS_UPPERCASE, &[sharp_s])?, Outcome::Value(sharp_s));
+        let sigma = vm.new_char('σ')?;
+        let Outcome::Value(upper_sigma) = vm.primitive(CHAR_AS_UPPERCASE, &[sigma])? else {
+            panic!("asUppercase failed");
+        };
+        assert_eq!(vm.char_value(upper_sigma), Some('Σ' as u32));
+
+        // Surrogates and negative codes aren't characters
+        assert_eq!(vm.primitive(CHAR_FROM_VALUE, &[ObjectPointer::from_small_integer(0xd800)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(CHAR_FROM_VALUE, &[ObjectPointer::from_small_integer(-1)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(CHAR_LESS, &[a, ObjectPointer::from_small_integer(1)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(CHAR_VALUE, &[ObjectPointer::from_small_integer(1)])?, Outcome::Failed);
+
+        // Wide chars are unique too, even when taken out of a string
+        let lambda = vm.new_char('λ')?;
+        assert_eq!(vm.new_char('λ')?, lambda);
+        let string = vm.new_string("aλ")?;
+        assert_eq!(vm.primitive(STRING_AT, &[string, ObjectPointer::from_small_integer(2)])?, Outcome::Value(lambda));
+        let again = vm.new_char('λ')?;
+        assert_eq!(vm.primitive(IDENTICAL, &[lambda, again])?, yes);
+
+        // The preallocated ones are roots. The others go once unreferenced
+        vm.set_global("Lambda", lambda)?;
+        vm.collect_garbage()?;
+        let objects = vm.memory().live_objects().len();
+        vm.new_char('π')?;
+        vm.collect_garbage()?;
+        assert_eq!(vm.memory().live_objects().len(), objects);
+        assert_eq!(vm.char_value(a), Some('a' as u32));
+        assert_eq!(vm.new_char('λ')?, lambda);
+        let pi = vm.new_char('π')?;
+        assert_eq!(vm.char_value(pi), Some('π' as u32));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_primitives_files() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let path = std::env::temp_dir().join(format!("lst-primitives-test-{}", std::process::id()));
+        let name = vm.new_string(path.to_str().unwrap())?;
+        let access = vm.new_symbol("w+")?;
+        let line = vm.new_string("hello")?;
+        let (zero, one) = (ObjectPointer::from_small_integer(0), ObjectPointer::from_small_integer(1));
+
+        let Outcome::Value(file) = vm.primitive(FILE_OPEN, &[name, access])? else { panic!("open failed") };
+        vm.memory_mut().increment_ref(file)?;
+        assert_eq!(vm.primitive(FILE_MODE, &[file, one])?, Outcome::Value(file));
+        assert_eq!(vm.primitive(FILE_WRITE, &[file, line])?, Outcome::Value(file));
+        assert_eq!(vm.primitive(FILE_POSITION, &[file])?, Outcome::Value(ObjectPointer::from_small_integer(6)));
+        assert_eq!(vm.primitive(FILE_SEEK, &[file, zero])?, Outcome::Value(zero));
+
+        let Outcome::Value(read) = vm.primitive(FILE_READ, &[file])? else { panic!("read failed") };
+        assert_eq!(vm.string_value(read)?, "hello");
+        assert_eq!(vm.primitive(FILE_READ, &[file])?, Outcome::Value(ObjectPointer::null()));
+
+        // Chars are bytes
+        vm.primitive(FILE_SEEK, &[file, zero])?;
+        vm.primitive(FILE_MODE, &[file, zero])?;
+        let Outcome::Value(char_object) = vm.primitive(FILE_READ, &[file])? else { panic!("read failed") };
+        assert_eq!(vm.char_value(char_object), Some('h' as u32));
+        let wide = vm.new_char('\u{3bb}')?;
+        assert_eq!(vm.primitive(FILE_WRITE, &[file, wide])?, Outcome::Failed);
+        // Values not matching the mode
+        assert_eq!(vm.primitive(FILE_WRITE, &[file, one])?, Outcome::Failed);
+        assert_eq!(vm.primitive(FILE_MODE, &[file, ObjectPointer::from_small_integer(3)])?, Outcome::Failed);
+
+        assert_eq!(vm.primitive(FILE_CLOSE, &[file])?, Outcome::Value(file));
+        assert_eq!(vm.primitive(FILE_READ, &[file])?, Outcome::Failed);
+        assert_eq!(vm.primitive(FILE_READ, &[one])?, Outcome::Failed);
+
+        // Reclaimed once unreferenced
+        vm.memory_mut().decrement_ref(file)?;
+        assert!(vm.memory().object(file).is_err());
+
+        let missing = vm.new_string("/nonexistent/lst/file")?;
+        let read_access = vm.new_string("r")?;
+        assert_eq!(vm.primitive(FILE_OPEN, &[missing, read_access])?, Outcome::Failed);
+
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,651 @@
+// Process scheduler
+//
+// Processes take turns, round robin. Runnable (Ready or Unblocked) processes wait
+// in a queue linked through their next and prev fields, whose ends are held by the
+// machine. The process at the front becomes Active and runs for up to TIME_SLICE
+// instructions, or until it yields, suspends, blocks or terminates. If it can still
+// run afterwards, it goes to the back of the queue.
+//
+// Processes blocked on a semaphore wait in a queue of the semaphore instead (see
+// semaphores), linked the same way.
+//
+// While a process isn't running, its interpreter field holds its active context.
+// Every pointer to a process, including the links of the queue and the ends held
+// by the machine, holds a reference to it.
+
+use crate::objects::{
+    block::Block,
+    object::{ObjectPointer, Pointer},
+    process::{InvalidTransition, Process, ProcessState},
+    semaphore::Semaphore,
+};
+use super::{ExecutionError, VirtualMachine};
+
+// Queues of processes: the ready queue, or the processes waiting on a semaphore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Queue {
+    Ready,
+    Semaphore(ObjectPointer),
+}
+
+// Instructions a process runs before giving way to the next one
+pub const TIME_SLICE: usize = 1000;
+
+pub(super) struct Scheduler {
+    first: ObjectPointer,
+    last: ObjectPointer,
+    // Process being run, or null
+    current: ObjectPointer,
+    // Set when the current process stops being Active before its time is up
+    switch: bool,
+}
+
+impl Scheduler {
+    pub(super) fn new() -> Self {
+        Scheduler {
+            first: ObjectPointer::null(),
+            last: ObjectPointer::null(),
+            current: ObjectPointer::null(),
+            switch: false,
+        }
+    }
+
+    // Scheduler for a saved ready queue, with nothing running
+    pub(super) fn restore(first: ObjectPointer, last: ObjectPointer) -> Self {
+        Scheduler { first, last, ..Scheduler::new() }
+    }
+
+    pub(super) fn roots(&self) -> [ObjectPointer; 3] {
+        [self.first, self.last, self.current]
+    }
+
+    // Updates the pointers to processes that were moved in memory
+    pub(super) fn update_roots(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.first = update(self.first);
+        self.last = update(self.last);
+        self.current = update(self.current);
+    }
+}
+
+impl VirtualMachine {
+    // Process currently running, or nil outside of the scheduler
+    pub fn active_process(&self) -> ObjectPointer {
+        self.scheduler.current
+    }
+
+    // Processes waiting for their turn, in order
+    pub fn ready_processes(&self) -> Result<Vec<ObjectPointer>, ExecutionError> {
+        self.queued(Queue::Ready)
+    }
+
+    pub fn process_state(&self, process: ObjectPointer) -> Result<ProcessState, ExecutionError> {
+        Ok(self.memory.get::<Process>(process)?.state())
+    }
+
+    // Creates a process evaluating a block without arguments, ready to run. The
+    // scheduler holds a reference to it while it's in the ready queue
+    pub fn fork(&mut self, block: ObjectPointer) -> Result<ObjectPointer, ExecutionError> {
+        let template = self.memory.get::<Block>(block)?.interpreter();
+        let context = self.block_context(template, ObjectPointer::null())?;
+        let process = self.memory.allocate_counted(Process::new(context))?;
+
+        self.memory.increment_ref(process)?;
+        self.enqueue(Queue::Ready, process)?;
+        self.memory.decrement_ref(process)?;
+
+        Ok(process)
+    }
+
+    // Stops a process until it's resumed
+    pub fn suspend(&mut self, process: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(process)?;
+        let result = self.change_state(process, ProcessState::Suspended);
+        self.memory.decrement_ref(process)?;
+
+        result
+    }
+
+    // Puts a suspended process back in the ready queue. The others are running or
+    // already waiting to, and Active may only become Ready by yielding
+    pub fn resume(&mut self, process: ObjectPointer) -> Result<(), ExecutionError> {
+        let state = self.process_state(process)?;
+        if state != ProcessState::Suspended {
+            return Err(InvalidTransition { from: state, to: ProcessState::Ready }.into());
+        }
+
+        self.memory.get_mut::<Process>(process)?.set_state(ProcessState::Ready)?;
+        self.enqueue(Queue::Ready, process)
+    }
+
+    // Lets the other processes run before the current one goes on. Does nothing
+    // outside of the scheduler
+    pub fn yield_process(&mut self) -> Result<(), ExecutionError> {
+        let process = self.scheduler.current;
+        if process.is_null() {
+            return Ok(());
+        }
+
+        self.memory.get_mut::<Process>(process)?.set_state(ProcessState::Ready)?;
+        self.scheduler.switch = true;
+
+        Ok(())
+    }
+
+    // Stops a process for good, releasing its contexts
+    pub fn terminate(&mut self, process: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(process)?;
+        let result = self.change_state(process, ProcessState::Terminated).and_then(|_| {
+            let context = self.memory.get_mut::<Process>(process)?.interpreter();
+            self.memory.get_mut::<Process>(process)?.set_interpreter(ObjectPointer::null());
+            Ok(self.memory.decrement_ref(context)?)
+        });
+        self.memory.decrement_ref(process)?;
+
+        result
+    }
+
+    // Runs processes until none is ready. A process failing with an uncaught error
+    // is terminated, and the others still run: the first error is returned once
+    // they are all done
+    pub fn run_processes(&mut self) -> Result<(), ExecutionError> {
+        // Processes only run from the outermost scheduler
+        if !self.scheduler.current.is_null() {
+            return Ok(());
+        }
+
+        self.run_processes_until(|_| Ok(false)).map(|_| ())
+    }
+
+    // Runs processes, as run_processes does, until the condition (checked between
+    // time slices) holds or none is ready. Returns whether the condition held. Once
+    // a process fails the caller won't go on, so the others run to the end as they
+    // would after it
+    pub(super) fn run_processes_until(
+        &mut self,
+        mut condition: impl FnMut(&mut Self) -> Result<bool, ExecutionError>,
+    ) -> Result<bool, ExecutionError> {
+        let mut result = Ok(());
+        let held = loop {
+            if result.is_ok() && condition(self)? {
+                break true;
+            }
+            let Some(process) = self.dequeue(Queue::Ready)? else {
+                break false;
+            };
+
+            let slice = self.run_slice(process);
+            self.memory.decrement_ref(process)?;
+            if let Err(err) = slice && result.is_ok() {
+                result = Err(err);
+            }
+        };
+
+        result.map(|_| held)
+    }
+
+    // Blocks the running process until the semaphore is signalled
+    pub(super) fn block_active_process(&mut self, semaphore: ObjectPointer) -> Result<(), ExecutionError> {
+        let process = self.scheduler.current;
+        self.memory.get_mut::<Process>(process)?.set_state(ProcessState::Blocked)?;
+        self.set_semaphore(process, semaphore)?;
+        self.enqueue(Queue::Semaphore(semaphore), process)?;
+        self.scheduler.switch = true;
+
+        Ok(())
+    }
+
+    // Runs a process (owned by the caller) for up to a time slice. Uncaught errors
+    // terminate it
+    fn run_slice(&mut self, process: ObjectPointer) -> Result<(), ExecutionError> {
+        let context = {
+            let process = self.memory.get_mut::<Process>(process)?;
+            process.set_state(ProcessState::Active)?;
+            let context = process.interpreter();
+            process.set_interpreter(ObjectPointer::null());
+            context
+        };
+
+        // The reference to the context moves from the process to the machine
+        let saved = std::mem::replace(&mut self.active, context);
+        self.scheduler.current = process;
+        self.scheduler.switch = false;
+
+        // Whether the process is done, returning from its block or failing
+        let mut finished = Ok(false);
+        for _ in 0..TIME_SLICE {
+            match self.step() {
+                Ok(Some(value)) => {
+                    finished = self.memory.decrement_ref(value).map(|_| true).map_err(ExecutionError::from);
+                    break;
+                }
+                Ok(None) if self.scheduler.switch => break,
+                Ok(None) => {}
+                Err(err) => {
+                    finished = Err(err);
+                    break;
+                }
+            }
+        }
+
+        self.scheduler.current = ObjectPointer::null();
+        self.scheduler.switch = false;
+        let context = std::mem::replace(&mut self.active, saved);
+
+        let mut state = self.memory.get::<Process>(process)?.state();
+        if !matches!(finished, Ok(false)) && state != ProcessState::Terminated {
+            self.memory.get_mut::<Process>(process)?.set_state(ProcessState::Terminated)?;
+            state = ProcessState::Terminated;
+        }
+
+        match state {
+            ProcessState::Terminated => self.memory.decrement_ref(context)?,
+            ProcessState::Active => {
+                let process_object = self.memory.get_mut::<Process>(process)?;
+                process_object.set_interpreter(context);
+                process_object.set_state(ProcessState::Ready)?;
+                self.enqueue(Queue::Ready, process)?;
+            }
+            ProcessState::Ready => {
+                self.memory.get_mut::<Process>(process)?.set_interpreter(context);
+                self.enqueue(Queue::Ready, process)?;
+            }
+            _ => self.memory.get_mut::<Process>(process)?.set_interpreter(context),
+        }
+
+        finished.map(|_| ())
+    }
+
+    // Moves a process to a state that takes it out of the queue it waits in, or
+    // stops it if it's running
+    fn change_state(&mut self, process: ObjectPointer, state: ProcessState) -> Result<(), ExecutionError> {
+        let previous = self.memory.get::<Process>(process)?.state();
+        self.memory.get_mut::<Process>(process)?.set_state(state)?;
+
+        match previous {
+            ProcessState::Active => self.scheduler.switch = true,
+            ProcessState::Ready | ProcessState::Unblocked => self.unlink(Queue::Ready, process)?,
+            ProcessState::Blocked => {
+                let semaphore = self.memory.get::<Process>(process)?.semaphore().unwrap_or(ObjectPointer::null());
+                self.unlink(Queue::Semaphore(semaphore), process)?;
+                self.set_semaphore(process, ObjectPointer::null())?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Adds a process to the back of a queue
+    pub(super) fn enqueue(&mut self, queue: Queue, process: ObjectPointer) -> Result<(), ExecutionError> {
+        let (_, last) = self.queue_ends(queue)?;
+        if last.is_null() {
+            self.set_first(queue, process)?;
+        } else {
+            self.set_next(last, process)?;
+        }
+        self.set_prev(process, last)?;
+        self.set_last(queue, process)
+    }
+
+    // Takes the process at the front of a queue. The caller owns a reference to it
+    pub(super) fn dequeue(&mut self, queue: Queue) -> Result<Option<ObjectPointer>, ExecutionError> {
+        let (process, _) = self.queue_ends(queue)?;
+        if process.is_null() {
+            return Ok(None);
+        }
+
+        self.memory.increment_ref(process)?;
+        self.unlink(queue, process)?;
+
+        Ok(Some(process))
+    }
+
+    fn unlink(&mut self, queue: Queue, process: ObjectPointer) -> Result<(), ExecutionError> {
+        let (prev, next) = {
+            let process = self.memory.get::<Process>(process)?;
+            (process.prev().unwrap_or(ObjectPointer::null()), process.next().unwrap_or(ObjectPointer::null()))
+        };
+
+        if prev.is_null() {
+            self.set_first(queue, next)?;
+        } else {
+            self.set_next(prev, next)?;
+        }
+        if next.is_null() {
+            self.set_last(queue, prev)?;
+        } else {
+            self.set_prev(next, prev)?;
+        }
+
+        self.set_next(process, ObjectPointer::null())?;
+        self.set_prev(process, ObjectPointer::null())
+    }
+
+    pub(super) fn queued(&self, queue: Queue) -> Result<Vec<ObjectPointer>, ExecutionError> {
+        let mut processes = vec![];
+        let (mut process, _) = self.queue_ends(queue)?;
+        while !process.is_null() {
+            processes.push(process);
+            process = self.memory.get::<Process>(process)?.next().unwrap_or(ObjectPointer::null());
+        }
+
+        Ok(processes)
+    }
+
+    fn queue_ends(&self, queue: Queue) -> Result<(ObjectPointer, ObjectPointer), ExecutionError> {
+        match queue {
+            Queue::Ready => Ok((self.scheduler.first, self.scheduler.last)),
+            Queue::Semaphore(semaphore) => {
+                let semaphore = self.memory.get::<Semaphore>(semaphore)?;
+                let first = semaphore.first().unwrap_or(ObjectPointer::null());
+                Ok((first, semaphore.last().unwrap_or(ObjectPointer::null())))
+            }
+        }
+    }
+
+    fn set_first(&mut self, queue: Queue, process: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(process)?;
+        let previous = match queue {
+            Queue::Ready => std::mem::replace(&mut self.scheduler.first, process),
+            Queue::Semaphore(semaphore) => {
+                let semaphore = self.memory.get_mut::<Semaphore>(semaphore)?;
+                let previous = semaphore.first().unwrap_or(ObjectPointer::null());
+                semaphore.set_first(Some(process).filter(|process| !process.is_null()));
+                previous
+            }
+        };
+        Ok(self.memory.decrement_ref(previous)?)
+    }
+
+    fn set_last(&mut self, queue: Queue, process: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(process)?;
+        let previous = match queue {
+            Queue::Ready => std::mem::replace(&mut self.scheduler.last, process),
+            Queue::Semaphore(semaphore) => {
+                let semaphore = self.memory.get_mut::<Semaphore>(semaphore)?;
+                let previous = semaphore.last().unwrap_or(ObjectPointer::null());
+                semaphore.set_last(Some(process).filter(|process| !process.is_null()));
+                previous
+            }
+        };
+        Ok(self.memory.decrement_ref(previous)?)
+    }
+
+    fn set_next(&mut self, process: ObjectPointer, next: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(next)?;
+        let previous = self.memory.get::<Process>(process)?.next();
+        self.memory.get_mut::<Process>(process)?.set_next(Some(next).filter(|next| !next.is_null()));
+        Ok(self.memory.decrement_ref(previous.unwrap_or(ObjectPointer::null()))?)
+    }
+
+    fn set_prev(&mut self, process: ObjectPointer, prev: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(prev)?;
+        let previous = self.memory.get::<Process>(process)?.prev();
+        self.memory.get_mut::<Process>(process)?.set_prev(Some(prev).filter(|prev| !prev.is_null()));
+        Ok(self.memory.decrement_ref(previous.unwrap_or(ObjectPointer::null()))?)
+    }
+
+    pub(super) fn set_semaphore(&mut self, process: ObjectPointer, semaphore: ObjectPointer) -> Result<(), ExecutionError> {
+        self.memory.increment_ref(semaphore)?;
+        let previous = self.memory.get::<Process>(process)?.semaphore();
+        self.memory.get_mut::<Process>(process)?.set_semaphore(Some(semaphore).filter(|semaphore| !semaphore.is_null()));
+        Ok(self.memory.decrement_ref(previous.unwrap_or(ObjectPointer::null()))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LoadError;
+    use crate::memory::ObjectMemory;
+
+    const SOURCE: &str = "\
+Class Object
+[
+    == other
+        ^ <1 self other>
+]
+Class Class
+[
+    new
+        ^ <3 self>
+]
+Class Block
+[
+    fork
+        ^ <100 self>
+]
+Class Process
+[
+    suspend
+        ^ <101 self>
+|
+    resume
+        ^ <102 self>
+|
+    terminate
+        ^ <104 self>
+|
+    state
+        ^ <106 self>
+]
+Class Cell
+| value next |
+[
+    value: aValue next: aCell
+        value <- aValue.
+        next <- aCell
+]
+Class Test
+[
+    log: aSymbol
+        Log <- Cell new value: aSymbol next: Log
+|
+    interleave
+        [ self log: #a1. <103>. self log: #a2 ] fork.
+        [ self log: #b1. <103>. self log: #b2 ] fork.
+        self log: #main
+|
+    spin
+        [ <1 Done nil> ] whileTrue: [ ].
+        self log: #spun
+|
+    stop
+        self log: #stopped.
+        Done <- true
+|
+    preempt
+        [ self spin ] fork.
+        [ self stop ] fork
+|
+    suspend
+        P <- [ self log: #resumed ] fork.
+        P suspend.
+        [ self log: #other. P resume ] fork
+|
+    park
+        P <- [ self log: #later ] fork.
+        P suspend
+|
+    terminate
+        [ self log: #terminating. <105> terminate. self log: #unreachable ] fork
+|
+    resumeActive
+        P <- [ <105> resume. <103>. self log: #yielded ] fork
+|
+    resumeReady
+        P <- [ self log: #ready ] fork.
+        P resume
+|
+    fail
+        [ 3 frobnicate ] fork.
+        [ <103>. 4 twiddle ] fork.
+        [ self log: #before. <103>. self log: #after ] fork
+]
+";
+
+    fn evaluate_symbol(vm: &mut VirtualMachine, source: &str) -> Result<String, LoadError> {
+        let result = vm.evaluate_source("test.st", source)?;
+        Ok(vm.symbol_value(result)?.to_string())
+    }
+
+    // Symbols logged so far, oldest first
+    fn log(vm: &VirtualMachine) -> Result<Vec<String>, ExecutionError> {
+        let mut entries = vec![];
+        let mut cell = vm.global("Log").unwrap_or(ObjectPointer::null());
+        while !cell.is_null() {
+            entries.push(vm.symbol_value(vm.memory().inst_var(cell, 0)?)?.to_string());
+            cell = vm.memory().inst_var(cell, 1)?;
+        }
+        entries.reverse();
+
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_scheduler_round_robin() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        // Forked processes run once the statement is done, taking turns as they yield
+        vm.load_source("test.st", "Test new interleave")?;
+        assert_eq!(log(&vm)?, ["main", "a1", "b1", "a2", "b2"]);
+        assert!(vm.ready_processes()?.is_empty());
+        assert!(vm.active_process().is_null());
+
+        // The first process only stops spinning thanks to the second, which it
+        // would never let run without preemption
+        vm.load_source("test.st", "Log <- nil. Test new preempt")?;
+        assert_eq!(log(&vm)?, ["stopped", "spun"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduler_suspend_resume_terminate() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        vm.load_source("test.st", "Test new suspend")?;
+        assert_eq!(log(&vm)?, ["other", "resumed"]);
+        let process = vm.global("P").unwrap();
+        assert_eq!(vm.process_state(process)?, ProcessState::Terminated);
+        assert_eq!(evaluate_symbol(&mut vm, "P state")?, "terminated");
+
+        // Terminated processes can't be resumed
+        assert_eq!(vm.resume(process), Err(ExecutionError::InvalidTransition(InvalidTransition {
+            from: ProcessState::Terminated,
+            to: ProcessState::Ready,
+        })));
+        assert!(vm.load_source("test.st", "P resume").is_ok());
+
+        vm.load_source("test.st", "Log <- nil. Test new terminate")?;
+        assert_eq!(log(&vm)?, ["terminating"]);
+
+        // Suspended processes stay out of the queue, holding on to their contexts
+        vm.load_source("test.st", "Log <- nil. Test new park")?;
+        let process = vm.global("P").unwrap();
+        assert_eq!(vm.process_state(process)?, ProcessState::Suspended);
+        assert!(vm.ready_processes()?.is_empty());
+        vm.collect_garbage()?;
+        vm.resume(process)?;
+        assert_eq!(vm.ready_processes()?, [process]);
+        vm.run_processes()?;
+        assert_eq!(log(&vm)?, ["later"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduler_resume_only_suspended() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        // Resuming the running process fails, leaving it out of the queue until it yields
+        vm.load_source("test.st", "Test new resumeActive")?;
+        assert_eq!(log(&vm)?, ["yielded"]);
+        assert_eq!(vm.process_state(vm.global("P").unwrap())?, ProcessState::Terminated);
+        assert!(vm.ready_processes()?.is_empty());
+
+        // Ready processes are queued once
+        vm.load_source("test.st", "Log <- nil. Test new resumeReady")?;
+        assert_eq!(log(&vm)?, ["ready"]);
+        assert!(vm.ready_processes()?.is_empty());
+
+        vm.load_source("test.st", "Log <- nil. Test new park")?;
+        let process = vm.global("P").unwrap();
+        vm.resume(process)?;
+        assert_eq!(vm.resume(process), Err(ExecutionError::InvalidTransition(InvalidTransition {
+            from: ProcessState::Ready,
+            to: ProcessState::Ready,
+        })));
+        assert_eq!(vm.ready_processes()?, [process]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduler_uncaught_errors() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        let result = vm.load_source("test.st", "P <- [ 3 frobnicate ] fork");
+        assert_eq!(result, Err(LoadError::Execution(ExecutionError::MessageNotUnderstood("frobnicate".to_string()))));
+        let process = vm.global("P").unwrap();
+        assert_eq!(vm.process_state(process)?, ProcessState::Terminated);
+        assert!(vm.active_process().is_null());
+        assert!(vm.ready_processes()?.is_empty());
+
+        // The other processes run to the end, and the first error is reported
+        let result = vm.load_source("test.st", "Test new fail");
+        assert_eq!(result, Err(LoadError::Execution(ExecutionError::MessageNotUnderstood("frobnicate".to_string()))));
+        assert_eq!(log(&vm)?, ["before", "after"]);
+        assert!(vm.ready_processes()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scheduler_survives_compaction() -> Result<(), LoadError> {
+        // One object per block, so that every hole lets the objects after it move
+        let mut vm = VirtualMachine::with_memory(ObjectMemory::with_block_size(1))?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        // Garbage allocated before the process, and released once it's waiting
+        vm.load_source("test.st", "Test new park. Junk <- P. Log <- nil")?;
+        let junk: Vec<ObjectPointer> = (0..10)
+            .map(|i| vm.new_string(&format!("junk{}", i)))
+            .collect::<Result<_, _>>()?;
+        for &ptr in &junk {
+            vm.memory_mut().increment_ref(ptr)?;
+        }
+        vm.new_symbol("junk")?;
+        vm.load_source("test.st", "Test new park")?;
+        let process = vm.global("P").unwrap();
+        vm.resume(process)?;
+
+        vm.set_global("Junk", ObjectPointer::null())?;
+        for ptr in junk {
+            vm.memory_mut().decrement_ref(ptr)?;
+        }
+        vm.collect_garbage()?;
+        let blocks = vm.memory().block_count();
+
+        assert!(vm.compact()? > 0);
+        assert!(vm.memory().block_count() < blocks);
+        let moved = vm.global("P").unwrap();
+        assert_ne!(moved, process);
+        assert_eq!(vm.ready_processes()?, [moved]);
+
+        // Classes, methods, symbols and special objects are all found again
+        vm.run_processes()?;
+        assert_eq!(log(&vm)?, ["later"]);
+        assert_eq!(vm.process_state(moved)?, ProcessState::Terminated);
+        vm.load_source("test.st", "Log <- nil. Test new suspend")?;
+        assert_eq!(log(&vm)?, ["other", "resumed"]);
+        assert_eq!(vm.evaluate_source("test.st", "P == P")?, vm.true_object());
+        assert_eq!(vm.evaluate_source("test.st", "$a")?, vm.new_char('a')?);
+
+        Ok(())
+    }
+}
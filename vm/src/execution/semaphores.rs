@@ -0,0 +1,245 @@
+// Semaphores
+//
+// Waiting takes one of the signals counted by the semaphore, if there's any.
+// Otherwise the active process blocks, joining the processes waiting on the
+// semaphore. Signalling wakes them up in the order they waited: the first one
+// becomes Unblocked, and goes to the back of the ready queue.
+//
+// Code running outside of any process, like the statements being loaded, can't
+// block. While it waits, the ready processes run instead, until one of them
+// signals. As with run_processes, a process failing doesn't stop the others: they
+// all run to the end, and then the first error is returned.
+
+use crate::objects::{
+    object::{ObjectPointer, Pointer},
+    process::{Process, ProcessState},
+    semaphore::Semaphore,
+};
+use super::scheduler::Queue;
+use super::{ExecutionError, VirtualMachine};
+
+impl VirtualMachine {
+    pub fn new_semaphore(&mut self, signals: u32) -> Result<ObjectPointer, ExecutionError> {
+        Ok(self.memory.allocate(Semaphore::new(signals))?)
+    }
+
+    pub fn wait(&mut self, semaphore: ObjectPointer) -> Result<(), ExecutionError> {
+        if self.take_signal(semaphore)? {
+            return Ok(());
+        } else if !self.active_process().is_null() {
+            return self.block_active_process(semaphore);
+        }
+
+        self.memory.increment_ref(semaphore)?;
+        let result = self.run_processes_until(|vm| vm.take_signal(semaphore));
+        self.memory.decrement_ref(semaphore)?;
+
+        match result? {
+            true => Ok(()),
+            false => Err(ExecutionError::Deadlock),
+        }
+    }
+
+    pub fn signal(&mut self, semaphore: ObjectPointer) -> Result<(), ExecutionError> {
+        let Some(process) = self.dequeue(Queue::Semaphore(semaphore))? else {
+            let semaphore = self.memory.get_mut::<Semaphore>(semaphore)?;
+            semaphore.set_signals(semaphore.signals().saturating_add(1));
+            return Ok(());
+        };
+
+        self.set_semaphore(process, ObjectPointer::null())?;
+        self.memory.get_mut::<Process>(process)?.set_state(ProcessState::Unblocked)?;
+        self.enqueue(Queue::Ready, process)?;
+        self.memory.decrement_ref(process)?;
+
+        Ok(())
+    }
+
+    // Processes blocked on a semaphore, in the order they'll be woken up
+    pub fn waiting_processes(&self, semaphore: ObjectPointer) -> Result<Vec<ObjectPointer>, ExecutionError> {
+        self.queued(Queue::Semaphore(semaphore))
+    }
+
+    fn take_signal(&mut self, semaphore: ObjectPointer) -> Result<bool, ExecutionError> {
+        let semaphore = self.memory.get_mut::<Semaphore>(semaphore)?;
+        if semaphore.signals() == 0 {
+            return Ok(false);
+        }
+
+        semaphore.set_signals(semaphore.signals() - 1);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::LoadError;
+
+    const SOURCE: &str = "\
+Class Object
+[
+    == other
+        ^ <1 self other>
+]
+Class Class
+[
+    new
+        ^ <3 self>
+]
+Class Block
+[
+    fork
+        ^ <100 self>
+]
+Class Process
+[
+    terminate
+        ^ <104 self>
+]
+Class Semaphore
+[
+    wait
+        ^ <108 self>
+|
+    signal
+        ^ <109 self>
+]
+Class Cell
+| value next |
+[
+    value: aValue next: aCell
+        value <- aValue.
+        next <- aCell
+]
+Class Test
+[
+    log: aSymbol
+        Log <- Cell new value: aSymbol next: Log
+|
+    produce: aSymbol
+        Empty wait.
+        Slot <- aSymbol.
+        self log: aSymbol.
+        Full signal
+|
+    consume
+        Full wait.
+        self log: #got.
+        Taken <- Cell new value: Slot next: Taken.
+        Empty signal
+|
+    pipeline
+        Empty <- <107 1>.
+        Full <- <107>.
+        [ self consume. self consume. self consume ] fork.
+        [ self produce: #a. self produce: #b. self produce: #c ] fork
+|
+    queue
+        S <- <107>.
+        [ S wait. self log: #one ] fork.
+        [ S wait. self log: #two ] fork.
+        [ S wait. self log: #three ] fork
+|
+    join
+        Done <- <107>.
+        [ self log: #worker. Done signal ] fork.
+        Done wait.
+        self log: #main
+|
+    joinFailing
+        Done <- <107>.
+        [ 3 frobnicate ] fork.
+        [ self log: #worker. Done signal ] fork.
+        [ self log: #later ] fork.
+        Done wait.
+        self log: #main
+]
+";
+
+    // Symbols in a list of cells, oldest first
+    fn symbols(vm: &VirtualMachine, global: &str) -> Result<Vec<String>, ExecutionError> {
+        let mut entries = vec![];
+        let mut cell = vm.global(global).unwrap_or(ObjectPointer::null());
+        while !cell.is_null() {
+            entries.push(vm.symbol_value(vm.memory().inst_var(cell, 0)?)?.to_string());
+            cell = vm.memory().inst_var(cell, 1)?;
+        }
+        entries.reverse();
+
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_semaphores_producer_consumer() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        vm.load_source("test.st", "Test new pipeline")?;
+        assert_eq!(symbols(&vm, "Taken")?, ["a", "b", "c"]);
+        assert_eq!(symbols(&vm, "Log")?, ["a", "got", "b", "got", "c", "got"]);
+        assert_eq!(vm.memory().get::<Semaphore>(vm.global("Empty").unwrap())?.signals(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_semaphores_wake_up_in_order() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        vm.load_source("test.st", "Test new queue")?;
+        let semaphore = vm.global("S").unwrap();
+        let waiting = vm.waiting_processes(semaphore)?;
+        assert_eq!(waiting.len(), 3);
+        for &process in &waiting {
+            assert_eq!(vm.process_state(process)?, ProcessState::Blocked);
+        }
+
+        vm.signal(semaphore)?;
+        assert_eq!(vm.process_state(waiting[0])?, ProcessState::Unblocked);
+        assert_eq!(vm.ready_processes()?, [waiting[0]]);
+        vm.run_processes()?;
+        assert_eq!(symbols(&vm, "Log")?, ["one"]);
+
+        // Terminating a blocked process takes it out of the queue
+        vm.terminate(waiting[1])?;
+        assert_eq!(vm.waiting_processes(semaphore)?, [waiting[2]]);
+
+        // Images keep the processes waiting
+        let mut bytes = vec![];
+        vm.write_image(&mut bytes).unwrap();
+        let mut vm = VirtualMachine::read_image(bytes.as_slice()).unwrap();
+        assert_eq!(vm.waiting_processes(semaphore)?, [waiting[2]]);
+
+        vm.load_source("test.st", "S signal. S signal")?;
+        assert_eq!(symbols(&vm, "Log")?, ["one", "three"]);
+        assert!(vm.waiting_processes(semaphore)?.is_empty());
+        assert_eq!(vm.memory().get::<Semaphore>(semaphore)?.signals(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_semaphores_outside_processes() -> Result<(), LoadError> {
+        let mut vm = VirtualMachine::new()?;
+        vm.load_source("kernel.st", SOURCE)?;
+
+        // The ready processes run while the statement waits
+        vm.load_source("test.st", "Test new join")?;
+        assert_eq!(symbols(&vm, "Log")?, ["worker", "main"]);
+
+        // A process failing doesn't keep the others from running, and its error is
+        // reported once they are done
+        let result = vm.load_source("test.st", "Log <- nil. Test new joinFailing");
+        assert_eq!(result, Err(LoadError::Execution(ExecutionError::MessageNotUnderstood("frobnicate".to_string()))));
+        assert_eq!(symbols(&vm, "Log")?, ["worker", "later"]);
+        assert!(vm.ready_processes()?.is_empty());
+        assert_eq!(vm.memory().get::<Semaphore>(vm.global("Done").unwrap())?.signals(), 1);
+
+        vm.load_source("test.st", "X <- <107>")?;
+        assert_eq!(vm.load_source("test.st", "X wait"), Err(LoadError::Execution(ExecutionError::Deadlock)));
+
+        Ok(())
+    }
+}
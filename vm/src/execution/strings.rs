@@ -0,0 +1,269 @@
+// String operations
+//
+// Strings are indexed by character, starting at 1, whatever the length of their
+// characters in UTF-8 (see objects::string). Operations that make a string answer
+// a new one, except at:put:, which changes the receiver.
+//
+// Strings and ByteArrays convert to each other with an encoding, named by a symbol:
+// #utf8, #latin1 or #ascii. Conversions fail for text the encoding can't represent
+// and bytes that aren't valid in it.
+
+use crate::objects::{
+    byte::ByteArray,
+    object::{ObjectPointer, Pointer},
+    number::Float,
+    string::{Encoding, StringObject},
+};
+use super::{ExecutionError, Number, VirtualMachine};
+use super::primitives::{
+    STRING_AT, STRING_AT_PUT, STRING_SIZE, STRING_CONCAT, STRING_LESS, STRING_EQUAL,
+    STRING_INDEX_OF, STRING_COPY, AS_SYMBOL, AS_NUMBER, STRING_REVERSED, STRING_AS_UPPERCASE,
+    STRING_ENCODE, STRING_DECODE,
+};
+
+impl VirtualMachine {
+    // Applies one of the string primitives, the receiver being the first argument.
+    // None when the arguments aren't the ones the primitive takes
+    pub(super) fn string_operation(&mut self, number: u8, args: &[ObjectPointer])
+        -> Result<Option<ObjectPointer>, ExecutionError>
+    {
+        if let (STRING_DECODE, &[bytes, encoding]) = (number, args) {
+            let value = self.memory.get::<ByteArray>(bytes).ok()
+                .zip(self.encoding(encoding))
+                .and_then(|(bytes, encoding)| encoding.decode(bytes.value()));
+            return value.map(|value| self.new_string(&value)).transpose();
+        }
+
+        let Some((&receiver, args)) = args.split_first() else {
+            return Ok(None);
+        };
+        let Ok(string) = self.memory.get::<StringObject>(receiver) else {
+            return Ok(None);
+        };
+
+        let value = match (number, args) {
+            (STRING_AT, &[index]) => match one_based(index).and_then(|index| string.at(index)) {
+                Some(value) => self.new_char(value)?,
+                None => return Ok(None),
+            },
+            (STRING_AT_PUT, &[index, value]) => {
+                let (Some(index), Some(character)) = (one_based(index), self.char_value(value).and_then(char::from_u32)) else {
+                    return Ok(None);
+                };
+                match self.memory.get_mut::<StringObject>(receiver)?.at_put(index, character) {
+                    Some(_) => value,
+                    None => return Ok(None),
+                }
+            }
+            (STRING_SIZE, &[]) => ObjectPointer::from_small_integer(string.size() as i32),
+            // Symbols can be appended too
+            (STRING_CONCAT, &[other]) => {
+                let Ok(other) = self.string_value(other).or_else(|_| self.symbol_value(other)) else {
+                    return Ok(None);
+                };
+                let value = format!("{}{}", string.value(), other);
+                self.new_string(&value)?
+            }
+            (STRING_LESS, &[other]) => match self.string_value(other) {
+                Ok(other) => self.boolean(string.value() < other),
+                Err(_) => return Ok(None),
+            },
+            // Different from anything that isn't a string
+            (STRING_EQUAL, &[other]) => self.boolean(self.string_value(other).is_ok_and(|other| other == string.value())),
+            // 0 when the character isn't there
+            (STRING_INDEX_OF, &[value]) => {
+                let Some(character) = self.char_value(value).and_then(char::from_u32) else {
+                    return Ok(None);
+                };
+                let index = string.value().chars().position(|c| c == character).map_or(0, |index| index + 1);
+                ObjectPointer::from_small_integer(index as i32)
+            }
+            // Both ends included. Ending right before the start gives an empty string
+            (STRING_COPY, &[from, to]) => {
+                let (Some(from), Some(to)) = (one_based(from), to.small_integer()) else {
+                    return Ok(None);
+                };
+                let Some(value) = usize::try_from(to).ok().and_then(|to| string.slice(from, to)) else {
+                    return Ok(None);
+                };
+                let value = value.to_string();
+                self.new_string(&value)?
+            }
+            (AS_SYMBOL, &[]) => {
+                let value = string.value().to_string();
+                self.new_symbol(&value)?
+            }
+            (AS_NUMBER, &[]) => match parse_number(string.value()) {
+                Some(value) => self.new_number(value)?,
+                None => return Ok(None),
+            },
+            (STRING_REVERSED, &[]) => {
+                let value: String = string.value().chars().rev().collect();
+                self.new_string(&value)?
+            }
+            (STRING_AS_UPPERCASE, &[]) => {
+                let value = string.value().to_uppercase();
+                self.new_string(&value)?
+            }
+            (STRING_ENCODE, &[encoding]) => match self.encoding(encoding).and_then(|encoding| encoding.encode(string.value())) {
+                Some(bytes) => self.memory.allocate(ByteArray::new(bytes))?,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+
+        Ok(Some(value))
+    }
+
+    fn encoding(&self, name: ObjectPointer) -> Option<Encoding> {
+        self.symbol_value(name).ok().and_then(Encoding::from_name)
+    }
+}
+
+// Zero based index, from a one based SmallInteger
+fn one_based(index: ObjectPointer) -> Option<usize> {
+    usize::try_from(index.small_integer()?).ok()?.checked_sub(1)
+}
+
+// Integers of any size and floats, written like literals
+fn parse_number(text: &str) -> Option<Number> {
+    let text = text.trim();
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        return text.parse().ok().map(Number::Integer);
+    }
+
+    Float::parse(text).map(Number::Float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::primitives::Outcome;
+
+    fn apply(vm: &mut VirtualMachine, number: u8, args: &[ObjectPointer]) -> Result<Option<String>, ExecutionError> {
+        Ok(match vm.primitive(number, args)? {
+            Outcome::Value(ptr) => vm.string_value(ptr).ok().map(str::to_string),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_strings_operations() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let hello = vm.new_string("hello")?;
+        vm.memory_mut().increment_ref(hello)?;
+        let world = vm.new_symbol(" world")?;
+        let int = ObjectPointer::from_small_integer;
+        let (yes, no) = (Outcome::Value(vm.true_object()), Outcome::Value(vm.false_object()));
+
+        assert_eq!(vm.primitive(STRING_SIZE, &[hello])?, Outcome::Value(int(5)));
+        assert_eq!(vm.primitive(STRING_AT, &[hello, int(2)])?, Outcome::Value(vm.new_char('e')?));
+        assert_eq!(vm.primitive(STRING_AT, &[hello, int(0)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(STRING_AT, &[hello, int(6)])?, Outcome::Failed);
+        assert_eq!(apply(&mut vm, STRING_CONCAT, &[hello, world])?.as_deref(), Some("hello world"));
+        assert_eq!(apply(&mut vm, STRING_COPY, &[hello, int(2), int(4)])?.as_deref(), Some("ell"));
+        assert_eq!(apply(&mut vm, STRING_COPY, &[hello, int(3), int(2)])?.as_deref(), Some(""));
+        assert_eq!(vm.primitive(STRING_COPY, &[hello, int(3), int(6)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(STRING_COPY, &[hello, int(3), int(1)])?, Outcome::Failed);
+        assert_eq!(apply(&mut vm, STRING_REVERSED, &[hello])?.as_deref(), Some("olleh"));
+        assert_eq!(apply(&mut vm, STRING_AS_UPPERCASE, &[hello])?.as_deref(), Some("HELLO"));
+        let l = vm.new_char('l')?;
+        assert_eq!(vm.primitive(STRING_INDEX_OF, &[hello, l])?, Outcome::Value(int(3)));
+        let z = vm.new_char('z')?;
+        assert_eq!(vm.primitive(STRING_INDEX_OF, &[hello, z])?, Outcome::Value(int(0)));
+
+        let help = vm.new_string("help")?;
+        let same = vm.new_string("hello")?;
+        assert_eq!(vm.primitive(STRING_LESS, &[hello, help])?, yes);
+        assert_eq!(vm.primitive(STRING_LESS, &[help, hello])?, no);
+        assert_eq!(vm.primitive(STRING_EQUAL, &[hello, same])?, yes);
+        assert_eq!(vm.primitive(STRING_EQUAL, &[hello, world])?, no);
+        assert_eq!(vm.primitive(STRING_LESS, &[hello, int(1)])?, Outcome::Failed);
+        assert_eq!(vm.primitive(STRING_SIZE, &[world])?, Outcome::Failed);
+
+        // Symbols are unique
+        assert_eq!(vm.primitive(AS_SYMBOL, &[hello])?, Outcome::Value(vm.new_symbol("hello")?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strings_unicode() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let string = vm.new_string("añb")?;
+        vm.memory_mut().increment_ref(string)?;
+        let int = ObjectPointer::from_small_integer;
+
+        assert_eq!(vm.primitive(STRING_SIZE, &[string])?, Outcome::Value(int(3)));
+        assert_eq!(vm.primitive(STRING_AT, &[string, int(2)])?, Outcome::Value(vm.new_char('ñ')?));
+
+        // Characters of different lengths in UTF-8 replace each other
+        let lambda = vm.new_char('λ')?;
+        assert_eq!(vm.primitive(STRING_AT_PUT, &[string, int(1), lambda])?, Outcome::Value(lambda));
+        let x = vm.new_char('x')?;
+        vm.primitive(STRING_AT_PUT, &[string, int(2), x])?;
+        let crab = vm.new_char('🦀')?;
+        vm.primitive(STRING_AT_PUT, &[string, int(3), crab])?;
+        assert_eq!(vm.string_value(string)?, "λx🦀");
+        assert_eq!(vm.primitive(STRING_AT_PUT, &[string, int(4), x])?, Outcome::Failed);
+        assert_eq!(vm.primitive(STRING_AT_PUT, &[string, int(1), int(1)])?, Outcome::Failed);
+
+        assert_eq!(apply(&mut vm, STRING_REVERSED, &[string])?.as_deref(), Some("🦀xλ"));
+        assert_eq!(apply(&mut vm, STRING_AS_UPPERCASE, &[string])?.as_deref(), Some("ΛX🦀"));
+        let sharp_s = vm.new_string("straße")?;
+        assert_eq!(apply(&mut vm, STRING_AS_UPPERCASE, &[sharp_s])?.as_deref(), Some("STRASSE"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strings_encodings() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let string = vm.new_string("añ€")?;
+        vm.memory_mut().increment_ref(string)?;
+        let (utf8, latin1, ebcdic) = (vm.new_symbol("utf8")?, vm.new_symbol("latin1")?, vm.new_symbol("ebcdic")?);
+
+        let Outcome::Value(bytes) = vm.primitive(STRING_ENCODE, &[string, utf8])? else {
+            panic!("utf8 encodes everything");
+        };
+        assert_eq!(vm.memory().get::<ByteArray>(bytes)?.value(), "añ€".as_bytes());
+        assert_eq!(apply(&mut vm, STRING_DECODE, &[bytes, utf8])?.as_deref(), Some("añ€"));
+        assert_eq!(apply(&mut vm, STRING_DECODE, &[bytes, latin1])?.as_deref(), Some("aÃ±â\u{82}¬"));
+        assert_eq!(vm.primitive(STRING_ENCODE, &[string, latin1])?, Outcome::Failed);
+        assert_eq!(vm.primitive(STRING_ENCODE, &[string, ebcdic])?, Outcome::Failed);
+
+        let invalid = vm.memory_mut().allocate(ByteArray::new(vec![b'a', 0xff]))?;
+        assert_eq!(vm.primitive(STRING_DECODE, &[invalid, utf8])?, Outcome::Failed);
+        assert_eq!(apply(&mut vm, STRING_DECODE, &[invalid, latin1])?.as_deref(), Some("aÿ"));
+        assert_eq!(vm.primitive(STRING_DECODE, &[string, utf8])?, Outcome::Failed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strings_as_number() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let mut as_number = |text: &str| -> Result<Option<Number>, ExecutionError> {
+            let string = vm.new_string(text)?;
+            Ok(match vm.primitive(AS_NUMBER, &[string])? {
+                Outcome::Value(ptr) => vm.number_value(ptr),
+                _ => None,
+            })
+        };
+
+        assert_eq!(as_number("42")?, Some(Number::Integer(42.into())));
+        assert_eq!(as_number(" -7 ")?, Some(Number::Integer((-7).into())));
+        assert_eq!(as_number("123456789012345678901234567890")?,
+                   Some(Number::Integer("123456789012345678901234567890".parse().unwrap())));
+        assert_eq!(as_number("2.5e-3")?, Some(Number::Float(2.5e-3)));
+        assert_eq!(as_number("abc")?, None);
+        assert_eq!(as_number("")?, None);
+        assert_eq!(as_number("-")?, None);
+        assert_eq!(as_number("1e5e")?, None);
+        assert_eq!(as_number("inf")?, None);
+        assert_eq!(as_number("nan")?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+// Symbol table
+//
+// Symbols are interned: the machine keeps a table from their values to the one
+// Symbol object with each value, so that selectors and other symbols can be
+// compared by pointer.
+//
+// The table holds a reference to every symbol in it, so reference counting alone
+// never reclaims them. It's not a root for the collector, though: symbols that are
+// only referenced by the table are reclaimed by collections, and dropped from it.
+
+use crate::objects::{
+    object::{ObjectPointer, ObjectType},
+    symbol::Symbol,
+};
+use super::{ExecutionError, VirtualMachine};
+
+impl VirtualMachine {
+    // The Symbol with the given value, created the first time it's asked for
+    pub fn new_symbol(&mut self, value: &str) -> Result<ObjectPointer, ExecutionError> {
+        if let Some(symbol) = self.find_symbol(value) {
+            return Ok(symbol);
+        }
+
+        let symbol = self.memory.allocate(Symbol::new(value.to_string()))?;
+        self.memory.increment_ref(symbol)?;
+        self.symbols.insert(value.to_string(), symbol);
+
+        Ok(symbol)
+    }
+
+    // Existing Symbol with the given value
+    pub fn find_symbol(&self, value: &str) -> Option<ObjectPointer> {
+        self.symbols.get(value).copied()
+    }
+
+    // Adds the symbols already in memory to the table. Duplicates can't be merged
+    // this way, so the first one found is kept
+    pub(super) fn intern_symbols(&mut self) -> Result<(), ExecutionError> {
+        for ptr in self.memory.live_objects() {
+            if self.memory.object_type(ptr)? != ObjectType::Symbol {
+                continue;
+            }
+
+            let value = self.symbol_value(ptr)?.to_string();
+            if !self.symbols.contains_key(&value) {
+                self.memory.increment_ref(ptr)?;
+                self.symbols.insert(value, ptr);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Drops the symbols reclaimed by a collection
+    pub(super) fn forget_reclaimed_symbols(&mut self) {
+        let memory = &self.memory;
+        self.symbols.retain(|_, &mut symbol| memory.object(symbol).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::ObjectMemory;
+
+    #[test]
+    fn test_symbols_are_interned() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let foo = vm.new_symbol("foo")?;
+
+        assert_eq!(vm.new_symbol("foo")?, foo);
+        assert_ne!(vm.new_symbol("bar")?, foo);
+        assert_eq!(vm.find_symbol("foo"), Some(foo));
+        assert_eq!(vm.find_symbol("baz"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbols_collected_when_unreferenced() -> Result<(), ExecutionError> {
+        let mut vm = VirtualMachine::new()?;
+        let kept = vm.new_symbol("kept")?;
+        vm.set_global("Kept", kept)?;
+        vm.new_symbol("dropped")?;
+
+        vm.collect_garbage()?;
+        assert_eq!(vm.find_symbol("kept"), Some(kept));
+        assert_eq!(vm.find_symbol("dropped"), None);
+        let dropped = vm.new_symbol("dropped")?;
+        assert_eq!(vm.symbol_value(dropped)?, "dropped");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbols_interned_from_memory() -> Result<(), ExecutionError> {
+        let mut memory = ObjectMemory::new();
+        let foo = memory.allocate(Symbol::new("foo".to_string()))?;
+        memory.increment_ref(foo)?;
+
+        let mut vm = VirtualMachine::with_memory(memory)?;
+        assert_eq!(vm.new_symbol("foo")?, foo);
+
+        Ok(())
+    }
+}
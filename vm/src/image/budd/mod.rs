@@ -0,0 +1,170 @@
+// Images of the C implementation of Little Smalltalk (versions 1 and 3)
+//
+// Both versions save a dump of their object table, one record per object, but lay
+// it out differently (see v1 and v3). Objects are converted to the model of this VM
+// the same way for both:
+//
+//   * classes become Class objects, set as globals with their name
+//   * objects of the built-in types become objects of the matching type. Version 1
+//     tells them apart by their size codes, the same ones as in objects::object,
+//     and version 3 by the name of their class
+//   * instances of UndefinedObject, True and False become nil, true and false
+//   * everything else becomes an ordinary object of the converted class
+//
+// Compiled methods can't be reused, as the bytecodes are different. They are
+// compiled again from their source text when the image keeps it, and the ones that
+// can't be are reported to the caller.
+
+mod v1;
+mod v3;
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use crate::execution::VirtualMachine;
+use crate::objects::{
+    byte::ByteArray,
+    number::Float,
+    object::{ObjectPointer, ObjectType, Pointer},
+};
+use super::ImageError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuddVersion {
+    V1,
+    V3,
+}
+
+// Sizes of the fields in the records, which depend on how the interpreter that
+// wrote the image was built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuddLayout {
+    pub version: BuddVersion,
+    // Ints: the object index at the start of each record, and in version 1 images
+    // the sizes and the other int fields
+    pub int_bytes: usize,
+    // Pointers (object), including the class of each record
+    pub pointer_bytes: usize,
+    // Padding after the size (a short) in version 3 images, to align the record
+    pub padding_bytes: usize,
+}
+
+impl BuddLayout {
+    // Ints and pointers of 32 bits
+    pub const V1: BuddLayout = BuddLayout { version: BuddVersion::V1, int_bytes: 4, pointer_bytes: 4, padding_bytes: 0 };
+    // Objects are shorts, as in the standard distribution
+    pub const V3: BuddLayout = BuddLayout { version: BuddVersion::V3, int_bytes: 4, pointer_bytes: 2, padding_bytes: 0 };
+    // Objects are ints
+    pub const V3_WIDE: BuddLayout = BuddLayout { version: BuddVersion::V3, int_bytes: 4, pointer_bytes: 4, padding_bytes: 2 };
+}
+
+impl VirtualMachine {
+    pub fn load_budd_image(path: impl AsRef<Path>, layout: BuddLayout) -> Result<(Self, Vec<String>), ImageError> {
+        Self::read_budd_image(fs::File::open(path)?, layout)
+    }
+
+    // Builds a machine with the contents of the image. Along with it, returns a
+    // description of the methods that couldn't be compiled
+    pub fn read_budd_image<R: Read>(mut reader: R, layout: BuddLayout) -> Result<(Self, Vec<String>), ImageError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+
+        match layout.version {
+            BuddVersion::V1 => v1::convert(&bytes, layout),
+            BuddVersion::V3 => v3::convert(&bytes, layout),
+        }
+    }
+}
+
+// A pointer in the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Nil,
+    Integer(i32),
+    Index(usize),
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Cursor { bytes, position: 0 }
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ImageError> {
+        let bytes = self.bytes.get(self.position..self.position + len)
+            .ok_or_else(|| ImageError::Corrupt("unexpected end of image".to_string()))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), ImageError> {
+        self.bytes(len).map(|_| ())
+    }
+
+    // Little endian
+    fn unsigned(&mut self, len: usize) -> Result<u64, ImageError> {
+        Ok(self.bytes(len)?
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    fn signed(&mut self, len: usize) -> Result<i64, ImageError> {
+        let shift = 64 - 8 * len as u32;
+        Ok(((self.unsigned(len)? << shift) as i64) >> shift)
+    }
+
+    // Bytes of a byte object, stored rounded up to a whole number of pointers
+    fn padded(&mut self, len: usize, pointer_bytes: usize) -> Result<&'a [u8], ImageError> {
+        let bytes = self.bytes(len.div_ceil(pointer_bytes) * pointer_bytes)?;
+        Ok(&bytes[..len])
+    }
+}
+
+// Contents of a byte object holding text. C strings keep their terminator
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// nil, true and false, which are instances of these classes in the image
+fn special_object(vm: &VirtualMachine, class_name: &str) -> Option<ObjectPointer> {
+    match class_name {
+        "UndefinedObject" => Some(ObjectPointer::null()),
+        "True" => Some(vm.true_object()),
+        "False" => Some(vm.false_object()),
+        _ => None,
+    }
+}
+
+// Object of one of the built-in types holding bytes. Floats are C doubles
+fn new_byte_object(vm: &mut VirtualMachine, object_type: ObjectType, index: usize, bytes: &[u8]) -> Result<ObjectPointer, ImageError> {
+    let object = match object_type {
+        ObjectType::Symbol => vm.new_symbol(&text(bytes))?,
+        ObjectType::String => vm.new_string(&text(bytes))?,
+        ObjectType::Float => match <[u8; 8]>::try_from(bytes) {
+            Ok(bytes) => vm.memory_mut().allocate(Float::new(f64::from_le_bytes(bytes)))?,
+            Err(_) => return Err(ImageError::Corrupt(format!("invalid float {}", index))),
+        },
+        _ => vm.memory_mut().allocate(ByteArray::new(bytes.to_vec()))?,
+    };
+
+    Ok(object)
+}
+
+fn new_char(vm: &mut VirtualMachine, index: usize, value: i64) -> Result<ObjectPointer, ImageError> {
+    let value = u32::try_from(value).ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| ImageError::Corrupt(format!("invalid char {}", index)))?;
+
+    Ok(vm.new_char(value)?)
+}
@@ -0,0 +1,447 @@
+// Images of version 1
+//
+// The objects of version 1 are C structs whose size field tells the built-in types
+// apart: ordinary objects store their number of instance variables there, and the
+// others one of the size codes (BLOCKSIZE, FLOATSIZE, SYMBOLSIZE... the constants in
+// objects::object). The image is written field by field, following those structs
+// without their reference counts, with object pointers replaced by indices in the
+// object table (0 being nil). It starts with the global variables: their number (an
+// int), then the name (a symbol) and value of each. Then comes one record per
+// object:
+//
+//   * its index and its size (ints)
+//   * for ordinary objects: the class, the super object holding the inherited
+//     instance variables, and the instance variables
+//   * for classes: name, superclass (the class or its name), file name, instance
+//     variable names (an array), context size (an int), message names (an array),
+//     methods and stack max (an int)
+//   * for symbols, strings and byte arrays: their length (an int), then their bytes,
+//     rounded up to a whole number of pointers
+//   * for chars and integers: their value (an int). For floats: a C double
+//
+// Objects of this VM have the same layout, super objects included, so they are
+// converted one to one. Integers are objects too, and become small integers.
+//
+// Blocks, contexts, processes and files hold the state of a running interpreter
+// (bytecodes, stacks, open files), which can't be carried over: images with any
+// of them are rejected. Methods don't keep their source text, so none of them can
+// be compiled again. They are all reported, along with the file that defined their
+// class, to be loaded from there.
+
+use std::collections::HashMap;
+
+use num_bigint::BigInt;
+
+use crate::execution::VirtualMachine;
+use crate::objects::object::{Object, ObjectPointer, ObjectSize, ObjectType, Pointer};
+use super::{new_byte_object, new_char, special_object, text, BuddLayout, Cursor, Value};
+use crate::image::ImageError;
+
+#[derive(Debug)]
+enum Record {
+    Object {
+        class: Value,
+        super_obj: Value,
+        inst_vars: Vec<Value>,
+    },
+    Class {
+        name: Value,
+        super_class: Value,
+        file_name: Value,
+        inst_vars: Value,
+        message_names: Value,
+    },
+    // Symbols, strings, byte arrays and floats
+    Bytes(ObjectType, Vec<u8>),
+    // Chars and integers
+    Int(ObjectType, i64),
+}
+
+struct Image {
+    // Names and values of the global variables
+    globals: Vec<(Value, Value)>,
+    records: HashMap<usize, Record>,
+}
+
+pub(super) fn convert(bytes: &[u8], layout: BuddLayout) -> Result<(VirtualMachine, Vec<String>), ImageError> {
+    let image = parse(bytes, layout)?;
+    let mut conversion = Conversion {
+        records: &image.records,
+        vm: VirtualMachine::new()?,
+        objects: HashMap::new(),
+        skipped: vec![],
+    };
+    conversion.run(&image.globals)?;
+
+    Ok((conversion.vm, conversion.skipped))
+}
+
+fn parse(bytes: &[u8], layout: BuddLayout) -> Result<Image, ImageError> {
+    let mut cursor = Cursor::new(bytes);
+    let pointer = |cursor: &mut Cursor| -> Result<Value, ImageError> {
+        Ok(match cursor.unsigned(layout.pointer_bytes)? {
+            0 => Value::Nil,
+            index => Value::Index(index as usize),
+        })
+    };
+
+    let count = cursor.unsigned(layout.int_bytes)?;
+    let globals = (0..count)
+        .map(|_| Ok((pointer(&mut cursor)?, pointer(&mut cursor)?)))
+        .collect::<Result<Vec<_>, ImageError>>()?;
+
+    let mut records = HashMap::new();
+    while !cursor.at_end() {
+        let index = cursor.unsigned(layout.int_bytes)? as usize;
+        let size = cursor.signed(layout.int_bytes)?;
+        let object_type = ObjectSize::try_from(size).ok().and_then(ObjectType::from_size);
+
+        let record = match object_type {
+            Some(ObjectType::Object) => Record::Object {
+                class: pointer(&mut cursor)?,
+                super_obj: pointer(&mut cursor)?,
+                inst_vars: (0..size).map(|_| pointer(&mut cursor)).collect::<Result<_, _>>()?,
+            },
+            Some(ObjectType::Class) => {
+                let name = pointer(&mut cursor)?;
+                let super_class = pointer(&mut cursor)?;
+                let file_name = pointer(&mut cursor)?;
+                let inst_vars = pointer(&mut cursor)?;
+                cursor.skip(layout.int_bytes)?;
+                let message_names = pointer(&mut cursor)?;
+                // The methods and the stack size are those of the old bytecodes
+                cursor.skip(layout.pointer_bytes + layout.int_bytes)?;
+                Record::Class { name, super_class, file_name, inst_vars, message_names }
+            }
+            Some(object_type @ (ObjectType::Symbol | ObjectType::String | ObjectType::ByteArray)) => {
+                let len = cursor.unsigned(layout.int_bytes)? as usize;
+                Record::Bytes(object_type, cursor.padded(len, layout.pointer_bytes)?.to_vec())
+            }
+            Some(ObjectType::Float) => Record::Bytes(ObjectType::Float, cursor.bytes(8)?.to_vec()),
+            Some(object_type @ (ObjectType::Char | ObjectType::Integer)) => Record::Int(object_type, cursor.signed(layout.int_bytes)?),
+            Some(object_type @ (ObjectType::Block | ObjectType::Interpreter | ObjectType::Process | ObjectType::File)) => {
+                return Err(ImageError::Unsupported(format!("object {} is a {:?}, which belongs to a running interpreter", index, object_type)));
+            }
+            // Types that version 1 doesn't have
+            _ => return Err(ImageError::Corrupt(format!("invalid size {} of object {}", size, index))),
+        };
+
+        if records.insert(index, record).is_some() {
+            return Err(ImageError::Corrupt(format!("object {} appears twice", index)));
+        }
+    }
+
+    Ok(Image { globals, records })
+}
+
+struct Conversion<'a> {
+    records: &'a HashMap<usize, Record>,
+    vm: VirtualMachine,
+    // Converted objects, classes included, by index in the image
+    objects: HashMap<usize, ObjectPointer>,
+    skipped: Vec<String>,
+}
+
+impl<'a> Conversion<'a> {
+    fn run(&mut self, globals: &[(Value, Value)]) -> Result<(), ImageError> {
+        let mut indices: Vec<usize> = self.records.keys().copied().collect();
+        indices.sort();
+
+        // Every object is allocated before filling in the instance variables, as
+        // they may refer to each other
+        for &index in &indices {
+            self.object(index, &mut vec![])?;
+        }
+        for &index in &indices {
+            self.fill(index)?;
+        }
+
+        for &(name, value) in globals {
+            let name = self.text(name)?;
+            let value = self.pointer(value)?;
+            self.vm.set_global(&name, value)?;
+        }
+
+        for &index in &indices {
+            self.report_methods(index)?;
+        }
+
+        // Nothing refers to the objects allocated for junk in the image
+        self.vm.collect_garbage()?;
+
+        Ok(())
+    }
+
+    fn record(&self, index: usize) -> Result<&'a Record, ImageError> {
+        self.records.get(&index)
+            .ok_or_else(|| ImageError::Corrupt(format!("missing object {}", index)))
+    }
+
+    fn text(&self, value: Value) -> Result<String, ImageError> {
+        match value {
+            Value::Index(index) => match self.record(index)? {
+                Record::Bytes(ObjectType::Symbol | ObjectType::String, bytes) => Ok(text(bytes)),
+                _ => Err(ImageError::Corrupt(format!("object {} is not a string", index))),
+            },
+            _ => Err(ImageError::Corrupt(format!("{:?} is not a string", value))),
+        }
+    }
+
+    // Converts an object, after the ones it can't be created without: its class
+    // and its super object
+    fn object(&mut self, index: usize, pending: &mut Vec<usize>) -> Result<ObjectPointer, ImageError> {
+        if let Some(&object) = self.objects.get(&index) {
+            return Ok(object);
+        }
+        if pending.contains(&index) {
+            return Err(ImageError::Corrupt(format!("object {} depends on itself", index)));
+        }
+
+        pending.push(index);
+        let object = match self.record(index)? {
+            Record::Object { class, super_obj, inst_vars } => {
+                let class = match *class {
+                    Value::Index(class) => self.class(Value::Index(class), pending)?,
+                    _ => ObjectPointer::null(),
+                };
+                match special_object(&self.vm, &self.vm.class_name(class)) {
+                    Some(object) => object,
+                    None => {
+                        let super_obj = match *super_obj {
+                            Value::Index(super_obj) => self.object(super_obj, pending)?,
+                            _ => ObjectPointer::null(),
+                        };
+                        self.vm.memory_mut().increment_ref(class)?;
+                        self.vm.memory_mut().increment_ref(super_obj)?;
+                        self.vm.memory_mut().allocate(Object::new(class, super_obj, inst_vars.len()))?
+                    }
+                }
+            }
+            Record::Class { name, super_class, inst_vars, .. } => {
+                let name = self.text(*name)?;
+                let super_class = self.class(*super_class, pending)?;
+                let names = match *inst_vars {
+                    Value::Index(inst_vars) => match self.record(inst_vars)? {
+                        Record::Object { inst_vars, .. } => inst_vars.iter()
+                            .map(|&name| self.text(name))
+                            .collect::<Result<Vec<_>, _>>()?,
+                        _ => return Err(ImageError::Corrupt(format!("invalid instance variables in class {}", index))),
+                    },
+                    _ => vec![],
+                };
+
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                let class = self.vm.new_class(&name, super_class, &names)?;
+                self.vm.set_global(&name, class)?;
+                class
+            }
+            Record::Bytes(object_type, bytes) => new_byte_object(&mut self.vm, *object_type, index, bytes)?,
+            Record::Int(ObjectType::Char, value) => new_char(&mut self.vm, index, *value)?,
+            Record::Int(_, value) => self.vm.new_integer(BigInt::from(*value))?,
+        };
+        pending.pop();
+
+        self.objects.insert(index, object);
+        Ok(object)
+    }
+
+    // The class a value refers to, directly or by name
+    fn class(&mut self, value: Value, pending: &mut Vec<usize>) -> Result<ObjectPointer, ImageError> {
+        let Value::Index(index) = value else {
+            return Ok(ObjectPointer::null());
+        };
+
+        let index = match self.record(index)? {
+            Record::Class { .. } => index,
+            Record::Bytes(ObjectType::Symbol, _) => {
+                let name = self.text(value)?;
+                self.class_named(&name)?
+                    .ok_or_else(|| ImageError::Corrupt(format!("undefined class {}", name)))?
+            }
+            _ => return Err(ImageError::Corrupt(format!("object {} is not a class", index))),
+        };
+
+        self.object(index, pending)
+    }
+
+    fn class_named(&self, name: &str) -> Result<Option<usize>, ImageError> {
+        for (&index, record) in self.records {
+            if let Record::Class { name: class_name, .. } = record && self.text(*class_name)? == name {
+                return Ok(Some(index));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn pointer(&self, value: Value) -> Result<ObjectPointer, ImageError> {
+        match value {
+            Value::Nil => Ok(ObjectPointer::null()),
+            Value::Integer(value) => Ok(ObjectPointer::from_small_integer(value)),
+            Value::Index(index) => self.objects.get(&index)
+                .copied()
+                .ok_or_else(|| ImageError::Corrupt(format!("missing object {}", index))),
+        }
+    }
+
+    fn fill(&mut self, index: usize) -> Result<(), ImageError> {
+        let Record::Object { inst_vars, .. } = self.record(index)? else {
+            return Ok(());
+        };
+        let object = self.objects[&index];
+        if !self.vm.is_kind_of(object, ObjectType::Object) || object == self.vm.true_object() || object == self.vm.false_object() {
+            return Ok(());
+        }
+
+        for (position, &value) in inst_vars.iter().enumerate() {
+            let value = self.pointer(value)?;
+            self.vm.memory_mut().put_inst_var(object, position, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn report_methods(&mut self, index: usize) -> Result<(), ImageError> {
+        let Record::Class { name, file_name, message_names, .. } = self.record(index)? else {
+            return Ok(());
+        };
+        let Value::Index(message_names) = *message_names else {
+            return Ok(());
+        };
+        let Record::Object { inst_vars: selectors, .. } = self.record(message_names)? else {
+            return Err(ImageError::Corrupt(format!("invalid message names in class {}", index)));
+        };
+
+        let class_name = self.text(*name)?;
+        let file = match file_name {
+            Value::Nil => String::new(),
+            &file_name => format!(", load it from {}", self.text(file_name)?),
+        };
+        for &selector in selectors {
+            let selector = self.text(selector)?;
+            self.skipped.push(format!("{}>>{}: version 1 images keep no source{}", class_name, selector, file));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::number::Float;
+    use crate::objects::object::{
+        BLOCKSIZE, CHARSIZE, CLASSSIZE, FLOATSIZE, FRACTIONSIZE, INTEGERSIZE, STRINGSIZE, SYMBOLSIZE,
+    };
+
+    fn int(image: &mut Vec<u8>, value: i32) {
+        image.extend(value.to_le_bytes());
+    }
+
+    fn pointers(image: &mut Vec<u8>, index: i32, class: i32, super_obj: i32, fields: &[i32]) {
+        int(image, index);
+        int(image, fields.len() as i32);
+        for field in [class, super_obj].iter().chain(fields) {
+            int(image, *field);
+        }
+    }
+
+    fn bytes(image: &mut Vec<u8>, index: i32, size: ObjectSize, value: &[u8]) {
+        int(image, index);
+        int(image, size);
+        int(image, value.len() as i32);
+        image.extend(value);
+        image.extend(vec![0; value.len().next_multiple_of(4) - value.len()]);
+    }
+
+    fn class(image: &mut Vec<u8>, index: i32, name: i32, super_class: i32, inst_vars: i32, message_names: i32) {
+        int(image, index);
+        int(image, CLASSSIZE);
+        for field in [name, super_class, 40, inst_vars, 0, message_names, 0, 0] {
+            int(image, field);
+        }
+    }
+
+    fn value(image: &mut Vec<u8>, index: i32, size: ObjectSize, value: i32) {
+        int(image, index);
+        int(image, size);
+        int(image, value);
+    }
+
+    // Point, a subclass of Object, and an instance of it in a global, whose
+    // coordinates are objects of every built-in type
+    fn image() -> Vec<u8> {
+        let mut image = vec![];
+        int(&mut image, 1);
+        int(&mut image, 20);
+        int(&mut image, 21);
+
+        for (index, name) in [(10, "Object"), (11, "Point"), (12, "x"), (13, "y"), (14, "sum"), (15, "UndefinedObject"), (20, "Origin")] {
+            bytes(&mut image, index, SYMBOLSIZE, name.as_bytes());
+        }
+        bytes(&mut image, 40, STRINGSIZE, b"point.st\0");
+        class(&mut image, 1, 10, 0, 0, 0);
+        // The superclass by name
+        class(&mut image, 2, 11, 10, 3, 4);
+        class(&mut image, 5, 15, 1, 0, 0);
+        pointers(&mut image, 3, 0, 0, &[12, 13]);
+        pointers(&mut image, 4, 0, 0, &[14]);
+
+        pointers(&mut image, 21, 2, 22, &[23, 24]);
+        pointers(&mut image, 22, 1, 0, &[]);
+        value(&mut image, 23, INTEGERSIZE, -7);
+        int(&mut image, 24);
+        int(&mut image, FLOATSIZE);
+        image.extend(2.5f64.to_le_bytes());
+
+        // Not referenced from the globals
+        value(&mut image, 30, CHARSIZE, 'a' as i32);
+        pointers(&mut image, 31, 5, 0, &[]);
+
+        image
+    }
+
+    #[test]
+    fn test_budd_v1_objects() -> Result<(), ImageError> {
+        let (vm, skipped) = VirtualMachine::read_budd_image(image().as_slice(), BuddLayout::V1)?;
+
+        let point = vm.global("Point").unwrap();
+        let object = vm.global("Object").unwrap();
+        assert_eq!(vm.memory().get::<crate::objects::class::Class>(point)?.super_class(), object);
+
+        let origin = vm.global("Origin").unwrap();
+        assert_eq!(vm.class_of(origin)?, point);
+        assert_eq!(vm.class_of(vm.super_object(origin))?, object);
+        assert_eq!(vm.memory().inst_var(origin, 0)?, ObjectPointer::from_small_integer(-7));
+        let y = vm.memory().inst_var(origin, 1)?;
+        assert_eq!(vm.memory().get::<Float>(y)?.value(), 2.5);
+
+        assert_eq!(skipped, ["Point>>sum: version 1 images keep no source, load it from point.st"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_budd_v1_rejected_objects() {
+        let mut block = image();
+        value(&mut block, 50, BLOCKSIZE, 0);
+        assert_eq!(
+            VirtualMachine::read_budd_image(block.as_slice(), BuddLayout::V1).err(),
+            Some(ImageError::Unsupported("object 50 is a Block, which belongs to a running interpreter".to_string())),
+        );
+
+        let mut fraction = image();
+        value(&mut fraction, 50, FRACTIONSIZE, 0);
+        assert_eq!(
+            VirtualMachine::read_budd_image(fraction.as_slice(), BuddLayout::V1).err(),
+            Some(ImageError::Corrupt("invalid size -22 of object 50".to_string())),
+        );
+
+        let mut cycle = image();
+        class(&mut cycle, 6, 12, 6, 0, 0);
+        assert_eq!(
+            VirtualMachine::read_budd_image(cycle.as_slice(), BuddLayout::V1).err(),
+            Some(ImageError::Corrupt("object 6 depends on itself".to_string())),
+        );
+    }
+}
@@ -0,0 +1,571 @@
+// Images of version 3
+//
+// Those images are a dump of the object table, as written by imageWrite. They
+// start with a pointer to the symbol table (the dictionary of globals), followed
+// by one record per object:
+//
+//   * its index in the object table (an int), its class (an object) and its size
+//     (a short), with the padding the C compiler added to the struct
+//   * its contents: size pointers, or -size bytes for byte objects, rounded up to
+//     a whole number of pointers
+//
+// Pointers with the low bit set are small integers, shifted one bit to the left.
+// The others are object indices, also shifted one bit. Index 0 is nil.
+//
+// There are no size codes in these images, so objects of the built-in types are
+// recognized by the name of their class. Instance variables are all stored in the
+// object, and are split between the levels of the converted object that belong to
+// each class. Methods keep their source text, and are compiled again from it.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::{MethodCompiler, Parser};
+use crate::execution::VirtualMachine;
+use crate::objects::{
+    class::Class,
+    object::{Object, ObjectPointer, ObjectType, Pointer},
+};
+use super::{new_byte_object, new_char, special_object, text, BuddLayout, Cursor, Value};
+use crate::image::ImageError;
+
+// Instance variables of the classes used by the loader
+const CLASS_NAME: usize = 0;
+const CLASS_SIZE: usize = 1;
+const CLASS_METHODS: usize = 2;
+const CLASS_SUPER_CLASS: usize = 3;
+const CLASS_VARIABLES: usize = 4;
+const DICTIONARY_HASH_TABLE: usize = 0;
+const LINK_KEY: usize = 0;
+const LINK_VALUE: usize = 1;
+const LINK_NEXT: usize = 2;
+const METHOD_TEXT: usize = 0;
+
+#[derive(Debug)]
+enum Contents {
+    Pointers(Vec<u64>),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct Record {
+    class: u64,
+    contents: Contents,
+}
+
+pub(super) fn convert(bytes: &[u8], layout: BuddLayout) -> Result<(VirtualMachine, Vec<String>), ImageError> {
+    let image = BuddImage::parse(bytes, layout)?;
+    let mut conversion = Conversion {
+        image: &image,
+        vm: VirtualMachine::new()?,
+        classes: HashMap::new(),
+        objects: HashMap::new(),
+        skipped: vec![],
+    };
+    conversion.run()?;
+
+    Ok((conversion.vm, conversion.skipped))
+}
+
+// Built-in types, by the name of their classes
+fn class_type(class_name: &str) -> Option<ObjectType> {
+    match class_name {
+        "ByteArray" => Some(ObjectType::ByteArray),
+        "Char" => Some(ObjectType::Char),
+        "Float" => Some(ObjectType::Float),
+        "String" => Some(ObjectType::String),
+        "Symbol" => Some(ObjectType::Symbol),
+        _ => None,
+    }
+}
+
+struct BuddImage {
+    layout: BuddLayout,
+    symbols: u64,
+    records: HashMap<usize, Record>,
+}
+
+impl BuddImage {
+    fn parse(bytes: &[u8], layout: BuddLayout) -> Result<Self, ImageError> {
+        let mut cursor = Cursor::new(bytes);
+        let symbols = cursor.unsigned(layout.pointer_bytes)?;
+        let mut records = HashMap::new();
+
+        while !cursor.at_end() {
+            let index = cursor.unsigned(layout.int_bytes)? as usize;
+            let class = cursor.unsigned(layout.pointer_bytes)?;
+            let size = cursor.unsigned(2)? as u16 as i16;
+            cursor.skip(layout.padding_bytes)?;
+
+            let contents = if size >= 0 {
+                let pointers = (0..size)
+                    .map(|_| cursor.unsigned(layout.pointer_bytes))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Contents::Pointers(pointers)
+            } else {
+                let len = -(size as isize) as usize;
+                Contents::Bytes(cursor.padded(len, layout.pointer_bytes)?.to_vec())
+            };
+
+            if records.insert(index, Record { class, contents }).is_some() {
+                return Err(ImageError::Corrupt(format!("object {} appears twice", index)));
+            }
+        }
+
+        Ok(BuddImage { layout, symbols, records })
+    }
+
+    fn value(&self, word: u64) -> Value {
+        let bits = self.layout.pointer_bytes * 8;
+        if word & 1 == 1 {
+            // Sign extend, then drop the tag
+            let shift = 64 - bits;
+            Value::Integer((((word << shift) as i64) >> shift >> 1) as i32)
+        } else if word == 0 {
+            Value::Nil
+        } else {
+            Value::Index((word >> 1) as usize)
+        }
+    }
+
+    fn record(&self, index: usize) -> Result<&Record, ImageError> {
+        self.records.get(&index)
+            .ok_or_else(|| ImageError::Corrupt(format!("missing object {}", index)))
+    }
+
+    fn pointers(&self, index: usize) -> Result<&[u64], ImageError> {
+        match &self.record(index)?.contents {
+            Contents::Pointers(pointers) => Ok(pointers),
+            Contents::Bytes(_) => Err(ImageError::Corrupt(format!("object {} has no pointers", index))),
+        }
+    }
+
+    fn field(&self, index: usize, field: usize) -> Result<Value, ImageError> {
+        let word = self.pointers(index)?
+            .get(field)
+            .copied()
+            .ok_or_else(|| ImageError::Corrupt(format!("object {} has no field {}", index, field + 1)))?;
+
+        Ok(self.value(word))
+    }
+
+    fn text(&self, value: Value) -> Result<String, ImageError> {
+        let Value::Index(index) = value else {
+            return Err(ImageError::Corrupt(format!("{:?} is not a string", value)));
+        };
+        let Contents::Bytes(bytes) = &self.record(index)?.contents else {
+            return Err(ImageError::Corrupt(format!("object {} is not a string", index)));
+        };
+
+        Ok(text(bytes))
+    }
+
+    fn class_of(&self, index: usize) -> Result<Option<usize>, ImageError> {
+        match self.value(self.record(index)?.class) {
+            Value::Index(class) => Ok(Some(class)),
+            _ => Ok(None),
+        }
+    }
+
+    fn class_name(&self, class: usize) -> Result<String, ImageError> {
+        self.text(self.field(class, CLASS_NAME)?)
+    }
+
+    // Key and value of every entry in a dictionary. The hash table holds triples
+    // of key, value and a chain of links for the colliding keys
+    fn dictionary(&self, dictionary: Value) -> Result<Vec<(Value, Value)>, ImageError> {
+        let Value::Index(dictionary) = dictionary else {
+            return Ok(vec![]);
+        };
+        let Value::Index(table) = self.field(dictionary, DICTIONARY_HASH_TABLE)? else {
+            return Ok(vec![]);
+        };
+
+        let mut entries = vec![];
+        for triple in self.pointers(table)?.chunks(3) {
+            let [key, value, link] = triple else { break };
+            if self.value(*key) != Value::Nil {
+                entries.push((self.value(*key), self.value(*value)));
+            }
+
+            let mut link = self.value(*link);
+            let mut visited = HashSet::new();
+            while let Value::Index(index) = link {
+                if !visited.insert(index) {
+                    return Err(ImageError::Corrupt(format!("cycle in the links of object {}", table)));
+                }
+                entries.push((self.field(index, LINK_KEY)?, self.field(index, LINK_VALUE)?));
+                link = self.field(index, LINK_NEXT)?;
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+struct Conversion<'a> {
+    image: &'a BuddImage,
+    vm: VirtualMachine,
+    // Converted classes, by index in the image
+    classes: HashMap<usize, ObjectPointer>,
+    objects: HashMap<usize, ObjectPointer>,
+    skipped: Vec<String>,
+}
+
+impl Conversion<'_> {
+    fn run(&mut self) -> Result<(), ImageError> {
+        let mut indices: Vec<usize> = self.image.records.keys().copied().collect();
+        indices.sort();
+
+        // Classes first, so that instances can be created. Then every object is
+        // allocated before filling them in, as they may refer to each other
+        let mut classes = vec![];
+        for &index in &indices {
+            if let Some(class) = self.image.class_of(index)? {
+                classes.push(class);
+            }
+        }
+        for class in classes {
+            self.define_class(class, &mut vec![])?;
+        }
+
+        for &index in &indices {
+            if !self.classes.contains_key(&index) {
+                let object = self.allocate(index)?;
+                self.objects.insert(index, object);
+            }
+        }
+        for &index in &indices {
+            self.fill(index)?;
+        }
+
+        for (name, value) in self.image.dictionary(self.image.value(self.image.symbols))? {
+            let name = self.image.text(name)?;
+            let value = self.pointer(value)?;
+            self.vm.set_global(&name, value)?;
+        }
+
+        let classes: Vec<(usize, ObjectPointer)> = self.classes.iter().map(|(&index, &class)| (index, class)).collect();
+        for (index, class) in classes {
+            self.compile_methods(index, class)?;
+        }
+
+        // Nothing refers to the objects allocated for junk in the image
+        self.vm.collect_garbage()?;
+
+        Ok(())
+    }
+
+    // Converts a class after its superclasses. Classes are set as globals with their
+    // name, which is where the rest of the machine finds them
+    fn define_class(&mut self, index: usize, pending: &mut Vec<usize>) -> Result<ObjectPointer, ImageError> {
+        if let Some(&class) = self.classes.get(&index) {
+            return Ok(class);
+        }
+        if pending.contains(&index) {
+            return Err(ImageError::Corrupt(format!("class {} inherits from itself", index)));
+        }
+
+        pending.push(index);
+        let name = self.image.class_name(index)?;
+        let (super_class, super_size) = match self.image.field(index, CLASS_SUPER_CLASS)? {
+            Value::Index(super_index) => {
+                let super_class = self.define_class(super_index, pending)?;
+                (super_class, self.instance_size(super_index)?)
+            }
+            _ => (ObjectPointer::null(), 0),
+        };
+        pending.pop();
+
+        // Only the variables added by the class belong to it
+        let size = self.instance_size(index)?;
+        let own = size.saturating_sub(super_size);
+        let variables = match self.image.field(index, CLASS_VARIABLES)? {
+            Value::Index(variables) => self.image.pointers(variables)?
+                .iter()
+                .map(|&word| self.image.text(self.image.value(word)))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => vec![],
+        };
+        let mut names: Vec<String> = variables[variables.len().saturating_sub(own)..].to_vec();
+        names.extend((names.len()..own).map(|position| format!("var{}", position + 1)));
+
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        let class = self.vm.new_class(&name, super_class, &names)?;
+        self.vm.set_global(&name, class)?;
+        self.classes.insert(index, class);
+
+        Ok(class)
+    }
+
+    fn instance_size(&self, class: usize) -> Result<usize, ImageError> {
+        match self.image.field(class, CLASS_SIZE)? {
+            Value::Integer(size) if size >= 0 => Ok(size as usize),
+            value => Err(ImageError::Corrupt(format!("invalid instance size {:?} in class {}", value, class))),
+        }
+    }
+
+    fn class_name_of(&self, index: usize) -> Result<Option<String>, ImageError> {
+        self.image.class_of(index)?
+            .map(|class| self.image.class_name(class))
+            .transpose()
+    }
+
+    fn allocate(&mut self, index: usize) -> Result<ObjectPointer, ImageError> {
+        let class_name = self.class_name_of(index)?;
+        let class = match self.image.class_of(index)? {
+            Some(class) => self.classes[&class],
+            None => ObjectPointer::null(),
+        };
+
+        if let Some(object) = class_name.as_deref().and_then(|name| special_object(&self.vm, name)) {
+            return Ok(object);
+        }
+
+        let object_type = class_name.as_deref().and_then(class_type);
+        let object = match (&self.image.record(index)?.contents, object_type) {
+            (Contents::Bytes(bytes), object_type) => {
+                new_byte_object(&mut self.vm, object_type.unwrap_or(ObjectType::ByteArray), index, bytes)?
+            }
+            (Contents::Pointers(pointers), Some(ObjectType::Char)) => match pointers.first().map(|&word| self.image.value(word)) {
+                Some(Value::Integer(value)) if pointers.len() == 1 => new_char(&mut self.vm, index, value.into())?,
+                _ => return Err(ImageError::Corrupt(format!("invalid char {}", index))),
+            },
+            (Contents::Pointers(pointers), _) if class_name.as_deref() == Some("Array") => self.vm.new_array(pointers.len())?,
+            (Contents::Pointers(pointers), _) if class.is_null() => {
+                self.vm.memory_mut().allocate(Object::new(class, ObjectPointer::null(), pointers.len()))?
+            }
+            (Contents::Pointers(_), _) => self.vm.memory_mut().instantiate(class)?,
+        };
+
+        Ok(object)
+    }
+
+    fn pointer(&self, value: Value) -> Result<ObjectPointer, ImageError> {
+        match value {
+            Value::Nil => Ok(ObjectPointer::null()),
+            Value::Integer(value) => Ok(ObjectPointer::from_small_integer(value)),
+            Value::Index(index) => self.objects.get(&index)
+                .or_else(|| self.classes.get(&index))
+                .copied()
+                .ok_or_else(|| ImageError::Corrupt(format!("missing object {}", index))),
+        }
+    }
+
+    // Stores the contents of an object. The instance variables of ordinary objects
+    // go, from the last, to the levels of the object that belong to each class
+    fn fill(&mut self, index: usize) -> Result<(), ImageError> {
+        let Some(&object) = self.objects.get(&index) else {
+            return Ok(());
+        };
+        let Contents::Pointers(words) = &self.image.record(index)?.contents else {
+            return Ok(());
+        };
+        if !self.vm.is_kind_of(object, ObjectType::Object) || object == self.vm.true_object() || object == self.vm.false_object() {
+            return Ok(());
+        }
+
+        let values = words.iter()
+            .map(|&word| self.pointer(self.image.value(word)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut remaining = values.as_slice();
+        let mut level = object;
+        while !level.is_null() && !remaining.is_empty() {
+            let size = self.vm.memory().get::<Object>(level)?.size();
+            let (rest, own) = remaining.split_at(remaining.len().saturating_sub(size));
+            for (position, &value) in own.iter().enumerate() {
+                self.vm.memory_mut().put_inst_var(level, position, value)?;
+            }
+
+            remaining = rest;
+            level = self.vm.memory().get::<Object>(level)?.super_obj();
+        }
+
+        Ok(())
+    }
+
+    fn compile_methods(&mut self, index: usize, class: ObjectPointer) -> Result<(), ImageError> {
+        let class_name = self.vm.class_name(class).to_string();
+        let inst_vars = self.inst_var_names(class)?;
+
+        for (selector, method) in self.image.dictionary(self.image.field(index, CLASS_METHODS)?)? {
+            let selector = self.image.text(selector).unwrap_or_default();
+            let Value::Index(method) = method else { continue };
+            let file = format!("{}>>{}", class_name, selector);
+
+            let compiled = self.image.text(self.image.field(method, METHOD_TEXT)?)
+                .map_err(|err| err.to_string())
+                .and_then(|text| {
+                    let definition = Parser::new(&file, &text)
+                        .and_then(|mut parser| parser.method_definition())
+                        .map_err(|err| err.to_string())?;
+                    MethodCompiler::new(&file, &text, &inst_vars)
+                        .compile(&definition)
+                        .map_err(|err| err.to_string())
+                })
+                .and_then(|compiled| self.vm.compiled_method(&compiled).map_err(|err| format!("{}: {}", file, err)));
+
+            match compiled {
+                Ok(method) => self.vm.install_method(class, method)?,
+                Err(err) => self.skipped.push(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn inst_var_names(&self, class: ObjectPointer) -> Result<Vec<String>, ImageError> {
+        let inst_vars = self.vm.memory().get::<Class>(class)?.c_inst_vars();
+        if inst_vars.is_null() {
+            return Ok(vec![]);
+        }
+
+        let size = self.vm.memory().get::<Object>(inst_vars)?.size();
+        (0..size)
+            .map(|position| {
+                let name = self.vm.memory().inst_var(inst_vars, position)?;
+                Ok(self.vm.symbol_value(name)?.to_string())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(index: u64) -> u64 {
+        index << 1
+    }
+
+    fn integer(value: i64) -> u64 {
+        ((value << 1) | 1) as u64
+    }
+
+    // Records written with the sizes of a layout
+    struct Fixture {
+        layout: BuddLayout,
+        bytes: Vec<u8>,
+    }
+
+    impl Fixture {
+        fn new(layout: BuddLayout, symbols: u64) -> Self {
+            let mut fixture = Fixture { layout, bytes: vec![] };
+            fixture.word(object(symbols), layout.pointer_bytes);
+            fixture
+        }
+
+        // Little endian, truncated to len bytes
+        fn word(&mut self, value: u64, len: usize) {
+            self.bytes.extend(&value.to_le_bytes()[..len]);
+        }
+
+        fn header(&mut self, index: u64, class: u64, size: i16) {
+            self.word(index, self.layout.int_bytes);
+            self.word(class, self.layout.pointer_bytes);
+            self.bytes.extend(size.to_le_bytes());
+            self.bytes.extend(vec![0; self.layout.padding_bytes]);
+        }
+
+        fn pointers(&mut self, index: u64, class: u64, fields: &[u64]) {
+            self.header(index, object(class), fields.len() as i16);
+            for &field in fields {
+                self.word(field, self.layout.pointer_bytes);
+            }
+        }
+
+        // Strings and symbols are stored with their terminator
+        fn text(&mut self, index: u64, class: u64, value: &str) {
+            let mut bytes = value.as_bytes().to_vec();
+            bytes.push(0);
+            self.header(index, object(class), -(bytes.len() as i16));
+            bytes.resize(bytes.len().next_multiple_of(self.layout.pointer_bytes), 0);
+            self.bytes.extend(bytes);
+        }
+
+        fn class(&mut self, index: u64, name: u64, size: i64, methods: u64, super_class: u64, variables: u64) {
+            self.pointers(index, 2, &[object(name), integer(size), methods, super_class, variables]);
+        }
+    }
+
+    // A small image, with a Point class and an instance of it in a global
+    fn image(layout: BuddLayout) -> Vec<u8> {
+        let mut image = Fixture::new(layout, 26);
+        image.pointers(0, 1, &[]);
+        image.class(1, 10, 0, 0, object(3), 0);
+        image.class(2, 11, 5, 0, object(3), 0);
+        image.class(3, 12, 0, 0, 0, 0);
+        image.class(4, 13, 0, 0, object(3), 0);
+        image.class(5, 14, 0, 0, object(3), 0);
+        image.class(6, 15, 2, object(30), object(3), object(20));
+        image.class(7, 16, 0, 0, object(3), 0);
+        image.class(8, 17, 1, 0, object(3), 0);
+        image.class(9, 18, 3, 0, object(3), 0);
+        image.class(34, 35, 1, 0, object(3), 0);
+        for (index, name) in [
+            (10, "UndefinedObject"), (11, "Class"), (12, "Object"), (13, "Symbol"), (14, "String"),
+            (15, "Point"), (16, "Array"), (17, "Dictionary"), (18, "Link"), (19, "x"), (21, "y"),
+            (22, "Origin"), (23, "sum"), (35, "Method"), (36, "bad"),
+        ] {
+            image.text(index, 4, name);
+        }
+        image.pointers(20, 7, &[object(19), object(21)]);
+        image.pointers(25, 6, &[integer(3), integer(-7)]);
+
+        // Globals: Origin in the hash table, Point in a link
+        image.pointers(26, 8, &[object(27)]);
+        image.pointers(27, 7, &[object(22), object(25), 0, 0, 0, object(28)]);
+        image.pointers(28, 9, &[object(15), object(6), 0]);
+
+        // Methods of Point. One of them doesn't compile
+        image.pointers(30, 8, &[object(31)]);
+        image.pointers(31, 7, &[object(23), object(32), 0, object(36), object(37), 0]);
+        image.pointers(32, 34, &[object(24)]);
+        image.text(24, 5, "sum\n    ^ x");
+        image.pointers(37, 34, &[object(38)]);
+        image.text(38, 5, "bad\n    ^ (");
+
+        image.bytes
+    }
+
+    #[test]
+    fn test_budd_image_classes_and_globals() -> Result<(), ImageError> {
+        for layout in [BuddLayout::V3, BuddLayout::V3_WIDE] {
+            let (mut vm, skipped) = VirtualMachine::read_budd_image(image(layout).as_slice(), layout)?;
+
+            let point = vm.global("Point").unwrap();
+            assert_eq!(vm.class_name(point), "Point");
+            assert_eq!(vm.memory().get::<Class>(point)?.super_class(), vm.global("Object").unwrap());
+
+            let origin = vm.global("Origin").unwrap();
+            assert_eq!(vm.class_of(origin)?, point);
+            assert_eq!(vm.memory().inst_var(origin, 1)?, ObjectPointer::from_small_integer(-7));
+            assert_eq!(vm.send(origin, "sum", &[])?, ObjectPointer::from_small_integer(3));
+
+            assert_eq!(skipped.len(), 1);
+            assert!(skipped[0].starts_with("Point>>bad:"), "{}", skipped[0]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_budd_image_errors() {
+        let image = image(BuddLayout::V3);
+        let truncated = &image[..image.len() - 1];
+        assert_eq!(
+            VirtualMachine::read_budd_image(truncated, BuddLayout::V3).err(),
+            Some(ImageError::Corrupt("unexpected end of image".to_string())),
+        );
+
+        // Read with the wrong layout
+        assert!(VirtualMachine::read_budd_image(image.as_slice(), BuddLayout::V3_WIDE).is_err());
+
+        let mut missing = Fixture::new(BuddLayout::V3, 26);
+        missing.pointers(1, 2, &[]);
+        assert_eq!(
+            VirtualMachine::read_budd_image(missing.bytes.as_slice(), BuddLayout::V3).err(),
+            Some(ImageError::Corrupt("missing object 2".to_string())),
+        );
+    }
+}
@@ -0,0 +1,188 @@
+// Encoding of the values stored in images
+//
+// Numbers are little endian. Byte arrays and strings are prefixed with their
+// length, as a u32.
+
+use std::io::{Read, Write};
+
+use crate::objects::object::{ObjectPointer, Pointer};
+use super::ImageError;
+
+// Objects that can be written to an image. The header is written separately, so
+// only the fields of the object are handled here
+pub trait ImageObject: Sized {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError>;
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError>;
+}
+
+pub struct ImageWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ImageWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ImageWriter { inner }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub fn raw(&mut self, bytes: &[u8]) -> Result<(), ImageError> {
+        Ok(self.inner.write_all(bytes)?)
+    }
+
+    pub fn u8(&mut self, value: u8) -> Result<(), ImageError> {
+        self.raw(&[value])
+    }
+
+    pub fn u32(&mut self, value: u32) -> Result<(), ImageError> {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub fn i32(&mut self, value: i32) -> Result<(), ImageError> {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> Result<(), ImageError> {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub fn f64(&mut self, value: f64) -> Result<(), ImageError> {
+        self.u64(value.to_bits())
+    }
+
+    pub fn length(&mut self, len: usize) -> Result<(), ImageError> {
+        let len = u32::try_from(len).map_err(|_| ImageError::Corrupt(format!("length too large: {}", len)))?;
+        self.u32(len)
+    }
+
+    pub fn pointer(&mut self, ptr: ObjectPointer) -> Result<(), ImageError> {
+        self.u64(ptr)
+    }
+
+    // Absent pointers are written as null. The flag keeps them apart from present
+    // null pointers
+    pub fn optional_pointer(&mut self, ptr: Option<ObjectPointer>) -> Result<(), ImageError> {
+        self.u8(ptr.is_some() as u8)?;
+        self.pointer(ptr.unwrap_or(ObjectPointer::null()))
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<(), ImageError> {
+        self.length(bytes.len())?;
+        self.raw(bytes)
+    }
+
+    pub fn str(&mut self, value: &str) -> Result<(), ImageError> {
+        self.bytes(value.as_bytes())
+    }
+}
+
+pub struct ImageReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> ImageReader<R> {
+    pub fn new(inner: R) -> Self {
+        ImageReader { inner }
+    }
+
+    pub fn raw<const N: usize>(&mut self) -> Result<[u8; N], ImageError> {
+        let mut bytes = [0; N];
+        self.inner.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.raw::<1>()?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ImageError> {
+        Ok(u32::from_le_bytes(self.raw()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, ImageError> {
+        Ok(i32::from_le_bytes(self.raw()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, ImageError> {
+        Ok(u64::from_le_bytes(self.raw()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64, ImageError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn length(&mut self) -> Result<usize, ImageError> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn pointer(&mut self) -> Result<ObjectPointer, ImageError> {
+        self.u64()
+    }
+
+    pub fn optional_pointer(&mut self) -> Result<Option<ObjectPointer>, ImageError> {
+        let present = self.u8()? != 0;
+        let ptr = self.pointer()?;
+        Ok(present.then_some(ptr))
+    }
+
+    pub fn bytes(&mut self) -> Result<Vec<u8>, ImageError> {
+        let len = self.length()?;
+        let mut bytes = vec![];
+        // Not allocated upfront, so that a corrupt length can't exhaust memory
+        (&mut self.inner).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(ImageError::Corrupt("unexpected end of image".to_string()));
+        }
+        Ok(bytes)
+    }
+
+    pub fn string(&mut self) -> Result<String, ImageError> {
+        String::from_utf8(self.bytes()?)
+            .map_err(|_| ImageError::Corrupt("invalid UTF-8 in string".to_string()))
+    }
+
+    // Whether the whole image was read
+    pub fn at_end(&mut self) -> Result<bool, ImageError> {
+        let mut byte = [0; 1];
+        Ok(self.inner.read(&mut byte)? == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_round_trip() -> Result<(), ImageError> {
+        let mut writer = ImageWriter::new(vec![]);
+        writer.u8(7)?;
+        writer.i32(-3)?;
+        writer.f64(1.5)?;
+        writer.optional_pointer(None)?;
+        writer.optional_pointer(Some(ObjectPointer::null()))?;
+        writer.str("héllo")?;
+
+        let bytes = writer.into_inner();
+        let mut reader = ImageReader::new(bytes.as_slice());
+        assert_eq!(reader.u8()?, 7);
+        assert_eq!(reader.i32()?, -3);
+        assert_eq!(reader.f64()?, 1.5);
+        assert_eq!(reader.optional_pointer()?, None);
+        assert_eq!(reader.optional_pointer()?, Some(ObjectPointer::null()));
+        assert_eq!(reader.string()?, "héllo");
+        assert!(reader.at_end()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoding_truncated() {
+        let mut reader = ImageReader::new([5, 0, 0, 0, b'a'].as_slice());
+        assert_eq!(reader.bytes(), Err(ImageError::Corrupt("unexpected end of image".to_string())));
+
+        let mut reader = ImageReader::new([1, 2].as_slice());
+        assert!(matches!(reader.u32(), Err(ImageError::Io(_))));
+    }
+}
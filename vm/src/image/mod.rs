@@ -0,0 +1,192 @@
+// Image files
+//
+// An image is a snapshot of the object memory, so that the class library doesn't
+// have to be loaded from sources every time. Objects are restored at the same
+// location they had, so pointers are kept as they are.
+//
+// Layout (see encoding for how values are written):
+//
+//   * magic ("LSTI") and format version (u32)
+//   * block size of the pools (u32)
+//   * roots of the virtual machine (see execution::image)
+//   * one section per pool: the tag of its type (u8), its number of blocks (u32),
+//     and its number of live objects (u64), followed by each of them: pointer
+//     (u64), reference count (u32) and fields
+
+mod budd;
+mod encoding;
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+
+use crate::execution::ExecutionError;
+use crate::memory::{MemoryError, MemoryObject, ObjectMemory};
+use crate::objects::{
+    block::Block,
+    byte::ByteArray,
+    character::Char,
+    class::Class,
+    file::File,
+    interp::Interpreter,
+    number::{Float, Fraction, Integer, LargeInteger},
+    object::{Object, ObjectPointer, MAX_BLOCK_ELEMENTS},
+    process::Process,
+    semaphore::Semaphore,
+    string::StringObject,
+    symbol::Symbol,
+};
+
+pub use budd::{BuddLayout, BuddVersion};
+pub use encoding::{ImageObject, ImageReader, ImageWriter};
+
+pub const IMAGE_MAGIC: &[u8; 4] = b"LSTI";
+pub const IMAGE_VERSION: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageError {
+    Io(String),
+    NotAnImage,
+    UnsupportedVersion(u32),
+    // Contents that are valid, but can't be converted to the model of this VM
+    Unsupported(String),
+    Corrupt(String),
+    Memory(MemoryError),
+    Execution(ExecutionError),
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(message) => write!(f, "I/O error: {}", message),
+            ImageError::NotAnImage => write!(f, "Not an image file"),
+            ImageError::UnsupportedVersion(version) => write!(f, "Unsupported image version: {}", version),
+            ImageError::Unsupported(message) => write!(f, "Unsupported image: {}", message),
+            ImageError::Corrupt(message) => write!(f, "Corrupt image: {}", message),
+            ImageError::Memory(err) => write!(f, "{}", err),
+            ImageError::Execution(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> Self {
+        ImageError::Io(err.to_string())
+    }
+}
+
+impl From<MemoryError> for ImageError {
+    fn from(err: MemoryError) -> Self {
+        ImageError::Memory(err)
+    }
+}
+
+impl From<ExecutionError> for ImageError {
+    fn from(err: ExecutionError) -> Self {
+        ImageError::Execution(err)
+    }
+}
+
+pub fn write_header<W: Write>(writer: &mut ImageWriter<W>, memory: &ObjectMemory) -> Result<(), ImageError> {
+    writer.raw(IMAGE_MAGIC)?;
+    writer.u32(IMAGE_VERSION)?;
+    writer.length(memory.block_size())
+}
+
+// Checks the magic and the version, and returns an empty memory with the block size
+// of the image
+pub fn read_header<R: Read>(reader: &mut ImageReader<R>) -> Result<ObjectMemory, ImageError> {
+    if reader.raw::<4>().map_err(|_| ImageError::NotAnImage)? != *IMAGE_MAGIC {
+        return Err(ImageError::NotAnImage);
+    }
+
+    let version = reader.u32()?;
+    if version != IMAGE_VERSION {
+        return Err(ImageError::UnsupportedVersion(version));
+    }
+
+    let block_size = reader.length()?;
+    if block_size == 0 || block_size > MAX_BLOCK_ELEMENTS {
+        return Err(ImageError::Corrupt(format!("invalid block size: {}", block_size)));
+    }
+
+    Ok(ObjectMemory::with_block_size(block_size))
+}
+
+pub fn write_objects<W: Write>(writer: &mut ImageWriter<W>, memory: &ObjectMemory) -> Result<(), ImageError> {
+    write_pool::<Integer, W>(writer, memory)?;
+    write_pool::<Float, W>(writer, memory)?;
+    write_pool::<LargeInteger, W>(writer, memory)?;
+    write_pool::<Fraction, W>(writer, memory)?;
+    write_pool::<Symbol, W>(writer, memory)?;
+    write_pool::<ByteArray, W>(writer, memory)?;
+    write_pool::<StringObject, W>(writer, memory)?;
+    write_pool::<Block, W>(writer, memory)?;
+    write_pool::<Class, W>(writer, memory)?;
+    write_pool::<Interpreter, W>(writer, memory)?;
+    write_pool::<Process, W>(writer, memory)?;
+    write_pool::<Semaphore, W>(writer, memory)?;
+    write_pool::<File, W>(writer, memory)?;
+    write_pool::<Char, W>(writer, memory)?;
+    write_pool::<Object, W>(writer, memory)
+}
+
+pub fn read_objects<R: Read>(reader: &mut ImageReader<R>, memory: &mut ObjectMemory) -> Result<(), ImageError> {
+    read_pool::<Integer, R>(reader, memory)?;
+    read_pool::<Float, R>(reader, memory)?;
+    read_pool::<LargeInteger, R>(reader, memory)?;
+    read_pool::<Fraction, R>(reader, memory)?;
+    read_pool::<Symbol, R>(reader, memory)?;
+    read_pool::<ByteArray, R>(reader, memory)?;
+    read_pool::<StringObject, R>(reader, memory)?;
+    read_pool::<Block, R>(reader, memory)?;
+    read_pool::<Class, R>(reader, memory)?;
+    read_pool::<Interpreter, R>(reader, memory)?;
+    read_pool::<Process, R>(reader, memory)?;
+    read_pool::<Semaphore, R>(reader, memory)?;
+    read_pool::<File, R>(reader, memory)?;
+    read_pool::<Char, R>(reader, memory)?;
+    read_pool::<Object, R>(reader, memory)
+}
+
+fn write_pool<T, W>(writer: &mut ImageWriter<W>, memory: &ObjectMemory) -> Result<(), ImageError>
+    where T: MemoryObject + ImageObject, W: Write
+{
+    let pool = T::pool(memory);
+    let live = pool.live_pointers();
+
+    writer.u8(T::TYPE.tag())?;
+    writer.length(pool.block_count())?;
+    writer.u64(live.len() as u64)?;
+    for ptr in live {
+        let object = pool.get(ptr).ok_or(MemoryError::FreedObject(ptr))?;
+        writer.pointer(ptr)?;
+        writer.u32(T::header(object).ref_count())?;
+        object.write_fields(writer)?;
+    }
+
+    Ok(())
+}
+
+fn read_pool<T, R>(reader: &mut ImageReader<R>, memory: &mut ObjectMemory) -> Result<(), ImageError>
+    where T: MemoryObject + ImageObject, R: Read
+{
+    let tag = reader.u8()?;
+    if tag != T::TYPE.tag() {
+        return Err(ImageError::Corrupt(format!("expected the {:?} pool, found tag {:#x}", T::TYPE, tag)));
+    }
+
+    let blocks = reader.length()?;
+    let count = reader.u64()?;
+    let mut objects: Vec<(ObjectPointer, T)> = vec![];
+    for _ in 0..count {
+        let ptr = reader.pointer()?;
+        let ref_count = reader.u32()?;
+        let mut object = T::read_fields(reader)?;
+        T::header_mut(&mut object).set_ref_count(ref_count);
+        objects.push((ptr, object));
+    }
+
+    Ok(T::pool_mut(memory).restore(blocks, objects)?)
+}
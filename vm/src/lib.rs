@@ -1,13 +1,5 @@
 pub mod objects;
 pub mod memory;
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    // #[test]
-    // fn it_works() {
-    //     let result = add(2, 2);
-    //     assert_eq!(result, 4);
-    // }
-}
+pub mod execution;
+pub mod compiler;
+pub mod image;
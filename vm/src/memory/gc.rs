@@ -0,0 +1,222 @@
+// Mark and sweep garbage collector
+//
+// Reference counting can't reclaim cycles, and the object model is full of them:
+// block interpreters point back to the contexts that created them, and processes
+// are kept in doubly linked lists. This collector complements reference counting
+// by tracing every object reachable from a set of roots (the globals, the process
+// scheduler, the symbol table...) and sweeping everything else back to the pools.
+//
+// Sweeping leaves holes in the memory blocks. Empty blocks at the end of the pools
+// are released after each collection, and the optional compaction pass relocates
+// objects so that more of them can be released.
+
+use std::collections::HashSet;
+
+use crate::objects::object::{ObjectPointer, Pointer};
+use super::memory_pool::MemoryError;
+use super::object_memory::{ForwardingTable, ObjectMemory};
+
+impl ObjectMemory {
+    // Deallocates every object that can't be reached from the roots. Returns the
+    // number of objects reclaimed
+    pub fn collect_garbage(&mut self, roots: &[ObjectPointer]) -> Result<usize, MemoryError> {
+        let marked = self.mark(roots)?;
+        let reclaimed = self.sweep(&marked)?;
+        self.release_empty_blocks();
+
+        Ok(reclaimed)
+    }
+
+    // Moves objects into as few blocks as possible, releasing the ones that become
+    // empty. References held by objects in memory are rewritten. Pointers held
+    // elsewhere have to be updated by the caller, using the returned table (see
+    // VirtualMachine::compact for the VM roots)
+    pub fn compact(&mut self) -> Result<ForwardingTable, MemoryError> {
+        let forwarding = self.compact_pools();
+        if forwarding.is_empty() {
+            return Ok(forwarding);
+        }
+
+        let mut update = |ptr| forwarding.get(&ptr).copied().unwrap_or(ptr);
+        for ptr in self.live_objects() {
+            self.object_mut(ptr)?.update_references(&mut update);
+        }
+
+        Ok(forwarding)
+    }
+
+    fn mark(&self, roots: &[ObjectPointer]) -> Result<HashSet<ObjectPointer>, MemoryError> {
+        let mut marked = HashSet::new();
+        let mut pending = roots.to_vec();
+
+        while let Some(ptr) = pending.pop() {
+            if ptr.is_null() || ptr.is_small_integer() || marked.contains(&ptr) {
+                continue;
+            }
+
+            pending.extend(self.object(ptr)?.references());
+            marked.insert(ptr);
+        }
+
+        Ok(marked)
+    }
+
+    fn sweep(&mut self, marked: &HashSet<ObjectPointer>) -> Result<usize, MemoryError> {
+        let garbage: Vec<ObjectPointer> = self.live_objects()
+            .into_iter()
+            .filter(|ptr| !marked.contains(ptr))
+            .collect();
+
+        for &ptr in &garbage {
+            // Surviving objects are not referenced from this one anymore. Their count
+            // never reaches zero here: whatever made them reachable holds a reference
+            let survivors: Vec<ObjectPointer> = self.object(ptr)?
+                .references()
+                .into_iter()
+                .filter(|child| marked.contains(child))
+                .collect();
+
+            for child in survivors {
+                self.object_mut(child)?.header_mut().decrement_ref_count();
+            }
+        }
+
+        for &ptr in &garbage {
+            self.deallocate(ptr)?;
+        }
+
+        Ok(garbage.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{
+        object::ObjectReferences,
+        process::Process,
+        string::StringObject,
+        symbol::Symbol,
+    };
+
+    // Builds a pair of processes pointing at each other
+    fn process_cycle(memory: &mut ObjectMemory, interpreter: ObjectPointer) -> Result<(ObjectPointer, ObjectPointer), MemoryError> {
+        let first = memory.allocate(Process::new(interpreter))?;
+        let second = memory.allocate(Process::new(interpreter))?;
+        memory.increment_ref(interpreter)?;
+        memory.increment_ref(interpreter)?;
+
+        memory.get_mut::<Process>(first)?.set_next(Some(second));
+        memory.increment_ref(second)?;
+        memory.get_mut::<Process>(second)?.set_prev(Some(first));
+        memory.increment_ref(first)?;
+
+        Ok((first, second))
+    }
+
+    #[test]
+    fn test_gc_collects_cycles() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let (first, second) = process_cycle(&mut memory, ObjectPointer::null())?;
+
+        // Reference counting alone can't get rid of these
+        assert_eq!(memory.ref_count(first)?, 1);
+        assert_eq!(memory.ref_count(second)?, 1);
+
+        assert_eq!(memory.collect_garbage(&[])?, 2);
+        assert!(memory.live_objects().is_empty());
+        // The pool is empty, so its block is gone as well
+        assert_eq!(memory.ref_count(first), Err(MemoryError::InvalidBlock(0)));
+        assert_eq!(memory.ref_count(second), Err(MemoryError::InvalidBlock(0)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_keeps_reachable_objects() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let interpreter = memory.allocate(Symbol::new("stand-in".to_string()))?;
+        let (first, second) = process_cycle(&mut memory, interpreter)?;
+        let unreachable = memory.allocate(Symbol::new("garbage".to_string()))?;
+
+        assert_eq!(memory.collect_garbage(&[second, memory.new_integer(1)])?, 1);
+        assert_eq!(memory.ref_count(unreachable), Err(MemoryError::FreedObject(unreachable)));
+        for ptr in [interpreter, first, second] {
+            assert!(memory.object(ptr).is_ok());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_releases_empty_blocks() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::with_block_size(4);
+        let root = memory.allocate(Symbol::new("root".to_string()))?;
+        for i in 0..10 {
+            memory.allocate(Symbol::new(format!("garbage{}", i)))?;
+        }
+        assert_eq!(memory.block_count(), 3);
+
+        assert_eq!(memory.collect_garbage(&[root])?, 10);
+        assert_eq!(memory.block_count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_compact_rewrites_references() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::with_block_size(4);
+        let mut strings = vec![];
+        let mut fillers = vec![];
+        let mut super_obj = ObjectPointer::null();
+        for i in 0..12 {
+            memory.increment_ref(super_obj)?;
+            super_obj = memory.allocate(StringObject::new(format!("s{}", i), super_obj))?;
+            strings.push(super_obj);
+            fillers.push(memory.allocate(StringObject::new("filler".to_string(), ObjectPointer::null()))?);
+        }
+        let (first, second) = process_cycle(&mut memory, strings[11])?;
+
+        // Leave holes all over the string pool
+        for ptr in fillers {
+            memory.deallocate(ptr)?;
+        }
+        let live = memory.live_objects().len();
+        let blocks = memory.block_count();
+
+        let forwarding = memory.compact()?;
+        assert!(!forwarding.is_empty());
+        assert!(memory.block_count() < blocks);
+        assert_eq!(memory.live_objects().len(), live);
+
+        // Follow the chain starting from the processes, through the moved pointers
+        let forward = |ptr: ObjectPointer| forwarding.get(&ptr).copied().unwrap_or(ptr);
+        let second = forward(second);
+        assert_eq!(memory.get::<Process>(second)?.prev(), Some(forward(first)));
+
+        let mut current = memory.object(second)?.references()[0];
+        for i in (0..12).rev() {
+            assert_eq!(current, forward(strings[i]));
+            let string = memory.get::<StringObject>(current)?;
+            current = string.references()[0];
+        }
+        assert!(current.is_null());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_releases_references_to_survivors() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let survivor = memory.allocate(StringObject::new("survivor".to_string(), ObjectPointer::null()))?;
+        let garbage = memory.allocate(StringObject::new("garbage".to_string(), survivor))?;
+        memory.increment_ref(survivor)?;
+        memory.increment_ref(survivor)?;
+
+        assert_eq!(memory.collect_garbage(&[survivor])?, 1);
+        assert_eq!(memory.ref_count(garbage), Err(MemoryError::FreedObject(garbage)));
+        assert_eq!(memory.ref_count(survivor)?, 1);
+
+        Ok(())
+    }
+}
@@ -1,12 +1,42 @@
-use std::{alloc::Layout, fmt::Debug};
+use std::{alloc::Layout, fmt::{self, Debug, Display}};
 use std::ops::{Index, IndexMut};
-use crate::objects::object::{ ObjectHeader, ObjectPointer, Pointer, ValidObject };
+use crate::objects::object::{ MAX_BLOCK_ELEMENTS, MAX_BLOCKS, ObjectPointer, Pointer, ValidObject };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    NullPointer,
+    InvalidBlock(usize),
+    InvalidOffset(usize),
+    FreedObject(ObjectPointer),
+    WrongType(ObjectPointer),
+    IndexOutOfBounds(usize),
+    OutOfMemory,
+}
+
+impl Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryError::NullPointer => write!(f, "Dereferencing a null pointer"),
+            MemoryError::InvalidBlock(index) => write!(f, "Invalid block index: {}", index),
+            MemoryError::InvalidOffset(offset) => write!(f, "Invalid offset: {}", offset),
+            MemoryError::FreedObject(ptr) => write!(f, "Pointer to a freed object: {:#x}", ptr),
+            MemoryError::WrongType(ptr) => write!(f, "Not a pointer to a valid object: {:#x}", ptr),
+            MemoryError::IndexOutOfBounds(index) => write!(f, "Index out of bounds: {}", index),
+            MemoryError::OutOfMemory => write!(f, "Out of memory"),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
 
 struct MemBlock<T: ValidObject + Debug> {
     max_elements: usize,
     element_size: usize,
     allocations: usize,
     elements: Vec<u8>,
+    // Tracks which slots hold a live object. Freed slots have their first bytes
+    // overwritten by the free list, so the header alone can't be trusted
+    live: Vec<bool>,
     _marker: std::marker::PhantomData<T>,
 }
 
@@ -21,6 +51,7 @@ impl<T> MemBlock<T>
             element_size,
             allocations: 0,
             elements: vec![0; total_size],
+            live: vec![false; max_elements],
             _marker: std::marker::PhantomData,
         }
     }
@@ -30,13 +61,23 @@ impl<T> MemBlock<T>
     }
 
     fn init(&mut self, index: usize, free_list_head: ObjectPointer) -> ObjectPointer {
+        self.link_free_slots(index, free_list_head)
+    }
+
+    // Threads the free slots of the block into a free list, in front of the given one
+    fn link_free_slots(&mut self, index: usize, free_list_head: ObjectPointer) -> ObjectPointer {
         let mut current_head: ObjectPointer = free_list_head;
 
         for i in 0..self.max_elements {
+            if self.live[i] {
+                continue;
+            }
+
             let o_pointer = self.offset_to_pointer(i) as *mut ObjectPointer;
 
+            // Slots are not necessarily aligned for a pointer (e.g. Integer)
             unsafe {
-                *o_pointer = current_head;
+                o_pointer.write_unaligned(current_head);
             }
             current_head = ObjectPointer::new_from_index_and_offset(index, i)
         }
@@ -46,14 +87,29 @@ impl<T> MemBlock<T>
 
     fn emplace(&mut self, offset: usize, value: T) -> ObjectPointer {
         self.allocations += 1;
+        self.live[offset] = true;
         unsafe {
             let ptr = self.offset_to_pointer(offset);
-            let next_free = (ptr as *const ObjectPointer).read();
+            let next_free = (ptr as *const ObjectPointer).read_unaligned();
             let element_ptr = ptr as *mut T;
 
             element_ptr.write(value);
 
-            return next_free;
+            next_free
+        }
+    }
+
+    // Moves the object out of its slot, leaving the slot free. The slot is not linked
+    // into the free list
+    fn take(&mut self, offset: usize) -> T {
+        if !self.live[offset] {
+            panic!("Attempting to move a freed object: {}", offset);
+        }
+
+        self.allocations -= 1;
+        self.live[offset] = false;
+        unsafe {
+            (self.offset_to_pointer(offset) as *const T).read()
         }
     }
 
@@ -63,6 +119,7 @@ impl<T> MemBlock<T>
         }
 
         self.allocations -= 1;
+        self.live[ptr.offset()] = false;
         let o_pointer = self.offset_to_pointer(ptr.offset());
 
         unsafe {
@@ -70,7 +127,7 @@ impl<T> MemBlock<T>
         }
         T::set_invalid(self.get_mut(ptr.offset()));
         unsafe {
-            (o_pointer as *mut ObjectPointer).write(next_free);
+            (o_pointer as *mut ObjectPointer).write_unaligned(next_free);
         }
 
         ptr
@@ -91,6 +148,42 @@ impl<T> MemBlock<T>
 
         &mut self[offset]
     }
+
+    fn live_offsets(&self) -> impl Iterator<Item = usize> + '_ {
+        self.live.iter()
+            .enumerate()
+            .filter_map(|(offset, &live)| live.then_some(offset))
+    }
+
+    fn check(&self, ptr: ObjectPointer) -> Result<usize, MemoryError> {
+        let offset = ptr.offset();
+        let obj_ref = self.get(offset)
+            .ok_or(MemoryError::InvalidOffset(offset))?;
+
+        if !self.live[offset] {
+            Err(MemoryError::FreedObject(ptr))
+        } else if !T::is_valid(obj_ref) {
+            Err(MemoryError::WrongType(ptr))
+        } else {
+            Ok(offset)
+        }
+    }
+}
+
+// Objects still alive when their block goes away are dropped with it, releasing
+// whatever they own (the descriptors of files, for instance)
+impl<T> Drop for MemBlock<T>
+    where T: ValidObject + Debug
+{
+    fn drop(&mut self) {
+        for offset in 0..self.max_elements {
+            if self.live[offset] {
+                unsafe {
+                    (self.offset_to_pointer(offset) as *mut T).drop_in_place();
+                }
+            }
+        }
+    }
 }
 
 impl<T> Index<usize> for MemBlock<T>
@@ -125,11 +218,12 @@ pub trait MemAlloc {
     type Item;
 
     fn allocate(&mut self, value: Self::Item) -> Option<ObjectPointer>;
-    fn deallocate(&mut self, ptr: ObjectPointer) -> Result<(), String>;
-    fn to_type(&self, ptr: ObjectPointer) -> Option<&Self::Item>;
+    fn deallocate(&mut self, ptr: ObjectPointer) -> Result<(), MemoryError>;
+    fn to_type(&self, ptr: ObjectPointer) -> Result<&Self::Item, MemoryError>;
+    fn to_type_mut(&mut self, ptr: ObjectPointer) -> Result<&mut Self::Item, MemoryError>;
 }
 
-struct MemPool<T: ValidObject + Debug> {
+pub struct MemPool<T: ValidObject + Debug> {
     max_elements_per_block: usize,
     free_list: ObjectPointer,
     blocks: Vec<MemBlock<T>>,
@@ -139,6 +233,9 @@ impl<T> MemPool<T>
     where T: ValidObject + Debug
 {
     pub fn new(max_elements_per_block: usize) -> Self {
+        assert!(max_elements_per_block <= MAX_BLOCK_ELEMENTS,
+                "Blocks can't hold more than {} elements", MAX_BLOCK_ELEMENTS);
+
         MemPool::<T> {
             max_elements_per_block,
             free_list: ObjectPointer::null(),
@@ -159,14 +256,121 @@ impl<T> MemPool<T>
         self.blocks.push(block);
     }
 
-    fn get(&self, ptr: ObjectPointer) -> Option<&T> {
+    fn rebuild_free_list(&mut self) {
+        let mut head = ObjectPointer::null();
+        for (index, block) in self.blocks.iter_mut().enumerate() {
+            head = block.link_free_slots(index, head);
+        }
+
+        self.free_list = head;
+    }
+
+    // Returns the empty blocks at the end of the pool to the allocator. Blocks in the
+    // middle are kept, as releasing them would change the index of the ones after
+    pub fn release_empty_blocks(&mut self) -> usize {
+        let initial_blocks = self.blocks.len();
+        while self.blocks.last().is_some_and(|block| block.allocations == 0) {
+            self.blocks.pop();
+        }
+
+        let released = initial_blocks - self.blocks.len();
+        if released > 0 {
+            self.rebuild_free_list();
+        }
+
+        released
+    }
+
+    // Moves the objects in the trailing blocks to the free slots of the first ones,
+    // releasing as many blocks as possible. Returns the list of (old, new) pointers
+    // for the objects that were moved, so that references to them can be updated
+    pub fn compact(&mut self) -> Vec<(ObjectPointer, ObjectPointer)> {
+        let live: usize = self.blocks.iter().map(|block| block.allocations).sum();
+        let needed_blocks = live.div_ceil(self.max_elements_per_block);
+
+        let free_slots = self.blocks[..needed_blocks].iter()
+            .enumerate()
+            .flat_map(|(index, block)| {
+                (0..block.max_elements)
+                    .filter(|&offset| !block.live[offset])
+                    .map(move |offset| ObjectPointer::new_from_index_and_offset(index, offset))
+            });
+        let moves: Vec<(ObjectPointer, ObjectPointer)> = self.live_pointers()
+            .into_iter()
+            .filter(|ptr| ptr.block_index() >= needed_blocks)
+            .zip(free_slots)
+            .collect();
+
+        for &(from, to) in &moves {
+            let value = self.blocks[from.block_index()].take(from.offset());
+            self.blocks[to.block_index()].emplace(to.offset(), value);
+        }
+        self.release_empty_blocks();
+
+        moves
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.max_elements_per_block
+    }
+
+    // Replaces the contents of the pool with the given number of blocks, holding the
+    // objects at the given locations. Used to load images
+    pub fn restore(&mut self, block_count: usize, objects: Vec<(ObjectPointer, T)>) -> Result<(), MemoryError> {
+        if block_count as u64 > MAX_BLOCKS {
+            return Err(MemoryError::InvalidBlock(block_count));
+        }
+
+        self.blocks.clear();
+        for _ in 0..block_count {
+            self.blocks.push(MemBlock::new(self.max_elements_per_block));
+        }
+
+        for (ptr, value) in objects {
+            let block = self.block_index(ptr)?;
+            let offset = ptr.offset();
+            let block = &mut self.blocks[block];
+            if offset >= block.max_elements || block.live[offset] {
+                return Err(MemoryError::InvalidOffset(offset));
+            }
+            block.emplace(offset, value);
+        }
+        self.rebuild_free_list();
+
+        Ok(())
+    }
+
+    fn block_index(&self, ptr: ObjectPointer) -> Result<usize, MemoryError> {
+        if ptr.is_null() {
+            return Err(MemoryError::NullPointer);
+        }
+
         let block_index = ptr.block_index();
         if block_index >= self.blocks.len() {
-            return None;
+            Err(MemoryError::InvalidBlock(block_index))
         } else {
-            self.blocks[block_index].get(ptr.offset())
+            Ok(block_index)
         }
     }
+
+    pub fn get(&self, ptr: ObjectPointer) -> Option<&T> {
+        self.to_type(ptr).ok()
+    }
+
+    // Pointers to every object currently allocated in the pool
+    pub fn live_pointers(&self) -> Vec<ObjectPointer> {
+        self.blocks.iter()
+            .enumerate()
+            .flat_map(|(index, block)| {
+                block.live_offsets()
+                    .map(move |offset| ObjectPointer::new_from_index_and_offset(index, offset))
+            })
+            .collect()
+    }
 }
 
 impl<T> MemAlloc for MemPool<T>
@@ -176,6 +380,9 @@ impl<T> MemAlloc for MemPool<T>
 
     fn allocate(&mut self, value: T) -> Option<ObjectPointer> {
         if self.free_list.is_null() {
+            if self.blocks.len() as u64 >= MAX_BLOCKS {
+                return None;
+            }
             self.add_block();
         }
 
@@ -186,27 +393,28 @@ impl<T> MemAlloc for MemPool<T>
         Some(target)
     }
 
-    fn deallocate(&mut self, ptr: ObjectPointer) -> Result<(), String> {
-        let block_index = ptr.block_index();
-        if block_index >= self.blocks.len() {
-            return Err(format!("Invalid block index: {}", block_index));
-        }
-
+    fn deallocate(&mut self, ptr: ObjectPointer) -> Result<(), MemoryError> {
+        let block_index = self.block_index(ptr)?;
         let block = &mut self.blocks[block_index];
-        let offset = ptr.offset();
-        let obj_ref = block.get(offset)
-            .ok_or("Invalid offset".to_string())?;
-        if T::is_valid(obj_ref) {
-            self.free_list = block.drop(ptr, self.free_list);
+        block.check(ptr)?;
+        self.free_list = block.drop(ptr, self.free_list);
 
-            Ok(())
-        } else {
-            Err(String::from("Not a pointer to a valid object"))
-        }
+        Ok(())
+    }
+
+    fn to_type(&self, ptr: ObjectPointer) -> Result<&Self::Item, MemoryError> {
+        let block = &self.blocks[self.block_index(ptr)?];
+        let offset = block.check(ptr)?;
+
+        Ok(&block[offset])
     }
 
-    fn to_type(&self, _ptr: ObjectPointer) -> Option<&Self::Item> {
-        todo!()
+    fn to_type_mut(&mut self, ptr: ObjectPointer) -> Result<&mut Self::Item, MemoryError> {
+        let block_index = self.block_index(ptr)?;
+        let block = &mut self.blocks[block_index];
+        let offset = block.check(ptr)?;
+
+        Ok(&mut block[offset])
     }
 }
 
@@ -219,38 +427,30 @@ mod tests {
         byte::ByteArray,
     };
 
+    // Free list pointers are 8 bytes long and written at the start of each 12 byte
+    // Integer slot
     static INITIALIZED_INTEGER_POOL: &[u8] = &[
-        255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0,
+        255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0,
         0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     static SECOND_INTEGER_POOL: &[u8] = &[
-        9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        2, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        3, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        4, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        5, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        6, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        7, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        8, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-
-    fn integer_to_bytes(i: Integer) -> Vec<u8> {
-        let integer_size = Layout::new::<Integer>().size();
-        unsafe {
-            std::slice::from_raw_parts(
-                (&i as *const Integer) as *const u8,
-                integer_size,
-            ).to_vec()
-        }
-    }
+        0, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 2, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 3, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 4, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 6, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 7, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+        0, 8, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
 
     fn initialize_block<T>(max_elements: usize) -> (MemBlock<T>, ObjectPointer)
         where T: ValidObject + Debug
@@ -365,4 +565,145 @@ mod tests {
         assert_eq!(pool.blocks.len(), 2);
         assert_eq!(pool.free_list, ObjectPointer::new_from_index_and_offset(1, 8));
     }
+
+    #[test]
+    fn test_mem_pool_live_pointers() -> Result<(), MemoryError> {
+        let mut pool: MemPool<Integer> = MemPool::new(4);
+        let pointers: Vec<_> = (0..6)
+            .map(|i| pool.allocate(Integer::new(i)).unwrap())
+            .collect();
+
+        pool.deallocate(pointers[1])?;
+        pool.deallocate(pointers[4])?;
+
+        let mut live = pool.live_pointers();
+        live.sort();
+        let mut expected = vec![pointers[0], pointers[2], pointers[3], pointers[5]];
+        expected.sort();
+        assert_eq!(live, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_pool_release_empty_blocks() -> Result<(), MemoryError> {
+        let mut pool: MemPool<Integer> = MemPool::new(4);
+        let pointers: Vec<_> = (0..10)
+            .map(|i| pool.allocate(Integer::new(i)).unwrap())
+            .collect();
+        assert_eq!(pool.block_count(), 3);
+
+        // The middle block can't be released while the last one is in use
+        for &ptr in &pointers[4..8] {
+            pool.deallocate(ptr)?;
+        }
+        assert_eq!(pool.release_empty_blocks(), 0);
+
+        for &ptr in &pointers[8..] {
+            pool.deallocate(ptr)?;
+        }
+        assert_eq!(pool.release_empty_blocks(), 2);
+        assert_eq!(pool.block_count(), 1);
+
+        // The free list doesn't point into released blocks anymore
+        for i in 0..4 {
+            let ptr = pool.allocate(Integer::new(i)).unwrap();
+            assert!(ptr.block_index() < 2);
+        }
+        assert_eq!(pool.block_count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_pool_compact() -> Result<(), MemoryError> {
+        let mut pool: MemPool<Symbol> = MemPool::new(4);
+        let pointers: Vec<_> = (0..12)
+            .map(|i| pool.allocate(Symbol::new(format!("s{}", i))).unwrap())
+            .collect();
+        for i in [0, 2, 5, 6, 7, 9] {
+            pool.deallocate(pointers[i])?;
+        }
+
+        let moves = pool.compact();
+        assert_eq!(moves.len(), 3);
+        assert_eq!(pool.block_count(), 2);
+
+        let mut remaining = 0;
+        for i in [1, 3, 4, 8, 10, 11] {
+            let ptr = moves.iter()
+                .find(|(from, _)| *from == pointers[i])
+                .map_or(pointers[i], |&(_, to)| to);
+            assert_eq!(pool.to_type(ptr)?, &Symbol::new(format!("s{}", i)));
+            remaining += 1;
+        }
+        assert_eq!(pool.live_pointers().len(), remaining);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_pool_to_type() -> Result<(), MemoryError> {
+        let mut pool: MemPool<Symbol> = MemPool::new(10);
+        let first = pool.allocate(Symbol::new("first".to_string())).unwrap();
+        let second = pool.allocate(Symbol::new("second".to_string())).unwrap();
+
+        assert_eq!(pool.to_type(first)?, &Symbol::new("first".to_string()));
+        assert_eq!(pool.to_type(second)?, &Symbol::new("second".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_pool_to_type_mut() -> Result<(), MemoryError> {
+        let mut pool: MemPool<ByteArray> = MemPool::new(10);
+        let ptr = pool.allocate(ByteArray::new(vec![1, 2, 3])).unwrap();
+
+        *pool.to_type_mut(ptr)? = ByteArray::new(vec![4, 5]);
+        assert_eq!(pool.to_type(ptr)?, &ByteArray::new(vec![4, 5]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_pool_to_type_invalid_pointers() {
+        let mut pool: MemPool<Integer> = MemPool::new(10);
+        pool.allocate(Integer::new(42));
+
+        assert_eq!(pool.to_type(ObjectPointer::null()), Err(MemoryError::NullPointer));
+        assert_eq!(
+            pool.to_type(ObjectPointer::new_from_index_and_offset(1, 0)),
+            Err(MemoryError::InvalidBlock(1))
+        );
+        assert_eq!(
+            pool.to_type(ObjectPointer::new_from_index_and_offset(0, 10)),
+            Err(MemoryError::InvalidOffset(10))
+        );
+    }
+
+    #[test]
+    fn test_mem_pool_to_type_freed() -> Result<(), MemoryError> {
+        let mut pool: MemPool<Integer> = MemPool::new(10);
+        let ptr = pool.allocate(Integer::new(42)).unwrap();
+        let never_used = ObjectPointer::new_from_index_and_offset(0, 0);
+
+        pool.deallocate(ptr)?;
+        assert_eq!(pool.to_type(ptr), Err(MemoryError::FreedObject(ptr)));
+        assert_eq!(pool.to_type(never_used), Err(MemoryError::FreedObject(never_used)));
+        assert_eq!(pool.deallocate(ptr), Err(MemoryError::FreedObject(ptr)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mem_pool_to_type_wrong_type() -> Result<(), MemoryError> {
+        let mut pool: MemPool<Integer> = MemPool::new(10);
+        let ptr = pool.allocate(Integer::new(42)).unwrap();
+
+        Integer::set_invalid(pool.to_type_mut(ptr)?);
+        assert_eq!(pool.to_type(ptr), Err(MemoryError::WrongType(ptr)));
+        assert!(pool.to_type_mut(ptr).is_err());
+
+        Ok(())
+    }
 }
@@ -1 +1,7 @@
+mod gc;
 mod memory_pool;
+mod object_memory;
+mod ref_count;
+
+pub use memory_pool::{MemAlloc, MemPool, MemoryError};
+pub use object_memory::{ForwardingTable, MemoryObject, ObjectMemory, ObjectRef, ObjectRefMut, POOL_BLOCK_SIZE};
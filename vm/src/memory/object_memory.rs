@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+
+use crate::objects::{
+    block::Block,
+    byte::ByteArray,
+    character::Char,
+    class::Class,
+    file::File,
+    interp::Interpreter,
+    number::{Float, Fraction, Integer, LargeInteger},
+    object::{Object, ObjectHeader, ObjectPointer, ObjectReferences, ObjectType, Pointer, ValidObject},
+    process::Process,
+    semaphore::Semaphore,
+    string::StringObject,
+    symbol::Symbol,
+};
+use super::memory_pool::{MemAlloc, MemPool, MemoryError};
+
+// Number of objects in each block allocated by the pools
+pub const POOL_BLOCK_SIZE: usize = 1024;
+
+// Maps the old location of objects moved during compaction to the new one
+pub type ForwardingTable = HashMap<ObjectPointer, ObjectPointer>;
+
+// Types that can be stored in the object memory. Each of them lives in its own pool
+pub trait MemoryObject: ValidObject + ObjectReferences + std::fmt::Debug + Sized {
+    const TYPE: ObjectType;
+
+    fn pool(memory: &ObjectMemory) -> &MemPool<Self>;
+    fn pool_mut(memory: &mut ObjectMemory) -> &mut MemPool<Self>;
+}
+
+macro_rules! object_memory {
+    ($($variant:ident => $ty:ty, $pool:ident;)*) => {
+        #[derive(Debug)]
+        pub enum ObjectRef<'a> {
+            SmallInteger(i32),
+            $($variant(&'a $ty),)*
+        }
+
+        #[derive(Debug)]
+        pub enum ObjectRefMut<'a> {
+            $($variant(&'a mut $ty),)*
+        }
+
+        impl ObjectRef<'_> {
+            // Immediate values have no header
+            pub fn header(&self) -> Option<&ObjectHeader> {
+                match self {
+                    ObjectRef::SmallInteger(_) => None,
+                    $(ObjectRef::$variant(obj) => Some(<$ty>::header(obj)),)*
+                }
+            }
+
+            pub fn references(&self) -> Vec<ObjectPointer> {
+                match self {
+                    ObjectRef::SmallInteger(_) => vec![],
+                    $(ObjectRef::$variant(obj) => obj.references(),)*
+                }
+            }
+        }
+
+        impl<'a> ObjectRefMut<'a> {
+            pub fn header_mut(self) -> &'a mut ObjectHeader {
+                match self {
+                    $(ObjectRefMut::$variant(obj) => <$ty>::header_mut(obj),)*
+                }
+            }
+
+            pub fn update_references(self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+                match self {
+                    $(ObjectRefMut::$variant(obj) => obj.update_references(update),)*
+                }
+            }
+        }
+
+        // Facade over the per-type memory pools.
+        //
+        // Each pool hands out untyped pointers relative to its own vector of blocks.
+        // The object memory tags them with the type of the pool, so that a bare
+        // pointer can be routed back to the pool that owns it.
+        pub struct ObjectMemory {
+            $($pool: MemPool<$ty>,)*
+        }
+
+        $(
+            impl MemoryObject for $ty {
+                const TYPE: ObjectType = ObjectType::$variant;
+
+                fn pool(memory: &ObjectMemory) -> &MemPool<Self> {
+                    &memory.$pool
+                }
+
+                fn pool_mut(memory: &mut ObjectMemory) -> &mut MemPool<Self> {
+                    &mut memory.$pool
+                }
+            }
+        )*
+
+        impl ObjectMemory {
+            pub fn new() -> Self {
+                Self::with_block_size(POOL_BLOCK_SIZE)
+            }
+
+            pub fn with_block_size(block_size: usize) -> Self {
+                ObjectMemory {
+                    $($pool: MemPool::new(block_size),)*
+                }
+            }
+
+            pub fn block_count(&self) -> usize {
+                0 $(+ self.$pool.block_count())*
+            }
+
+            pub fn release_empty_blocks(&mut self) -> usize {
+                0 $(+ self.$pool.release_empty_blocks())*
+            }
+
+            pub(super) fn compact_pools(&mut self) -> ForwardingTable {
+                let mut forwarding = ForwardingTable::new();
+                $(
+                    forwarding.extend(self.$pool.compact()
+                                          .into_iter()
+                                          .map(|(from, to)| (from.tagged(ObjectType::$variant),
+                                                             to.tagged(ObjectType::$variant))));
+                )*
+                forwarding
+            }
+
+            pub fn object(&self, ptr: ObjectPointer) -> Result<ObjectRef<'_>, MemoryError> {
+                if let Some(value) = ptr.small_integer() {
+                    return Ok(ObjectRef::SmallInteger(value));
+                }
+
+                match Self::pool_type(ptr)? {
+                    $(ObjectType::$variant => self.$pool.to_type(ptr).map(ObjectRef::$variant),)*
+                }
+            }
+
+            pub fn object_mut(&mut self, ptr: ObjectPointer) -> Result<ObjectRefMut<'_>, MemoryError> {
+                match Self::pool_type(ptr)? {
+                    $(ObjectType::$variant => self.$pool.to_type_mut(ptr).map(ObjectRefMut::$variant),)*
+                }
+            }
+
+            // Pointers to every object allocated in any of the pools
+            pub fn live_objects(&self) -> Vec<ObjectPointer> {
+                let mut live = vec![];
+                $(
+                    live.extend(self.$pool.live_pointers()
+                                    .into_iter()
+                                    .map(|ptr| ptr.tagged(ObjectType::$variant)));
+                )*
+                live
+            }
+
+            pub fn deallocate(&mut self, ptr: ObjectPointer) -> Result<(), MemoryError> {
+                match Self::pool_type(ptr)? {
+                    $(ObjectType::$variant => self.$pool.deallocate(ptr),)*
+                }
+            }
+        }
+    };
+}
+
+object_memory! {
+    Integer => Integer, integers;
+    Float => Float, floats;
+    LargeInteger => LargeInteger, large_integers;
+    Fraction => Fraction, fractions;
+    Symbol => Symbol, symbols;
+    ByteArray => ByteArray, byte_arrays;
+    String => StringObject, strings;
+    Block => Block, blocks;
+    Class => Class, classes;
+    Interpreter => Interpreter, interpreters;
+    Process => Process, processes;
+    Semaphore => Semaphore, semaphores;
+    File => File, files;
+    Char => Char, chars;
+    Object => Object, objects;
+}
+
+impl ObjectMemory {
+    // Number of objects in each block. All pools use the same
+    pub fn block_size(&self) -> usize {
+        self.objects.block_size()
+    }
+
+    pub fn allocate<T: MemoryObject>(&mut self, value: T) -> Result<ObjectPointer, MemoryError> {
+        let ptr = T::pool_mut(self).allocate(value)
+            .ok_or(MemoryError::OutOfMemory)?;
+
+        Ok(ptr.tagged(T::TYPE))
+    }
+
+    pub fn get<T: MemoryObject>(&self, ptr: ObjectPointer) -> Result<&T, MemoryError> {
+        Self::check_type::<T>(ptr)?;
+
+        T::pool(self).to_type(ptr)
+    }
+
+    pub fn get_mut<T: MemoryObject>(&mut self, ptr: ObjectPointer) -> Result<&mut T, MemoryError> {
+        Self::check_type::<T>(ptr)?;
+
+        T::pool_mut(self).to_type_mut(ptr)
+    }
+
+    // Integers are stored as immediate values, so creating one never allocates
+    pub fn new_integer(&self, value: i32) -> ObjectPointer {
+        ObjectPointer::from_small_integer(value)
+    }
+
+    pub fn integer_value(&self, ptr: ObjectPointer) -> Result<i32, MemoryError> {
+        match ptr.small_integer() {
+            Some(value) => Ok(value),
+            None => self.get::<Integer>(ptr).map(Integer::value),
+        }
+    }
+
+    // Creates an instance of a class. Its size is the number of instance variables
+    // defined by the class. The ones inherited from superclasses are kept in the
+    // chain of super objects, which is instantiated as well
+    pub fn instantiate(&mut self, class: ObjectPointer) -> Result<ObjectPointer, MemoryError> {
+        let class_ref = self.get::<Class>(class)?;
+        let (super_class, c_inst_vars) = (class_ref.super_class(), class_ref.c_inst_vars());
+
+        let size = if c_inst_vars.is_null() {
+            0
+        } else {
+            self.get::<Object>(c_inst_vars)?.size()
+        };
+        let super_obj = if super_class.is_null() {
+            ObjectPointer::null()
+        } else {
+            self.instantiate(super_class)?
+        };
+
+        self.increment_ref(class)?;
+        self.increment_ref(super_obj)?;
+        self.allocate(Object::new(class, super_obj, size))
+    }
+
+    pub fn inst_var(&self, obj: ObjectPointer, index: usize) -> Result<ObjectPointer, MemoryError> {
+        self.get::<Object>(obj)?
+            .inst_var(index)
+            .ok_or(MemoryError::IndexOutOfBounds(index))
+    }
+
+    // Stores a pointer in one of the instance variables of an object, keeping the
+    // reference counts up to date
+    pub fn put_inst_var(&mut self, obj: ObjectPointer, index: usize, value: ObjectPointer) -> Result<(), MemoryError> {
+        if index >= self.get::<Object>(obj)?.size() {
+            return Err(MemoryError::IndexOutOfBounds(index));
+        }
+
+        self.increment_ref(value)?;
+        let old_value = self.get_mut::<Object>(obj)?
+            .set_inst_var(index, value)
+            .ok_or(MemoryError::IndexOutOfBounds(index))?;
+        self.decrement_ref(old_value)
+    }
+
+    // Replaces an instance variable with null, returning the previous value without
+    // releasing it. The reference held by the object is handed to the caller
+    pub fn take_inst_var(&mut self, obj: ObjectPointer, index: usize) -> Result<ObjectPointer, MemoryError> {
+        self.get_mut::<Object>(obj)?
+            .set_inst_var(index, ObjectPointer::null())
+            .ok_or(MemoryError::IndexOutOfBounds(index))
+    }
+
+    pub fn object_type(&self, ptr: ObjectPointer) -> Result<ObjectType, MemoryError> {
+        if ptr.is_small_integer() {
+            Ok(ObjectType::Integer)
+        } else {
+            Self::pool_type(ptr)
+        }
+    }
+
+    fn pool_type(ptr: ObjectPointer) -> Result<ObjectType, MemoryError> {
+        if ptr.is_null() {
+            return Err(MemoryError::NullPointer);
+        }
+
+        ptr.object_type().ok_or(MemoryError::WrongType(ptr))
+    }
+
+    fn check_type<T: MemoryObject>(ptr: ObjectPointer) -> Result<(), MemoryError> {
+        match Self::pool_type(ptr)? {
+            object_type if object_type == T::TYPE => Ok(()),
+            _ => Err(MemoryError::WrongType(ptr)),
+        }
+    }
+}
+
+impl Default for ObjectMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_memory_allocate_and_get() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let int_ptr = memory.allocate(Integer::new(42))?;
+        let sym_ptr = memory.allocate(Symbol::new("foo".to_string()))?;
+
+        assert_ne!(int_ptr, sym_ptr);
+        assert_eq!(memory.get::<Integer>(int_ptr)?, &Integer::new(42));
+        assert_eq!(memory.get::<Symbol>(sym_ptr)?, &Symbol::new("foo".to_string()));
+        assert_eq!(memory.get::<Symbol>(int_ptr), Err(MemoryError::WrongType(int_ptr)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_memory_routes_bare_pointers() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let pointers = [
+            memory.allocate(Integer::new(1))?,
+            memory.allocate(ByteArray::new(vec![1, 2]))?,
+            memory.allocate(Class::new())?,
+            memory.allocate(StringObject::new("bar".to_string(), ObjectPointer::null()))?,
+        ];
+
+        assert!(matches!(memory.object(pointers[0])?, ObjectRef::Integer(i) if *i == Integer::new(1)));
+        assert!(matches!(memory.object(pointers[1])?, ObjectRef::ByteArray(_)));
+        assert!(matches!(memory.object(pointers[2])?, ObjectRef::Class(_)));
+        assert!(matches!(memory.object_mut(pointers[3])?, ObjectRefMut::String(_)));
+        assert_eq!(memory.object_type(pointers[3])?, ObjectType::String);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_memory_get_mut() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let ptr = memory.allocate(ByteArray::new(vec![1, 2, 3]))?;
+
+        *memory.get_mut::<ByteArray>(ptr)? = ByteArray::new(vec![4]);
+        assert_eq!(memory.get::<ByteArray>(ptr)?, &ByteArray::new(vec![4]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_memory_deallocate() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let ptr = memory.allocate(Float::new(1.5))?;
+
+        memory.deallocate(ptr)?;
+        assert!(matches!(memory.object(ptr), Err(MemoryError::FreedObject(p)) if p == ptr));
+        assert_eq!(memory.deallocate(ptr), Err(MemoryError::FreedObject(ptr)));
+        assert_eq!(memory.object_type(ObjectPointer::null()), Err(MemoryError::NullPointer));
+
+        Ok(())
+    }
+
+    // Builds a class whose instances have the given number of instance variables
+    fn class_with_vars(memory: &mut ObjectMemory, super_class: ObjectPointer, vars: usize) -> Result<ObjectPointer, MemoryError> {
+        let names = memory.allocate(Object::new(ObjectPointer::null(), ObjectPointer::null(), vars))?;
+        let mut class = Class::new();
+        class.set_super_class(super_class);
+        class.set_c_inst_vars(names);
+        memory.increment_ref(super_class)?;
+        memory.increment_ref(names)?;
+
+        memory.allocate(class)
+    }
+
+    #[test]
+    fn test_object_memory_instantiate() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let root = memory.allocate(Class::new())?;
+        let parent = class_with_vars(&mut memory, root, 2)?;
+        let child = class_with_vars(&mut memory, parent, 3)?;
+
+        let obj = memory.instantiate(child)?;
+        let instance = memory.get::<Object>(obj)?;
+        assert_eq!(instance.class(), child);
+        assert_eq!(instance.size(), 3);
+
+        let super_obj = memory.get::<Object>(instance.super_obj())?;
+        assert_eq!(super_obj.class(), parent);
+        assert_eq!(super_obj.size(), 2);
+
+        let root_obj = memory.get::<Object>(super_obj.super_obj())?;
+        assert_eq!(root_obj.class(), root);
+        assert_eq!(root_obj.size(), 0);
+        assert!(root_obj.super_obj().is_null());
+
+        assert_eq!(memory.instantiate(obj), Err(MemoryError::WrongType(obj)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_memory_inst_vars() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let class = class_with_vars(&mut memory, ObjectPointer::null(), 2)?;
+        let obj = memory.instantiate(class)?;
+        let symbol = memory.allocate(Symbol::new("value".to_string()))?;
+
+        memory.put_inst_var(obj, 0, symbol)?;
+        memory.put_inst_var(obj, 1, memory.new_integer(5))?;
+        assert_eq!(memory.inst_var(obj, 0)?, symbol);
+        assert_eq!(memory.integer_value(memory.inst_var(obj, 1)?)?, 5);
+        assert_eq!(memory.ref_count(symbol)?, 1);
+        assert_eq!(memory.inst_var(obj, 2), Err(MemoryError::IndexOutOfBounds(2)));
+        assert_eq!(memory.put_inst_var(obj, 2, symbol), Err(MemoryError::IndexOutOfBounds(2)));
+
+        // Overwriting the variable releases the symbol
+        memory.put_inst_var(obj, 0, ObjectPointer::null())?;
+        assert_eq!(memory.ref_count(symbol), Err(MemoryError::FreedObject(symbol)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_memory_small_integers() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let small = memory.new_integer(-7);
+        let boxed = memory.allocate(Integer::new(7))?;
+
+        assert!(matches!(memory.object(small)?, ObjectRef::SmallInteger(-7)));
+        assert_eq!(memory.object_type(small)?, ObjectType::Integer);
+        assert_eq!(memory.object_type(boxed)?, ObjectType::Integer);
+        assert_eq!(memory.integer_value(small)?, -7);
+        assert_eq!(memory.integer_value(boxed)?, 7);
+        assert_eq!(memory.get::<Integer>(small), Err(MemoryError::WrongType(small)));
+        assert!(memory.object_mut(small).is_err());
+        assert!(memory.deallocate(small).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_memory_untyped_pointers() {
+        let memory = ObjectMemory::new();
+        let untyped = ObjectPointer::new_from_index_and_offset(0, 0);
+
+        assert!(matches!(memory.object(untyped), Err(MemoryError::WrongType(_))));
+        assert_eq!(memory.object_type(untyped), Err(MemoryError::WrongType(untyped)));
+    }
+
+    #[test]
+    fn test_object_memory_pools_grow_independently() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let mut integers = vec![];
+        for i in 0..(POOL_BLOCK_SIZE as i32 + 1) {
+            integers.push(memory.allocate(Integer::new(i))?);
+            memory.allocate(Symbol::new(format!("s{}", i)))?;
+        }
+
+        for (i, ptr) in integers.into_iter().enumerate() {
+            assert_eq!(memory.get::<Integer>(ptr)?, &Integer::new(i as i32));
+        }
+
+        Ok(())
+    }
+}
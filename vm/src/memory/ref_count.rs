@@ -0,0 +1,172 @@
+// Reference counting
+//
+// Every object header keeps a count of the pointers referencing the object. As in
+// the original Little Smalltalk, objects are reclaimed as soon as that count drops
+// to zero, releasing in turn the objects they point to.
+//
+// Newly allocated objects start with a count of zero. Whoever stores a pointer to
+// an object is responsible for incrementing its count, and for decrementing it
+// once the pointer is overwritten or dropped.
+
+use crate::objects::object::{ObjectPointer, Pointer, RefCount};
+use super::memory_pool::MemoryError;
+use super::object_memory::{MemoryObject, ObjectMemory};
+
+impl ObjectMemory {
+    pub fn ref_count(&self, ptr: ObjectPointer) -> Result<RefCount, MemoryError> {
+        self.object(ptr)?
+            .header()
+            .map(|header| header.ref_count())
+            .ok_or(MemoryError::WrongType(ptr))
+    }
+
+    // Allocates an object, taking a reference to every object it points to
+    pub fn allocate_counted<T: MemoryObject>(&mut self, value: T) -> Result<ObjectPointer, MemoryError> {
+        for ptr in value.references() {
+            self.increment_ref(ptr)?;
+        }
+
+        self.allocate(value)
+    }
+
+    pub fn increment_ref(&mut self, ptr: ObjectPointer) -> Result<(), MemoryError> {
+        if Self::is_counted(ptr) {
+            self.object_mut(ptr)?.header_mut().increment_ref_count();
+        }
+
+        Ok(())
+    }
+
+    // Decrements the reference count of an object, deallocating it if it's not
+    // referenced anymore. Objects pointed to from a deallocated one are released
+    // as well. This is done iteratively, to support long chains of objects
+    pub fn decrement_ref(&mut self, ptr: ObjectPointer) -> Result<(), MemoryError> {
+        let mut pending = vec![ptr];
+
+        while let Some(ptr) = pending.pop() {
+            if !Self::is_counted(ptr) {
+                continue;
+            }
+
+            if self.object_mut(ptr)?.header_mut().decrement_ref_count() == 0 {
+                pending.extend(self.object(ptr)?.references());
+                self.deallocate(ptr)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Null pointers and immediate values don't refer to objects in memory
+    fn is_counted(ptr: ObjectPointer) -> bool {
+        !(ptr.is_null() || ptr.is_small_integer())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::{
+        block::Block,
+        string::StringObject,
+        symbol::Symbol,
+    };
+
+    fn string(memory: &mut ObjectMemory, value: &str, super_obj: ObjectPointer) -> Result<ObjectPointer, MemoryError> {
+        memory.increment_ref(super_obj)?;
+        memory.allocate(StringObject::new(value.to_string(), super_obj))
+    }
+
+    #[test]
+    fn test_ref_count_increment_and_decrement() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let ptr = memory.allocate(Symbol::new("foo".to_string()))?;
+
+        assert_eq!(memory.ref_count(ptr)?, 0);
+        memory.increment_ref(ptr)?;
+        memory.increment_ref(ptr)?;
+        assert_eq!(memory.ref_count(ptr)?, 2);
+        memory.decrement_ref(ptr)?;
+        assert_eq!(memory.ref_count(ptr)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_count_reclaims_at_zero() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let ptr = memory.allocate(Symbol::new("foo".to_string()))?;
+
+        memory.increment_ref(ptr)?;
+        memory.decrement_ref(ptr)?;
+        assert_eq!(memory.ref_count(ptr), Err(MemoryError::FreedObject(ptr)));
+
+        // The slot gets reused by the next allocation
+        assert_eq!(memory.allocate(Symbol::new("bar".to_string()))?, ptr);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_count_releases_references() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let first = string(&mut memory, "first", ObjectPointer::null())?;
+        let second = string(&mut memory, "second", first)?;
+        let third = string(&mut memory, "third", second)?;
+        let block = memory.allocate(Block::new(third, 0, 0))?;
+        memory.increment_ref(third)?;
+
+        memory.increment_ref(block)?;
+        memory.decrement_ref(block)?;
+
+        for ptr in [block, third, second, first] {
+            assert_eq!(memory.ref_count(ptr), Err(MemoryError::FreedObject(ptr)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_count_keeps_shared_references() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let shared = string(&mut memory, "shared", ObjectPointer::null())?;
+        let first = string(&mut memory, "first", shared)?;
+        let second = string(&mut memory, "second", shared)?;
+        memory.increment_ref(first)?;
+        memory.increment_ref(second)?;
+
+        memory.decrement_ref(first)?;
+        assert_eq!(memory.ref_count(shared)?, 1);
+        memory.decrement_ref(second)?;
+        assert_eq!(memory.ref_count(shared), Err(MemoryError::FreedObject(shared)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_count_allocate_counted() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let first = memory.allocate(Symbol::new("first".to_string()))?;
+        let block = memory.allocate_counted(Block::new(first, 0, 0))?;
+
+        assert_eq!(memory.ref_count(first)?, 1);
+        memory.increment_ref(block)?;
+        memory.decrement_ref(block)?;
+        assert_eq!(memory.ref_count(first), Err(MemoryError::FreedObject(first)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ref_count_ignores_immediates() -> Result<(), MemoryError> {
+        let mut memory = ObjectMemory::new();
+        let small = memory.new_integer(3);
+
+        memory.increment_ref(small)?;
+        memory.decrement_ref(small)?;
+        memory.decrement_ref(ObjectPointer::null())?;
+        assert_eq!(memory.ref_count(small), Err(MemoryError::WrongType(small)));
+
+        Ok(())
+    }
+}
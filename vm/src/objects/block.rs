@@ -1,8 +1,15 @@
+use std::io::{Read, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
 use super::object::{
     BLOCKSIZE,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectPointer, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
+#[derive(Debug, ValidSmalltalkObject)]
 pub struct Block {
     header: ObjectHeader,
     interpreter: ObjectPointer,
@@ -10,6 +17,28 @@ pub struct Block {
     arglocation: u32,
 }
 
+impl ObjectReferences for Block {
+    fn references(&self) -> Vec<ObjectPointer> {
+        vec![self.interpreter]
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.interpreter = update(self.interpreter);
+    }
+}
+
+impl ImageObject for Block {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.pointer(self.interpreter)?;
+        writer.u32(self.numargs)?;
+        writer.u32(self.arglocation)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Block::new(reader.pointer()?, reader.u32()?, reader.u32()?))
+    }
+}
+
 impl Block {
     const SIZE: ObjectSize = BLOCKSIZE;
 
@@ -21,4 +50,18 @@ impl Block {
             arglocation,
         }
     }
+
+    // Template for the contexts evaluating the block
+    pub fn interpreter(&self) -> ObjectPointer {
+        self.interpreter
+    }
+
+    pub fn numargs(&self) -> u32 {
+        self.numargs
+    }
+
+    // Index of the temporary where the first argument is stored
+    pub fn arglocation(&self) -> u32 {
+        self.arglocation
+    }
 }
@@ -1,10 +1,13 @@
+use std::io::{Read, Write};
+
 use proc_macros::ValidSmalltalkObject;
 
 use super::object::{
     BYTEARRAYSIZE,
-    ValidObject,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
 #[derive(Debug, ValidSmalltalkObject)]
 pub struct ByteArray {
@@ -12,6 +15,18 @@ pub struct ByteArray {
     value: Vec<u8>,
 }
 
+impl ObjectReferences for ByteArray {}
+
+impl ImageObject for ByteArray {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.bytes(&self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(ByteArray::new(reader.bytes()?))
+    }
+}
+
 impl ByteArray {
     const SIZE: ObjectSize = BYTEARRAYSIZE;
 
@@ -21,6 +36,10 @@ impl ByteArray {
             value,
         }
     }
+
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
 }
 
 impl PartialEq for ByteArray {
@@ -0,0 +1,81 @@
+use std::io::{Read, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
+use super::object::{
+    CHARSIZE,
+    ObjectReferences, ValidObject,
+    ObjectHeader, ObjectSize,
+};
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
+
+// Number of Chars the virtual machine preallocates, one for each byte value. They
+// are the only Chars with those values
+pub const PREALLOCATED_CHARS: usize = 256;
+
+// Character, by its Unicode code point. Codes up to 255 are also bytes
+#[derive(Debug, ValidSmalltalkObject)]
+pub struct Char {
+    header: ObjectHeader,
+    value: u32,
+}
+
+impl ObjectReferences for Char {}
+
+impl ImageObject for Char {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.u32(self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Char::new(reader.u32()?))
+    }
+}
+
+impl Char {
+    const SIZE: ObjectSize = CHARSIZE;
+
+    pub fn new(value: u32) -> Self {
+        Char {
+            header: ObjectHeader::new(Self::SIZE),
+            value,
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    // Codes that aren't valid Unicode characters (surrogates) are NUL
+    pub fn as_char(&self) -> char {
+        char::from_u32(self.value).unwrap_or_default()
+    }
+
+    pub fn is_vowel(&self) -> bool {
+        matches!(self.as_char().to_ascii_lowercase(), 'a' | 'e' | 'i' | 'o' | 'u')
+    }
+
+    pub fn is_letter(&self) -> bool {
+        self.as_char().is_alphabetic()
+    }
+
+    pub fn is_digit(&self) -> bool {
+        self.as_char().is_ascii_digit()
+    }
+
+    // Case conversions that would take more than one character leave it as it is
+    pub fn to_uppercase(&self) -> u32 {
+        single(self.as_char().to_uppercase()).map_or(self.value, u32::from)
+    }
+
+    pub fn to_lowercase(&self) -> u32 {
+        single(self.as_char().to_lowercase()).map_or(self.value, u32::from)
+    }
+}
+
+fn single(mut chars: impl Iterator<Item = char>) -> Option<char> {
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(c),
+        _ => None,
+    }
+}
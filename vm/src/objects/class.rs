@@ -1,10 +1,17 @@
+use std::io::{Read, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
 use super::object::{
-    ObjectPointer, ObjectHeader,
+    CLASSSIZE,
+    ObjectReferences, ValidObject,
+    ObjectPointer, ObjectHeader, ObjectSize,
     Pointer,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
-
-struct Class {
+#[derive(Debug, ValidSmalltalkObject)]
+pub struct Class {
     header:         ObjectHeader,
     name:           ObjectPointer,
     super_class:    ObjectPointer,
@@ -16,10 +23,61 @@ struct Class {
     stack_max:      u32,
 }
 
+impl ObjectReferences for Class {
+    fn references(&self) -> Vec<ObjectPointer> {
+        vec![
+            self.name,
+            self.super_class,
+            self.file_name,
+            self.c_inst_vars,
+            self.message_names,
+            self.methods,
+        ]
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.name = update(self.name);
+        self.super_class = update(self.super_class);
+        self.file_name = update(self.file_name);
+        self.c_inst_vars = update(self.c_inst_vars);
+        self.message_names = update(self.message_names);
+        self.methods = update(self.methods);
+    }
+}
+
+impl ImageObject for Class {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.pointer(self.name)?;
+        writer.pointer(self.super_class)?;
+        writer.pointer(self.file_name)?;
+        writer.pointer(self.c_inst_vars)?;
+        writer.u32(self.context_size)?;
+        writer.pointer(self.message_names)?;
+        writer.pointer(self.methods)?;
+        writer.u32(self.stack_max)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Class {
+            header: ObjectHeader::new(Self::SIZE),
+            name: reader.pointer()?,
+            super_class: reader.pointer()?,
+            file_name: reader.pointer()?,
+            c_inst_vars: reader.pointer()?,
+            context_size: reader.u32()?,
+            message_names: reader.pointer()?,
+            methods: reader.pointer()?,
+            stack_max: reader.u32()?,
+        })
+    }
+}
+
 impl Class {
+    const SIZE: ObjectSize = CLASSSIZE;
+
     pub fn new() -> Self {
         Self {
-            header: ObjectHeader::new(0),
+            header: ObjectHeader::new(Self::SIZE),
             name: ObjectPointer::null(),
             super_class: ObjectPointer::null(),
             file_name: ObjectPointer::null(),
@@ -30,4 +88,71 @@ impl Class {
             stack_max: 0,
         }
     }
+
+    pub fn name(&self) -> ObjectPointer {
+        self.name
+    }
+
+    pub fn set_name(&mut self, name: ObjectPointer) {
+        self.name = name;
+    }
+
+    pub fn super_class(&self) -> ObjectPointer {
+        self.super_class
+    }
+
+    pub fn set_super_class(&mut self, super_class: ObjectPointer) {
+        self.super_class = super_class;
+    }
+
+    // Array with the names of the instance variables defined by the class
+    pub fn c_inst_vars(&self) -> ObjectPointer {
+        self.c_inst_vars
+    }
+
+    pub fn set_c_inst_vars(&mut self, c_inst_vars: ObjectPointer) {
+        self.c_inst_vars = c_inst_vars;
+    }
+
+    // Array with the selectors of the methods defined by the class
+    pub fn message_names(&self) -> ObjectPointer {
+        self.message_names
+    }
+
+    pub fn set_message_names(&mut self, message_names: ObjectPointer) {
+        self.message_names = message_names;
+    }
+
+    // Array with the methods, in the same order as their selectors
+    pub fn methods(&self) -> ObjectPointer {
+        self.methods
+    }
+
+    pub fn set_methods(&mut self, methods: ObjectPointer) {
+        self.methods = methods;
+    }
+
+    // Largest number of temporaries needed by any of the methods
+    pub fn context_size(&self) -> u32 {
+        self.context_size
+    }
+
+    pub fn set_context_size(&mut self, context_size: u32) {
+        self.context_size = context_size;
+    }
+
+    // Largest stack needed by any of the methods
+    pub fn stack_max(&self) -> u32 {
+        self.stack_max
+    }
+
+    pub fn set_stack_max(&mut self, stack_max: u32) {
+        self.stack_max = stack_max;
+    }
+}
+
+impl Default for Class {
+    fn default() -> Self {
+        Self::new()
+    }
 }
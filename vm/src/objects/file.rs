@@ -1,22 +1,238 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
 use super::object::{
     FILESIZE,
-    ObjectHeader, ObjectSize,
+    ObjectReferences, ValidObject,
+    ObjectHeader, ObjectPointer, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
-enum FileMode {
+// What reading and writing deal with: single characters (bytes), whole lines, or
+// binary integers (32 bits, little endian)
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
     CharMode,
     StrMode,
     InteMode,
 }
 
+impl FileMode {
+    pub fn from_number(number: i32) -> Option<FileMode> {
+        match number {
+            0 => Some(FileMode::CharMode),
+            1 => Some(FileMode::StrMode),
+            2 => Some(FileMode::InteMode),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> i32 {
+        match self {
+            FileMode::CharMode => 0,
+            FileMode::StrMode => 1,
+            FileMode::InteMode => 2,
+        }
+    }
+}
+
+// Values read from or written to a file, depending on its mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileValue {
+    Char(u8),
+    Line(String),
+    Integer(i32),
+}
+
+// File in the host file system. The descriptor is closed along with the object,
+// and files restored from an image start closed
+#[derive(Debug, ValidSmalltalkObject)]
 pub struct File {
     header: ObjectHeader,
     file_mode: FileMode,
-    // Placeholder. Later we may implement it based on std::fs::File
-    descriptor: usize,
+    name: String,
+    // Reads are buffered. Seeking discards the buffer, which is done before writing
+    // too, so that writes happen where the reads left off
+    descriptor: Option<BufReader<fs::File>>,
+}
+
+impl ObjectReferences for File {
+    fn references(&self) -> Vec<ObjectPointer> {
+        vec![]
+    }
+
+    fn update_references(&mut self, _update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {}
+}
+
+impl ImageObject for File {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.u8(self.file_mode.number() as u8)?;
+        writer.str(&self.name)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        let mode = reader.u8()?;
+        let file_mode = FileMode::from_number(mode as i32)
+            .ok_or_else(|| ImageError::Corrupt(format!("invalid file mode: {}", mode)))?;
+
+        Ok(File {
+            header: ObjectHeader::new(Self::SIZE),
+            file_mode,
+            name: reader.string()?,
+            descriptor: None,
+        })
+    }
 }
 
 impl File {
     const SIZE: ObjectSize = FILESIZE;
+
+    // Opens a file with an fopen style access: r, w, a, r+, w+ or a+
+    pub fn open(name: &str, access: &str) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        match access {
+            "r" => options.read(true),
+            "w" => options.write(true).create(true).truncate(true),
+            "a" => options.append(true).create(true),
+            "r+" => options.read(true).write(true),
+            "w+" => options.read(true).write(true).create(true).truncate(true),
+            "a+" => options.read(true).append(true).create(true),
+            _ => return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid access: {}", access))),
+        };
+
+        Ok(File {
+            header: ObjectHeader::new(Self::SIZE),
+            file_mode: FileMode::CharMode,
+            name: name.to_string(),
+            descriptor: Some(BufReader::new(options.open(name)?)),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mode(&self) -> FileMode {
+        self.file_mode
+    }
+
+    pub fn set_mode(&mut self, mode: FileMode) {
+        self.file_mode = mode;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.descriptor.is_some()
+    }
+
+    pub fn close(&mut self) {
+        self.descriptor = None;
+    }
+
+    // Next value in the file, or None at its end
+    pub fn read(&mut self) -> io::Result<Option<FileValue>> {
+        let mode = self.file_mode;
+        let descriptor = self.descriptor()?;
+
+        match mode {
+            FileMode::CharMode => {
+                let mut byte = [0];
+                match descriptor.read(&mut byte)? {
+                    0 => Ok(None),
+                    _ => Ok(Some(FileValue::Char(byte[0]))),
+                }
+            }
+            FileMode::StrMode => {
+                let mut line = vec![];
+                if descriptor.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.last() == Some(&b'\n') {
+                    line.pop();
+                }
+                Ok(Some(FileValue::Line(String::from_utf8_lossy(&line).into_owned())))
+            }
+            FileMode::InteMode => {
+                let mut bytes = [0; 4];
+                match descriptor.read_exact(&mut bytes) {
+                    Ok(()) => Ok(Some(FileValue::Integer(i32::from_le_bytes(bytes)))),
+                    Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                    Err(err) => Err(err),
+                }
+            }
+        }
+    }
+
+    // Writes a value of the kind the mode deals with. Lines get their newline added
+    pub fn write(&mut self, value: &FileValue) -> io::Result<()> {
+        let bytes = match (self.file_mode, value) {
+            (FileMode::CharMode, FileValue::Char(byte)) => vec![*byte],
+            (FileMode::StrMode, FileValue::Line(line)) => format!("{}\n", line).into_bytes(),
+            (FileMode::InteMode, FileValue::Integer(value)) => value.to_le_bytes().to_vec(),
+            _ => return Err(io::Error::new(ErrorKind::InvalidInput, "value doesn't match the file mode")),
+        };
+
+        let descriptor = self.descriptor()?;
+        let position = descriptor.stream_position()?;
+        descriptor.seek(SeekFrom::Start(position))?;
+        descriptor.get_mut().write_all(&bytes)
+    }
+
+    // Moves to a position, in bytes from the start. Returns the new position
+    pub fn seek(&mut self, position: u64) -> io::Result<u64> {
+        self.descriptor()?.seek(SeekFrom::Start(position))
+    }
+
+    pub fn position(&mut self) -> io::Result<u64> {
+        self.descriptor()?.stream_position()
+    }
+
+    fn descriptor(&mut self) -> io::Result<&mut BufReader<fs::File>> {
+        self.descriptor.as_mut().ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "file is closed"))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_modes() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("lst-file-test-{}", std::process::id()));
+        let name = path.to_str().unwrap();
+
+        let mut file = File::open(name, "w+")?;
+        file.set_mode(FileMode::StrMode);
+        file.write(&FileValue::Line("first line".to_string()))?;
+        file.write(&FileValue::Line("second".to_string()))?;
+        file.set_mode(FileMode::InteMode);
+        file.write(&FileValue::Integer(-2))?;
+        assert!(file.write(&FileValue::Char(b'x')).is_err());
+
+        assert_eq!(file.seek(0)?, 0);
+        file.set_mode(FileMode::CharMode);
+        assert_eq!(file.read()?, Some(FileValue::Char(b'f')));
+        file.set_mode(FileMode::StrMode);
+        assert_eq!(file.read()?, Some(FileValue::Line("irst line".to_string())));
+        assert_eq!(file.read()?, Some(FileValue::Line("second".to_string())));
+
+        // Writes go where reading left off, despite the buffering
+        assert_eq!(file.position()?, 18);
+        file.set_mode(FileMode::InteMode);
+        assert_eq!(file.read()?, Some(FileValue::Integer(-2)));
+        assert_eq!(file.read()?, None);
+        file.seek(18)?;
+        file.write(&FileValue::Integer(7))?;
+        file.seek(18)?;
+        assert_eq!(file.read()?, Some(FileValue::Integer(7)));
+
+        file.close();
+        assert!(!file.is_open());
+        assert_eq!(file.read().map_err(|err| err.kind()), Err(ErrorKind::NotConnected));
+        assert!(File::open(name, "rw").is_err());
+
+        std::fs::remove_file(path)
+    }
+}
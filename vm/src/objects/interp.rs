@@ -1,37 +1,214 @@
+use std::io::{Read, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
 use super::object::{
     INTERPSIZE,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectPointer, ObjectSize,
+    Pointer,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
+// Execution context for a method or a block.
+//
+// Block contexts share the bytecode, literals, arguments and temporaries (context)
+// of the method context where the block was created. Their creator points to that
+// method context, which is where a ^ return inside the block returns from. For
+// method contexts, creator is null.
+#[derive(Debug, ValidSmalltalkObject)]
 pub struct Interpreter {
     header: ObjectHeader,
     creator: ObjectPointer,
     sender: ObjectPointer,
+    method: ObjectPointer,
     bytecode: ObjectPointer,
+    // The object holding the instance variables accessible from the method. With
+    // inherited methods this is one of the super objects of self
     receiver: ObjectPointer,
     literals: ObjectPointer,
+    // Array with self, followed by the arguments of the method
+    arguments: ObjectPointer,
+    // Array with the temporaries of the method, including the block arguments
     context: ObjectPointer,
     stack: ObjectPointer,
-    // stacktop: ... In C this is object**. Figure out how it is used
+    // Number of elements in the stack. In C this was a pointer to the top
+    stack_top: u32,
     current_byte: u32,
 }
 
+impl ObjectReferences for Interpreter {
+    fn references(&self) -> Vec<ObjectPointer> {
+        vec![
+            self.creator,
+            self.sender,
+            self.method,
+            self.bytecode,
+            self.receiver,
+            self.literals,
+            self.arguments,
+            self.context,
+            self.stack,
+        ]
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.creator = update(self.creator);
+        self.sender = update(self.sender);
+        self.method = update(self.method);
+        self.bytecode = update(self.bytecode);
+        self.receiver = update(self.receiver);
+        self.literals = update(self.literals);
+        self.arguments = update(self.arguments);
+        self.context = update(self.context);
+        self.stack = update(self.stack);
+    }
+}
+
+impl ImageObject for Interpreter {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        for ptr in self.references() {
+            writer.pointer(ptr)?;
+        }
+        writer.u32(self.stack_top)?;
+        writer.u32(self.current_byte)
+    }
+
+    // Pointers come in the same order as references()
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Interpreter {
+            header: ObjectHeader::new(Self::SIZE),
+            creator: reader.pointer()?,
+            sender: reader.pointer()?,
+            method: reader.pointer()?,
+            bytecode: reader.pointer()?,
+            receiver: reader.pointer()?,
+            literals: reader.pointer()?,
+            arguments: reader.pointer()?,
+            context: reader.pointer()?,
+            stack: reader.pointer()?,
+            stack_top: reader.u32()?,
+            current_byte: reader.u32()?,
+        })
+    }
+}
+
 impl Interpreter {
     const SIZE: ObjectSize = INTERPSIZE;
-}
 
-// impl Interpreter {
-//     pub fn new() -> Self {
-//         Interpreter {
-//             header: ObjectHeader::new(INTERPSIZE),
-//             // creator: ObjectPointer::null(),
-//             // sender: ObjectPointer::null(),
-//             // bytecode: ObjectPointer::null(),
-//             // receiver: ObjectPointer::null(),
-//             // literals: ObjectPointer::null(),
-//             // context: ObjectPointer::null(),
-//             // stack: ObjectPointer::null(),
-//             current_byte: 0,
-//         }
-//     }
-// }
+    pub fn new(
+        method: ObjectPointer,
+        bytecode: ObjectPointer,
+        literals: ObjectPointer,
+        receiver: ObjectPointer,
+        arguments: ObjectPointer,
+        context: ObjectPointer,
+        stack: ObjectPointer,
+    ) -> Self {
+        Interpreter {
+            header: ObjectHeader::new(Self::SIZE),
+            creator: ObjectPointer::null(),
+            sender: ObjectPointer::null(),
+            method,
+            bytecode,
+            receiver,
+            literals,
+            arguments,
+            context,
+            stack,
+            stack_top: 0,
+            current_byte: 0,
+        }
+    }
+
+    // Template for the contexts of a block created from this one. Its body starts
+    // at the given byte. The stack is allocated when the block is evaluated
+    pub fn block_template(&self, this: ObjectPointer, start: u32) -> Self {
+        let creator = if self.creator.is_null() { this } else { self.creator };
+
+        Interpreter {
+            header: ObjectHeader::new(Self::SIZE),
+            creator,
+            sender: ObjectPointer::null(),
+            method: self.method,
+            bytecode: self.bytecode,
+            receiver: self.receiver,
+            literals: self.literals,
+            arguments: self.arguments,
+            context: self.context,
+            stack: ObjectPointer::null(),
+            stack_top: 0,
+            current_byte: start,
+        }
+    }
+
+    // New context evaluating the block described by this template
+    pub fn block_context(&self, sender: ObjectPointer, stack: ObjectPointer) -> Self {
+        Interpreter {
+            header: ObjectHeader::new(Self::SIZE),
+            sender,
+            stack,
+            ..*self
+        }
+    }
+
+    pub fn is_block_context(&self) -> bool {
+        !self.creator.is_null()
+    }
+
+    pub fn creator(&self) -> ObjectPointer {
+        self.creator
+    }
+
+    pub fn sender(&self) -> ObjectPointer {
+        self.sender
+    }
+
+    pub fn set_sender(&mut self, sender: ObjectPointer) {
+        self.sender = sender;
+    }
+
+    pub fn method(&self) -> ObjectPointer {
+        self.method
+    }
+
+    pub fn bytecode(&self) -> ObjectPointer {
+        self.bytecode
+    }
+
+    pub fn receiver(&self) -> ObjectPointer {
+        self.receiver
+    }
+
+    pub fn literals(&self) -> ObjectPointer {
+        self.literals
+    }
+
+    pub fn arguments(&self) -> ObjectPointer {
+        self.arguments
+    }
+
+    pub fn context(&self) -> ObjectPointer {
+        self.context
+    }
+
+    pub fn stack(&self) -> ObjectPointer {
+        self.stack
+    }
+
+    pub fn stack_top(&self) -> u32 {
+        self.stack_top
+    }
+
+    pub fn set_stack_top(&mut self, stack_top: u32) {
+        self.stack_top = stack_top;
+    }
+
+    pub fn current_byte(&self) -> u32 {
+        self.current_byte
+    }
+
+    pub fn set_current_byte(&mut self, current_byte: u32) {
+        self.current_byte = current_byte;
+    }
+}
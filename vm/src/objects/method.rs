@@ -0,0 +1,10 @@
+// Compiled methods are stored as arrays (ordinary objects) with the following layout
+
+pub const METHOD_SELECTOR: usize =       0;
+pub const METHOD_BYTECODE: usize =       1;
+pub const METHOD_LITERALS: usize =       2;
+pub const METHOD_STACK_MAX: usize =      3;
+pub const METHOD_CONTEXT_SIZE: usize =   4;
+pub const METHOD_CLASS: usize =          5;
+
+pub const METHOD_SIZE: usize =           6;
@@ -1,10 +1,13 @@
 pub mod block;
 pub mod byte;
+pub mod character;
 pub mod class;
 pub mod file;
 pub mod interp;
+pub mod method;
 pub mod number;
 pub mod object;
 pub mod process;
+pub mod semaphore;
 pub mod string;
 pub mod symbol;
@@ -1,8 +1,16 @@
+use std::fmt::{self, Display};
+use std::io::{Read, Write};
+
+use num_bigint::{BigInt, Sign};
+use num_rational::BigRational;
+use num_traits::{One, Zero};
+
 use super::object::{
-    FLOATSIZE, INTEGERSIZE,
-    ValidObject,
+    FLOATSIZE, FRACTIONSIZE, INTEGERSIZE, LARGEINTSIZE,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 use proc_macros::ValidSmalltalkObject;
 
 #[derive(Debug, ValidSmalltalkObject)]
@@ -11,6 +19,18 @@ pub struct Integer {
     value: i32,
 }
 
+impl ObjectReferences for Integer {}
+
+impl ImageObject for Integer {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.i32(self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Integer::new(reader.i32()?))
+    }
+}
+
 impl Integer {
     const SIZE: ObjectSize = INTEGERSIZE;
 
@@ -20,6 +40,10 @@ impl Integer {
             value,
         }
     }
+
+    pub fn value(&self) -> i32 {
+        self.value
+    }
 }
 
 impl PartialEq for Integer {
@@ -28,12 +52,117 @@ impl PartialEq for Integer {
     }
 }
 
+// Integer beyond the range of small integers. The virtual machine only creates
+// them for such values, and converts results back to small integers when they fit
+#[derive(Debug, ValidSmalltalkObject)]
+pub struct LargeInteger {
+    header: ObjectHeader,
+    value: BigInt,
+}
+
+impl ObjectReferences for LargeInteger {}
+
+impl ImageObject for LargeInteger {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        write_big_integer(writer, &self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(LargeInteger::new(read_big_integer(reader)?))
+    }
+}
+
+impl LargeInteger {
+    const SIZE: ObjectSize = LARGEINTSIZE;
+
+    pub fn new(value: BigInt) -> Self {
+        LargeInteger {
+            header: ObjectHeader::new(Self::SIZE),
+            value,
+        }
+    }
+
+    pub fn value(&self) -> &BigInt {
+        &self.value
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.value.sign() == Sign::Minus
+    }
+}
+
+// Exact quotient of two integers. It is kept in lowest terms, with a positive
+// denominator, and the virtual machine turns the ones with a denominator of one into
+// integers, so each value has a single representation
+#[derive(Debug, ValidSmalltalkObject)]
+pub struct Fraction {
+    header: ObjectHeader,
+    value: BigRational,
+}
+
+impl ObjectReferences for Fraction {}
+
+impl ImageObject for Fraction {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        write_big_integer(writer, self.value.numer())?;
+        write_big_integer(writer, self.value.denom())
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        let numerator = read_big_integer(reader)?;
+        let denominator = read_big_integer(reader)?;
+        if denominator.is_zero() {
+            return Err(ImageError::Corrupt("fraction with a zero denominator".to_string()));
+        }
+
+        Ok(Fraction::new(BigRational::new(numerator, denominator)))
+    }
+}
+
+impl Fraction {
+    const SIZE: ObjectSize = FRACTIONSIZE;
+
+    pub fn new(value: BigRational) -> Self {
+        Fraction {
+            header: ObjectHeader::new(Self::SIZE),
+            value,
+        }
+    }
+
+    pub fn value(&self) -> &BigRational {
+        &self.value
+    }
+}
+
+// Written like a division, which is how they come to be: 1/3
+impl Display for Fraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.value.denom().is_one() {
+            write!(f, "{}", self.value.numer())
+        } else {
+            write!(f, "{}/{}", self.value.numer(), self.value.denom())
+        }
+    }
+}
+
 #[derive(Debug, ValidSmalltalkObject)]
 pub struct Float {
     header: ObjectHeader,
     value: f64,
 }
 
+impl ObjectReferences for Float {}
+
+impl ImageObject for Float {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.f64(self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Float::new(reader.f64()?))
+    }
+}
+
 impl Float {
     const SIZE: ObjectSize = FLOATSIZE;
 
@@ -43,4 +172,92 @@ impl Float {
             value,
         }
     }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    // Parses the float literals of the language, following the same rule as the
+    // lexer: digits, then an optional fraction and exponent (2, 1.5, -1.5e-3). Parsing
+    // is exact: the result is the float closest to the text. Literals too large or
+    // too small for a float, which would become infinity or zero, are rejected
+    pub fn parse(text: &str) -> Option<f64> {
+        let text = text.trim();
+        let mut rest = digits(text.strip_prefix('-').unwrap_or(text))?;
+        if let Some(fraction) = rest.strip_prefix('.') {
+            rest = digits(fraction)?;
+        }
+        if let Some(exponent) = rest.strip_prefix('e') {
+            rest = digits(exponent.strip_prefix('-').unwrap_or(exponent))?;
+        }
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        let value: f64 = text.parse().ok()?;
+        let significand = text.split('e').next().unwrap_or(text);
+        let underflow = value == 0.0 && significand.contains(|c: char| ('1'..='9').contains(&c));
+        Some(value).filter(|value| value.is_finite() && !underflow)
+    }
+
+    // Parses what Display writes: float literals, and nan, inf and -inf
+    pub fn read(text: &str) -> Option<f64> {
+        match text.trim() {
+            "nan" => Some(f64::NAN),
+            "inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            text => Self::parse(text),
+        }
+    }
+}
+
+// Text after the digits at its start, if there are any
+fn digits(text: &str) -> Option<&str> {
+    let rest = text.trim_start_matches(|c: char| c.is_ascii_digit());
+    (rest.len() < text.len()).then_some(rest)
+}
+
+impl PartialEq for Float {
+    fn eq(&self, other: &Self) -> bool {
+        Float::is_valid(other) && self.value == other.value
+    }
+}
+
+// Shortest text that parses back to the same value. It is a float literal too,
+// always with a fraction (1.0, 0.1, 1.0e300), except for nan, inf and -inf
+impl Display for Float {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            value if value.is_nan() => write!(f, "nan"),
+            value if value.is_infinite() => write!(f, "{}inf", if value < 0.0 { "-" } else { "" }),
+            value => {
+                // Without a fraction, the exponent would make an integer literal
+                let text = format!("{:?}", value);
+                match text.split_once('e') {
+                    Some((significand, exponent)) if !significand.contains('.') => {
+                        write!(f, "{}.0e{}", significand, exponent)
+                    }
+                    _ => write!(f, "{}", text),
+                }
+            }
+        }
+    }
+}
+
+// Integers of any size are written as their sign (1 when negative) followed by their
+// magnitude, in little endian bytes
+fn write_big_integer<W: Write>(writer: &mut ImageWriter<W>, value: &BigInt) -> Result<(), ImageError> {
+    let (sign, magnitude) = value.to_bytes_le();
+    writer.u8((sign == Sign::Minus) as u8)?;
+    writer.bytes(&magnitude)
+}
+
+fn read_big_integer<R: Read>(reader: &mut ImageReader<R>) -> Result<BigInt, ImageError> {
+    let sign = match reader.u8()? {
+        0 => Sign::Plus,
+        1 => Sign::Minus,
+        sign => return Err(ImageError::Corrupt(format!("invalid sign: {}", sign))),
+    };
+    Ok(BigInt::from_bytes_le(sign, &reader.bytes()?))
 }
@@ -1,8 +1,12 @@
 // Object descriptors for special types
 
+use std::io::{Read, Write};
+
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
+
 pub type RefCount = u32;
 pub type ObjectSize = i32;
-pub type ObjectPointer = u32;
+pub type ObjectPointer = u64;
 
 pub const INVALIDSIZE: ObjectSize =   -1;
 
@@ -12,9 +16,12 @@ pub const CHARSIZE: ObjectSize =      -33;
 pub const CLASSSIZE: ObjectSize =     -3;
 pub const FILESIZE: ObjectSize =      -5;
 pub const FLOATSIZE: ObjectSize =     -31415;
+pub const FRACTIONSIZE: ObjectSize =  -22;
 pub const INTEGERSIZE: ObjectSize =   -17;
 pub const INTERPSIZE: ObjectSize =    -15;
+pub const LARGEINTSIZE: ObjectSize =  -65;
 pub const PROCSIZE: ObjectSize =      -100;
+pub const SEMAPHORESIZE: ObjectSize = -101;
 pub const STRINGSIZE: ObjectSize =    -258;
 pub const SYMBOLSIZE: ObjectSize =    -14;
 
@@ -26,13 +33,34 @@ pub const SYMBOLSIZE: ObjectSize =    -14;
 // specific object class being allocated (e.g. Block, ByteArray, etc).
 //
 // These memory blocks are stored in vectors (one per object class). A pointer must
-// then be able to reference two pieces of information:
+// then be able to reference three pieces of information:
 //
+//   * the type of the object, which tells which vector of blocks to look into
 //   * the index of the memory block in the vector
 //   * the offset within that memory block (expressed as an index)
 //
-// Naively we'll use the upper 16 bits of the pointer to store the block index and
-// the lower 16 bits to store the offset.
+// Pointers are 64 bits wide. The lower 8 bits are a tag, the next 24 bits store the
+// offset and the upper 32 bits the block index. The tag can be:
+//
+//   * UNTYPED_TAG: a pointer handed out by a memory pool, which knows its own type
+//   * SMALLINTEGER_TAG: an immediate integer. There's no object behind the pointer,
+//     the value is stored in the upper 32 bits instead
+//   * the tag for one of the object types (see ObjectType::tag)
+//
+// The null pointer has all of its bits set.
+
+pub const UNTYPED_TAG: u8 =         0x00;
+pub const SMALLINTEGER_TAG: u8 =    0x01;
+pub const NULL_TAG: u8 =            0xFF;
+
+pub const MAX_BLOCK_ELEMENTS: usize =   1 << 24;
+// The block index takes the upper 32 bits, more than usize holds on 32 bit targets
+pub const MAX_BLOCKS: u64 =             1 << (u64::BITS - BLOCK_SHIFT);
+
+const TAG_MASK: u64 =       0xFF;
+const OFFSET_SHIFT: u32 =   8;
+const OFFSET_MASK: u64 =    0xFF_FFFF;
+const BLOCK_SHIFT: u32 =    32;
 
 pub trait Pointer {
     fn null() -> Self;
@@ -40,13 +68,19 @@ pub trait Pointer {
     fn new_from_index_and_offset(block_index: usize, offset: usize) -> Self;
     fn block_index(&self) -> usize;
     fn offset(&self) -> usize;
+    fn tag(&self) -> u8;
+    fn tagged(&self, object_type: ObjectType) -> Self;
+    fn object_type(&self) -> Option<ObjectType>;
+    fn from_small_integer(value: i32) -> Self;
+    fn is_small_integer(&self) -> bool;
+    fn small_integer(&self) -> Option<i32>;
 }
 
 impl Pointer for ObjectPointer {
     fn null() -> Self
         where Self: Sized
     {
-        u32::max_value()
+        u64::MAX
     }
 
     fn is_null(&self) -> bool {
@@ -54,21 +88,63 @@ impl Pointer for ObjectPointer {
     }
 
     fn new_from_index_and_offset(block_index: usize, offset: usize) -> Self {
-        ((block_index as u32) << 16) | (offset as u32)
+        ((block_index as u64) << BLOCK_SHIFT)
+            | (((offset as u64) & OFFSET_MASK) << OFFSET_SHIFT)
+            | UNTYPED_TAG as u64
     }
 
     fn block_index(&self) -> usize {
-        (*self as u32 >> 16) as usize
+        (*self >> BLOCK_SHIFT) as usize
     }
 
     fn offset(&self) -> usize {
-        (*self & 0xFFFF) as usize
+        ((*self >> OFFSET_SHIFT) & OFFSET_MASK) as usize
+    }
+
+    fn tag(&self) -> u8 {
+        (*self & TAG_MASK) as u8
+    }
+
+    fn tagged(&self, object_type: ObjectType) -> Self {
+        (*self & !TAG_MASK) | object_type.tag() as u64
+    }
+
+    fn object_type(&self) -> Option<ObjectType> {
+        ObjectType::from_tag(self.tag())
+    }
+
+    fn from_small_integer(value: i32) -> Self {
+        ((value as u32 as u64) << BLOCK_SHIFT) | SMALLINTEGER_TAG as u64
+    }
+
+    fn is_small_integer(&self) -> bool {
+        self.tag() == SMALLINTEGER_TAG
+    }
+
+    fn small_integer(&self) -> Option<i32> {
+        self.is_small_integer()
+            .then_some((*self >> BLOCK_SHIFT) as u32 as i32)
     }
 }
 
 pub trait ValidObject {
     fn is_valid(obj: &Self) -> bool;
     fn set_invalid(obj: &mut Self);
+    fn header(obj: &Self) -> &ObjectHeader;
+    fn header_mut(obj: &mut Self) -> &mut ObjectHeader;
+}
+
+// Objects that hold pointers to other objects. Used by the memory manager to
+// find out what gets released along with an object
+pub trait ObjectReferences {
+    fn references(&self) -> Vec<ObjectPointer> {
+        vec![]
+    }
+
+    // Replaces every reference with the result of calling `update` on it. Used when
+    // objects are moved around in memory
+    fn update_references(&mut self, _update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+    }
 }
 
 #[derive(Debug)]
@@ -89,6 +165,24 @@ impl ObjectHeader {
         self.size == size
     }
 
+    pub fn ref_count(&self) -> RefCount {
+        self.ref_count
+    }
+
+    pub fn set_ref_count(&mut self, ref_count: RefCount) {
+        self.ref_count = ref_count;
+    }
+
+    pub fn increment_ref_count(&mut self) -> RefCount {
+        self.ref_count = self.ref_count.saturating_add(1);
+        self.ref_count
+    }
+
+    pub fn decrement_ref_count(&mut self) -> RefCount {
+        self.ref_count = self.ref_count.saturating_sub(1);
+        self.ref_count
+    }
+
     pub fn set_invalid(&mut self) {
         self.ref_count = 0;
         self.size = INVALIDSIZE;
@@ -102,6 +196,8 @@ impl ObjectHeader {
     }
 }
 
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ObjectType {
     Block,
     ByteArray,
@@ -109,30 +205,70 @@ pub enum ObjectType {
     Class,
     File,
     Float,
+    Fraction,
     Integer,
     Interpreter,
+    LargeInteger,
     Object,
     Process,
+    Semaphore,
     String,
     Symbol,
 }
 
 impl ObjectType {
+    const FIRST_TAG: u8 = 0x02;
+    const ALL: [ObjectType; 15] = [
+        ObjectType::Block,
+        ObjectType::ByteArray,
+        ObjectType::Char,
+        ObjectType::Class,
+        ObjectType::File,
+        ObjectType::Float,
+        ObjectType::Fraction,
+        ObjectType::Integer,
+        ObjectType::Interpreter,
+        ObjectType::LargeInteger,
+        ObjectType::Object,
+        ObjectType::Process,
+        ObjectType::Semaphore,
+        ObjectType::String,
+        ObjectType::Symbol,
+    ];
+
+    pub fn tag(self) -> u8 {
+        Self::FIRST_TAG + self as u8
+    }
+
+    pub fn from_tag(tag: u8) -> Option<ObjectType> {
+        let index = tag.checked_sub(Self::FIRST_TAG)?;
+        Self::ALL.get(index as usize).copied()
+    }
+
     pub fn find(ptr: *const u8) -> Option<ObjectType> {
         let header: &ObjectHeader = unsafe {
             &*(ptr as *const ObjectHeader)
         };
 
-        match header.size {
+        Self::from_size(header.size)
+    }
+
+    // Type of the objects with a given size: ordinary objects store their number of
+    // instance variables, the others one of the size codes
+    pub fn from_size(size: ObjectSize) -> Option<ObjectType> {
+        match size {
             BLOCKSIZE => Some(ObjectType::Block),
             BYTEARRAYSIZE => Some(ObjectType::ByteArray),
             CHARSIZE => Some(ObjectType::Char),
             CLASSSIZE => Some(ObjectType::Class),
             FILESIZE => Some(ObjectType::File),
             FLOATSIZE => Some(ObjectType::Float),
+            FRACTIONSIZE => Some(ObjectType::Fraction),
             INTEGERSIZE => Some(ObjectType::Integer),
             INTERPSIZE => Some(ObjectType::Interpreter),
+            LARGEINTSIZE => Some(ObjectType::LargeInteger),
             PROCSIZE => Some(ObjectType::Process),
+            SEMAPHORESIZE => Some(ObjectType::Semaphore),
             STRINGSIZE => Some(ObjectType::String),
             SYMBOLSIZE => Some(ObjectType::Symbol),
             s if s >= 0 => Some(ObjectType::Object),
@@ -141,9 +277,177 @@ impl ObjectType {
     }
 }
 
+#[derive(Debug)]
 pub struct Object {
     header:     ObjectHeader,
     class:      ObjectPointer,
     super_obj:  ObjectPointer,
     inst_var:   Vec<ObjectPointer>,
 }
+
+impl Object {
+    // Instances hold their own instance variables, and a pointer to an instance of
+    // the superclass (super_obj) holding the ones inherited from it
+    pub fn new(class: ObjectPointer, super_obj: ObjectPointer, size: usize) -> Self {
+        Object {
+            header: ObjectHeader::new(size as ObjectSize),
+            class,
+            super_obj,
+            inst_var: vec![ObjectPointer::null(); size],
+        }
+    }
+
+    pub fn class(&self) -> ObjectPointer {
+        self.class
+    }
+
+    pub fn super_obj(&self) -> ObjectPointer {
+        self.super_obj
+    }
+
+    pub fn size(&self) -> usize {
+        self.inst_var.len()
+    }
+
+    pub fn inst_var(&self, index: usize) -> Option<ObjectPointer> {
+        self.inst_var.get(index).copied()
+    }
+
+    // Returns the previous value, or None if the index is out of bounds
+    pub fn set_inst_var(&mut self, index: usize, value: ObjectPointer) -> Option<ObjectPointer> {
+        self.inst_var.get_mut(index)
+            .map(|var| std::mem::replace(var, value))
+    }
+}
+
+impl ValidObject for Object {
+    fn is_valid(obj: &Self) -> bool {
+        // Ordinary objects store their number of instance variables as size
+        obj.header.size >= 0
+    }
+
+    fn set_invalid(obj: &mut Self) {
+        obj.header.set_invalid();
+    }
+
+    fn header(obj: &Self) -> &ObjectHeader {
+        &obj.header
+    }
+
+    fn header_mut(obj: &mut Self) -> &mut ObjectHeader {
+        &mut obj.header
+    }
+}
+
+impl ImageObject for Object {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.pointer(self.class)?;
+        writer.pointer(self.super_obj)?;
+        writer.length(self.inst_var.len())?;
+        for &var in &self.inst_var {
+            writer.pointer(var)?;
+        }
+
+        Ok(())
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        let class = reader.pointer()?;
+        let super_obj = reader.pointer()?;
+        let size = reader.length()?;
+        let inst_var = (0..size)
+            .map(|_| reader.pointer())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Object {
+            header: ObjectHeader::new(size as ObjectSize),
+            class,
+            super_obj,
+            inst_var,
+        })
+    }
+}
+
+impl ObjectReferences for Object {
+    fn references(&self) -> Vec<ObjectPointer> {
+        let mut refs = vec![self.class, self.super_obj];
+        refs.extend(&self.inst_var);
+        refs
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.class = update(self.class);
+        self.super_obj = update(self.super_obj);
+        for var in self.inst_var.iter_mut() {
+            *var = update(*var);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_instance_variables() {
+        let class = ObjectPointer::new_from_index_and_offset(0, 1).tagged(ObjectType::Class);
+        let mut obj = Object::new(class, ObjectPointer::null(), 3);
+
+        assert!(Object::is_valid(&obj));
+        assert!(obj.header.is_size(3));
+        assert_eq!(obj.size(), 3);
+        assert_eq!(obj.class(), class);
+        assert_eq!(obj.inst_var(2), Some(ObjectPointer::null()));
+        assert_eq!(obj.inst_var(3), None);
+
+        let value = ObjectPointer::from_small_integer(42);
+        assert_eq!(obj.set_inst_var(1, value), Some(ObjectPointer::null()));
+        assert_eq!(obj.inst_var(1), Some(value));
+        assert_eq!(obj.set_inst_var(3, value), None);
+
+        Object::set_invalid(&mut obj);
+        assert!(!Object::is_valid(&obj));
+    }
+
+    #[test]
+    fn test_pointer_index_and_offset() {
+        let ptr = ObjectPointer::new_from_index_and_offset(70000, 123456);
+
+        assert_eq!(ptr.block_index(), 70000);
+        assert_eq!(ptr.offset(), 123456);
+        assert_eq!(ptr.tag(), UNTYPED_TAG);
+        assert_eq!(ptr.object_type(), None);
+        assert!(!ptr.is_null());
+    }
+
+    #[test]
+    fn test_pointer_tagging() {
+        let ptr = ObjectPointer::new_from_index_and_offset(3, 5).tagged(ObjectType::Symbol);
+
+        assert_eq!(ptr.object_type(), Some(ObjectType::Symbol));
+        assert_eq!(ptr.block_index(), 3);
+        assert_eq!(ptr.offset(), 5);
+        assert_eq!(ptr.tagged(ObjectType::Class).object_type(), Some(ObjectType::Class));
+
+        for object_type in ObjectType::ALL {
+            assert_eq!(ObjectType::from_tag(object_type.tag()), Some(object_type));
+        }
+        assert_eq!(ObjectType::from_tag(SMALLINTEGER_TAG), None);
+        assert_eq!(ObjectType::from_tag(NULL_TAG), None);
+    }
+
+    #[test]
+    fn test_pointer_small_integers() {
+        for value in [0, 1, -1, 42, i32::MAX, i32::MIN] {
+            let ptr = ObjectPointer::from_small_integer(value);
+
+            assert!(ptr.is_small_integer());
+            assert!(!ptr.is_null());
+            assert_eq!(ptr.small_integer(), Some(value));
+            assert_eq!(ptr.object_type(), None);
+        }
+
+        assert_eq!(ObjectPointer::null().small_integer(), None);
+        assert_eq!(ObjectPointer::new_from_index_and_offset(0, 1).small_integer(), None);
+    }
+}
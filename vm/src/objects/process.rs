@@ -1,8 +1,19 @@
+use std::fmt::{self, Display};
+use std::io::{Read, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
 use super::object::{
     PROCSIZE,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectPointer, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
+// Ready and Unblocked processes wait in the ready queue of the scheduler. Blocked
+// ones wait on a semaphore, and become Unblocked once it's signalled. Only one
+// process, the one running, is Active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
     Active,
     Suspended,
@@ -12,12 +23,116 @@ pub enum ProcessState {
     Terminated,
 }
 
+impl ProcessState {
+    // Whether a process in this state may move to the other one
+    pub fn can_become(self, state: ProcessState) -> bool {
+        use ProcessState::*;
+
+        matches!(
+            (self, state),
+            (Ready | Unblocked, Active)
+                | (Active, Ready | Blocked)
+                | (Active | Ready | Blocked | Unblocked, Suspended)
+                | (Suspended, Ready)
+                | (Blocked, Unblocked)
+                | (Active | Suspended | Ready | Blocked | Unblocked, Terminated)
+        )
+    }
+}
+
+impl Display for ProcessState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProcessState::Active => "active",
+            ProcessState::Suspended => "suspended",
+            ProcessState::Ready => "ready",
+            ProcessState::Blocked => "blocked",
+            ProcessState::Unblocked => "unblocked",
+            ProcessState::Terminated => "terminated",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: ProcessState,
+    pub to: ProcessState,
+}
+
+impl Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a {} process can't become {}", self.from, self.to)
+    }
+}
+
+#[derive(Debug, ValidSmalltalkObject)]
 pub struct Process {
     header: ObjectHeader,
     interpreter: ObjectPointer,
     state: ProcessState,
     next: Option<ObjectPointer>,
     prev: Option<ObjectPointer>,
+    // Semaphore a Blocked process waits on
+    semaphore: Option<ObjectPointer>,
+}
+
+impl ObjectReferences for Process {
+    fn references(&self) -> Vec<ObjectPointer> {
+        [Some(self.interpreter), self.next, self.prev, self.semaphore]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.interpreter = update(self.interpreter);
+        self.next = self.next.map(&mut *update);
+        self.prev = self.prev.map(&mut *update);
+        self.semaphore = self.semaphore.map(&mut *update);
+    }
+}
+
+impl ImageObject for Process {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        let state = match self.state {
+            ProcessState::Active => 0,
+            ProcessState::Suspended => 1,
+            ProcessState::Ready => 2,
+            ProcessState::Blocked => 3,
+            ProcessState::Unblocked => 4,
+            ProcessState::Terminated => 5,
+        };
+
+        writer.pointer(self.interpreter)?;
+        writer.u8(state)?;
+        writer.optional_pointer(self.next)?;
+        writer.optional_pointer(self.prev)?;
+        writer.optional_pointer(self.semaphore)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        let interpreter = reader.pointer()?;
+        let state = match reader.u8()? {
+            0 => ProcessState::Active,
+            1 => ProcessState::Suspended,
+            2 => ProcessState::Ready,
+            3 => ProcessState::Blocked,
+            4 => ProcessState::Unblocked,
+            5 => ProcessState::Terminated,
+            state => return Err(ImageError::Corrupt(format!("invalid process state: {}", state))),
+        };
+
+        Ok(Process {
+            header: ObjectHeader::new(Self::SIZE),
+            interpreter,
+            state,
+            next: reader.optional_pointer()?,
+            prev: reader.optional_pointer()?,
+            semaphore: reader.optional_pointer()?,
+        })
+    }
 }
 
 impl Process {
@@ -30,6 +145,78 @@ impl Process {
             state: ProcessState::Ready,
             next: None,
             prev: None,
+            semaphore: None,
+        }
+    }
+
+    // Active context of the process, while it isn't running
+    pub fn interpreter(&self) -> ObjectPointer {
+        self.interpreter
+    }
+
+    pub fn set_interpreter(&mut self, interpreter: ObjectPointer) {
+        self.interpreter = interpreter;
+    }
+
+    pub fn state(&self) -> ProcessState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ProcessState) -> Result<(), InvalidTransition> {
+        if !self.state.can_become(state) {
+            return Err(InvalidTransition { from: self.state, to: state });
+        }
+
+        self.state = state;
+        Ok(())
+    }
+
+    pub fn next(&self) -> Option<ObjectPointer> {
+        self.next
+    }
+
+    pub fn set_next(&mut self, next: Option<ObjectPointer>) {
+        self.next = next;
+    }
+
+    pub fn semaphore(&self) -> Option<ObjectPointer> {
+        self.semaphore
+    }
+
+    pub fn set_semaphore(&mut self, semaphore: Option<ObjectPointer>) {
+        self.semaphore = semaphore;
+    }
+
+    pub fn prev(&self) -> Option<ObjectPointer> {
+        self.prev
+    }
+
+    pub fn set_prev(&mut self, prev: Option<ObjectPointer>) {
+        self.prev = prev;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_state_transitions() {
+        let mut process = Process::new(0);
+        assert_eq!(process.state(), ProcessState::Ready);
+        assert_eq!(process.set_state(ProcessState::Active), Ok(()));
+        assert_eq!(process.set_state(ProcessState::Blocked), Ok(()));
+        assert_eq!(
+            process.set_state(ProcessState::Ready),
+            Err(InvalidTransition { from: ProcessState::Blocked, to: ProcessState::Ready })
+        );
+        assert_eq!(process.set_state(ProcessState::Unblocked), Ok(()));
+        assert_eq!(process.set_state(ProcessState::Terminated), Ok(()));
+
+        // Nothing comes after termination
+        for state in [ProcessState::Active, ProcessState::Ready, ProcessState::Suspended, ProcessState::Terminated] {
+            assert!(!ProcessState::Terminated.can_become(state));
         }
+        assert!(!ProcessState::Suspended.can_become(ProcessState::Suspended));
     }
 }
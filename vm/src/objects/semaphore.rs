@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+
+use proc_macros::ValidSmalltalkObject;
+
+use super::object::{
+    SEMAPHORESIZE,
+    ObjectReferences, ValidObject,
+    ObjectHeader, ObjectPointer, ObjectSize,
+};
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
+
+// Counting semaphore. Signals nobody waits for are counted, so that as many waits
+// go through without blocking. Blocked processes queue up, in the order they
+// waited, linked through their next and prev fields
+#[derive(Debug, ValidSmalltalkObject)]
+pub struct Semaphore {
+    header: ObjectHeader,
+    signals: u32,
+    first: Option<ObjectPointer>,
+    last: Option<ObjectPointer>,
+}
+
+impl ObjectReferences for Semaphore {
+    fn references(&self) -> Vec<ObjectPointer> {
+        [self.first, self.last]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.first = self.first.map(&mut *update);
+        self.last = self.last.map(&mut *update);
+    }
+}
+
+impl ImageObject for Semaphore {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.u32(self.signals)?;
+        writer.optional_pointer(self.first)?;
+        writer.optional_pointer(self.last)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Semaphore {
+            header: ObjectHeader::new(Self::SIZE),
+            signals: reader.u32()?,
+            first: reader.optional_pointer()?,
+            last: reader.optional_pointer()?,
+        })
+    }
+}
+
+impl Semaphore {
+    const SIZE: ObjectSize = SEMAPHORESIZE;
+
+    pub fn new(signals: u32) -> Self {
+        Semaphore {
+            header: ObjectHeader::new(Self::SIZE),
+            signals,
+            first: None,
+            last: None,
+        }
+    }
+
+    pub fn signals(&self) -> u32 {
+        self.signals
+    }
+
+    pub fn set_signals(&mut self, signals: u32) {
+        self.signals = signals;
+    }
+
+    // First process waiting
+    pub fn first(&self) -> Option<ObjectPointer> {
+        self.first
+    }
+
+    pub fn set_first(&mut self, first: Option<ObjectPointer>) {
+        self.first = first;
+    }
+
+    pub fn last(&self) -> Option<ObjectPointer> {
+        self.last
+    }
+
+    pub fn set_last(&mut self, last: Option<ObjectPointer>) {
+        self.last = last;
+    }
+}
@@ -1,12 +1,64 @@
+use std::cell::OnceCell;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use proc_macros::ValidSmalltalkObject;
+
 use super::object::{
     STRINGSIZE,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectPointer, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
+// Strings and characters
+//
+// Strings are sequences of characters, which are Unicode code points (see Char).
+// They are indexed by character, never by byte, so at: answers the same character
+// whatever the text is written in. Bytes are ByteArrays, and going from one to the
+// other always goes through an explicit encoding, which fails rather than guess
+// when the text can't be represented.
+//
+// Strings are stored as UTF-8. Finding a character by its index would mean scanning
+// the string, so the byte offset of every character is computed on first use and
+// kept along with it. Strings that are all ASCII, the most common ones, don't need
+// the offsets: there the index of a character is that of its byte.
+
+#[derive(Debug, ValidSmalltalkObject)]
 pub struct StringObject {
     header: ObjectHeader,
     super_obj: ObjectPointer,
     value: String,
+    index: OnceCell<CharIndex>,
+}
+
+#[derive(Debug)]
+enum CharIndex {
+    Ascii,
+    // Byte offset of each character, followed by the length of the string
+    Offsets(Vec<usize>),
+}
+
+impl ObjectReferences for StringObject {
+    fn references(&self) -> Vec<ObjectPointer> {
+        vec![self.super_obj]
+    }
+
+    fn update_references(&mut self, update: &mut dyn FnMut(ObjectPointer) -> ObjectPointer) {
+        self.super_obj = update(self.super_obj);
+    }
+}
+
+impl ImageObject for StringObject {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.pointer(self.super_obj)?;
+        writer.str(&self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        let super_obj = reader.pointer()?;
+        Ok(StringObject::new(reader.string()?, super_obj))
+    }
 }
 
 impl StringObject {
@@ -17,6 +69,152 @@ impl StringObject {
             header: ObjectHeader::new(Self::SIZE),
             super_obj,
             value,
+            index: OnceCell::new(),
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    // Number of characters
+    pub fn size(&self) -> usize {
+        match self.index() {
+            CharIndex::Ascii => self.value.len(),
+            CharIndex::Offsets(offsets) => offsets.len() - 1,
+        }
+    }
+
+    // Character at a zero based index
+    pub fn at(&self, index: usize) -> Option<char> {
+        self.value[self.byte_range(index, index + 1)?].chars().next()
+    }
+
+    // Replaces the character at a zero based index. Returns the one replaced.
+    // Characters as long in UTF-8 as the one they replace are written in place, the
+    // others shift the rest of the string, and the offsets are computed again
+    pub fn at_put(&mut self, index: usize, value: char) -> Option<char> {
+        let range = self.byte_range(index, index + 1)?;
+        let old = self.value[range.clone()].chars().next()?;
+        if old.len_utf8() != value.len_utf8() {
+            self.index.take();
         }
+        self.value.replace_range(range, value.encode_utf8(&mut [0; 4]));
+        Some(old)
+    }
+
+    // Characters from a zero based index, up to another one (excluded)
+    pub fn slice(&self, start: usize, end: usize) -> Option<&str> {
+        Some(&self.value[self.byte_range(start, end)?])
+    }
+
+    fn byte_range(&self, start: usize, end: usize) -> Option<Range<usize>> {
+        if start > end || end > self.size() {
+            return None;
+        }
+
+        match self.index() {
+            CharIndex::Ascii => Some(start..end),
+            CharIndex::Offsets(offsets) => Some(offsets[start]..offsets[end]),
+        }
+    }
+
+    fn index(&self) -> &CharIndex {
+        self.index.get_or_init(|| {
+            if self.value.is_ascii() {
+                CharIndex::Ascii
+            } else {
+                let offsets = self.value.char_indices()
+                    .map(|(offset, _)| offset)
+                    .chain([self.value.len()])
+                    .collect();
+                CharIndex::Offsets(offsets)
+            }
+        })
+    }
+}
+
+// Ways of writing strings as bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    // ISO 8859-1: each byte is the character with the same code, up to 255
+    Latin1,
+    Ascii,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "utf8" => Some(Encoding::Utf8),
+            "latin1" => Some(Encoding::Latin1),
+            "ascii" => Some(Encoding::Ascii),
+            _ => None,
+        }
+    }
+
+    // None when some character has no encoding
+    pub fn encode(self, value: &str) -> Option<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Some(value.as_bytes().to_vec()),
+            Encoding::Latin1 => value.chars().map(|c| u8::try_from(c).ok()).collect(),
+            Encoding::Ascii => value.is_ascii().then(|| value.as_bytes().to_vec()),
+        }
+    }
+
+    // None when the bytes aren't valid in the encoding
+    pub fn decode(self, bytes: &[u8]) -> Option<String> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            Encoding::Latin1 => Some(bytes.iter().map(|&byte| char::from(byte)).collect()),
+            Encoding::Ascii => bytes.is_ascii().then(|| String::from_utf8_lossy(bytes).into_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::object::Pointer;
+
+    #[test]
+    fn test_string_indexing() {
+        let mut string = StringObject::new("abc".to_string(), ObjectPointer::null());
+        assert_eq!(string.size(), 3);
+        assert_eq!(string.at(2), Some('c'));
+        assert_eq!(string.at(3), None);
+
+        // No longer ASCII
+        assert_eq!(string.at_put(1, 'é'), Some('b'));
+        assert_eq!(string.value(), "aéc");
+        assert_eq!(string.size(), 3);
+        assert_eq!(string.at(2), Some('c'));
+        assert_eq!(string.at_put(0, 'λ'), Some('a'));
+        assert_eq!(string.at_put(2, '€'), Some('c'));
+        assert_eq!(string.slice(1, 3), Some("é€"));
+        assert_eq!(string.slice(2, 4), None);
+        assert_eq!(string.slice(2, 1), None);
+
+        // Back to ASCII
+        string.at_put(0, 'x');
+        string.at_put(1, 'y');
+        string.at_put(2, 'z');
+        assert_eq!(string.value(), "xyz");
+        assert!(matches!(string.index(), CharIndex::Ascii));
+        assert_eq!(string.slice(0, 2), Some("xy"));
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        assert_eq!(Encoding::Utf8.encode("é"), Some(vec![0xc3, 0xa9]));
+        assert_eq!(Encoding::Latin1.encode("é"), Some(vec![0xe9]));
+        assert_eq!(Encoding::Latin1.encode("€"), None);
+        assert_eq!(Encoding::Ascii.encode("é"), None);
+
+        assert_eq!(Encoding::Utf8.decode(&[0xc3, 0xa9]).as_deref(), Some("é"));
+        assert_eq!(Encoding::Utf8.decode(&[0xe9]), None);
+        assert_eq!(Encoding::Latin1.decode(&[0xe9]).as_deref(), Some("é"));
+        assert_eq!(Encoding::Ascii.decode(&[0xe9]), None);
+        assert_eq!(Encoding::from_name("ebcdic"), None);
     }
 }
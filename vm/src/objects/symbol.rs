@@ -1,10 +1,13 @@
+use std::io::{Read, Write};
+
 use proc_macros::ValidSmalltalkObject;
 
 use super::object::{
     SYMBOLSIZE,
-    ValidObject,
+    ObjectReferences, ValidObject,
     ObjectHeader, ObjectSize,
 };
+use crate::image::{ImageError, ImageObject, ImageReader, ImageWriter};
 
 #[derive(Debug, ValidSmalltalkObject)]
 pub struct Symbol {
@@ -12,6 +15,18 @@ pub struct Symbol {
     value: String,
 }
 
+impl ObjectReferences for Symbol {}
+
+impl ImageObject for Symbol {
+    fn write_fields<W: Write>(&self, writer: &mut ImageWriter<W>) -> Result<(), ImageError> {
+        writer.str(&self.value)
+    }
+
+    fn read_fields<R: Read>(reader: &mut ImageReader<R>) -> Result<Self, ImageError> {
+        Ok(Symbol::new(reader.string()?))
+    }
+}
+
 impl Symbol {
     const SIZE: ObjectSize = SYMBOLSIZE;
 
@@ -21,6 +36,10 @@ impl Symbol {
             value,
         }
     }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
 }
 
 impl PartialEq for Symbol {